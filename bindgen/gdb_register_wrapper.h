@@ -147,6 +147,78 @@ enum GdbRegisterNum {
   DREG_64_YMM13H,
   DREG_64_YMM14H,
   DREG_64_YMM15H,
+  DREG_64_XMM16,
+  DREG_64_XMM17,
+  DREG_64_XMM18,
+  DREG_64_XMM19,
+  DREG_64_XMM20,
+  DREG_64_XMM21,
+  DREG_64_XMM22,
+  DREG_64_XMM23,
+  DREG_64_XMM24,
+  DREG_64_XMM25,
+  DREG_64_XMM26,
+  DREG_64_XMM27,
+  DREG_64_XMM28,
+  DREG_64_XMM29,
+  DREG_64_XMM30,
+  DREG_64_XMM31,
+  DREG_64_YMM16H,
+  DREG_64_YMM17H,
+  DREG_64_YMM18H,
+  DREG_64_YMM19H,
+  DREG_64_YMM20H,
+  DREG_64_YMM21H,
+  DREG_64_YMM22H,
+  DREG_64_YMM23H,
+  DREG_64_YMM24H,
+  DREG_64_YMM25H,
+  DREG_64_YMM26H,
+  DREG_64_YMM27H,
+  DREG_64_YMM28H,
+  DREG_64_YMM29H,
+  DREG_64_YMM30H,
+  DREG_64_YMM31H,
+  DREG_64_K0,
+  DREG_64_K1,
+  DREG_64_K2,
+  DREG_64_K3,
+  DREG_64_K4,
+  DREG_64_K5,
+  DREG_64_K6,
+  DREG_64_K7,
+  DREG_64_ZMM0H,
+  DREG_64_ZMM1H,
+  DREG_64_ZMM2H,
+  DREG_64_ZMM3H,
+  DREG_64_ZMM4H,
+  DREG_64_ZMM5H,
+  DREG_64_ZMM6H,
+  DREG_64_ZMM7H,
+  DREG_64_ZMM8H,
+  DREG_64_ZMM9H,
+  DREG_64_ZMM10H,
+  DREG_64_ZMM11H,
+  DREG_64_ZMM12H,
+  DREG_64_ZMM13H,
+  DREG_64_ZMM14H,
+  DREG_64_ZMM15H,
+  DREG_64_ZMM16H,
+  DREG_64_ZMM17H,
+  DREG_64_ZMM18H,
+  DREG_64_ZMM19H,
+  DREG_64_ZMM20H,
+  DREG_64_ZMM21H,
+  DREG_64_ZMM22H,
+  DREG_64_ZMM23H,
+  DREG_64_ZMM24H,
+  DREG_64_ZMM25H,
+  DREG_64_ZMM26H,
+  DREG_64_ZMM27H,
+  DREG_64_ZMM28H,
+  DREG_64_ZMM29H,
+  DREG_64_ZMM30H,
+  DREG_64_ZMM31H,
   DREG_NUM_LINUX_X86_64,
   // Last register we can find in user_regs_struct (except for orig_rax).
   DREG_64_NUM_USER_REGS = DREG_64_GS + 1,
@@ -1,6 +1,7 @@
 // enum from rr-debugger/rr ( https://github.com/rr-debugger/rr ) src/GdbConnection.h
 // enum as of rr-debugger/rr git revision abd344288878c9b4046e0b8664927992947a46eb
 // Also DREQ_RR_CMD -> DREQ_RD_CMD
+// Also DREQ_GET_LIBRARIES_SVR4, which rr doesn't have
 enum GdbRequestType {
   DREQ_NONE = 0,
 
@@ -83,5 +84,9 @@ enum GdbRequestType {
   DREQ_FILE_PREAD,
   // vFile:close packet, uses params.file_close.
   DREQ_FILE_CLOSE,
+
+  // qXfer:libraries-svr4:read packet, uses params.target. Last so that it
+  // doesn't renumber rr's requests.
+  DREQ_GET_LIBRARIES_SVR4,
 };
 
@@ -1708,6 +1708,12 @@ fsconfig = UnsupportedSyscall(x86=431, x64=431)
 fsmount = UnsupportedSyscall(x86=432, x64=432)
 fspick = UnsupportedSyscall(x86=433, x64=433)
 
+#  int epoll_pwait2(int epfd, struct epoll_event *events, int maxevents,
+#                   const struct timespec *timeout, const sigset_t *sigmask);
+#
+# Like epoll_pwait(), but the timeout is a timespec with nanosecond resolution.
+epoll_pwait2 = IrregularEmulatedSyscall(x86=441, x64=441)
+
 # restart_syscall is a little special.
 restart_syscall = RestartSyscall(x86=0, x64=219)
 
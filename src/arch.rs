@@ -536,6 +536,7 @@ pub trait Architecture: 'static + Default {
     const FSCONFIG: i32;
     const FSMOUNT: i32;
     const FSPICK: i32;
+    const EPOLL_PWAIT2: i32;
     const RDCALL_INIT_PRELOAD: i32;
     const RDCALL_INIT_BUFFERS: i32;
     const RDCALL_NOTIFY_SYSCALL_HOOK_EXIT: i32;
@@ -1122,13 +1123,14 @@ impl Architecture for X86Arch {
     const FSCONFIG: i32 = 431;
     const FSMOUNT: i32 = 432;
     const FSPICK: i32 = 433;
+    const EPOLL_PWAIT2: i32 = 441;
     const RDCALL_INIT_PRELOAD: i32 = 442;
     const RDCALL_INIT_BUFFERS: i32 = 443;
     const RDCALL_NOTIFY_SYSCALL_HOOK_EXIT: i32 = 444;
     const RDCALL_NOTIFY_CONTROL_MSG: i32 = 445;
     const RDCALL_RELOAD_AUXV: i32 = 446;
     const RDCALL_MPROTECT_RECORD: i32 = 447;
-    const VALID_SYSCALL_COUNT: i32 = 419;
+    const VALID_SYSCALL_COUNT: i32 = 420;
     const INVALID_SYSCALL_COUNT: i32 = 17;
     // End list from generate_syscalls.py. See above.
 
@@ -1718,13 +1720,14 @@ impl Architecture for X64Arch {
     const FSCONFIG: i32 = 431;
     const FSMOUNT: i32 = 432;
     const FSPICK: i32 = 433;
+    const EPOLL_PWAIT2: i32 = 441;
     const RDCALL_INIT_PRELOAD: i32 = 442;
     const RDCALL_INIT_BUFFERS: i32 = 443;
     const RDCALL_NOTIFY_SYSCALL_HOOK_EXIT: i32 = 444;
     const RDCALL_NOTIFY_CONTROL_MSG: i32 = 445;
     const RDCALL_RELOAD_AUXV: i32 = 446;
     const RDCALL_MPROTECT_RECORD: i32 = 447;
-    const VALID_SYSCALL_COUNT: i32 = 350;
+    const VALID_SYSCALL_COUNT: i32 = 351;
     const INVALID_SYSCALL_COUNT: i32 = 86;
     // End list from generate_syscalls.py. See above.
 
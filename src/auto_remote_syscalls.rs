@@ -532,12 +532,7 @@ impl<'a> AutoRemoteSyscalls<'a> {
                         MemParamsEnabled::DisableMemoryParams,
                     );
 
-                    rd_infallible_syscall!(
-                        remote,
-                        syscall_number_for_munmap(remote.arch()),
-                        self.fixed_sp.unwrap().as_usize() - 4096,
-                        4096
-                    );
+                    remote.infallible_munmap_syscall(self.fixed_sp.unwrap() - 4096, 4096);
                 }
 
                 if !self.replaced_bytes.is_empty() {
@@ -567,12 +562,7 @@ impl<'a> AutoRemoteSyscalls<'a> {
                         MemParamsEnabled::DisableMemoryParams,
                     );
 
-                    rd_infallible_syscall!(
-                        remote,
-                        syscall_number_for_munmap(remote.arch()),
-                        sp,
-                        4096
-                    );
+                    remote.infallible_munmap_syscall(RemotePtr::from(sp), 4096);
                 }
 
                 if !self.replaced_bytes.is_empty() {
@@ -676,6 +666,17 @@ impl<'a> AutoRemoteSyscalls<'a> {
         ret
     }
 
+    /// Unmap `length` bytes at `addr` in the tracee. The caller is
+    /// responsible for updating the AddressSpace.
+    pub fn infallible_munmap_syscall(&mut self, addr: RemotePtr<Void>, length: usize) {
+        rd_infallible_syscall!(
+            self,
+            syscall_number_for_munmap(self.arch()),
+            addr.as_usize(),
+            length
+        );
+    }
+
     /// Note: offset is signed.
     pub fn infallible_lseek_syscall(&mut self, fd: i32, offset: i64, whence: i32) -> isize {
         match self.arch() {
@@ -1172,12 +1173,7 @@ impl<'a> AutoRemoteSyscalls<'a> {
         }
 
         // Finally unmap the original segment
-        rd_infallible_syscall!(
-            remote2,
-            syscall_number_for_munmap(arch),
-            free_mem.as_usize(),
-            sz
-        );
+        remote2.infallible_munmap_syscall(free_mem, sz);
         remote2.vm().unmap(remote2.task(), free_mem, sz);
         true
     }
@@ -4,14 +4,21 @@ pub mod build_id_command;
 pub mod dump_command;
 pub mod env_command;
 pub mod exit_result;
+pub mod find_command;
 pub mod gdb_command;
 pub mod gdb_command_handler;
 pub mod gdb_server;
+pub mod gdb_session;
+pub mod gdb_stop_policy;
+pub mod json_output;
+pub mod map_diff_command;
+pub mod pack_command;
 pub mod ps_command;
 pub mod rd_options;
 pub mod record_command;
 pub mod replay_command;
 pub mod rerun_command;
+pub mod sources_command;
 pub mod trace_info_command;
 
 pub trait RdCommand {
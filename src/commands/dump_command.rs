@@ -1,6 +1,7 @@
 use super::exit_result::ExitResult;
 use crate::{
     commands::{
+        json_output::write_json,
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
@@ -10,16 +11,22 @@ use crate::{
     log::notifying_abort,
     preload_interface::{stored_record_size, syscallbuf_hdr, syscallbuf_record},
     session::address_space::kernel_mapping::KernelMapping,
+    ticks::Ticks,
     trace::{
+        trace_annotation::{is_annotation_frame, read_annotation_for_frame},
         trace_frame::{FrameTime, TraceFrame},
         trace_reader::{TraceReader, ValidateSourceFile},
         trace_stream,
-        trace_stream::{MappedData, MappedDataSource},
+        trace_stream::{FrameIndexCheckpoint, MappedData, MappedDataSource},
         trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
     },
+    util::get_num_cpus,
 };
+use libc::{dev_t, ino_t, pid_t};
 use nix::sys::mman::{MapFlags, ProtFlags};
+use serde::Serialize;
 use std::{
+    cmp::{max, min},
     collections::HashMap,
     ffi::OsString,
     io,
@@ -27,8 +34,11 @@ use std::{
     mem::size_of,
     os::unix::ffi::{OsStrExt, OsStringExt},
     path::PathBuf,
+    sync::Arc,
+    thread,
 };
 
+#[derive(Clone)]
 pub struct DumpCommand {
     pub dump_syscallbuf: bool,
     pub dump_task_events: bool,
@@ -39,6 +49,76 @@ pub struct DumpCommand {
     pub only_tid: Option<libc::pid_t>,
     pub trace_dir: Option<PathBuf>,
     pub event_spec: Option<(FrameTime, Option<FrameTime>)>,
+    /// How many threads may scan the trace at the same time.
+    pub jobs: usize,
+    pub json: bool,
+}
+
+/// A part of the trace that one thread scans.
+struct Shard {
+    /// Where to start reading. `None` means the beginning of the trace.
+    from: Option<FrameIndexCheckpoint>,
+    /// The last frame time that belongs to this shard.
+    end: FrameTime,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonFrame {
+    time: FrameTime,
+    tid: pid_t,
+    event: String,
+    ticks: Ticks,
+    monotonic_sec: f64,
+    ip: usize,
+    /// Only with `--syscallbuf`.
+    syscallbuf: Option<Vec<JsonSyscallbufRecord>>,
+    /// Only with `--task-events`.
+    task_event: Option<String>,
+    /// Only with `--mmaps`.
+    mmaps: Option<Vec<JsonMapping>>,
+    annotation: Option<String>,
+    /// Only with `--recorded-metadata`.
+    recorded_data: Option<Vec<JsonRecordedData>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonSyscallbufRecord {
+    syscall: String,
+    ret: i64,
+    size: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonMapping {
+    map_file: String,
+    addr: usize,
+    length: usize,
+    prot: i32,
+    flags: i32,
+    file_offset: u64,
+    device: dev_t,
+    inode: ino_t,
+    data_file: String,
+    data_offset: usize,
+    file_size: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonRecordedData {
+    tid: pid_t,
+    addr: usize,
+    length: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonStatistics {
+    uncompressed_bytes: u64,
+    compressed_bytes: u64,
 }
 
 impl DumpCommand {
@@ -52,6 +132,8 @@ impl DumpCommand {
                 raw_dump,
                 statistics,
                 only_tid,
+                jobs,
+                json,
                 trace_dir,
                 event_spec,
             } => DumpCommand {
@@ -64,6 +146,8 @@ impl DumpCommand {
                 only_tid,
                 trace_dir,
                 event_spec,
+                jobs: jobs.unwrap_or_else(|| get_num_cpus() as usize),
+                json,
             },
             _ => panic!("Unexpected RdSubCommand variant. Not a Dump variant!"),
         }
@@ -72,7 +156,7 @@ impl DumpCommand {
     pub fn dump(&self, f: &mut dyn Write) -> io::Result<()> {
         let mut trace = TraceReader::new(self.trace_dir.as_ref());
 
-        if self.raw_dump {
+        if self.raw_dump && !self.json {
             writeln!(
                 f,
                 "global_time tid reason ticks \
@@ -81,9 +165,32 @@ impl DumpCommand {
             )?;
         }
 
-        self.dump_events_matching(&mut trace, f)?;
+        let (start, end): (FrameTime, FrameTime) = match self.event_spec {
+            None => (0, FrameTime::MAX),
+            Some((s, None)) => (s, s),
+            Some((s, Some(e))) => (s, e),
+        };
+        let task_events = read_task_events(&mut trace);
+        let shards = self.shards(&trace.frame_index(), start, end);
+        if shards.len() > 1 {
+            self.dump_shards(&trace, shards, task_events, start, f)?;
+        } else {
+            if let Some(checkpoint) = shards[0].from.as_ref() {
+                trace.seek_to_checkpoint(checkpoint);
+            }
+            self.dump_events_matching(&mut trace, &task_events, start, end, f)?;
+        }
 
         if self.statistics {
+            if self.json {
+                return write_json(
+                    f,
+                    &JsonStatistics {
+                        uncompressed_bytes: trace.uncompressed_bytes(),
+                        compressed_bytes: trace.compressed_bytes(),
+                    },
+                );
+            }
             return self.dump_statistics(&mut trace, f);
         }
 
@@ -103,43 +210,100 @@ impl DumpCommand {
         )
     }
 
-    /// Dump all events from the current to trace that match `self.event_spec` to `f`.
-    ///
-    /// This function is side-effect-y, in that the trace file isn't
-    /// rewound in between matching each spec.  Therefore specs should be
-    /// constructed so as to match properly on a serial linear scan; that
-    /// is, they should comprise disjoint and monotonically increasing
-    /// event sets.  No attempt is made to enforce this or normalize specs.
-    fn dump_events_matching(&self, trace: &mut TraceReader, f: &mut dyn Write) -> io::Result<()> {
-        let (start, end): (FrameTime, FrameTime) = match self.event_spec {
-            None => (0, FrameTime::MAX),
-            Some((s, None)) => (s, s),
-            Some((s, Some(e))) => (s, e),
-        };
+    /// Split the frames in `start..=end` into at most `self.jobs` shards, each
+    /// starting at one of the index `checkpoints`. Without an index, there
+    /// is a single shard covering the whole trace.
+    fn shards(
+        &self,
+        checkpoints: &[FrameIndexCheckpoint],
+        start: FrameTime,
+        end: FrameTime,
+    ) -> Vec<Shard> {
+        // The last checkpoint at or before `start`, followed by every
+        // checkpoint inside the range.
+        let first = checkpoints.iter().rposition(|c| c.time <= start);
+        let mut starts: Vec<Option<FrameIndexCheckpoint>> = vec![first.map(|i| checkpoints[i])];
+        starts.extend(
+            checkpoints
+                .iter()
+                .filter(|c| start < c.time && c.time <= end)
+                .map(|c| Some(*c)),
+        );
 
-        let mut task_events: HashMap<FrameTime, TraceTaskEvent> = HashMap::new();
-        let mut last_time: FrameTime = 0;
-        loop {
-            let mut the_time: FrameTime = 0;
-            let maybe_r = trace.read_task_event(Some(&mut the_time));
-            if maybe_r.is_none() {
-                break;
+        let count = max(1, min(self.jobs, starts.len()));
+        let mut shards: Vec<Shard> = Vec::with_capacity(count);
+        let mut shard_end = end;
+        for i in (0..count).rev() {
+            let from = starts[i * starts.len() / count];
+            shards.push(Shard {
+                from,
+                end: shard_end,
+            });
+            // Only the first shard can start at the beginning of the trace.
+            if let Some(checkpoint) = from {
+                shard_end = checkpoint.time - 1;
             }
+        }
+        shards.reverse();
+        shards
+    }
 
-            if the_time < last_time {
-                fatal!(
-                    "TraceTaskEvent times non-monotonic (time:{}, last time:{})",
-                    the_time,
-                    last_time
-                );
-            }
+    /// Scan every shard on its own thread and write their output to `f` in
+    /// trace order.
+    ///
+    /// Each shard's output is buffered until the shards before it are done.
+    fn dump_shards(
+        &self,
+        trace: &TraceReader,
+        shards: Vec<Shard>,
+        task_events: HashMap<FrameTime, TraceTaskEvent>,
+        start: FrameTime,
+        f: &mut dyn Write,
+    ) -> io::Result<()> {
+        let trace_dir = PathBuf::from(trace.trace_stream().dir());
+        let task_events = Arc::new(task_events);
+        let mut threads = Vec::with_capacity(shards.len());
+        for shard in shards {
+            let cmd = self.clone();
+            let trace_dir = trace_dir.clone();
+            let task_events = task_events.clone();
+            threads.push(thread::spawn(move || -> io::Result<Vec<u8>> {
+                // Trace readers aren't Send so every thread opens its own.
+                let mut trace = TraceReader::new(Some(&trace_dir));
+                if let Some(checkpoint) = shard.from.as_ref() {
+                    trace.seek_to_checkpoint(checkpoint);
+                }
+                let mut out = Vec::new();
+                cmd.dump_events_matching(&mut trace, &task_events, start, shard.end, &mut out)?;
+                Ok(out)
+            }));
+        }
 
-            let r = maybe_r.unwrap();
-            task_events.insert(the_time, r);
-            last_time = the_time;
+        for t in threads {
+            match t.join() {
+                Ok(result) => f.write_all(&result?)?,
+                Err(_) => fatal!("Trace scanning thread panicked"),
+            }
         }
+        Ok(())
+    }
 
-        let process_raw_data = self.dump_syscallbuf || self.dump_recorded_data_metadata;
+    /// Dump all events from the current position in the trace with times in
+    /// `start..=end` to `f`.
+    ///
+    /// This function is side-effect-y, in that the trace file isn't
+    /// rewound in between matching each spec.  Therefore specs should be
+    /// constructed so as to match properly on a serial linear scan; that
+    /// is, they should comprise disjoint and monotonically increasing
+    /// event sets.  No attempt is made to enforce this or normalize specs.
+    fn dump_events_matching(
+        &self,
+        trace: &mut TraceReader,
+        task_events: &HashMap<FrameTime, TraceTaskEvent>,
+        start: FrameTime,
+        end: FrameTime,
+        f: &mut dyn Write,
+    ) -> io::Result<()> {
         while !trace.at_end() {
             let frame = trace.read_frame();
             if end < frame.time() {
@@ -149,6 +313,10 @@ impl DumpCommand {
                 && frame.time() <= end
                 && (self.only_tid.is_none() || self.only_tid.unwrap() == frame.tid())
             {
+                if self.json {
+                    self.dump_frame_json(trace, task_events, &frame, f)?;
+                    continue;
+                }
                 if self.raw_dump {
                     frame.dump_raw(Some(f))?;
                 } else {
@@ -253,6 +421,11 @@ impl DumpCommand {
                     }
                 }
 
+                if is_annotation_frame(&frame) {
+                    if let Some(annotation) = read_annotation_for_frame(trace, &frame) {
+                        writeln!(f, "  {{ annotation:{:?} }}", annotation.name)?;
+                    }
+                }
                 while let Some(data) = trace.read_raw_data_metadata_for_frame() {
                     if self.dump_recorded_data_metadata {
                         // DIFF NOTE rr prints `(nil)` if addr is 0 or length is 0.
@@ -282,13 +455,116 @@ impl DumpCommand {
                         break;
                     }
                 }
-                while process_raw_data && trace.read_raw_data_metadata_for_frame().is_some() {}
+                // Keep the raw data stream in step with the frames. Annotations are
+                // read from it.
+                while trace.read_raw_data_metadata_for_frame().is_some() {}
             }
         }
         Ok(())
     }
 }
 
+impl DumpCommand {
+    /// Write `frame`, and whatever else was asked for that belongs to it, as
+    /// one line of JSON.
+    fn dump_frame_json(
+        &self,
+        trace: &mut TraceReader,
+        task_events: &HashMap<FrameTime, TraceTaskEvent>,
+        frame: &TraceFrame,
+        f: &mut dyn Write,
+    ) -> io::Result<()> {
+        let syscallbuf = if self.dump_syscallbuf {
+            let records = unsafe { syscallbuf_records(trace, frame) };
+            Some(
+                records
+                    .into_iter()
+                    .map(|(syscall, ret, size)| JsonSyscallbufRecord { syscall, ret, size })
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let task_event = if self.dump_task_events {
+            task_events.get(&frame.time()).map(|e| {
+                let mut out = Vec::new();
+                dump_task_event(&mut out, e).unwrap();
+                String::from_utf8_lossy(&out).trim().to_owned()
+            })
+        } else {
+            None
+        };
+
+        let mut mmaps = Vec::new();
+        loop {
+            let mut data = MappedData::default();
+            let km = match trace.read_mapped_region(
+                Some(&mut data),
+                Some(ValidateSourceFile::DontValidate),
+                None,
+                None,
+                None,
+            ) {
+                Some(km) => km,
+                None => break,
+            };
+            mmaps.push(JsonMapping {
+                map_file: if data.source == MappedDataSource::Zero {
+                    "<ZERO>".into()
+                } else {
+                    km.fsname().to_string_lossy().into_owned()
+                },
+                addr: km.start().as_usize(),
+                length: km.len(),
+                prot: km.prot().bits(),
+                flags: km.flags().bits(),
+                file_offset: km.file_offset_bytes(),
+                device: km.device(),
+                inode: km.inode(),
+                data_file: data.filename.to_string_lossy().into_owned(),
+                data_offset: data.data_offset_bytes,
+                file_size: data.file_size_bytes,
+            });
+        }
+
+        let annotation = if is_annotation_frame(frame) {
+            read_annotation_for_frame(trace, frame)
+                .map(|annotation| annotation.name.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+        let mut recorded_data = Vec::new();
+        while let Some(data) = trace.read_raw_data_metadata_for_frame() {
+            recorded_data.push(JsonRecordedData {
+                tid: data.rec_tid,
+                addr: data.addr.as_usize(),
+                length: data.size,
+            });
+        }
+
+        write_json(
+            f,
+            &JsonFrame {
+                time: frame.time(),
+                tid: frame.tid(),
+                event: frame.event().to_string(),
+                ticks: frame.ticks(),
+                monotonic_sec: frame.monotonic_time(),
+                ip: frame.regs_ref().ip().register_value() as usize,
+                syscallbuf,
+                task_event,
+                mmaps: if self.dump_mmaps { Some(mmaps) } else { None },
+                annotation,
+                recorded_data: if self.dump_recorded_data_metadata {
+                    Some(recorded_data)
+                } else {
+                    None
+                },
+            },
+        )
+    }
+}
+
 impl RdCommand for DumpCommand {
     fn run(&mut self) -> ExitResult<()> {
         match self.dump(&mut stdout()) {
@@ -298,6 +574,31 @@ impl RdCommand for DumpCommand {
     }
 }
 
+pub(super) fn read_task_events(trace: &mut TraceReader) -> HashMap<FrameTime, TraceTaskEvent> {
+    let mut task_events: HashMap<FrameTime, TraceTaskEvent> = HashMap::new();
+    let mut last_time: FrameTime = 0;
+    loop {
+        let mut the_time: FrameTime = 0;
+        let maybe_r = trace.read_task_event(Some(&mut the_time));
+        if maybe_r.is_none() {
+            break;
+        }
+
+        if the_time < last_time {
+            fatal!(
+                "TraceTaskEvent times non-monotonic (time:{}, last time:{})",
+                the_time,
+                last_time
+            );
+        }
+
+        let r = maybe_r.unwrap();
+        task_events.insert(the_time, r);
+        last_time = the_time;
+    }
+    task_events
+}
+
 fn dump_task_event(out: &mut dyn Write, event: &TraceTaskEvent) -> io::Result<()> {
     match event.event_variant() {
         TraceTaskEventVariant::Clone(ev) => {
@@ -332,8 +633,24 @@ unsafe fn dump_syscallbuf_data(
     out: &mut dyn Write,
     frame: &TraceFrame,
 ) -> io::Result<()> {
+    for (syscall, ret, size) in syscallbuf_records(trace, frame) {
+        writeln!(
+            out,
+            "  {{ syscall:'{}', ret:{:#x}, size:{:#x} }}",
+            syscall, ret, size
+        )?;
+    }
+    Ok(())
+}
+
+/// The name, result and size of every record in `frame`'s syscallbuf flush.
+unsafe fn syscallbuf_records(
+    trace: &mut TraceReader,
+    frame: &TraceFrame,
+) -> Vec<(String, i64, u32)> {
+    let mut records = Vec::new();
     if frame.event().event_type() != EventType::EvSyscallbufFlush {
-        return Ok(());
+        return records;
     }
     let buf = trace.read_raw_data();
     let mut bytes_remaining = (buf.data.len() - size_of::<syscallbuf_hdr>()) as u32;
@@ -349,18 +666,16 @@ unsafe fn dump_syscallbuf_data(
     while record_ptr.lt(&end_ptr) {
         let record = record_ptr as *const syscallbuf_record;
         // Buffered syscalls always use the task arch
-        writeln!(
-            out,
-            "  {{ syscall:'{}', ret:{:#x}, size:{:#x} }}",
+        records.push((
             syscall_name((*record).syscallno as i32, frame.regs_ref().arch()),
             (*record).ret,
-            (*record).size
-        )?;
+            (*record).size,
+        ));
         if ((*record).size as usize) < size_of::<syscallbuf_record>() {
             eprintln!("Malformed trace file (bad record size)");
             notifying_abort(backtrace::Backtrace::new());
         }
         record_ptr = record_ptr.add(stored_record_size((*record).size) as usize);
     }
-    Ok(())
+    records
 }
@@ -49,6 +49,7 @@ impl EnvCommand {
             redirect_stdio: false,
             share_private_mappings: false,
             cpu_unbound: self.cpu_unbound,
+            ignore_ticks_mismatch: false,
         }
     }
 
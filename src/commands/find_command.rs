@@ -0,0 +1,273 @@
+use super::{dump_command::read_task_events, exit_result::ExitResult};
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    event::SyscallState,
+    kernel_abi::SupportedArch,
+    kernel_metadata::{syscall_name, syscall_number_for_name},
+    remote_ptr::RemotePtr,
+    sig::Sig,
+    trace::{
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::{TraceReader, ValidateSourceFile},
+        trace_task_event::TraceTaskEventVariant,
+    },
+};
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    io::{self, stdout, Write},
+    path::PathBuf,
+};
+
+/// Syscalls whose first argument is an fd.
+const FD_ARG_SYSCALLS: [&str; 37] = [
+    "read",
+    "write",
+    "close",
+    "pread64",
+    "pwrite64",
+    "readv",
+    "writev",
+    "preadv",
+    "pwritev",
+    "preadv2",
+    "pwritev2",
+    "lseek",
+    "_llseek",
+    "ioctl",
+    "fcntl",
+    "fcntl64",
+    "fstat",
+    "fstat64",
+    "fsync",
+    "fdatasync",
+    "ftruncate",
+    "getdents",
+    "getdents64",
+    "dup",
+    "dup2",
+    "dup3",
+    "connect",
+    "bind",
+    "listen",
+    "accept",
+    "accept4",
+    "sendto",
+    "recvfrom",
+    "sendmsg",
+    "recvmsg",
+    "shutdown",
+    "epoll_ctl",
+];
+
+/// Syscalls that return a new fd.
+const FD_RESULT_SYSCALLS: [&str; 18] = [
+    "open",
+    "openat",
+    "creat",
+    "dup",
+    "dup2",
+    "dup3",
+    "socket",
+    "accept",
+    "accept4",
+    "eventfd",
+    "eventfd2",
+    "signalfd",
+    "signalfd4",
+    "timerfd_create",
+    "epoll_create",
+    "epoll_create1",
+    "memfd_create",
+    "perf_event_open",
+];
+
+pub struct FindCommand {
+    syscall: Option<String>,
+    path: Option<Regex>,
+    fd: Option<i32>,
+    signal: Option<Sig>,
+    address: Option<usize>,
+    only_tid: Option<libc::pid_t>,
+    trace_dir: Option<PathBuf>,
+    event_spec: Option<(FrameTime, Option<FrameTime>)>,
+}
+
+impl FindCommand {
+    pub fn new(options: &RdOptions) -> FindCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Find {
+                syscall,
+                path,
+                fd,
+                signal,
+                address,
+                only_tid,
+                trace_dir,
+                event_spec,
+            } => FindCommand {
+                syscall,
+                path,
+                fd,
+                signal,
+                address,
+                only_tid,
+                trace_dir,
+                event_spec,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Find` variant!"),
+        }
+    }
+}
+
+impl RdCommand for FindCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.find(&mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+impl FindCommand {
+    fn find(&self, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(name) = self.syscall.as_ref() {
+            if syscall_number_for_name(name, SupportedArch::X64).is_none()
+                && syscall_number_for_name(name, SupportedArch::X86).is_none()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown syscall `{}`", name),
+                ));
+            }
+        }
+
+        let mut trace = TraceReader::new(self.trace_dir.as_ref());
+        let (start, end): (FrameTime, FrameTime) = match self.event_spec {
+            None => (0, FrameTime::MAX),
+            Some((s, None)) => (s, s),
+            Some((s, Some(e))) => (s, e),
+        };
+        let task_events = if self.path.is_some() {
+            read_task_events(&mut trace)
+        } else {
+            HashMap::new()
+        };
+        // Skip straight to the last index checkpoint before the range.
+        if let Some(checkpoint) = trace.frame_index().iter().rev().find(|c| c.time <= start) {
+            trace.seek_to_checkpoint(checkpoint);
+        }
+
+        while !trace.at_end() {
+            let frame = trace.read_frame();
+            if end < frame.time() {
+                break;
+            }
+            // The mappings and raw data of every frame have to be read to keep
+            // the streams in step, even if we aren't interested in the frame.
+            let mut path_matched = false;
+            let mut address_matched = false;
+            while let Some(km) = trace.read_mapped_region(
+                None,
+                Some(ValidateSourceFile::DontValidate),
+                None,
+                None,
+                None,
+            ) {
+                path_matched |= self.path_matches(&km.fsname().to_string_lossy());
+                address_matched |= self
+                    .address
+                    .map_or(false, |addr| km.contains_ptr(RemotePtr::new(addr)));
+            }
+            while let Some(data) = trace.read_raw_data_metadata_for_frame() {
+                address_matched |= self.address.map_or(false, |addr| {
+                    data.addr.as_usize() <= addr && addr < data.addr.as_usize() + data.size
+                });
+            }
+            if let Some(TraceTaskEventVariant::Exec(exec)) =
+                task_events.get(&frame.time()).map(|e| e.event_variant())
+            {
+                path_matched |= self.path_matches(&exec.file_name().to_string_lossy());
+            }
+
+            if frame.time() < start
+                || self.only_tid.map_or(false, |tid| tid != frame.tid())
+                || (self.path.is_some() && !path_matched)
+                || (self.address.is_some() && !address_matched)
+                || !self.syscall_matches(&frame)
+                || !self.fd_matches(&frame)
+                || !self.signal_matches(&frame)
+            {
+                continue;
+            }
+            write_match(out, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn path_matches(&self, path: &str) -> bool {
+        self.path.as_ref().map_or(false, |re| re.is_match(path))
+    }
+
+    fn syscall_matches(&self, frame: &TraceFrame) -> bool {
+        let name = match self.syscall.as_ref() {
+            Some(name) => name,
+            None => return true,
+        };
+        let ev = frame.event();
+        ev.is_syscall_event()
+            && syscall_name(ev.syscall_event().number, ev.syscall_event().arch()) == *name
+    }
+
+    fn fd_matches(&self, frame: &TraceFrame) -> bool {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return true,
+        };
+        let ev = frame.event();
+        if !ev.is_syscall_event() {
+            return false;
+        }
+        let name = syscall_name(ev.syscall_event().number, ev.syscall_event().arch());
+        let regs = frame.regs_ref();
+        match ev.syscall_event().state {
+            SyscallState::EnteringSyscall => {
+                FD_ARG_SYSCALLS.contains(&name.as_str()) && regs.arg1() as i32 == fd
+            }
+            SyscallState::ExitingSyscall => {
+                FD_RESULT_SYSCALLS.contains(&name.as_str())
+                    && !regs.syscall_failed()
+                    && regs.syscall_result_signed() == fd as isize
+            }
+            _ => false,
+        }
+    }
+
+    fn signal_matches(&self, frame: &TraceFrame) -> bool {
+        let sig = match self.signal {
+            Some(sig) => sig,
+            None => return true,
+        };
+        let ev = frame.event();
+        ev.is_signal_event() && ev.signal_event().siginfo.si_signo == sig.as_raw()
+    }
+}
+
+fn write_match(out: &mut dyn Write, frame: &TraceFrame) -> io::Result<()> {
+    let ev = frame.event();
+    if ev.is_syscall_event() {
+        writeln!(
+            out,
+            "{} tid {} {} {}",
+            frame.time(),
+            frame.tid(),
+            ev,
+            ev.syscall_event().state
+        )
+    } else {
+        writeln!(out, "{} tid {} {}", frame.time(), frame.tid(), ev)
+    }
+}
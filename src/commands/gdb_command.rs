@@ -1,17 +1,33 @@
 use super::gdb_command_handler::GdbCommandHandler;
 use crate::{
-    commands::gdb_server::{Checkpoint, ExplicitCheckpoint, GdbServer},
-    replay_timeline::Mark,
-    session::task::Task,
+    commands::{
+        gdb_server::{Checkpoint, ExplicitCheckpoint, GdbServer},
+        gdb_session::write_checkpoints,
+    },
+    gdb_connection::GdbThreadId,
+    kernel_metadata::syscall_number_for_name,
+    remote_code_ptr::RemoteCodePtr,
+    remote_ptr::RemotePtr,
+    replay_notifier::{self, Notification},
+    session::{
+        address_space::memory_range::MemoryRange, replay_session::ReplayStatus,
+        session_inner::RunCommand, task::Task,
+    },
+    ticks::Ticks,
+    trace::{
+        trace_annotation::{read_annotations, TraceAnnotation},
+        trace_frame::FrameTime,
+    },
+    util::{checksum_memory_range, recorded_checksum, str16_to_usize, MAX_CHECKSUM_RANGE},
 };
+use libc::pid_t;
 use std::{
     collections::HashMap,
     ffi::{OsStr, OsString},
     io::Write,
     ops::{Deref, DerefMut},
     os::unix::ffi::{OsStrExt, OsStringExt},
-    str,
-    sync::atomic::{AtomicU64, Ordering},
+    path::Path,
 };
 
 /// DIFF NOTE: Simply called GdbCommand in rr
@@ -19,6 +35,7 @@ pub struct BaseGdbCommand {
     cmd_name: String,
     documentation: String,
     cmd_auto_args: Vec<OsString>,
+    moves_replay: bool,
 }
 
 impl BaseGdbCommand {
@@ -41,12 +58,34 @@ impl BaseGdbCommand {
         &self.cmd_auto_args
     }
 
+    /// Whether the command can move the replay to a different point. gdb
+    /// doesn't know when this happens inside a command, so its wrapper flushes
+    /// gdb's register and frame caches afterwards.
+    pub fn moves_replay(&self) -> bool {
+        self.moves_replay
+    }
+
     /// Setup all the automatic auto_args for our commands.
     pub fn init_auto_args() {
         gdb_command_map_mut()
             .get_mut("checkpoint")
             .unwrap()
             .add_auto_arg(&OsString::from("rd-where"));
+        gdb_command_map_mut()
+            .get_mut("rd-divert-env")
+            .unwrap()
+            .add_auto_arg(&OsString::from("rd-environ-address"));
+        for name in &[
+            "back",
+            "forward",
+            "rd-history",
+            "seek-annotation",
+            "seek-checkpoint",
+            "seek-to-event",
+            "step-until-ticks",
+        ] {
+            gdb_command_map_mut().get_mut(*name).unwrap().moves_replay = true;
+        }
     }
 }
 
@@ -58,9 +97,9 @@ pub trait GdbCommand: DerefMut<Target = BaseGdbCommand> {
     fn invoke(&self, gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString;
 }
 
-type InvokerFn = dyn Fn(&mut GdbServer, &dyn Task, &[OsString]) -> OsString;
+pub type InvokerFn = dyn Fn(&mut GdbServer, &dyn Task, &[OsString]) -> OsString;
 
-struct SimpleGdbCommand {
+pub struct SimpleGdbCommand {
     base_gdb_command: BaseGdbCommand,
     invoker: &'static InvokerFn,
 }
@@ -79,6 +118,7 @@ impl SimpleGdbCommand {
                 cmd_name,
                 documentation: documentation.to_owned(),
                 cmd_auto_args: Default::default(),
+                moves_replay: false,
             },
             invoker,
         }
@@ -128,6 +168,16 @@ pub(super) fn gdb_command_map_mut() -> &'static mut GdbCommandMap {
     }
 }
 
+/// Make `cmd` available to gdb, in addition to the commands defined here.
+/// This has to happen before the gdb macros are generated (i.e. before a
+/// debugger connects) for gdb to know about the command.
+pub fn register_gdb_command(cmd: Box<dyn GdbCommand>) {
+    let name = cmd.name().to_owned();
+    if gdb_command_map_mut().insert(name.clone(), cmd).is_some() {
+        fatal!("gdb command {:?} registered twice", name);
+    }
+}
+
 fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
     let mut command_list: HashMap<String, Box<dyn GdbCommand>> = HashMap::new();
 
@@ -164,6 +214,49 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         )),
     );
 
+    command_list.insert(
+        String::from("when-position"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("when-position"),
+            "Print the current rd event number, the tick count of the current thread and the\n\
+                            direction of the last resume, as <event>,<ticks>,<forward|reverse>.",
+            &when_position,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-convenience-variables"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-convenience-variables"),
+            "Print the values of $_rd_event, $_rd_ticks and $_rd_tid for the current thread.\n\
+                            gdb refreshes these convenience variables every time the program stops.",
+            &convenience_variables,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-divert-env"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-divert-env"),
+            "Change the environment that functions run with 'call' or 'print' see, e.g.\n\
+                            'rd-divert-env DEBUG=1'. 'rd-divert-env -u NAME' unsets NAME and\n\
+                            'rd-divert-env --clear' drops all changes. The replay is not affected.\n\
+                            With no arguments, list the argv and environment changes.",
+            &rd_divert_env,
+        )),
+    );
+
+    command_list.insert(
+        String::from("rd-divert-arg"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-divert-arg"),
+            "Change argv[<n>] as seen by functions run with 'call' or 'print', e.g.\n\
+                            'rd-divert-arg 1 --verbose'. 'rd-divert-arg --clear' drops all changes.\n\
+                            The replay is not affected.",
+            &rd_divert_arg,
+        )),
+    );
+
     command_list.insert(
         String::from("rd-history-push"),
         Box::new(SimpleGdbCommand::new(
@@ -173,6 +266,17 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         )),
     );
 
+    command_list.insert(
+        String::from("rd-history"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("rd-history"),
+            "List the replay positions visited so far, marking the current one with '*'.\n\
+                            'rd-history <n>' jumps to entry <n>. Entries are added every time\n\
+                            the program stops; use 'back' and 'forward' to step through them.",
+            &rd_history,
+        )),
+    );
+
     command_list.insert(
         String::from("back"),
         Box::new(SimpleGdbCommand::new(
@@ -196,6 +300,7 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         Box::new(SimpleGdbCommand::new(
             String::from("checkpoint"),
             "create a checkpoint representing a point in the execution\n\
+                            an optional name can be given, e.g. 'checkpoint before_parse'\n\
                             use the 'restart' command to return to the checkpoint",
             &invoke_checkpoint,
         )),
@@ -205,11 +310,20 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         String::from("delete checkpoint"),
         Box::new(SimpleGdbCommand::new(
             String::from("delete checkpoint"),
-            "remove a checkpoint created with the 'checkpoint' command",
+            "remove a checkpoint created with the 'checkpoint' command, by id or name",
             &invoke_delete_checkpoint,
         )),
     );
 
+    command_list.insert(
+        String::from("seek-checkpoint"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("seek-checkpoint"),
+            "move the replay to a checkpoint created with the 'checkpoint' command, by id or name",
+            &invoke_seek_checkpoint,
+        )),
+    );
+
     command_list.insert(
         String::from("info checkpoints"),
         Box::new(SimpleGdbCommand::new(
@@ -219,6 +333,121 @@ fn gdb_command_map_init() -> HashMap<String, Box<dyn GdbCommand>> {
         )),
     );
 
+    command_list.insert(
+        String::from("save-checkpoints"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("save-checkpoints"),
+            "write the gdb commands that recreate the current checkpoints to a file\n\
+                            usage: save-checkpoints <file>\n\
+                            run 'source <file>' in a later session of the same trace to restore them",
+            &save_checkpoints,
+        )),
+    );
+
+    command_list.insert(
+        String::from("step-until-ticks"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("step-until-ticks"),
+            "run forward until a thread has retired the given number of ticks\n\
+                            usage: step-until-ticks <ticks> [<tid>]\n\
+                            the current thread is used if no tid is given",
+            &step_until_ticks,
+        )),
+    );
+
+    command_list.insert(
+        String::from("checksum"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("checksum"),
+            "checksum a memory range of the current thread and compare it to the recording\n\
+                            usage: checksum <start>-<end>\n\
+                            a recorded checksum exists only if the trace was recorded with\n\
+                            --checksum and the range is exactly one mapping",
+            &invoke_checksum,
+        )),
+    );
+
+    command_list.insert(
+        String::from("info annotations"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("info annotations"),
+            "list all annotations the tracee wrote to RD_MAGIC_ANNOTATE_FD during recording",
+            &invoke_info_annotations,
+        )),
+    );
+
+    command_list.insert(
+        String::from("seek-annotation"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("seek-annotation"),
+            "move the replay to just before the first annotation with the given name",
+            &invoke_seek_annotation,
+        )),
+    );
+
+    command_list.insert(
+        String::from("seek-to-event"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("seek-to-event"),
+            "move the replay to the start of the given event\n\
+                            usage: seek-to-event <event>\n\
+                            the event numbers are the ones 'when' prints",
+            &invoke_seek_to_event,
+        )),
+    );
+
+    command_list.insert(
+        String::from("raw-syscallbuf-frames"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("raw-syscallbuf-frames"),
+            "show or hide rd's syscall buffering code in backtraces\n\
+                            usage: raw-syscallbuf-frames [on|off]\n\
+                            when off (the default), a thread stopped inside a buffered syscall\n\
+                            is shown at the call site of that syscall",
+            &raw_syscallbuf_frames,
+        )),
+    );
+
+    command_list.insert(
+        String::from("break-on-syscall"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("break-on-syscall"),
+            "stop when replay enters or exits the named syscalls\n\
+                            usage: break-on-syscall [-d] [<syscall>...]\n\
+                            with no syscalls, list the current ones; -d removes the given\n\
+                            syscalls, or all of them. Only works when running forward, and\n\
+                            buffered syscalls are only seen in traces recorded with -n",
+            &break_on_syscall,
+        )),
+    );
+
+    command_list.insert(
+        String::from("break-on-exec"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("break-on-exec"),
+            "stop whenever a process in the trace execs, printing the new program\n\
+                            usage: break-on-exec [on|off]\n\
+                            Execs in processes gdb can't be told about (see -p and\n\
+                            --all-processes) are printed without stopping. Only works when\n\
+                            running forward",
+            &break_on_exec,
+        )),
+    );
+
+    command_list.insert(
+        String::from("thread-breakpoint"),
+        Box::new(SimpleGdbCommand::new(
+            String::from("thread-breakpoint"),
+            "make the breakpoint at an address stop only one thread\n\
+                            usage: thread-breakpoint [-d] <address> [<tid>]\n\
+                            <tid> defaults to the current thread. Other threads that hit the\n\
+                            breakpoint carry on without stopping. Use it together with gdb's\n\
+                            `break ... thread N`; it applies when gdb next inserts breakpoints.\n\
+                            With no arguments, list the thread-specific breakpoints",
+            &thread_breakpoint,
+        )),
+    );
+
     command_list
 }
 
@@ -274,87 +503,462 @@ fn when_tid(_: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
     OsString::from_vec(rets)
 }
 
-/// @TODO Avoid static mut
-static mut BACK_STACK: Vec<Mark> = Vec::new();
-static mut CURRENT_HISTORY_CP: Option<Mark> = None;
-static mut FORWARD_STACK: Vec<Mark> = Vec::new();
+fn when_position(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+    match gdb_server.replay_position(t) {
+        // Exactly the documented format, front-ends parse it.
+        Some(position) => OsString::from(position.to_string()),
+        None => GdbCommandHandler::cmd_end_diversion(),
+    }
+}
 
-fn rd_history_push(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+fn convenience_variables(_: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+    // This runs on every stop, including the ones inside a diversion. Ending
+    // the diversion here would end every gdb `call` at its first stop.
     if !t.session().is_replaying() {
-        // Don't create new history state inside a diversion
         return OsString::new();
     }
 
-    // @TODO Avoid unsafe?
-    unsafe {
-        if CURRENT_HISTORY_CP.is_some() {
-            BACK_STACK.push(CURRENT_HISTORY_CP.as_ref().unwrap().clone());
-        }
+    let mut rets = Vec::<u8>::new();
+    write!(
+        rets,
+        "_rd_event={} _rd_ticks={} _rd_tid={}",
+        t.as_replay_task().unwrap().current_trace_frame().time(),
+        t.tick_count(),
+        t.tid()
+    )
+    .unwrap();
+    OsString::from_vec(rets)
+}
 
-        CURRENT_HISTORY_CP = Some(gdb_server.timeline_unwrap_mut().mark());
-        FORWARD_STACK.clear();
+fn step_until_ticks(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
     }
+    let usage = || OsString::from("Usage: step-until-ticks <ticks> [<tid>]");
+    let ticks = match args.get(1).and_then(|a| a.to_str()?.parse::<Ticks>().ok()) {
+        Some(ticks) => ticks,
+        None => return usage(),
+    };
+    let tid = match args.get(2) {
+        Some(a) => match a.to_str().and_then(|a| a.parse::<pid_t>().ok()) {
+            Some(tid) => tid,
+            None => return usage(),
+        },
+        None => t.rec_tid(),
+    };
 
-    OsString::new()
+    let reached = gdb_server.timeline_unwrap_mut().seek_to_ticks(tid, ticks);
+    let mut rets = Vec::<u8>::new();
+    if reached {
+        write!(rets, "Thread {} is at tick {}", tid, ticks).unwrap();
+    } else {
+        write!(rets, "Thread {} can't be stopped at tick {}", tid, ticks).unwrap();
+    }
+    OsString::from_vec(rets)
 }
 
-fn back(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+fn invoke_checksum(_: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
     if !t.session().is_replaying() {
         return GdbCommandHandler::cmd_end_diversion();
     }
-    // @TODO Avoid unsafe?
-    unsafe {
-        if BACK_STACK.is_empty() {
-            return OsString::from("Can't go back. No more history entries.");
+    let range = match args.get(1) {
+        Some(arg) => match parse_memory_range(t, arg.as_bytes()) {
+            Ok(range) => range,
+            Err(e) => return OsString::from(e),
+        },
+        None => return OsString::from(CHECKSUM_USAGE),
+    };
+
+    let time = t.as_replay_task().unwrap().current_trace_frame().time();
+    let checksum = checksum_memory_range(t, range);
+    let mut rets = Vec::<u8>::new();
+    write!(rets, "Checksum of {}: {:#x}", range, checksum).unwrap();
+    match recorded_checksum(t, range, time) {
+        Some(rec_checksum) if rec_checksum == checksum => {
+            write!(rets, " (matches the recording at event {})", time).unwrap()
+        }
+        Some(rec_checksum) => write!(
+            rets,
+            " (DIVERGED: recorded {:#x} at event {})",
+            rec_checksum, time
+        )
+        .unwrap(),
+        None => write!(rets, " (no recorded checksum at event {})", time).unwrap(),
+    }
+    OsString::from_vec(rets)
+}
+
+const CHECKSUM_USAGE: &str = "Usage: checksum <start>-<end>";
+
+/// Parse `<start>-<end>` into a range of `t`'s memory that can be
+/// checksummed. On failure, returns the message for the user.
+fn parse_memory_range(t: &dyn Task, arg: &[u8]) -> Result<MemoryRange, String> {
+    let usage = || String::from(CHECKSUM_USAGE);
+    let mut rest: &[u8] = Default::default();
+    let start = str16_to_usize(arg, &mut rest).map_err(|_| usage())?;
+    if rest.first() != Some(&b'-') {
+        return Err(usage());
+    }
+    let mut end_rest: &[u8] = Default::default();
+    let end = str16_to_usize(&rest[1..], &mut end_rest).map_err(|_| usage())?;
+    if !end_rest.is_empty() || end <= start {
+        return Err(usage());
+    }
+    let range = MemoryRange::from_range(start.into(), end.into());
+    if range.len() > MAX_CHECKSUM_RANGE {
+        return Err(format!(
+            "{} is too large, at most {:#x} bytes can be checksummed",
+            range, MAX_CHECKSUM_RANGE
+        ));
+    }
+    let mut addr = range.start();
+    while addr < range.end() {
+        match t.vm().mapping_of(addr) {
+            Some(m) => addr = m.map.end(),
+            None => return Err(format!("{} isn't mapped at {}", range, addr)),
+        }
+    }
+    Ok(range)
+}
+
+fn save_checkpoints(gdb_server: &mut GdbServer, _: &dyn Task, args: &[OsString]) -> OsString {
+    let path = match args.get(1) {
+        Some(path) => Path::new(path),
+        None => return OsString::from("Usage: save-checkpoints <file>"),
+    };
+    let mut rets = Vec::<u8>::new();
+    match write_checkpoints(path, &gdb_server.checkpoints) {
+        Ok(()) => write!(rets, "Saved checkpoints to {:?}", path).unwrap(),
+        Err(e) => write!(rets, "Unable to save checkpoints to {:?}: {}", path, e).unwrap(),
+    }
+    OsString::from_vec(rets)
+}
+
+fn raw_syscallbuf_frames(gdb_server: &mut GdbServer, _: &dyn Task, args: &[OsString]) -> OsString {
+    match args.get(1).and_then(|a| a.to_str()) {
+        Some("on") => gdb_server.raw_syscallbuf_frames = true,
+        Some("off") => gdb_server.raw_syscallbuf_frames = false,
+        Some(_) => return OsString::from("Usage: raw-syscallbuf-frames [on|off]"),
+        None => (),
+    }
+    let mut rets = Vec::<u8>::new();
+    write!(
+        rets,
+        "Raw syscallbuf frames are {}",
+        if gdb_server.raw_syscallbuf_frames {
+            "on"
+        } else {
+            "off"
+        }
+    )
+    .unwrap();
+    if args.len() > 1 {
+        // gdb caches registers per stop, so make sure it asks us again.
+        write!(rets, " (run 'flushregs' to refresh the current frame)").unwrap();
+    }
+    OsString::from_vec(rets)
+}
+
+fn break_on_syscall(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    let delete = args.get(1).map_or(false, |a| a == "-d");
+    let names = &args[if delete { 2 } else { 1 }..];
+    let mut rets = Vec::<u8>::new();
+    for name in names {
+        let name = name.to_string_lossy();
+        if syscall_number_for_name(&name, t.arch()).is_none() {
+            write!(rets, "Unknown syscall '{}'", name).unwrap();
+            return OsString::from_vec(rets);
+        }
+    }
+
+    let breaks = &mut gdb_server.syscall_breaks;
+    if delete && names.is_empty() {
+        breaks.clear();
+    }
+    for name in names {
+        let name = name.to_string_lossy().into_owned();
+        if delete {
+            breaks.retain(|b| *b != name);
+        } else if !breaks.contains(&name) {
+            breaks.push(name);
+        }
+    }
+
+    if breaks.is_empty() {
+        write!(rets, "Not stopping on any syscalls").unwrap();
+    } else {
+        write!(rets, "Stopping on syscalls: {}", breaks.join(", ")).unwrap();
+    }
+    OsString::from_vec(rets)
+}
+
+fn break_on_exec(gdb_server: &mut GdbServer, _: &dyn Task, args: &[OsString]) -> OsString {
+    match args.get(1).and_then(|a| a.to_str()) {
+        None => (),
+        Some("on") => gdb_server.break_on_exec = true,
+        Some("off") => gdb_server.break_on_exec = false,
+        Some(_) => return OsString::from("Usage: break-on-exec [on|off]"),
+    }
+    if gdb_server.break_on_exec {
+        OsString::from("Stopping at every exec")
+    } else {
+        OsString::from("Only stopping at execs of the debugged process")
+    }
+}
+
+fn thread_breakpoint(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    let usage = || OsString::from("Usage: thread-breakpoint [-d] <address> [<tid>]");
+    let mut rets = Vec::<u8>::new();
+    if args.len() <= 1 {
+        if gdb_server.thread_breakpoints.is_empty() {
+            write!(rets, "No thread-specific breakpoints").unwrap();
         }
-        FORWARD_STACK.push(CURRENT_HISTORY_CP.as_ref().unwrap().clone());
-        CURRENT_HISTORY_CP = Some(BACK_STACK.pop().unwrap());
+        let mut breakpoints: Vec<_> = gdb_server.thread_breakpoints.iter().collect();
+        breakpoints.sort_by_key(|(addr, _)| **addr);
+        for (addr, thread) in breakpoints {
+            writeln!(rets, "{} thread {}", addr, thread.tid).unwrap();
+        }
+        return OsString::from_vec(rets);
+    }
 
-        gdb_server
-            .timeline_unwrap_mut()
-            .seek_to_mark(CURRENT_HISTORY_CP.as_ref().unwrap());
+    let delete = args[1] == "-d";
+    let addr_arg = match args.get(if delete { 2 } else { 1 }) {
+        Some(a) => a.as_bytes(),
+        None => return usage(),
+    };
+    let mut rest: &[u8] = Default::default();
+    let addr = match str16_to_usize(addr_arg, &mut rest) {
+        Ok(addr) if rest.is_empty() => RemoteCodePtr::from(addr),
+        _ => return usage(),
+    };
+    if delete {
+        match gdb_server.thread_breakpoints.remove(&addr) {
+            Some(_) => write!(rets, "Breakpoint at {} stops any thread", addr).unwrap(),
+            None => write!(rets, "No thread-specific breakpoint at {}", addr).unwrap(),
+        }
+        return OsString::from_vec(rets);
     }
+
+    let thread = match args.get(2) {
+        Some(a) => match a.to_str().and_then(|a| a.parse::<pid_t>().ok()) {
+            Some(tid) => GdbThreadId::new(0, tid),
+            None => return usage(),
+        },
+        None => GdbThreadId::new(t.tgid(), t.rec_tid()),
+    };
+    gdb_server.thread_breakpoints.insert(addr, thread);
+    write!(
+        rets,
+        "Breakpoint at {} only stops thread {}",
+        addr, thread.tid
+    )
+    .unwrap();
+    OsString::from_vec(rets)
+}
+
+fn rd_history_push(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        // Don't create new history state inside a diversion
+        return OsString::new();
+    }
+
+    let mark = gdb_server.timeline_unwrap_mut().mark();
+    gdb_server.history.push(mark);
     OsString::new()
 }
 
+fn back(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    match gdb_server.history.current() {
+        Some(current) if current > 0 => go_to_history_entry(gdb_server, current - 1),
+        _ => OsString::from("Can't go back. No more history entries."),
+    }
+}
+
 fn forward(gdb_server: &mut GdbServer, t: &dyn Task, _: &[OsString]) -> OsString {
     if !t.session().is_replaying() {
         return GdbCommandHandler::cmd_end_diversion();
     }
-    // @TODO Avoid unsafe?
-    unsafe {
-        if FORWARD_STACK.is_empty() {
-            return OsString::from("Can't go forward. No more history entries.");
+    match gdb_server.history.current() {
+        Some(current) if current + 1 < gdb_server.history.len() => {
+            go_to_history_entry(gdb_server, current + 1)
         }
-        BACK_STACK.push(CURRENT_HISTORY_CP.as_ref().unwrap().clone());
-        CURRENT_HISTORY_CP = Some(FORWARD_STACK.pop().unwrap());
-        gdb_server
-            .timeline_unwrap_mut()
-            .seek_to_mark(CURRENT_HISTORY_CP.as_ref().unwrap());
+        _ => OsString::from("Can't go forward. No more history entries."),
     }
+}
 
+fn rd_history(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    if let Some(arg) = args.get(1) {
+        return match arg.to_str().and_then(|s| s.parse::<usize>().ok()) {
+            Some(index) if index < gdb_server.history.len() => {
+                go_to_history_entry(gdb_server, index)
+            }
+            _ => {
+                let mut rets = Vec::<u8>::new();
+                rets.extend_from_slice(b"Invalid history entry `");
+                rets.extend_from_slice(arg.as_bytes());
+                rets.extend_from_slice(b"'.");
+                OsString::from_vec(rets)
+            }
+        };
+    }
+
+    let current = match gdb_server.history.current() {
+        Some(current) => current,
+        None => return OsString::from("No history entries."),
+    };
+    let mut out = Vec::new();
+    out.extend_from_slice(b"  #\tWhen\tTicks");
+    for (i, mark) in gdb_server.history.iter().enumerate() {
+        let marker = if i == current { '*' } else { ' ' };
+        write!(out, "\n{} {}\t{}\t{}", marker, i, mark.time(), mark.ticks()).unwrap();
+    }
+    OsString::from_vec(out)
+}
+
+fn go_to_history_entry(gdb_server: &mut GdbServer, index: usize) -> OsString {
+    let mark = gdb_server.history.go_to(index).unwrap();
+    gdb_server.timeline_unwrap_mut().seek_to_mark(&mark);
     OsString::new()
 }
 
-fn invoke_checkpoint(gdb_server: &mut GdbServer, _t: &dyn Task, args: &[OsString]) -> OsString {
-    static NEXT_CHECKPOINT_ID: AtomicU64 = AtomicU64::new(1);
-    let where_ = &args[1];
-    let checkpoint_id = NEXT_CHECKPOINT_ID.fetch_add(1, Ordering::SeqCst);
+fn rd_divert_env(gdb_server: &mut GdbServer, _: &dyn Task, args: &[OsString]) -> OsString {
+    // Work out the whole change before making any of it, so that a rejected
+    // command leaves the overrides as they were.
+    let change: Option<(OsString, Option<OsString>)> = match args.get(2).map(|a| a.as_bytes()) {
+        None => return list_diversion_overrides(gdb_server),
+        Some(b"--clear") if args.len() == 3 => None,
+        Some(b"-u") if args.len() == 4 => Some((args[3].clone(), None)),
+        Some(b"-u") => return OsString::from("Usage: rd-divert-env -u NAME"),
+        Some(var) if args.len() == 3 => match var.iter().position(|&c| c == b'=') {
+            Some(pos) if pos > 0 => Some((
+                OsString::from_vec(var[..pos].to_vec()),
+                Some(OsString::from_vec(var[pos + 1..].to_vec())),
+            )),
+            _ => return OsString::from("Usage: rd-divert-env NAME=VALUE"),
+        },
+        Some(_) => return OsString::from("Usage: rd-divert-env [NAME=VALUE | -u NAME | --clear]"),
+    };
+    // Computed by gdb every time, in case libc wasn't loaded the last time.
+    let environ_addr = args[1]
+        .to_str()
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .unwrap_or(0);
 
+    let overrides = &mut gdb_server.diversion_overrides;
+    if change.is_some() && environ_addr == 0 && overrides.environ_addr.is_null() {
+        return OsString::from(
+            "Can't find `environ`, so the environment can't be changed until libc is loaded.",
+        );
+    }
+    if environ_addr != 0 {
+        overrides.environ_addr = RemotePtr::new(environ_addr);
+    }
+    match change {
+        None => overrides.env.clear(),
+        Some((name, value)) => {
+            overrides.env.insert(name, value);
+        }
+    }
+    OsString::new()
+}
+
+fn rd_divert_arg(gdb_server: &mut GdbServer, _: &dyn Task, args: &[OsString]) -> OsString {
+    let overrides = &mut gdb_server.diversion_overrides;
+    match (args.get(1), args.get(2)) {
+        (None, _) => return list_diversion_overrides(gdb_server),
+        (Some(a), None) if a == "--clear" => overrides.args.clear(),
+        (Some(n), Some(value)) => match n.to_str().and_then(|s| s.parse::<usize>().ok()) {
+            Some(n) => {
+                overrides.args.insert(n, value.clone());
+            }
+            None => return OsString::from("Usage: rd-divert-arg <n> <value>"),
+        },
+        _ => return OsString::from("Usage: rd-divert-arg <n> <value>"),
+    }
+    OsString::new()
+}
+
+fn list_diversion_overrides(gdb_server: &GdbServer) -> OsString {
+    let overrides = &gdb_server.diversion_overrides;
+    if overrides.is_empty() {
+        return OsString::from("Diversions see the recorded argv and environment.");
+    }
+    let mut out = Vec::new();
+    out.extend_from_slice(b"Diversions see these changes:");
+    for (n, value) in &overrides.args {
+        write!(out, "\n  argv[{}]=", n).unwrap();
+        out.extend_from_slice(value.as_bytes());
+    }
+    for (name, value) in &overrides.env {
+        out.extend_from_slice(b"\n  ");
+        match value {
+            Some(value) => {
+                out.extend_from_slice(name.as_bytes());
+                out.push(b'=');
+                out.extend_from_slice(value.as_bytes());
+            }
+            None => {
+                out.extend_from_slice(b"unset ");
+                out.extend_from_slice(name.as_bytes());
+            }
+        }
+    }
+    OsString::from_vec(out)
+}
+
+fn invoke_checkpoint(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if args.len() > 3 {
+        return OsString::from("Usage: checkpoint [<name>]");
+    }
+    let where_ = &args[1];
+    let maybe_name = args.get(2);
+    if let Some(name) = maybe_name {
+        if name.as_bytes().iter().all(u8::is_ascii_digit) {
+            return OsString::from("Checkpoint names can't be numbers.");
+        }
+        if gdb_server.checkpoints.find_id(name).is_some() {
+            let mut rets = Vec::<u8>::new();
+            rets.extend_from_slice(b"Checkpoint ");
+            rets.extend_from_slice(name.as_bytes());
+            rets.extend_from_slice(b" already exists.");
+            return OsString::from_vec(rets);
+        }
+    }
     let e = if gdb_server.timeline_unwrap().can_add_checkpoint() {
         ExplicitCheckpoint::Explicit
     } else {
         ExplicitCheckpoint::NotExplicit
     };
-    let checkpoint = Checkpoint::new(
+    let mut checkpoint = Checkpoint::new(
         &mut gdb_server.timeline_unwrap_mut(),
         gdb_server.last_continue_tuid,
         e,
         where_,
     );
-    gdb_server.checkpoints.insert(checkpoint_id, checkpoint);
+    checkpoint.name = maybe_name.cloned();
+    checkpoint.rec_tid = Some(t.rec_tid());
+    let event = checkpoint.mark.time();
+    let checkpoint_id = gdb_server.checkpoints.add(checkpoint);
+    replay_notifier::publish(&Notification::CheckpointCreated {
+        id: checkpoint_id,
+        name: maybe_name.map(|name| name.to_string_lossy().into_owned()),
+        event,
+        location: where_.to_string_lossy().into_owned(),
+    });
     let mut rets = Vec::<u8>::new();
-    write!(rets, "Checkpoint {} at ", checkpoint_id).unwrap();
+    write!(rets, "Checkpoint {}", checkpoint_id).unwrap();
+    if let Some(name) = maybe_name {
+        rets.extend_from_slice(b" (");
+        rets.extend_from_slice(name.as_bytes());
+        rets.push(b')');
+    }
+    rets.extend_from_slice(b" at ");
     rets.extend_from_slice(where_.as_bytes());
     OsString::from_vec(rets)
 }
@@ -364,22 +968,45 @@ fn invoke_delete_checkpoint(
     _t: &dyn Task,
     args: &[OsString],
 ) -> OsString {
-    let id: u64 = str::parse::<u64>(str::from_utf8(args[1].as_bytes()).unwrap()).unwrap();
-    // Clone it because we want to then delete it
-    let it = gdb_server.checkpoints.get(&id).cloned();
-    match it {
-        Some(checkpoint) => {
+    let id_or_name = match args {
+        [_, arg] => arg,
+        _ => return OsString::from("Usage: delete checkpoint <id|name>"),
+    };
+    match gdb_server.checkpoints.find_id(id_or_name) {
+        Some(id) => {
+            // Remove it from the registry first, then drop the timeline checkpoint
+            let checkpoint = gdb_server.checkpoints.remove(id).unwrap();
             if checkpoint.is_explicit == ExplicitCheckpoint::Explicit {
                 gdb_server
                     .timeline_unwrap_mut()
                     .remove_explicit_checkpoint(&checkpoint.mark);
             }
-            gdb_server.checkpoints.remove(&id);
             let ret = format!("Deleted checkpoint {}.", id);
             OsString::from(ret)
         }
         None => {
-            let ret = format!("No checkpoint number {}.", id);
+            let ret = format!("No checkpoint {}.", id_or_name.to_string_lossy());
+            OsString::from(ret)
+        }
+    }
+}
+
+fn invoke_seek_checkpoint(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let id_or_name = match args {
+        [_, arg] => arg,
+        _ => return OsString::from("Usage: seek-checkpoint <id|name>"),
+    };
+    match gdb_server.checkpoints.find_id(id_or_name) {
+        Some(id) => {
+            let mark = gdb_server.checkpoints.get(id).unwrap().mark.clone();
+            gdb_server.timeline_unwrap_mut().seek_to_mark(&mark);
+            OsString::new()
+        }
+        None => {
+            let ret = format!("No checkpoint {}.", id_or_name.to_string_lossy());
             OsString::from(ret)
         }
     }
@@ -394,10 +1021,102 @@ fn invoke_info_checkpoints(
         return OsString::from("No checkpoints.");
     }
     let mut out = Vec::new();
-    out.extend_from_slice(b"ID\tWhen\tWhere");
-    for (&id, c) in gdb_server.checkpoints.iter() {
-        write!(out, "\n{}\t{}\t", id, c.mark.time()).unwrap();
+    out.extend_from_slice(b"ID\tName\tWhen\tTicks\tWhere");
+    for (id, c) in gdb_server.checkpoints.iter() {
+        write!(out, "\n{}\t", id).unwrap();
+        match c.name.as_ref() {
+            Some(name) => out.extend_from_slice(name.as_bytes()),
+            None => out.push(b'-'),
+        }
+        write!(out, "\t{}\t{}\t", c.mark.time(), c.mark.ticks()).unwrap();
         out.extend_from_slice(c.where_.as_bytes());
     }
     OsString::from_vec(out)
 }
+
+fn trace_annotations(gdb_server: &GdbServer) -> Vec<TraceAnnotation> {
+    let trace_dir = gdb_server
+        .timeline_unwrap()
+        .current_session()
+        .trace_reader()
+        .trace_stream()
+        .dir()
+        .to_owned();
+    read_annotations(Some(trace_dir))
+}
+
+fn invoke_info_annotations(
+    gdb_server: &mut GdbServer,
+    t: &dyn Task,
+    _args: &[OsString],
+) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let annotations = trace_annotations(gdb_server);
+    if annotations.is_empty() {
+        return OsString::from("No annotations.");
+    }
+    let mut out = Vec::new();
+    out.extend_from_slice(b"When\tTid\tName");
+    for a in annotations {
+        write!(out, "\n{}\t{}\t", a.time, a.tid).unwrap();
+        out.extend_from_slice(a.name.as_bytes());
+    }
+    OsString::from_vec(out)
+}
+
+fn invoke_seek_annotation(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let name = match args.get(1) {
+        Some(arg) => arg,
+        None => return OsString::from("Usage: seek-annotation <name>"),
+    };
+    match trace_annotations(gdb_server)
+        .into_iter()
+        .find(|a| &a.name == name)
+    {
+        Some(a) => {
+            gdb_server
+                .timeline_unwrap_mut()
+                .seek_to_before_event(a.time);
+            OsString::new()
+        }
+        None => {
+            let ret = format!("No annotation {}.", name.to_string_lossy());
+            OsString::from(ret)
+        }
+    }
+}
+
+fn invoke_seek_to_event(gdb_server: &mut GdbServer, t: &dyn Task, args: &[OsString]) -> OsString {
+    if !t.session().is_replaying() {
+        return GdbCommandHandler::cmd_end_diversion();
+    }
+    let event = match args
+        .get(1)
+        .and_then(|a| a.to_str()?.parse::<FrameTime>().ok())
+    {
+        Some(event) if event > 0 => event,
+        _ => return OsString::from("Usage: seek-to-event <event>"),
+    };
+    let mut timeline = gdb_server.timeline_unwrap_mut();
+    timeline.seek_to_before_event(event);
+    while timeline.current_session().current_frame_time() < event {
+        // Breakpoints may stop us early. Carry on regardless.
+        let result = timeline.replay_step_forward(RunCommand::Continue, event);
+        if result.status == ReplayStatus::ReplayExited {
+            let ret = format!(
+                "Reached the end of the trace at event {}.",
+                timeline.current_session().current_frame_time()
+            );
+            return OsString::from(ret);
+        }
+    }
+    OsString::from(format!(
+        "At event {}.",
+        timeline.current_session().current_frame_time()
+    ))
+}
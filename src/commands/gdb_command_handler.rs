@@ -60,12 +60,42 @@ class RDWhere(gdb.Command):
 
 RDWhere()
 
+class RDEnvironAddress(gdb.Command):
+    """Helper to get the address of libc's environ for rd-divert-env. Used by auto-args"""
+    def __init__(self):
+        gdb.Command.__init__(self, 'rd-environ-address',
+                             gdb.COMMAND_USER, gdb.COMPLETE_NONE, False)
+
+    def invoke(self, arg, from_tty):
+        try:
+            rv = str(int(gdb.parse_and_eval("(unsigned long)&environ")))
+        except:
+            rv = "0" # libc isn't loaded yet
+        gdb.write(rv)
+
+RDEnvironAddress()
+
+def flush_caches():
+    # Tell gdb that registers and memory may have changed behind its back.
+    # flushregs is the name before gdb 12.
+    for cmd in ("maint flush register-cache", "flushregs"):
+        try:
+            gdb.execute(cmd, to_string=True)
+            break
+        except gdb.error:
+            pass
+    try:
+        gdb.execute("maint flush dcache", to_string=True)
+    except gdb.error:
+        pass
+
 class RDCmd(gdb.Command):
-    def __init__(self, name, auto_args):
+    def __init__(self, name, auto_args, moves_replay=False):
         gdb.Command.__init__(self, name,
                              gdb.COMMAND_USER, gdb.COMPLETE_NONE, False)
         self.cmd_name = name
         self.auto_args = auto_args
+        self.moves_replay = moves_replay
 
     def invoke(self, arg, from_tty):
         args = gdb.string_to_argv(arg)
@@ -84,10 +114,16 @@ class RDCmd(gdb.Command):
             gdb.write("Response error: " + rv)
             return
         response = gdb_unescape(rv_match.group(1))
+        if self.moves_replay:
+            flush_caches()
         gdb.write(response)
 
 def history_push(p):
-    gdb.execute("rd-history-push", to_string=True)
+    try:
+        gdb.execute("rd-history-push", to_string=True)
+    except Exception:
+        # The connection may already be gone, e.g. when the replay exited.
+        pass
 
 rd_suppress_run_hook = False
 
@@ -112,10 +148,32 @@ class RDSetSuppressRunHook(gdb.Command):
 RDHookRun()
 RDSetSuppressRunHook()
 
+def rd_set_convenience_variables(event):
+    try:
+        rv = gdb.execute("maint packet qRDCmd:" + gdb_escape("rd-convenience-variables"),
+                         to_string=True)
+        rv_match = re.search('received: "(.*)"', rv, re.MULTILINE)
+        response = gdb_unescape(rv_match.group(1))
+    except Exception:
+        # The connection may already be gone. Leave the previous values.
+        return
+    # The reply is empty in a diversion, leaving the previous values.
+    for assignment in response.split():
+        name, _, value = assignment.partition("=")
+        try:
+            gdb.set_convenience_variable(name, int(value))
+        except ValueError:
+            continue
+
+#Keep $_rd_event, $_rd_ticks and $_rd_tid up to date. Note that gdb evaluates
+#breakpoint conditions before this runs, so a condition sees the values of the
+#previous stop.
+gdb.events.stop.connect(rd_set_convenience_variables)
+
 #Automatically push an history entry when the program execution stops
-#(signal, breakpoint).This is fired before an interactive prompt is shown.
-#Disabled for now since it's not fully working.
-#gdb.events.stop.connect(history_push)
+#(signal, breakpoint, end of a step). This is fired before an interactive prompt
+#is shown, so the single steps gdb makes internally don't create entries.
+gdb.events.stop.connect(history_push)
 
 end
 "##;
@@ -228,7 +286,12 @@ fn gdb_macro_binding(cmd: &dyn GdbCommand) -> String {
         auto_args_str.push_str(&format!("{:?}", arg));
     }
     auto_args_str.push(']');
-    let mut ret = format!("python RDCmd('{}', {})\n", cmd.name(), auto_args_str);
+    let mut ret = format!(
+        "python RDCmd('{}', {}, {})\n",
+        cmd.name(),
+        auto_args_str,
+        if cmd.moves_replay() { "True" } else { "False" }
+    );
     if !cmd.docs().is_empty() {
         ret.push_str(&format!("document {}\n{}\nend\n", cmd.name(), cmd.docs()));
     }
@@ -1,41 +1,55 @@
 #[allow(unused_imports)]
 use crate::{
+    arch::Architecture,
     bindings::signal::siginfo_t,
     breakpoint_condition::BreakpointCondition,
-    commands::gdb_command_handler::GdbCommandHandler,
+    commands::{
+        gdb_command_handler::GdbCommandHandler,
+        gdb_session::GdbSessionFiles,
+        gdb_stop_policy::{GdbStopPolicy, StopReason},
+    },
+    event::SyscallState,
     extra_registers::ExtraRegisters,
     gdb_connection::{
         GdbActionType, GdbConnection, GdbConnectionFeatures, GdbContAction, GdbRegisterValue,
-        GdbRegisterValueData, GdbRequest, GdbRequestType, GdbRestartType, GdbThreadId, DREQ_CONT,
-        DREQ_DETACH, DREQ_FILE_CLOSE, DREQ_FILE_OPEN, DREQ_FILE_PREAD, DREQ_FILE_SETFS,
-        DREQ_GET_AUXV, DREQ_GET_CURRENT_THREAD, DREQ_GET_EXEC_FILE, DREQ_GET_IS_THREAD_ALIVE,
-        DREQ_GET_MEM, DREQ_GET_OFFSETS, DREQ_GET_REG, DREQ_GET_REGS, DREQ_GET_STOP_REASON,
-        DREQ_GET_THREAD_EXTRA_INFO, DREQ_GET_THREAD_LIST, DREQ_INTERRUPT, DREQ_NONE, DREQ_QSYMBOL,
-        DREQ_RD_CMD, DREQ_READ_SIGINFO, DREQ_REMOVE_HW_BREAK, DREQ_REMOVE_RDWR_WATCH,
-        DREQ_REMOVE_RD_WATCH, DREQ_REMOVE_SW_BREAK, DREQ_REMOVE_WR_WATCH, DREQ_RESTART,
-        DREQ_SEARCH_MEM, DREQ_SET_CONTINUE_THREAD, DREQ_SET_HW_BREAK, DREQ_SET_MEM,
-        DREQ_SET_QUERY_THREAD, DREQ_SET_RDWR_WATCH, DREQ_SET_RD_WATCH, DREQ_SET_REG,
-        DREQ_SET_SW_BREAK, DREQ_SET_WR_WATCH, DREQ_TLS, DREQ_WRITE_SIGINFO,
+        GdbRegisterValueData, GdbRequest, GdbRequestType, GdbRestartType, GdbThreadId,
+        ReplayPosition, DREQ_CONT, DREQ_DETACH, DREQ_FILE_CLOSE, DREQ_FILE_OPEN, DREQ_FILE_PREAD,
+        DREQ_FILE_SETFS, DREQ_GET_AUXV, DREQ_GET_CURRENT_THREAD, DREQ_GET_EXEC_FILE,
+        DREQ_GET_IS_THREAD_ALIVE, DREQ_GET_LIBRARIES_SVR4, DREQ_GET_MEM, DREQ_GET_OFFSETS,
+        DREQ_GET_REG, DREQ_GET_REGS, DREQ_GET_STOP_REASON, DREQ_GET_THREAD_EXTRA_INFO,
+        DREQ_GET_THREAD_LIST, DREQ_INTERRUPT, DREQ_NONE, DREQ_QSYMBOL, DREQ_RD_CMD,
+        DREQ_READ_SIGINFO, DREQ_REMOVE_HW_BREAK, DREQ_REMOVE_RDWR_WATCH, DREQ_REMOVE_RD_WATCH,
+        DREQ_REMOVE_SW_BREAK, DREQ_REMOVE_WR_WATCH, DREQ_RESTART, DREQ_SEARCH_MEM,
+        DREQ_SET_CONTINUE_THREAD, DREQ_SET_HW_BREAK, DREQ_SET_MEM, DREQ_SET_QUERY_THREAD,
+        DREQ_SET_RDWR_WATCH, DREQ_SET_RD_WATCH, DREQ_SET_REG, DREQ_SET_SW_BREAK, DREQ_SET_WR_WATCH,
+        DREQ_TLS, DREQ_WRITE_SIGINFO,
     },
     gdb_expression::{GdbExpression, GdbExpressionValue},
-    gdb_register::{GdbRegister, DREG_64_YMM15H, DREG_ORIG_EAX, DREG_ORIG_RAX, DREG_YMM7H},
+    gdb_register::{
+        GdbRegister, DREG_64_YMM15H, DREG_64_ZMM31H, DREG_ORIG_EAX, DREG_ORIG_RAX, DREG_YMM7H,
+    },
     kernel_abi::{syscall_number_for_execve, SupportedArch},
+    kernel_metadata::{errno_name, syscall_name},
     log::dump_rd_stack,
     log::{LogDebug, LogError, LogInfo, LogWarn},
+    preload_interface_arch::{preload_thread_locals, syscall_info},
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
+    replay_notifier::{self, Notification},
     replay_timeline::{self, Mark, ReplayTimeline, ReplayTimelineSharedPtr, RunDirection},
+    replay_watchdog,
     scoped_fd::{ScopedFd, ScopedFdSharedPtr, ScopedFdSharedWeakPtr},
     session::{
         address_space::{
             memory_range::MemoryRange, BreakpointType, MappingFlags, WatchType, BREAKPOINT_INSN,
         },
-        diversion_session::{DiversionSession, DiversionStatus},
+        diversion_session::{DiversionOverrides, DiversionSession, DiversionStatus},
         replay_session::{ReplayResult, ReplaySession, ReplayStatus},
         session_inner::{BreakStatus, RunCommand},
         task::{
             replay_task::ReplayTask,
+            task_common::read_val_mem,
             task_inner::{TaskInner, WriteFlags},
             Task, TaskSharedPtr,
         },
@@ -50,16 +64,22 @@ use crate::{
     util::write_all,
     util::{
         cpuid, create_temporary_file, find, flat_env, floor_page_size, open_socket, page_size,
-        to_cstring_array, trace_instructions_up_to_event, u8_slice, u8_slice_mut, word_size,
-        ProbePort, AVX_FEATURE_FLAG, CPUID_GETFEATURES, OSXSAVE_FEATURE_FLAG,
+        to_cstring_array, trace_instructions_up_to_event, u8_slice, u8_slice_mut, word_at,
+        word_size, xsave_native_layout, ProbePort, AVX_FEATURE_FLAG, CPUID_GETFEATURES,
+        OSXSAVE_FEATURE_FLAG,
     },
 };
-use libc::{pid_t, SIGKILL, SIGTRAP};
+use goblin::elf::{
+    dynamic::DT_DEBUG,
+    program_header::{PT_DYNAMIC, PT_PHDR},
+};
+use libc::{pid_t, AT_PHDR, AT_PHNUM, SIGKILL, SIGTRAP};
 use nix::{
     errno::{errno, Errno},
+    fcntl::OFlag,
     sys::{
         mman::{MapFlags, ProtFlags},
-        stat::{major, minor},
+        stat::{fstat, major, minor},
     },
     unistd::{dup, execvpe, getpid, read, unlink, write},
 };
@@ -70,14 +90,16 @@ use std::{
     convert::{TryFrom, TryInto},
     env,
     ffi::{CString, OsStr, OsString},
+    fmt::{self, Display},
     fs::File,
     io::{stderr, Write},
     mem,
+    mem::size_of,
     os::unix::{
         ffi::{OsStrExt, OsStringExt},
         io::FromRawFd,
     },
-    path::{Component, Path, PathBuf},
+    path::{Path, PathBuf},
     ptr,
     ptr::copy_nonoverlapping,
     rc::Rc,
@@ -110,6 +132,11 @@ pub struct ConnectionFlags {
     // Name of the debugger to suggest. Only used if debugger_params_write_pipe
     // is Weak::new().
     pub debugger_name: PathBuf,
+    /// Expose every thread group in the session to gdb through the
+    /// multiprocess extensions, not just the one being debugged.
+    pub all_thread_groups: bool,
+    /// Add the replay position to every stop reply.
+    pub position_in_stop_reply: bool,
 }
 
 impl ConnectionFlags {
@@ -130,6 +157,8 @@ impl Default for ConnectionFlags {
             keep_listening: false,
             debugger_params_write_pipe: None,
             debugger_name: PathBuf::new(),
+            all_thread_groups: false,
+            position_in_stop_reply: false,
         }
     }
 }
@@ -146,6 +175,12 @@ pub(super) struct Checkpoint {
     pub last_continue_tuid: TaskUid,
     pub is_explicit: ExplicitCheckpoint,
     pub where_: OsString,
+    /// Optional user supplied name. Checkpoints can be referred to by name
+    /// anywhere a checkpoint id is accepted.
+    pub name: Option<OsString>,
+    /// The recorded tid of the task whose ticks `mark` counts. Only known for
+    /// checkpoints created with the 'checkpoint' command.
+    pub rec_tid: Option<pid_t>,
 }
 
 impl Checkpoint {
@@ -165,10 +200,114 @@ impl Checkpoint {
             last_continue_tuid,
             is_explicit: e,
             where_: where_.to_owned(),
+            name: None,
+            rec_tid: None,
         }
     }
 }
 
+/// The checkpoints created with the 'checkpoint' command.
+///
+/// The state of the replay is kept alive by the checkpoint's Mark (an
+/// explicit timeline checkpoint holds a clone of the replay session) so the
+/// registry only has to hand out ids and keep the user facing metadata.
+///
+/// DIFF NOTE: rr keeps a plain map in GdbServer plus a global id counter.
+#[derive(Default)]
+pub(super) struct CheckpointRegistry {
+    /// Use a BTreeMap so that result is sorted by checkpoint id
+    checkpoints: BTreeMap<u64, Checkpoint>,
+    /// Ids are never reused. Starts at 1 like gdb's own checkpoints.
+    last_id: u64,
+}
+
+impl CheckpointRegistry {
+    /// Add `checkpoint` and return its id
+    pub fn add(&mut self, checkpoint: Checkpoint) -> u64 {
+        self.last_id += 1;
+        self.checkpoints.insert(self.last_id, checkpoint);
+        self.last_id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Checkpoint> {
+        self.checkpoints.get(&id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Checkpoint> {
+        self.checkpoints.remove(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Iterate over (id, checkpoint) pairs in id order
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Checkpoint)> {
+        self.checkpoints.iter().map(|(&id, c)| (id, c))
+    }
+
+    /// Find the id of the checkpoint referred to by `id_or_name`, which is
+    /// either a checkpoint number or a checkpoint name.
+    pub fn find_id(&self, id_or_name: &OsStr) -> Option<u64> {
+        if let Some(id) = id_or_name.to_str().and_then(|s| s.parse::<u64>().ok()) {
+            if self.checkpoints.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        self.iter()
+            .find(|(_, c)| c.name.as_deref() == Some(id_or_name))
+            .map(|(id, _)| id)
+    }
+}
+
+/// The replay positions visited during the debugging session, like a browser
+/// history. gdb pushes an entry every time the program stops and `back`,
+/// `forward` and `rd-history <n>` move between entries without dropping any.
+#[derive(Default)]
+pub(super) struct ReplayHistory {
+    entries: Vec<Mark>,
+    /// Index of the current entry. Meaningless while `entries` is empty.
+    current: usize,
+}
+
+impl ReplayHistory {
+    /// Make `mark` the current entry. Like a browser, any entries after the
+    /// current one are dropped.
+    pub fn push(&mut self, mark: Mark) {
+        if !self.entries.is_empty() {
+            if self.entries[self.current] == mark {
+                return;
+            }
+            self.entries.truncate(self.current + 1);
+        }
+        self.entries.push(mark);
+        self.current = self.entries.len() - 1;
+    }
+
+    /// Make entry `index` the current one and return its mark
+    pub fn go_to(&mut self, index: usize) -> Option<Mark> {
+        let mark = self.entries.get(index)?.clone();
+        self.current = index;
+        Some(mark)
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some(self.current)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mark> {
+        self.entries.iter()
+    }
+}
+
 pub type GdbConnectionSharedPtr = Rc<RefCell<GdbConnection>>;
 
 pub struct GdbServer {
@@ -205,8 +344,12 @@ pub struct GdbServer {
     /// in rr. We have an more explicit Option<>
     debugger_restart_checkpoint: Option<Checkpoint>,
     /// gdb checkpoints, indexed by ID
-    /// Use a BTreeMap so that result is sorted by checkpoint id
-    pub(super) checkpoints: BTreeMap<u64, Checkpoint>,
+    pub(super) checkpoints: CheckpointRegistry,
+    /// Replay positions visited so far, see `rd-history`
+    pub(super) history: ReplayHistory,
+    /// argv and environment changes applied to every diversion, see
+    /// `rd-divert-env` and `rd-divert-arg`
+    pub(super) diversion_overrides: DiversionOverrides,
     /// Set of symbols to look for, for qSymbol
     symbols: Vec<OsString>,
     symbols_loc: Option<usize>,
@@ -214,6 +357,35 @@ pub struct GdbServer {
     /// The pid for gdb's last vFile:setfs
     /// NOTE: @TODO Zero if not set. Change to option?
     file_scope_pid: pid_t,
+    /// Which stops get reported to gdb
+    stop_policy: GdbStopPolicy,
+    /// When false, a task stopped inside the syscallbuf hook is reported to
+    /// gdb as if it were stopped in the user frame that made the buffered
+    /// syscall. See `syscallbuf_user_frame_regs()`.
+    pub(super) raw_syscallbuf_frames: bool,
+    /// The direction of the last resume request.
+    run_direction: RunDirection,
+    /// Names of the syscalls set with `break-on-syscall`.
+    pub(super) syscall_breaks: Vec<String>,
+    /// Set when the last forward replay step replayed the entry to or exit
+    /// from one of `syscall_breaks`.
+    syscall_break_hit: Option<SyscallBreak>,
+    /// Set with `break-on-exec`: stop at the exec of every process we can
+    /// report, not just the process being debugged.
+    pub(super) break_on_exec: bool,
+    /// Replay other thread groups in batches and with less validation. See
+    /// `set_fast_skip()`.
+    fast_skip: bool,
+    /// Breakpoints that only stop one thread, by address. Set with the
+    /// `thread-breakpoint` command.
+    pub(super) thread_breakpoints: HashMap<RemoteCodePtr, GdbThreadId>,
+}
+
+/// A syscall entry or exit in the trace that `break-on-syscall` stops at.
+struct SyscallBreak {
+    tuid: TaskUid,
+    time: FrameTime,
+    description: String,
 }
 
 impl GdbServer {
@@ -269,11 +441,37 @@ impl GdbServer {
             emergency_debug_session: Default::default(),
             debugger_restart_checkpoint: Default::default(),
             checkpoints: Default::default(),
+            history: Default::default(),
+            diversion_overrides: Default::default(),
             symbols: Default::default(),
             symbols_loc: Default::default(),
             files: Default::default(),
             file_scope_pid: Default::default(),
+            stop_policy: Default::default(),
+            raw_syscallbuf_frames: false,
+            run_direction: Default::default(),
+            syscall_breaks: Vec::new(),
+            syscall_break_hit: None,
+            break_on_exec: false,
+            fast_skip: false,
+            thread_breakpoints: HashMap::new(),
+        }
+    }
+
+    pub fn set_stop_policy(&mut self, stop_policy: GdbStopPolicy) {
+        if let Some(timeline) = self.timeline.as_ref() {
+            timeline
+                .borrow_mut()
+                .set_report_time_slice_signals(stop_policy.reports_internal_signals());
         }
+        self.stop_policy = stop_policy;
+    }
+
+    /// When gdb can only see the thread group being debugged, replay other
+    /// thread groups without validating them against the trace and without
+    /// going back to gdb between their events.
+    pub fn set_fast_skip(&mut self, fast_skip: bool) {
+        self.fast_skip = fast_skip;
     }
 
     fn new_from(dbg: GdbConnection, t: &TaskInner) -> GdbServer {
@@ -294,9 +492,19 @@ impl GdbServer {
             timeline: Default::default(),
             debugger_restart_checkpoint: Default::default(),
             checkpoints: Default::default(),
+            history: Default::default(),
+            diversion_overrides: Default::default(),
             symbols: Default::default(),
             symbols_loc: Default::default(),
             files: Default::default(),
+            stop_policy: Default::default(),
+            raw_syscallbuf_frames: false,
+            run_direction: Default::default(),
+            syscall_breaks: Vec::new(),
+            syscall_break_hit: None,
+            break_on_exec: false,
+            fast_skip: false,
+            thread_breakpoints: HashMap::new(),
         }
     }
 
@@ -359,6 +567,10 @@ impl GdbServer {
 
     /// Actually run the server. Returns only when the debugger disconnects.
     pub fn serve_replay(&mut self, flags: &ConnectionFlags) {
+        replay_watchdog::set_activity(Some(format!(
+            "replaying to event {} before the debugger connects",
+            self.target.event
+        )));
         loop {
             let result = self
                 .timeline_unwrap_mut()
@@ -367,10 +579,12 @@ impl GdbServer {
                 log!(LogInfo, "Debugger was not launched before end of trace");
                 return;
             }
+            self.publish_progress();
             if self.at_target() {
                 break;
             }
         }
+        replay_watchdog::set_activity(None);
 
         let mut port: u16 = match flags.dbg_port {
             Some(port) => port,
@@ -436,6 +650,12 @@ impl GdbServer {
                     .close();
             }
             self.debuggee_tguid = t.thread_group().borrow().tguid();
+            if self.fast_skip && !flags.all_thread_groups {
+                // Checkpoints taken on the way here keep validating everything.
+                self.timeline_unwrap()
+                    .current_session()
+                    .set_validated_thread_group(Some(self.debuggee_tguid));
+            }
 
             let first_run_event = t.vm().first_run_event();
             if first_run_event > 0 {
@@ -444,13 +664,15 @@ impl GdbServer {
             }
         }
 
+        let features = GdbConnectionFeatures {
+            all_thread_groups: flags.all_thread_groups,
+            position_in_stop_reply: flags.position_in_stop_reply,
+            ..Default::default()
+        };
         loop {
             log!(LogDebug, "initializing debugger connection");
             self.dbg = Some(Rc::new(RefCell::new(await_connection(
-                t_tgid,
-                t_arch,
-                &listen_fd,
-                GdbConnectionFeatures::default(),
+                t_tgid, t_arch, &listen_fd, features,
             ))));
             self.activate_debugger();
 
@@ -478,9 +700,13 @@ impl GdbServer {
         params_pipe_fd: &ScopedFd,
         gdb_binary_file_path: &Path,
         gdb_options: &[OsString],
+        session_files: Option<&GdbSessionFiles>,
     ) {
-        let macros = gdb_rd_macros();
-        let gdb_command_file = create_gdb_command_file(macros);
+        let mut macros = gdb_rd_macros().to_owned();
+        if let Some(session_files) = session_files {
+            macros.push_str(&session_files.quit_hook());
+        }
+        let gdb_command_file = create_gdb_command_file(&macros);
 
         let mut params = DebuggerParams::default();
         let mut res;
@@ -515,12 +741,18 @@ impl GdbServer {
                 && needs_target(&gdb_options[i + 1])
             {
                 push_target_remote_cmd(&mut args, host, params.port);
+                if let Some(session_files) = session_files {
+                    session_files.push_restore_cmds(&mut args);
+                }
                 did_set_remote = true;
             }
             args.push(gdb_options[i].clone());
         }
         if !did_set_remote {
             push_target_remote_cmd(&mut args, host, params.port);
+            if let Some(session_files) = session_files {
+                session_files.push_restore_cmds(&mut args);
+            }
         }
         args.push(exe_image);
 
@@ -562,6 +794,8 @@ impl GdbServer {
         // mode (and we don't want to require users to do that)
         let features: GdbConnectionFeatures = GdbConnectionFeatures {
             reverse_execution: false,
+            all_thread_groups: false,
+            position_in_stop_reply: false,
         };
         let mut port: u16 = t.tid() as u16;
         let listen_fd = open_socket(LOCALHOST_ADDR, &mut port, ProbePort::ProbePort);
@@ -611,7 +845,9 @@ impl GdbServer {
     fn dispatch_regs_request(&mut self, regs: &Registers, extra_regs: &ExtraRegisters) {
         // Send values for all the registers we sent XML register descriptions for.
         // Those descriptions are controlled by GdbConnection::cpu_features().
-        let have_avx = (self.dbg_unwrap().cpu_features() & GdbConnection::CPU_AVX) != 0;
+        let cpu_features = self.dbg_unwrap().cpu_features();
+        let have_avx = (cpu_features & GdbConnection::CPU_AVX) != 0;
+        let have_avx512 = (cpu_features & GdbConnection::CPU_AVX512) != 0;
         let end = match regs.arch() {
             SupportedArch::X86 => {
                 if have_avx {
@@ -621,7 +857,9 @@ impl GdbServer {
                 }
             }
             SupportedArch::X64 => {
-                if have_avx {
+                if have_avx512 {
+                    DREG_64_ZMM31H
+                } else if have_avx {
                     DREG_64_YMM15H
                 } else {
                     DREG_ORIG_RAX
@@ -644,6 +882,18 @@ impl GdbServer {
         self.dbg_unwrap_mut().reply_get_regs(&rs);
     }
 
+    /// The registers of `target` as gdb should see them. Unless the user asked
+    /// for raw frames, this hides the syscallbuf machinery when `target` is
+    /// stopped inside it.
+    fn regs_for_debugger(&self, target: &dyn Task) -> Registers {
+        if !self.raw_syscallbuf_frames {
+            if let Some(regs) = syscallbuf_user_frame_regs(target) {
+                return regs;
+            }
+        }
+        target.regs_ref().clone()
+    }
+
     fn maybe_intercept_mem_request(target: &dyn Task, req: &GdbRequest, result: &mut [u8]) {
         // Crazy hack!
         // When gdb tries to read the word at the top of the stack, and we're in our
@@ -708,17 +958,19 @@ impl GdbServer {
                     "Replay interrupts should be handled at a higher level"
                 );
                 if let Some(t) = maybe_t {
-                    debug_assert_eq!(t.thread_group().borrow().tguid(), self.debuggee_tguid);
+                    debug_assert!(
+                        self.dbg_unwrap().reports_all_thread_groups()
+                            || t.thread_group().borrow().tguid() == self.debuggee_tguid
+                    );
                     let threadid = get_threadid(&**t);
                     self.dbg_unwrap_mut()
                         .notify_stop(threadid, None, RemotePtr::null());
                     self.last_query_tuid = t.tuid();
                     self.last_continue_tuid = t.tuid();
                 } else {
-                    self.dbg_unwrap_mut().notify_stop(
-                        GdbThreadId::default(),
-                        None,
-                        RemotePtr::null(),
+                    log!(
+                        LogDebug,
+                        "No task to report interrupt for; ignoring it until the next stop"
                     );
                 }
                 self.stop_siginfo = Default::default();
@@ -749,7 +1001,8 @@ impl GdbServer {
                 return;
             }
             DREQ_FILE_SETFS => {
-                // Only the filesystem as seen by the remote stub is supported currently
+                // Later vFile requests are resolved as this process sees the
+                // filesystem. See open_tracee_file().
                 self.file_scope_pid = req.file_setfs().pid;
                 self.dbg_unwrap_mut().reply_setfs(0);
                 return;
@@ -794,17 +1047,17 @@ impl GdbServer {
         }
 
         let is_query = req.type_ != DREQ_SET_CONTINUE_THREAD;
-        let maybe_target: Option<TaskSharedPtr> = if req.target.tid > 0 {
-            session.find_task_from_rec_tid(req.target.tid)
-        } else {
-            session.find_task_from_task_uid(if is_query {
+        let resolved = resolve_threadid(
+            session,
+            req.target,
+            if is_query {
                 self.last_query_tuid
             } else {
                 self.last_continue_tuid
-            })
-        };
+            },
+        );
 
-        if let Some(t) = maybe_target.as_ref() {
+        if let Ok(t) = resolved.as_ref() {
             if is_query {
                 self.last_query_tuid = t.tuid();
             } else {
@@ -816,22 +1069,11 @@ impl GdbServer {
         match req.type_ {
             DREQ_GET_IS_THREAD_ALIVE => {
                 self.dbg_unwrap_mut()
-                    .reply_get_is_thread_alive(maybe_target.is_some());
+                    .reply_get_is_thread_alive(resolved.is_ok());
                 return;
             }
-            DREQ_GET_THREAD_EXTRA_INFO => {
-                self.dbg_unwrap_mut()
-                    .reply_get_thread_extra_info(&maybe_target.as_ref().unwrap().name());
-                return;
-            }
-            DREQ_SET_CONTINUE_THREAD => {
-                self.dbg_unwrap_mut()
-                    .reply_select_thread(maybe_target.is_some());
-                return;
-            }
-            DREQ_SET_QUERY_THREAD => {
-                self.dbg_unwrap_mut()
-                    .reply_select_thread(maybe_target.is_some());
+            DREQ_SET_CONTINUE_THREAD | DREQ_SET_QUERY_THREAD => {
+                self.dbg_unwrap_mut().reply_select_thread(resolved.is_ok());
                 return;
             }
             _ => (),
@@ -839,17 +1081,31 @@ impl GdbServer {
         // These requests require a valid target task.  We don't trust
         // the debugger to use the information provided above to only
         // query valid tasks.
-        if maybe_target.is_none() {
-            self.dbg_unwrap_mut().notify_no_such_thread(req);
-            return;
-        }
-        let target = maybe_target.unwrap();
+        let target = match resolved {
+            Ok(t) => t,
+            Err(e) => {
+                log!(LogError, "Can't find the target of the gdb request: {}", e);
+                self.dbg_unwrap_mut().notify_no_such_thread(req);
+                return;
+            }
+        };
         match req.type_ {
+            DREQ_GET_THREAD_EXTRA_INFO => {
+                self.dbg_unwrap_mut()
+                    .reply_get_thread_extra_info(&target.name());
+                return;
+            }
             DREQ_GET_AUXV => {
                 self.dbg_unwrap_mut()
                     .reply_get_auxv(&target.vm().saved_auxv());
                 return;
             }
+            DREQ_GET_LIBRARIES_SVR4 => {
+                let libraries = libraries_svr4(&**target).unwrap_or_default();
+                self.dbg_unwrap_mut()
+                    .reply_get_libraries_svr4(libraries.as_bytes());
+                return;
+            }
             DREQ_GET_MEM => {
                 let mut mem: Vec<u8> = vec![0u8; req.mem().len];
                 let nread = target.read_bytes_fallible(req.mem().addr, &mut mem);
@@ -907,13 +1163,14 @@ impl GdbServer {
                 return;
             }
             DREQ_GET_REG => {
-                let reg =
-                    Self::get_reg(&target.regs_ref(), &target.extra_regs_ref(), req.reg().name);
+                let regs = self.regs_for_debugger(target);
+                let reg = Self::get_reg(&regs, &target.extra_regs_ref(), req.reg().name);
                 self.dbg_unwrap_mut().reply_get_reg(&reg);
                 return;
             }
             DREQ_GET_REGS => {
-                self.dispatch_regs_request(&target.regs_ref(), &target.extra_regs_ref());
+                let regs = self.regs_for_debugger(target);
+                self.dispatch_regs_request(&regs, &target.extra_regs_ref());
                 return;
             }
             DREQ_SET_REG => {
@@ -948,6 +1205,9 @@ impl GdbServer {
             DREQ_GET_STOP_REASON => {
                 let threadid = get_threadid_from_tuid(session, self.last_continue_tuid);
                 let maybe_sig = Sig::try_from(self.stop_siginfo.si_signo).ok();
+                if let Some(t) = session.find_task_from_task_uid(self.last_continue_tuid) {
+                    self.update_replay_position(&**t);
+                }
                 self.dbg_unwrap_mut()
                     .reply_get_stop_reason(threadid, maybe_sig);
                 return;
@@ -969,7 +1229,7 @@ impl GdbServer {
                 let ok = self.timeline_unwrap_mut().add_breakpoint(
                     replay_task.as_replay_task().unwrap(),
                     req.watch().addr.to_code_ptr(),
-                    breakpoint_condition(req),
+                    self.breakpoint_condition_for(req),
                 );
                 if ok
                     && !session
@@ -995,7 +1255,7 @@ impl GdbServer {
                     req.watch().addr,
                     req.watch().kind,
                     watchpoint_type(req.type_),
-                    breakpoint_condition(req),
+                    self.breakpoint_condition_for(req),
                 );
                 if ok
                     && !session
@@ -1271,13 +1531,19 @@ impl GdbServer {
 
         let mut maybe_checkpoint_to_restore = None;
         if req.restart().type_ == GdbRestartType::FromCheckpoint {
-            let maybe_it = self.checkpoints.get(&req.restart().param).cloned();
+            let maybe_it = self
+                .checkpoints
+                .find_id(OsStr::new(&req.restart().param_str))
+                .and_then(|id| self.checkpoints.get(id).cloned());
             match maybe_it {
                 None => {
                     println!("Checkpoint {} not found.", req.restart().param_str);
                     println!("Valid checkpoints:");
-                    for &i in self.checkpoints.keys() {
-                        println!(" {}", i);
+                    for (i, c) in self.checkpoints.iter() {
+                        match c.name.as_ref() {
+                            Some(name) => println!(" {} ({})", i, name.to_string_lossy()),
+                            None => println!(" {}", i),
+                        }
                     }
                     println!();
                     self.dbg_unwrap_mut().notify_restart_failed();
@@ -1355,7 +1621,12 @@ impl GdbServer {
     fn process_debugger_requests(&mut self, maybe_state: Option<ReportState>) -> GdbRequest {
         loop {
             let state = maybe_state.unwrap_or(ReportState::ReportNormal);
+            replay_watchdog::set_activity(None);
             let mut req = self.dbg_unwrap_mut().get_request();
+            replay_watchdog::set_activity(Some(format!(
+                "handling debugger request {} for thread {}",
+                req.type_, req.target
+            )));
             req.suppress_debugger_stop = false;
             self.try_lazy_reverse_singlesteps(&mut req);
 
@@ -1453,6 +1724,50 @@ impl GdbServer {
         fatal!("Received continue/interrupt request after end-of-trace.");
     }
 
+    /// Should replay stop before `t` completes an exec? We always stop for
+    /// the process being debugged, and with `break-on-exec` for any process
+    /// whose stops gdb would be told about.
+    fn stops_at_exec(&self, t: &dyn Task) -> bool {
+        if t.thread_group().borrow().tguid() == self.debuggee_tguid {
+            return true;
+        }
+        self.break_on_exec
+            && (self.dbg_unwrap().reports_all_thread_groups()
+                || self.stop_policy.reports_task(t, self.debuggee_tguid))
+    }
+
+    /// With fast skipping on, keep replaying forward while the current task is
+    /// in a thread group gdb can't see and nothing happened that could stop
+    /// replay. This avoids a round trip through gdb request handling for every
+    /// event of the other thread groups.
+    fn skip_other_thread_groups(&mut self, mut result: ReplayResult) -> ReplayResult {
+        if !self.fast_skip || self.dbg_unwrap().reports_all_thread_groups() {
+            return result;
+        }
+        let gdb_connection = self.dbg.as_ref().unwrap().clone();
+        while result.status == ReplayStatus::ReplayContinue
+            && !result.break_status.any_break()
+            && !result.break_status.task_exit
+            && self.syscall_breaks.is_empty()
+            && !gdb_connection.borrow_mut().sniff_packet()
+        {
+            let maybe_t = self.timeline_unwrap().current_session().current_task();
+            let t = match maybe_t {
+                Some(t) => t,
+                None => break,
+            };
+            if t.thread_group().borrow().tguid() == self.debuggee_tguid
+                || (self.break_on_exec && is_in_exec(&self.timeline_unwrap()).is_some())
+            {
+                break;
+            }
+            result = self
+                .timeline_unwrap_mut()
+                .replay_step_forward(RunCommand::Continue, self.target.event);
+        }
+        result
+    }
+
     fn debug_one_step(&mut self, last_resume_request: &mut GdbRequest) -> ContinueOrStop {
         let mut result: ReplayResult = Default::default();
         let mut req: GdbRequest;
@@ -1489,6 +1804,15 @@ impl GdbServer {
 
         if req.is_resume_request() {
             *last_resume_request = req.clone();
+            self.run_direction = req.cont().run_direction;
+            replay_watchdog::set_activity(Some(format!(
+                "running {} for the debugger, thread {}",
+                match self.run_direction {
+                    RunDirection::RunForward => "forward",
+                    RunDirection::RunBackward => "backward",
+                },
+                req.target
+            )));
         } else {
             debug_assert_eq!(req.type_, DREQ_INTERRUPT);
             self.interrupt_pending = true;
@@ -1510,29 +1834,35 @@ impl GdbServer {
                 } else {
                     None
                 };
+                self.update_replay_position(&**t);
                 self.dbg_unwrap_mut()
                     .notify_stop(threadid, maybe_sig, RemotePtr::null());
+                self.publish_stop(&**t, maybe_sig);
                 self.stop_siginfo = Default::default();
                 return ContinueOrStop::ContinueDebugging;
             }
         }
 
+        self.syscall_break_hit = None;
         if req.cont().run_direction == RunDirection::RunForward {
-            if is_in_exec(&self.timeline_unwrap()).is_some()
-                && self
-                    .timeline_unwrap()
-                    .current_session()
-                    .current_task()
-                    .unwrap()
-                    .thread_group()
-                    .borrow()
-                    .tguid()
-                    == self.debuggee_tguid
+            let maybe_exec_t = is_in_exec(&self.timeline_unwrap());
+            if maybe_exec_t
+                .as_ref()
+                .map_or(false, |exec_t| self.stops_at_exec(&***exec_t))
             {
                 // Don't go any further forward. maybe_notify_stop will generate a
                 // stop.
                 result = ReplayResult::default();
             } else {
+                if let Some(exec_t) = maybe_exec_t.filter(|_| self.break_on_exec) {
+                    // gdb can't be told about this process, but the user still
+                    // wants to know where it went.
+                    eprintln!(
+                        "{} (not stopping: use -p {} or --all-processes to debug it)",
+                        exec_description(&**exec_t),
+                        exec_t.tgid()
+                    );
+                }
                 let mut signal_to_deliver: Option<Sig> = None;
                 let task = self
                     .timeline_unwrap()
@@ -1541,10 +1871,22 @@ impl GdbServer {
                     .unwrap();
                 let command: RunCommand =
                     compute_run_command_from_actions(&**task, &req, &mut signal_to_deliver);
+                let syscall_break =
+                    next_syscall_break(&self.timeline_unwrap(), &self.syscall_breaks);
                 // Ignore gdb's |signal_to_deliver|; we just have to follow the replay.
                 result = self
                     .timeline_unwrap_mut()
                     .replay_step_forward(command, self.target.event);
+                if command == RunCommand::Continue {
+                    result = self.skip_other_thread_groups(result);
+                }
+                // The step may have stopped early, e.g. at a breakpoint. Only
+                // stop for the syscall once its frame has been replayed.
+                let now = self
+                    .timeline_unwrap()
+                    .current_session()
+                    .current_frame_time();
+                self.syscall_break_hit = syscall_break.filter(|b| now > b.time);
             }
         } else {
             let mut allowed_tasks: Vec<AllowedTasks> = Vec::new();
@@ -1557,8 +1899,12 @@ impl GdbServer {
                 &mut allowed_tasks,
             );
             let debugee_tguid = self.debuggee_tguid;
+            // Reverse execution decides where to stop by itself, so the stop
+            // policy has to be consulted here rather than in maybe_notify_stop.
+            let stop_policy = self.stop_policy.clone();
+            let all_thread_groups = self.dbg_unwrap().reports_all_thread_groups();
             let stop_filter = move |t: &ReplayTask| -> bool {
-                if t.thread_group().borrow().tguid() != debugee_tguid {
+                if !all_thread_groups && !stop_policy.reports_task(t, debugee_tguid) {
                     return false;
                 }
                 // If gdb's requested actions don't allow the task to run, we still
@@ -1721,11 +2067,10 @@ impl GdbServer {
                 }
 
                 DREQ_SET_QUERY_THREAD => {
-                    if req.target.tid > 0 {
-                        if let Some(next) = diversion_session.find_task_from_rec_tid(req.target.tid)
-                        {
-                            self.last_query_tuid = next.tuid();
-                        }
+                    if let Ok(next) =
+                        resolve_threadid(diversion_session, req.target, self.last_query_tuid)
+                    {
+                        self.last_query_tuid = next.tuid();
                     }
                 }
 
@@ -1790,6 +2135,17 @@ impl GdbServer {
                 .apply_breakpoints_and_watchpoints();
         }
         let diversion_session = replay.clone_diversion();
+        if !self.diversion_overrides.is_empty() {
+            if let Some(t) = diversion_session.find_task_from_task_uid(self.last_continue_tuid) {
+                if let Err(e) = diversion_session
+                    .as_diversion()
+                    .unwrap()
+                    .apply_overrides(&**t, &self.diversion_overrides)
+                {
+                    log!(LogWarn, "Can't apply rd-divert-env/rd-divert-arg: {}", e);
+                }
+            }
+        }
         let mut diversion_refcount: usize = 1;
         let saved_query_tuid = self.last_query_tuid;
 
@@ -1812,7 +2168,8 @@ impl GdbServer {
                 continue;
             }
 
-            let maybe_t = diversion_session.find_task_from_task_uid(self.last_continue_tuid);
+            let maybe_t = diversion_task_for_actions(diversion_session, &req)
+                .or_else(|| diversion_session.find_task_from_task_uid(self.last_continue_tuid));
             if maybe_t.is_none() {
                 diversion_refcount = 0;
                 req = GdbRequest::new(DREQ_NONE);
@@ -1853,29 +2210,60 @@ impl GdbServer {
     fn maybe_notify_stop(&mut self, req: &GdbRequest, break_status: &BreakStatus) {
         let mut do_stop = false;
         let mut watch_addr: RemotePtr<Void> = Default::default();
-        if !break_status.watchpoints_hit.is_empty() {
+        let mut maybe_t = break_status.task.upgrade();
+        // gdb may have resumed only some threads, but the replay has to run
+        // whichever task the trace says runs next. A completed singlestep in a
+        // task gdb didn't resume isn't reported since nothing happened there.
+        // Breakpoints, watchpoints and signals are always reported, whichever
+        // task hits them.
+        let resumed = match maybe_t.as_ref() {
+            Some(t) => {
+                req.cont().run_direction == RunDirection::RunBackward
+                    || is_resumed_by_actions(&**t, req)
+            }
+            None => true,
+        };
+        if !break_status.watchpoints_hit.is_empty()
+            && self.stop_policy.reports(StopReason::Watchpoint)
+        {
             do_stop = true;
             self.stop_siginfo = Default::default();
             self.stop_siginfo.si_signo = SIGTRAP;
             watch_addr = break_status.watchpoints_hit[0].addr;
             log!(LogDebug, "Stopping for watchpoint at {}", watch_addr);
         }
-        if break_status.breakpoint_hit || break_status.singlestep_complete {
-            do_stop = true;
-            self.stop_siginfo = Default::default();
-            self.stop_siginfo.si_signo = SIGTRAP;
-            if break_status.breakpoint_hit {
+        if break_status.breakpoint_hit {
+            if self.stop_policy.reports(StopReason::Breakpoint) {
+                do_stop = true;
+                self.stop_siginfo = Default::default();
+                self.stop_siginfo.si_signo = SIGTRAP;
                 log!(LogDebug, "Stopping for breakpoint");
-            } else {
-                log!(LogDebug, "Stopping for singlestep");
             }
         }
-        if break_status.signal.is_some() {
+        if resumed
+            && break_status.singlestep_complete
+            && self.stop_policy.reports(StopReason::Singlestep)
+        {
             do_stop = true;
-            self.stop_siginfo = **break_status.signal.as_ref().unwrap();
-            log!(LogDebug, "Stopping for signal {}", self.stop_siginfo);
+            self.stop_siginfo = Default::default();
+            self.stop_siginfo.si_signo = SIGTRAP;
+            log!(LogDebug, "Stopping for singlestep");
+        }
+        if let Some(siginfo) = break_status.signal.as_ref() {
+            let reported = match Sig::try_from(siginfo.si_signo) {
+                Ok(sig) => self.stop_policy.reports(StopReason::Signal(sig)),
+                Err(_) => true,
+            };
+            if reported {
+                do_stop = true;
+                self.stop_siginfo = **siginfo;
+                log!(LogDebug, "Stopping for signal {}", self.stop_siginfo);
+            }
         }
-        if is_last_thread_exit(break_status) && self.dbg_unwrap().features().reverse_execution {
+        if is_last_thread_exit(break_status)
+            && self.dbg_unwrap().features().reverse_execution
+            && self.stop_policy.reports(StopReason::LastThreadExit)
+        {
             do_stop = true;
             self.stop_siginfo = Default::default();
             if req.cont().run_direction == RunDirection::RunForward {
@@ -1893,104 +2281,149 @@ impl GdbServer {
                 );
             }
         }
-        let mut maybe_t = break_status.task.upgrade();
+        let mut exec_stop_description = None;
         let maybe_in_exec_task = is_in_exec(&self.timeline_unwrap());
         if let Some(in_exec_task) = maybe_in_exec_task {
-            do_stop = true;
-            self.stop_siginfo = Default::default();
-            maybe_t = Some(in_exec_task);
-            log!(LogDebug, "Stopping at exec");
+            if self.stop_policy.reports(StopReason::Exec) {
+                do_stop = true;
+                self.stop_siginfo = Default::default();
+                if self.break_on_exec {
+                    exec_stop_description = Some(exec_description(&**in_exec_task));
+                }
+                maybe_t = Some(in_exec_task);
+                log!(LogDebug, "Stopping at exec");
+            }
+        }
+        let mut syscall_description = None;
+        if let Some(syscall_break) = self.syscall_break_hit.take() {
+            let maybe_syscall_t = self
+                .timeline_unwrap()
+                .current_session()
+                .find_task_from_task_uid(syscall_break.tuid);
+            if let Some(syscall_t) = maybe_syscall_t {
+                if self.stop_policy.reports(StopReason::Syscall) {
+                    do_stop = true;
+                    self.stop_siginfo = Default::default();
+                    self.stop_siginfo.si_signo = SIGTRAP;
+                    maybe_t = Some(syscall_t);
+                    log!(LogDebug, "Stopping for {}", syscall_break.description);
+                    syscall_description = Some(syscall_break.description);
+                }
+            }
         }
         if do_stop {
             let t = maybe_t.unwrap();
-            if t.thread_group().borrow().tguid() == self.debuggee_tguid {
+            if self.dbg_unwrap().reports_all_thread_groups()
+                || self.stop_policy.reports_task(&**t, self.debuggee_tguid)
+            {
+                if let Some(description) = syscall_description.or(exec_stop_description) {
+                    eprintln!("{}", description);
+                }
                 // Notify the debugger and process any new requests
                 // that might have triggered before resuming.
                 let signo = self.stop_siginfo.si_signo;
                 let threadid = get_threadid(&**t);
+                self.update_replay_position(&**t);
                 self.dbg_unwrap_mut()
                     .notify_stop(threadid, Sig::try_from(signo).ok(), watch_addr);
+                self.publish_stop(&**t, Sig::try_from(signo).ok());
                 self.last_continue_tuid = t.tuid();
                 self.last_query_tuid = t.tuid();
             }
         }
     }
 
+    /// The condition gdb gave for the breakpoint or watchpoint `req` sets,
+    /// further restricted to one thread if `thread-breakpoint` asked for it.
+    /// Other threads then hit the breakpoint and carry on without gdb ever
+    /// hearing about it.
+    fn breakpoint_condition_for(&self, req: &GdbRequest) -> Option<Box<dyn BreakpointCondition>> {
+        let condition = breakpoint_condition(req);
+        if req.type_ != DREQ_SET_SW_BREAK && req.type_ != DREQ_SET_HW_BREAK {
+            return condition;
+        }
+        match self.thread_breakpoints.get(&req.watch().addr.to_code_ptr()) {
+            Some(&thread) => Some(Box::new(ThreadBreakpointCondition { thread, condition })),
+            None => condition,
+        }
+    }
+
+    /// Where the replay of `t` is. `None` in a diversion, which doesn't move
+    /// the replay.
+    pub(super) fn replay_position(&self, t: &dyn Task) -> Option<ReplayPosition> {
+        if !t.session().is_replaying() {
+            return None;
+        }
+        Some(ReplayPosition {
+            event: t.as_replay_task().unwrap().current_trace_frame().time(),
+            ticks: t.tick_count(),
+            direction: self.run_direction,
+        })
+    }
+
+    /// Record the position sent with the next stop reply. Diversions keep
+    /// reporting the position they were started at.
+    fn update_replay_position(&mut self, t: &dyn Task) {
+        if let Some(position) = self.replay_position(t) {
+            self.dbg_unwrap_mut().set_replay_position(Some(position));
+        }
+    }
+
+    /// Tell notification clients how far the replay has got.
+    fn publish_progress(&self) {
+        let timeline = self.timeline_unwrap();
+        let trace_reader = timeline.current_session().trace_reader();
+        replay_notifier::publish(&Notification::Progress {
+            event: trace_reader.time(),
+            recording_time: trace_reader.recording_time(),
+        });
+    }
+
+    /// Tell notification clients about a stop just reported to the debugger.
+    /// Stops in a diversion aren't published since they don't move the
+    /// replay.
+    fn publish_stop(&self, t: &dyn Task, maybe_sig: Option<Sig>) {
+        if let Some(position) = self.replay_position(t) {
+            replay_notifier::publish(&Notification::Stop {
+                event: position.event,
+                tid: t.rec_tid(),
+                ticks: position.ticks,
+                signal: maybe_sig.map(|sig| sig.to_string()),
+            });
+        }
+    }
+
     /// Handle GDB file open requests. If we can serve this read request, add
     /// an entry to `files` with the file contents and return our internal
     /// file descriptor.
-    #[allow(clippy::unnecessary_unwrap)]
+    ///
+    /// We serve the /proc files `info proc` reads from the replay's model of
+    /// the tracee, since the real ones would describe the replay machine.
+    /// Other files are only served if the trace recorded them, see
+    /// `open_tracee_file()`.
     fn open_file(&mut self, session: &dyn Session, pathname: &OsStr) -> i32 {
-        // XXX should we require file_scope_pid == 0 here?
         log!(LogDebug, "Trying to open {:?}", pathname);
 
-        let mut content = ScopedFd::new();
-        let pathname = Path::new(pathname);
-        let mut components = pathname.components();
-        let maybe_rootdir = components.next();
-        let maybe_proc = components.next();
-        let maybe_pid_os_str = components.next();
-        let maybe_task_or_maps = components.next();
-        let maybe_tid_os_str = components.next();
-        let maybe_maps = components.next();
-        if (maybe_rootdir, maybe_proc, maybe_task_or_maps, maybe_maps)
-            == (
-                Some(Component::RootDir),
-                Some(Component::Normal(OsStr::new("proc"))),
-                Some(Component::Normal(OsStr::new("task"))),
-                Some(Component::Normal(OsStr::new("maps"))),
-            )
-            && maybe_pid_os_str.is_some()
-            && maybe_tid_os_str.is_some()
-        {
-            let maybe_pid_str =
-                std::str::from_utf8(maybe_pid_os_str.unwrap().as_os_str().as_bytes()).ok();
-            let maybe_tid_str =
-                std::str::from_utf8(maybe_tid_os_str.unwrap().as_os_str().as_bytes()).ok();
-            match (maybe_pid_str, maybe_tid_str) {
-                (Some(pid_s), Some(tid_s)) => {
-                    let maybe_pid = pid_s.parse::<pid_t>().ok();
-                    let maybe_tid = tid_s.parse::<pid_t>().ok();
-                    match (maybe_pid, maybe_tid) {
-                        (Some(pid), Some(tid)) if pid == tid => {
-                            if let Some(t) = session.find_task_from_rec_tid(tid) {
-                                content = generate_fake_proc_maps(&**t)
-                            } else {
-                                return -1;
-                            }
-                        }
-                        _ => return -1,
-                    }
-                }
-                _ => return -1,
-            }
-        } else if (maybe_rootdir, maybe_proc, maybe_task_or_maps)
-            == (
-                Some(Component::RootDir),
-                Some(Component::Normal(OsStr::new("proc"))),
-                Some(Component::Normal(OsStr::new("maps"))),
-            )
-            && maybe_pid_os_str.is_some()
-        {
-            let maybe_pid_str =
-                std::str::from_utf8(maybe_pid_os_str.unwrap().as_os_str().as_bytes()).ok();
-            match maybe_pid_str {
-                Some(pid_s) => {
-                    let maybe_pid = pid_s.parse::<pid_t>().ok();
-                    match maybe_pid {
-                        Some(pid) => {
-                            if let Some(t) = session.find_task_from_rec_tid(pid) {
-                                content = generate_fake_proc_maps(&**t)
-                            } else {
-                                return -1;
-                            }
-                        }
-                        _ => return -1,
-                    }
+        let content = match parse_proc_path(Path::new(pathname)) {
+            Some((pid, tid, file)) => {
+                let t = match session.find_task_from_rec_tid(tid) {
+                    Some(t) if t.tgid() == pid => t,
+                    _ => return -1,
+                };
+                if file == OsStr::new("maps") {
+                    generate_fake_proc_maps(&**t)
+                } else if file == OsStr::new("status") {
+                    generate_fake_proc_status(&**t)
+                } else {
+                    return -1;
                 }
-                _ => return -1,
             }
-        }
+            None => match self.open_tracee_file(session, pathname) {
+                Some(fd) => fd,
+                None => return -1,
+            },
+        };
+
         let mut ret_fd: i32 = 0;
         while self.files.get(&ret_fd).is_some() {
             ret_fd += 1;
@@ -1998,6 +2431,147 @@ impl GdbServer {
         self.files.insert(ret_fd, content);
         ret_fd
     }
+
+    /// Open `pathname` for reading as a tracee of the process selected with
+    /// vFile:setfs sees it, or the current thread's process if gdb didn't
+    /// select one.
+    ///
+    /// Only files the trace recorded a mapping of are served, so gdb can't
+    /// read arbitrary files of the replay host. Those that were copied into
+    /// the trace directory during recording (or by `rd pack`) are served from
+    /// that copy. The others are opened on the host, under the tracee's root
+    /// directory so that they're resolved in the tracee's mount namespace, and
+    /// refused if their inode changed since, i.e. they were replaced. Changes
+    /// made in place are not detected.
+    fn open_tracee_file(&self, session: &dyn Session, pathname: &OsStr) -> Option<ScopedFd> {
+        if !pathname.as_bytes().starts_with(b"/") {
+            return None;
+        }
+        let t = if self.file_scope_pid != 0 {
+            session
+                .find_task_from_rec_tid(self.file_scope_pid)
+                .filter(|t| t.tgid() == self.file_scope_pid)?
+        } else {
+            session
+                .find_task_from_task_uid(self.last_query_tuid)
+                .or_else(|| session.find_task_from_task_uid(self.last_continue_tuid))?
+        };
+
+        let trace_dir = self.timeline.as_ref().map(|timeline| {
+            PathBuf::from(
+                timeline
+                    .borrow()
+                    .current_session()
+                    .trace_reader()
+                    .trace_stream()
+                    .dir(),
+            )
+        });
+        let mut recorded_inode = None;
+        let vms = t.session().vms();
+        let own_vm_first = vms
+            .iter()
+            .filter(|vm| Rc::ptr_eq(vm, &t.vm()))
+            .chain(vms.iter().filter(|vm| !Rc::ptr_eq(vm, &t.vm())));
+        for vm in own_vm_first {
+            for (_, m) in &vm.maps() {
+                if m.recorded_map.fsname() != pathname {
+                    continue;
+                }
+                let in_trace = trace_dir
+                    .as_ref()
+                    .map_or(false, |dir| Path::new(m.map.fsname()).starts_with(dir));
+                if in_trace {
+                    let fd =
+                        ScopedFd::open_path(m.map.fsname(), OFlag::O_RDONLY | OFlag::O_CLOEXEC);
+                    if fd.is_open() {
+                        log!(LogDebug, "Serving {:?} from {:?}", pathname, m.map.fsname());
+                        return Some(fd);
+                    }
+                }
+                recorded_inode.get_or_insert(m.recorded_map.inode());
+            }
+        }
+
+        let inode = match recorded_inode {
+            Some(inode) => inode,
+            None => {
+                log!(
+                    LogDebug,
+                    "{:?} isn't in the trace, not serving it",
+                    pathname
+                );
+                return None;
+            }
+        };
+        let mut root_path = OsString::from(format!("/proc/{}/root", t.tid()));
+        root_path.push(pathname);
+        let fd = ScopedFd::open_path(root_path.as_os_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC);
+        if !fd.is_open() {
+            return None;
+        }
+        match fstat(fd.as_raw()) {
+            Ok(st) if st.st_ino == inode => Some(fd),
+            _ => {
+                log!(
+                    LogWarn,
+                    "{:?} was replaced after it was recorded, not serving it",
+                    pathname
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Split `/proc/<pid>/<file>` or `/proc/<pid>/task/<tid>/<file>` into the
+/// pid, the tid (the pid for the former) and the file name.
+fn parse_proc_path(path: &Path) -> Option<(pid_t, pid_t, &OsStr)> {
+    let parse_pid = |s: &OsStr| s.to_str()?.parse::<pid_t>().ok();
+    let mut components = path.strip_prefix("/proc").ok()?.iter();
+    let pid = parse_pid(components.next()?)?;
+    let mut tid = pid;
+    let mut file = components.next()?;
+    if file == OsStr::new("task") {
+        tid = parse_pid(components.next()?)?;
+        file = components.next()?;
+    }
+    if components.next().is_some() {
+        return None;
+    }
+    Some((pid, tid, file))
+}
+
+/// The subset of /proc/<tid>/status we know at replay time.
+fn generate_fake_proc_status(t: &dyn Task) -> ScopedFd {
+    let file = create_temporary_file(b"rd-fake-proc-status-XXXXXX");
+    unlink(file.name.as_os_str()).unwrap();
+
+    let fd = match dup(file.fd.as_raw()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            fatal!("Cannot dup: {:?}", e)
+        }
+    };
+    let mut f = unsafe { File::from_raw_fd(fd) };
+
+    let tg_shr = t.thread_group();
+    let tg = tg_shr.borrow();
+    let ppid = tg.parent().map_or(0, |parent| parent.borrow().tgid);
+    let mut s = Vec::<u8>::new();
+    s.extend_from_slice(b"Name:\t");
+    s.extend_from_slice(t.name().as_bytes());
+    write!(
+        s,
+        "\nTgid:\t{}\nPid:\t{}\nPPid:\t{}\nThreads:\t{}\n",
+        tg.tgid,
+        t.rec_tid(),
+        ppid,
+        tg.task_set().inner_hashset().len()
+    )
+    .unwrap();
+    f.write_all(&s).unwrap();
+    file.fd
 }
 
 fn generate_fake_proc_maps(t: &dyn Task) -> ScopedFd {
@@ -2152,6 +2726,26 @@ fn compute_run_command_from_actions(
     RunCommand::Continue
 }
 
+/// Whether any of gdb's actions in `req` resumes `t`.
+fn is_resumed_by_actions(t: &dyn Task, req: &GdbRequest) -> bool {
+    req.cont()
+        .actions
+        .iter()
+        .any(|action| matches_threadid(t, action.target))
+}
+
+/// The task a diversion should run for `req`: the first thread gdb names
+/// explicitly, if it exists in `session`.
+///
+/// DIFF NOTE: rr always runs the last continued task in a diversion.
+fn diversion_task_for_actions(session: &dyn Session, req: &GdbRequest) -> Option<TaskSharedPtr> {
+    req.cont()
+        .actions
+        .iter()
+        .filter(|action| action.target.tid > 0)
+        .find_map(|action| resolve_threadid(session, action.target, TaskUid::default()).ok())
+}
+
 fn needs_target(option: &OsStr) -> bool {
     option.as_bytes() == b"continue"
 }
@@ -2246,7 +2840,7 @@ define restart
   run c$arg0
 end
 document restart
-restart at checkpoint N
+restart at checkpoint N, where N is a checkpoint id or name
 checkpoints are created with the 'checkpoint' command
 end
 define hook-run
@@ -2338,6 +2932,244 @@ fn is_in_patch_stubs(t: &dyn Task, ip: RemoteCodePtr) -> bool {
             .contains(MappingFlags::IS_PATCH_STUBS)
 }
 
+/// If `t` is stopped inside the syscallbuf hook, return the registers of the
+/// user-level frame that made the buffered syscall: the ip just after the
+/// patched syscall, the user's stack pointer and the original syscall
+/// arguments. Returns `None` when `t` isn't inside the hook, or is in the short
+/// stretches of stub and trampoline code that run before the hook has
+/// published its `syscall_info`.
+///
+/// DIFF NOTE: rr always reports the raw registers and relies on the CFI in
+/// the preload library to let gdb unwind out of it.
+fn syscallbuf_user_frame_regs(t: &dyn Task) -> Option<Registers> {
+    if !t.vm().syscallbuf_enabled() {
+        return None;
+    }
+    rd_arch_function_selfless!(syscallbuf_user_frame_regs_arch, t.arch(), t)
+}
+
+fn syscallbuf_user_frame_regs_arch<Arch: Architecture>(t: &dyn Task) -> Option<Registers> {
+    let info_ptr = {
+        let locals = t.fetch_preload_thread_locals();
+        let locals = locals.as_ptr() as *const preload_thread_locals<Arch>;
+        Arch::as_rptr(unsafe { (*locals).original_syscall_parameters })
+    };
+    if info_ptr.is_null() {
+        return None;
+    }
+    // Signals that arrive while a task is in the syscallbuf code are stashed
+    // until it leaves, so whatever stopped `t` with the parameters published
+    // must be rd-internal code running on the syscallbuf stack. Check anyway
+    // so a stale pointer can't make us report garbage.
+    let sp = t.regs_ref().sp();
+    let alt_stack_end = t.syscallbuf_alt_stack();
+    if sp < t.scratch_ptr.get() || sp >= alt_stack_end {
+        return None;
+    }
+
+    // syscall_hook.S builds the `syscall_info` on the stack right below the
+    // return address and the user's stack pointer that the stub (x86-64) or
+    // _switch_stack_vsyscall (x86) pushed. In between are the return
+    // addresses of the calls into the trampoline, plus the saved %rbx on
+    // x86-64.
+    let word = size_of::<Arch::unsigned_word>();
+    let frame_words = match Arch::arch() {
+        SupportedArch::X86 => 1,
+        SupportedArch::X64 => 3,
+    };
+    let return_addr_ptr =
+        RemotePtr::<Void>::cast(info_ptr) + size_of::<syscall_info<Arch>>() + frame_words * word;
+    let mut ok = true;
+    let info = read_val_mem(t, info_ptr, Some(&mut ok));
+    let return_addr = read_val_mem(
+        t,
+        RemotePtr::<Arch::unsigned_word>::cast(return_addr_ptr),
+        Some(&mut ok),
+    );
+    let user_sp = read_val_mem(
+        t,
+        RemotePtr::<Arch::unsigned_word>::cast(return_addr_ptr + word),
+        Some(&mut ok),
+    );
+    if !ok {
+        return None;
+    }
+
+    let mut r = t.regs_ref().clone();
+    r.set_ip(RemoteCodePtr::from_val(return_addr.try_into().unwrap()));
+    r.set_sp(RemotePtr::new(user_sp.try_into().unwrap()));
+    r.set_syscallno(Arch::long_as_isize(info.no));
+    r.set_arg1(Arch::long_as_usize(info.args[0]));
+    r.set_arg2(Arch::long_as_usize(info.args[1]));
+    r.set_arg3(Arch::long_as_usize(info.args[2]));
+    r.set_arg4(Arch::long_as_usize(info.args[3]));
+    r.set_arg5(Arch::long_as_usize(info.args[4]));
+    r.set_arg6(Arch::long_as_usize(info.args[5]));
+    Some(r)
+}
+
+/// Build the `qXfer:libraries-svr4` document for the process of `t` the way
+/// gdbserver does: find the dynamic linker's `r_debug` through the
+/// executable's DT_DEBUG entry and walk its `link_map` list. Returns None if
+/// the tracee's memory couldn't be read.
+fn libraries_svr4(t: &dyn Task) -> Option<String> {
+    rd_arch_function_selfless!(libraries_svr4_arch, t.arch(), t)
+}
+
+fn libraries_svr4_arch<Arch: Architecture>(t: &dyn Task) -> Option<String> {
+    let word = size_of::<Arch::unsigned_word>();
+    let read_word = |addr: usize, ok: &mut bool| -> usize {
+        read_val_mem(t, RemotePtr::<Arch::unsigned_word>::new(addr), Some(ok))
+            .try_into()
+            .unwrap()
+    };
+
+    let mut phdr = 0;
+    let mut phnum = 0;
+    for pair in t.vm().saved_auxv().chunks_exact(2 * word) {
+        let value = word_at(&pair[word..]) as usize;
+        match word_at(&pair[..word]) {
+            AT_PHDR => phdr = value,
+            AT_PHNUM => phnum = value,
+            _ => (),
+        }
+    }
+
+    // Only p_type and p_vaddr are needed, and they sit at different offsets
+    // in Elf32_Phdr and Elf64_Phdr.
+    let (phdr_size, vaddr_offset) = match Arch::arch() {
+        SupportedArch::X86 => (32, 8),
+        SupportedArch::X64 => (56, 16),
+    };
+    let mut ok = true;
+    let mut load_bias = 0usize;
+    let mut dynamic = None;
+    for i in 0..phnum {
+        let ph = phdr + i * phdr_size;
+        let p_type = read_val_mem(t, RemotePtr::<u32>::new(ph), Some(&mut ok));
+        let p_vaddr = read_word(ph + vaddr_offset, &mut ok);
+        match p_type {
+            PT_PHDR => load_bias = phdr.wrapping_sub(p_vaddr),
+            PT_DYNAMIC => dynamic = Some(p_vaddr),
+            _ => (),
+        }
+    }
+    if !ok {
+        return None;
+    }
+
+    // A statically linked executable has no libraries, and until the dynamic
+    // linker has filled in DT_DEBUG there are none to report yet.
+    let mut r_debug = 0;
+    if let Some(dynamic) = dynamic {
+        let mut d = dynamic.wrapping_add(load_bias);
+        loop {
+            let tag = read_word(d, &mut ok);
+            if !ok {
+                return None;
+            }
+            if tag == 0 {
+                break;
+            }
+            if tag as u64 == DT_DEBUG {
+                r_debug = read_word(d + word, &mut ok);
+                break;
+            }
+            d += 2 * word;
+        }
+    }
+
+    let mut main_lm = None;
+    let mut libraries = Vec::new();
+    // r_map follows the int r_version, which is padded to a word.
+    let mut lm = if r_debug != 0 {
+        read_word(r_debug + word, &mut ok)
+    } else {
+        0
+    };
+    let mut prev = 0;
+    while ok && lm != 0 {
+        let l_addr = read_word(lm, &mut ok);
+        let l_name = read_word(lm + word, &mut ok);
+        let l_ld = read_word(lm + 2 * word, &mut ok);
+        let l_next = read_word(lm + 3 * word, &mut ok);
+        let l_prev = read_word(lm + 4 * word, &mut ok);
+        if !ok {
+            break;
+        }
+        if l_prev != prev {
+            log!(LogWarn, "Corrupt link_map list at {:#x}", lm);
+            break;
+        }
+        if main_lm.is_none() {
+            // The first entry is the executable itself.
+            main_lm = Some(lm);
+        } else if l_name != 0 {
+            let name = t.read_c_str(RemotePtr::new(l_name));
+            if !name.as_bytes().is_empty() {
+                libraries.push(Svr4Library {
+                    name: String::from_utf8_lossy(name.as_bytes()).into_owned(),
+                    lm,
+                    l_addr,
+                    l_ld,
+                });
+            }
+        }
+        prev = lm;
+        lm = l_next;
+    }
+    if !ok {
+        return None;
+    }
+
+    Some(render_library_list_svr4(main_lm, &libraries))
+}
+
+/// A shared library in the dynamic linker's `link_map` list.
+struct Svr4Library {
+    name: String,
+    /// Address of the library's `link_map` entry
+    lm: usize,
+    l_addr: usize,
+    l_ld: usize,
+}
+
+/// The `qXfer:libraries-svr4:read` reply. `main_lm` is the executable's own
+/// `link_map` entry.
+fn render_library_list_svr4(main_lm: Option<usize>, libraries: &[Svr4Library]) -> String {
+    let mut doc = String::from("<library-list-svr4 version=\"1.0\"");
+    if let Some(main_lm) = main_lm {
+        doc.push_str(&format!(" main-lm=\"{:#x}\"", main_lm));
+    }
+    doc.push('>');
+    for lib in libraries {
+        doc.push_str(&format!(
+            "<library name=\"{}\" lm=\"{:#x}\" l_addr=\"{:#x}\" l_ld=\"{:#x}\"/>",
+            xml_escape(&lib.name),
+            lib.lm,
+            lib.l_addr,
+            lib.l_ld
+        ));
+    }
+    doc.push_str("</library-list-svr4>");
+    doc
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// Wait for exactly one gdb host to connect to this remote target on
 /// the specified IP address |host|, port |port|.  If |probe| is nonzero,
 /// a unique port based on |start_port| will be searched for.  Otherwise,
@@ -2383,6 +3215,13 @@ fn get_cpu_features(arch: SupportedArch) -> u32 {
     // rd is totally broken anyway.
     if (cpuid_data.ecx & avx_cpuid_flags) == avx_cpuid_flags {
         cpu_features |= GdbConnection::CPU_AVX;
+        // Linux enables all the AVX-512 state components the CPU supports.
+        let avx512_features = (1 << 5) | (1 << 6) | (1 << 7);
+        if arch == SupportedArch::X64
+            && xsave_native_layout().supported_feature_bits & avx512_features == avx512_features
+        {
+            cpu_features |= GdbConnection::CPU_AVX512;
+        }
     }
 
     cpu_features
@@ -2401,6 +3240,76 @@ fn is_in_exec(timeline: &ReplayTimeline) -> Option<TaskSharedPtr> {
     }
 }
 
+/// Describe the exec `t` is about to complete. Replay hasn't run the exec
+/// yet, so the filename the tracee passed is still in its registers and
+/// memory.
+fn exec_description(t: &dyn Task) -> String {
+    let filename = t.read_c_str(RemotePtr::from(t.regs_ref().arg1()));
+    format!(
+        "Process {} exec'd {} at event {}",
+        t.tgid(),
+        filename.to_string_lossy(),
+        t.as_replay_task().unwrap().current_frame_time()
+    )
+}
+
+/// If the next replay step replays the entry to or exit from one of the
+/// syscalls in `syscall_breaks`, describe it.
+///
+/// Unlike gdb's `catch syscall`, this works without gdb's syscall XML files
+/// and lets us print what the trace recorded.
+fn next_syscall_break(
+    timeline: &ReplayTimeline,
+    syscall_breaks: &[String],
+) -> Option<SyscallBreak> {
+    if syscall_breaks.is_empty() {
+        return None;
+    }
+    let session = timeline.current_session();
+    let t = session.current_task()?;
+    let frame = session.current_trace_frame();
+    if !frame.event().is_syscall_event() {
+        return None;
+    }
+    let ev = frame.event().syscall_event();
+    let name = syscall_name(ev.number, ev.arch());
+    if !syscall_breaks.contains(&name) {
+        return None;
+    }
+    let regs = frame.regs_ref();
+    let description = match ev.state {
+        SyscallState::EnteringSyscall => format!(
+            "Thread {} entered {}({:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x})",
+            frame.tid(),
+            name,
+            regs.arg1(),
+            regs.arg2(),
+            regs.arg3(),
+            regs.arg4(),
+            regs.arg5(),
+            regs.arg6()
+        ),
+        SyscallState::ExitingSyscall if regs.syscall_failed() => format!(
+            "Thread {} exited {} = -1 {}",
+            frame.tid(),
+            name,
+            errno_name(-regs.syscall_result_signed() as i32)
+        ),
+        SyscallState::ExitingSyscall => format!(
+            "Thread {} exited {} = {:#x}",
+            frame.tid(),
+            name,
+            regs.syscall_result()
+        ),
+        _ => return None,
+    };
+    Some(SyscallBreak {
+        tuid: t.tuid(),
+        time: frame.time(),
+        description,
+    })
+}
+
 fn get_threadid(t: &dyn Task) -> GdbThreadId {
     GdbThreadId::new(t.tgid(), t.rec_tid())
 }
@@ -2445,6 +3354,20 @@ impl BreakpointCondition for GdbBreakpointCondition {
     }
 }
 
+/// Only lets one thread stop at a breakpoint, and only when gdb's condition
+/// (if any) holds. Otherwise gdb would have to resume every time another
+/// thread hits the breakpoint.
+struct ThreadBreakpointCondition {
+    thread: GdbThreadId,
+    condition: Option<Box<dyn BreakpointCondition>>,
+}
+
+impl BreakpointCondition for ThreadBreakpointCondition {
+    fn evaluate(&self, t: &dyn Task) -> bool {
+        matches_threadid(t, self.thread) && self.condition.as_ref().map_or(true, |c| c.evaluate(t))
+    }
+}
+
 fn breakpoint_condition(request: &GdbRequest) -> Option<Box<dyn BreakpointCondition>> {
     if request.watch().conditions.is_empty() {
         return None;
@@ -2490,6 +3413,68 @@ fn search_memory(t: &dyn Task, where_: MemoryRange, find_s: &[u8]) -> Option<Rem
     None
 }
 
+/// Why a `GdbThreadId` from gdb doesn't name a live task.
+#[derive(Copy, Clone)]
+enum ThreadResolveError {
+    /// No live task has this thread id, or the task isn't in the process
+    /// that the id names.
+    NoSuchThread(GdbThreadId),
+    /// No live thread group has this pid.
+    NoSuchProcess(pid_t),
+    /// gdb didn't pick a thread and the default task has exited.
+    DefaultTaskExited(TaskUid),
+}
+
+impl Display for ThreadResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadResolveError::NoSuchThread(id) => write!(f, "thread {} doesn't exist", id),
+            ThreadResolveError::NoSuchProcess(pid) => write!(f, "process {} doesn't exist", pid),
+            ThreadResolveError::DefaultTaskExited(tuid) => {
+                write!(f, "the current thread {} has exited", tuid.tid())
+            }
+        }
+    }
+}
+
+/// Find the task of `session` that gdb means by `id`.
+///
+/// A positive tid names that thread. Otherwise (`GdbThreadId::ANY` or
+/// `GdbThreadId::ALL`) the task `default_tuid` is used. With the multiprocess
+/// extensions gdb can also select a process rather than a thread, e.g.
+/// `Hgp<pid>.0`: the default task is kept if it's in that process, otherwise
+/// any of the process's threads is picked.
+///
+/// DIFF NOTE: rr looks the task up separately for each request and ignores
+/// the pid.
+fn resolve_threadid(
+    session: &dyn Session,
+    id: GdbThreadId,
+    default_tuid: TaskUid,
+) -> Result<TaskSharedPtr, ThreadResolveError> {
+    if id.tid > 0 {
+        return match session.find_task_from_rec_tid(id.tid) {
+            Some(t) if id.pid <= 0 || id.pid == t.tgid() => Ok(t),
+            _ => Err(ThreadResolveError::NoSuchThread(id)),
+        };
+    }
+
+    let maybe_default = session.find_task_from_task_uid(default_tuid);
+    if id.pid <= 0 {
+        return maybe_default.ok_or(ThreadResolveError::DefaultTaskExited(default_tuid));
+    }
+    match maybe_default {
+        Some(t) if t.tgid() == id.pid => Ok(t),
+        _ => {
+            let mut maybe_t = None;
+            if let Some(tg) = session.find_thread_group_from_pid(id.pid) {
+                maybe_t = tg.borrow().task_set().iter().next();
+            }
+            maybe_t.ok_or(ThreadResolveError::NoSuchProcess(id.pid))
+        }
+    }
+}
+
 fn get_threadid_from_tuid(session: &dyn Session, tuid: TaskUid) -> GdbThreadId {
     let maybe_t = session.find_task_from_task_uid(tuid);
     let pid = match maybe_t {
@@ -2575,3 +3560,134 @@ struct AllowedTasks {
     task: TaskUid,
     command: RunCommand,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(name: Option<&str>) -> Checkpoint {
+        Checkpoint {
+            mark: Mark::new_detached(1),
+            last_continue_tuid: Default::default(),
+            is_explicit: ExplicitCheckpoint::NotExplicit,
+            where_: OsString::from("main"),
+            name: name.map(OsString::from),
+            rec_tid: None,
+        }
+    }
+
+    #[test]
+    fn checkpoint_registry_test() {
+        let mut checkpoints = CheckpointRegistry::default();
+        assert!(checkpoints.is_empty());
+        assert_eq!(1, checkpoints.add(checkpoint(None)));
+        assert_eq!(2, checkpoints.add(checkpoint(Some("before"))));
+        assert_eq!(3, checkpoints.add(checkpoint(None)));
+
+        // Ids aren't reused once a checkpoint is deleted.
+        assert!(checkpoints.remove(1).is_some());
+        assert!(checkpoints.remove(1).is_none());
+        assert!(checkpoints.get(1).is_none());
+        assert_eq!(4, checkpoints.add(checkpoint(None)));
+        assert_eq!(
+            vec![2, 3, 4],
+            checkpoints.iter().map(|(id, _)| id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(&OsString::from("main")),
+            checkpoints.get(3).map(|c| &c.where_)
+        );
+    }
+
+    #[test]
+    fn checkpoint_registry_find_id_test() {
+        let mut checkpoints = CheckpointRegistry::default();
+        checkpoints.add(checkpoint(None));
+        checkpoints.add(checkpoint(Some("before")));
+        // A name that looks like an id.
+        checkpoints.add(checkpoint(Some("7")));
+
+        assert_eq!(Some(1), checkpoints.find_id(OsStr::new("1")));
+        assert_eq!(Some(2), checkpoints.find_id(OsStr::new("before")));
+        assert_eq!(Some(3), checkpoints.find_id(OsStr::new("7")));
+        assert_eq!(None, checkpoints.find_id(OsStr::new("4")));
+        assert_eq!(None, checkpoints.find_id(OsStr::new("after")));
+        assert_eq!(None, checkpoints.find_id(OsStr::new("")));
+        checkpoints.remove(2);
+        assert_eq!(None, checkpoints.find_id(OsStr::new("before")));
+    }
+
+    #[test]
+    fn xml_escape_test() {
+        assert_eq!("", xml_escape(""));
+        assert_eq!("/lib/libc.so.6", xml_escape("/lib/libc.so.6"));
+        assert_eq!(
+            "a&amp;b&lt;c&gt;d&quot;e&apos;f",
+            xml_escape("a&b<c>d\"e'f")
+        );
+        // Already escaped text is escaped again.
+        assert_eq!("&amp;amp;", xml_escape("&amp;"));
+        assert_eq!("/tmp/\u{e9}t\u{e9}.so", xml_escape("/tmp/\u{e9}t\u{e9}.so"));
+    }
+
+    #[test]
+    fn render_library_list_svr4_test() {
+        assert_eq!(
+            "<library-list-svr4 version=\"1.0\"></library-list-svr4>",
+            render_library_list_svr4(None, &[])
+        );
+        let libraries = [
+            Svr4Library {
+                name: String::from("/lib/x86_64-linux-gnu/libc.so.6"),
+                lm: 0x7f00_0000_1000,
+                l_addr: 0x7f00_0010_0000,
+                l_ld: 0x7f00_0030_0e00,
+            },
+            Svr4Library {
+                name: String::from("/tmp/a&b.so"),
+                lm: 0x2000,
+                l_addr: 0,
+                l_ld: 0x3e00,
+            },
+        ];
+        assert_eq!(
+            "<library-list-svr4 version=\"1.0\" main-lm=\"0x1000\">\
+             <library name=\"/lib/x86_64-linux-gnu/libc.so.6\" lm=\"0x7f0000001000\" \
+             l_addr=\"0x7f0000100000\" l_ld=\"0x7f0000300e00\"/>\
+             <library name=\"/tmp/a&amp;b.so\" lm=\"0x2000\" l_addr=\"0x0\" l_ld=\"0x3e00\"/>\
+             </library-list-svr4>",
+            render_library_list_svr4(Some(0x1000), &libraries)
+        );
+    }
+
+    #[test]
+    fn replay_history_test() {
+        let marks: Vec<Mark> = (1..=4).map(Mark::new_detached).collect();
+        let mut history = ReplayHistory::default();
+        assert_eq!(None, history.current());
+        assert!(history.go_to(0).is_none());
+
+        for m in &marks[..3] {
+            history.push(m.clone());
+        }
+        assert_eq!(3, history.len());
+        assert_eq!(Some(2), history.current());
+        // Stopping at the same place again doesn't add an entry.
+        history.push(marks[2].clone());
+        assert_eq!(3, history.len());
+
+        assert!(history.go_to(0) == Some(marks[0].clone()));
+        assert_eq!(Some(0), history.current());
+        // Going nowhere keeps the current entry.
+        assert!(history.go_to(3).is_none());
+        assert_eq!(Some(0), history.current());
+        history.push(marks[0].clone());
+        assert_eq!(3, history.len());
+
+        // A new stop drops the entries after the current one.
+        history.push(marks[3].clone());
+        assert_eq!(Some(1), history.current());
+        let entries: Vec<Mark> = history.iter().cloned().collect();
+        assert!(entries == vec![marks[0].clone(), marks[3].clone()]);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    commands::gdb_server::CheckpointRegistry, session::record_session::TraceUuid,
+    trace::trace_stream::trace_save_dir, util::ensure_dir,
+};
+use nix::sys::stat::Mode;
+use std::{
+    ffi::OsString,
+    fs,
+    fs::File,
+    io,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// The files that carry the user's breakpoints, watchpoints and checkpoints
+/// over to the next `rd replay --save-session` of the same trace.
+///
+/// Breakpoints and watchpoints belong to gdb, so gdb saves and restores them
+/// itself with `save breakpoints` and `source`. Checkpoints belong to rd. We
+/// write them out as the gdb commands that recreate them: restart at the
+/// checkpoint's event and run forward to its tick count.
+pub struct GdbSessionFiles {
+    breakpoints: PathBuf,
+    checkpoints: PathBuf,
+}
+
+impl GdbSessionFiles {
+    /// The session files of the trace with `uuid`. Creates the directory
+    /// they live in if necessary.
+    pub fn new(uuid: &TraceUuid) -> GdbSessionFiles {
+        let mut dir = trace_save_dir();
+        dir.push("gdb-sessions");
+        ensure_dir(&dir, "gdb session directory", Mode::S_IRWXU);
+
+        let mut key = String::new();
+        for b in uuid.inner_bytes() {
+            key.push_str(&format!("{:02x}", b));
+        }
+        GdbSessionFiles {
+            breakpoints: dir.join(format!("{}-breakpoints.gdb", key)),
+            checkpoints: dir.join(format!("{}-checkpoints.gdb", key)),
+        }
+    }
+
+    /// gdb macros that save the session when the user quits.
+    ///
+    /// A failing command in a hook would stop gdb from quitting, so every
+    /// step is allowed to fail. `save breakpoints` refuses to write an empty
+    /// file, so remove the old one first or deleted breakpoints would come
+    /// back next time.
+    pub fn quit_hook(&self) -> String {
+        format!(
+            r##"
+define hook-quit
+python
+import os
+try:
+    os.remove({breakpoints:?})
+except OSError:
+    pass
+for cmd in ['save breakpoints ' + {breakpoints:?}, 'save-checkpoints ' + {checkpoints:?}]:
+    try:
+        gdb.execute(cmd, False, True)
+    except gdb.error:
+        pass
+end
+end
+"##,
+            breakpoints = self.breakpoints.to_string_lossy(),
+            checkpoints = self.checkpoints.to_string_lossy(),
+        )
+    }
+
+    /// Push the gdb options that restore the saved session. These must come
+    /// after the option that connects gdb to rd.
+    ///
+    /// Checkpoints are restored first. Recreating them moves the replay
+    /// around, which would otherwise stop at the restored breakpoints.
+    pub fn push_restore_cmds(&self, args: &mut Vec<OsString>) {
+        for path in &[&self.checkpoints, &self.breakpoints] {
+            if path.exists() {
+                let mut cmd = OsString::from("source ");
+                cmd.push(path.as_os_str());
+                args.push("-ex".into());
+                args.push(cmd);
+            }
+        }
+    }
+}
+
+/// Write the gdb commands that recreate `checkpoints` to `path`. If there
+/// are no checkpoints, `path` is removed.
+pub(super) fn write_checkpoints(path: &Path, checkpoints: &CheckpointRegistry) -> io::Result<()> {
+    if checkpoints.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+
+    let mut f = File::create(path)?;
+    writeln!(
+        f,
+        "# Checkpoints saved by rd. Recreating them replays to each one."
+    )?;
+    writeln!(f, "set confirm off")?;
+    for (id, c) in checkpoints.iter() {
+        // The location can span several lines.
+        let where_: Vec<u8> = c
+            .where_
+            .as_bytes()
+            .iter()
+            .map(|&b| if b == b'\n' { b' ' } else { b })
+            .collect();
+        write!(f, "# checkpoint {} at ", id)?;
+        f.write_all(&where_)?;
+        writeln!(f)?;
+        writeln!(f, "run {}", c.mark.time())?;
+        if let Some(rec_tid) = c.rec_tid {
+            writeln!(f, "step-until-ticks {} {}", c.mark.ticks(), rec_tid)?;
+        }
+        f.write_all(b"checkpoint")?;
+        if let Some(name) = c.name.as_ref() {
+            f.write_all(b" ")?;
+            f.write_all(name.as_bytes())?;
+        }
+        writeln!(f)?;
+    }
+    // Go back to where the debugging session started.
+    writeln!(f, "run")?;
+    writeln!(f, "set confirm on")
+}
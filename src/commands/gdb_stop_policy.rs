@@ -0,0 +1,144 @@
+use crate::{
+    log::LogDebug, perf_counters, session::task::Task, sig::Sig, taskish_uid::ThreadGroupUid,
+};
+
+/// The reasons replay can stop at that might be worth telling gdb about.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    Watchpoint,
+    /// A breakpoint gdb set was hit. Replay steps over rd's internal
+    /// breakpoints without ever stopping at them.
+    Breakpoint,
+    Singlestep,
+    Signal(Sig),
+    /// The last thread of a thread group is about to exit.
+    LastThreadExit,
+    Exec,
+    /// Replay entered or exited a syscall named in `break-on-syscall`.
+    Syscall,
+}
+
+/// Decides which stops are reported to gdb and which ones are hidden.
+///
+/// A hidden stop leaves the current resume request in place, so replay just
+/// carries on to the next stop we're willing to tell gdb about. Replay only
+/// produces stops for rd's time-slice signal on the steps GdbServer asks the
+/// timeline for, and only when `reports_internal_signals()` is set.
+///
+/// Having these decisions in one place lets the user ask for noisier stops
+/// when debugging rd itself.
+#[derive(Clone, Debug, Default)]
+pub struct GdbStopPolicy {
+    /// Report signals rd uses for its own purposes (the time-slice signal).
+    report_internal_signals: bool,
+    /// Report stops in tasks outside the thread group being debugged.
+    report_other_thread_groups: bool,
+    /// Signals that should never be reported.
+    hidden_signals: Vec<Sig>,
+}
+
+impl GdbStopPolicy {
+    /// Report every kind of stop we know of, including rd-internal ones.
+    /// Which thread groups get reported is left alone.
+    pub fn report_all() -> GdbStopPolicy {
+        GdbStopPolicy {
+            report_internal_signals: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_report_internal_signals(&mut self, report: bool) -> &mut Self {
+        self.report_internal_signals = report;
+        self
+    }
+
+    pub fn set_report_other_thread_groups(&mut self, report: bool) -> &mut Self {
+        self.report_other_thread_groups = report;
+        self
+    }
+
+    pub fn hide_signal(&mut self, sig: Sig) -> &mut Self {
+        if !self.hidden_signals.contains(&sig) {
+            self.hidden_signals.push(sig);
+        }
+        self
+    }
+
+    pub fn reports_internal_signals(&self) -> bool {
+        self.report_internal_signals
+    }
+
+    /// Should a stop for `reason` be reported to gdb?
+    pub fn reports(&self, reason: StopReason) -> bool {
+        let report = match reason {
+            StopReason::Signal(sig) => {
+                if self.hidden_signals.contains(&sig) {
+                    false
+                } else {
+                    self.report_internal_signals || !is_internal_signal(sig)
+                }
+            }
+            StopReason::Breakpoint
+            | StopReason::Watchpoint
+            | StopReason::Singlestep
+            | StopReason::LastThreadExit
+            | StopReason::Exec
+            | StopReason::Syscall => true,
+        };
+        if !report {
+            log!(LogDebug, "Stop policy hides stop for {:?}", reason);
+        }
+        report
+    }
+
+    /// Should a stop of `t` be reported when we're debugging `debuggee_tguid`?
+    pub fn reports_task(&self, t: &dyn Task, debuggee_tguid: ThreadGroupUid) -> bool {
+        let tguid = t.thread_group().borrow().tguid();
+        if tguid == debuggee_tguid || self.report_other_thread_groups {
+            return true;
+        }
+        log!(
+            LogDebug,
+            "ignoring stop of {} because we're debugging tgid {}",
+            t.tid(),
+            debuggee_tguid.tid()
+        );
+        false
+    }
+}
+
+/// Signals that rd generates for its own bookkeeping. The tracee never
+/// asked for these.
+fn is_internal_signal(sig: Sig) -> bool {
+    sig == perf_counters::TIME_SLICE_SIGNAL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sig;
+
+    #[test]
+    fn internal_signals_test() {
+        let time_slice = StopReason::Signal(perf_counters::TIME_SLICE_SIGNAL);
+        let mut policy = GdbStopPolicy::default();
+        assert!(!policy.reports(time_slice));
+        assert!(policy.reports(StopReason::Signal(sig::SIGSEGV)));
+
+        policy.set_report_internal_signals(true);
+        assert!(policy.reports_internal_signals());
+        assert!(policy.reports(time_slice));
+        assert!(GdbStopPolicy::report_all().reports(time_slice));
+        assert!(!GdbStopPolicy::report_all().report_other_thread_groups);
+    }
+
+    #[test]
+    fn hidden_signals_test() {
+        let mut policy = GdbStopPolicy::report_all();
+        policy.hide_signal(sig::SIGSEGV).hide_signal(sig::SIGSEGV);
+        assert_eq!(policy.hidden_signals, vec![sig::SIGSEGV]);
+        assert!(!policy.reports(StopReason::Signal(sig::SIGSEGV)));
+        assert!(policy.reports(StopReason::Signal(sig::SIGINT)));
+        assert!(policy.reports(StopReason::Breakpoint));
+    }
+}
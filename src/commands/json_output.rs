@@ -0,0 +1,24 @@
+//! Machine-parseable output for the subcommands that take `--json`.
+//!
+//! Each value is written as a single line of JSON so that consumers can read
+//! the output a line at a time. Field names are camelCase, except where they
+//! follow rr's (`sources`). Fields are only ever added, never renamed or
+//! removed, and a field that doesn't apply is `null` rather than missing.
+
+use serde::Serialize;
+use std::{ffi::OsString, io, io::Write};
+
+/// Write `value` followed by a newline.
+pub fn write_json<T: Serialize>(out: &mut dyn Write, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)
+}
+
+/// Command lines, paths and the like are not necessarily UTF-8 but JSON
+/// strings have to be. Invalid sequences are replaced.
+pub fn json_strings(strings: &[OsString]) -> Vec<String> {
+    strings
+        .iter()
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect()
+}
@@ -0,0 +1,192 @@
+use super::exit_result::ExitResult;
+use crate::{
+    assert_prerequisites,
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    session::{
+        address_space::kernel_mapping::KernelMapping,
+        replay_session,
+        replay_session::{ReplaySession, ReplayStatus},
+        session_inner::RunCommand,
+        SessionSharedPtr,
+    },
+    taskish_uid::AddressSpaceUid,
+    trace::trace_frame::FrameTime,
+    util::raise_resource_limits,
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// The mappings of every address space, keyed by address range.
+type MapSnapshot = BTreeMap<AddressSpaceUid, BTreeMap<(usize, usize), KernelMapping>>;
+
+pub struct MapDiffCommand {
+    from: FrameTime,
+    to: FrameTime,
+    cpu_unbound: bool,
+    trace_dir: Option<PathBuf>,
+}
+
+impl MapDiffCommand {
+    pub fn new(options: &RdOptions) -> MapDiffCommand {
+        match options.cmd.clone() {
+            RdSubCommand::MapDiff {
+                from,
+                to,
+                cpu_unbound,
+                trace_dir,
+            } => MapDiffCommand {
+                from,
+                to,
+                cpu_unbound,
+                trace_dir,
+            },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `MapDiff` variant!"),
+        }
+    }
+}
+
+impl RdCommand for MapDiffCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        if self.from >= self.to {
+            return ExitResult::err_from(
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("<from> ({}) must be before <to> ({})", self.from, self.to),
+                ),
+                1,
+            );
+        }
+
+        assert_prerequisites(None);
+        match self.map_diff(&mut io::stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+impl MapDiffCommand {
+    fn map_diff(&self, out: &mut dyn Write) -> io::Result<()> {
+        let session: SessionSharedPtr = ReplaySession::create(
+            self.trace_dir.as_ref(),
+            replay_session::Flags {
+                log_writes_fd: Default::default(),
+                log_reads_fd: Default::default(),
+                redirect_stdio: false,
+                share_private_mappings: false,
+                cpu_unbound: self.cpu_unbound,
+                ignore_ticks_mismatch: false,
+            },
+        );
+        let replay_session = session.as_replay().unwrap();
+
+        // Now that we've spawned the replay, raise our resource limits if possible.
+        raise_resource_limits();
+
+        replay_to(replay_session, self.from)?;
+        let before = snapshot(replay_session);
+        replay_to(replay_session, self.to)?;
+        let after = snapshot(replay_session);
+
+        writeln!(
+            out,
+            "Memory map changes from event {} to {}:",
+            self.from, self.to
+        )?;
+        write_diff(out, &before, &after)
+    }
+}
+
+fn replay_to(replay_session: &ReplaySession, target: FrameTime) -> io::Result<()> {
+    while replay_session.trace_reader().time() < target {
+        let result = replay_session.replay_step(RunCommand::Continue);
+        if result.status == ReplayStatus::ReplayExited {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Trace ended at event {} before reaching event {}",
+                    replay_session.trace_reader().time(),
+                    target
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn snapshot(replay_session: &ReplaySession) -> MapSnapshot {
+    let mut res = MapSnapshot::new();
+    for vm in &replay_session.vms() {
+        let maps = res.entry(vm.uid()).or_default();
+        for (_, m) in &vm.maps() {
+            maps.insert(
+                (m.map.start().as_usize(), m.map.end().as_usize()),
+                m.map.clone(),
+            );
+        }
+    }
+    res
+}
+
+fn vm_label(uid: &AddressSpaceUid) -> String {
+    format!("address space of {} (exec {})", uid.tid(), uid.exec_count())
+}
+
+/// `+` marks a new range, `-` a removed one and `~` a range whose protection
+/// or flags changed. A range that was split or merged shows up as removed and
+/// added.
+fn write_diff(out: &mut dyn Write, before: &MapSnapshot, after: &MapSnapshot) -> io::Result<()> {
+    let empty = BTreeMap::new();
+    for (uid, old_maps) in before {
+        if !after.contains_key(uid) {
+            writeln!(out, "- {} is gone", vm_label(uid))?;
+            for m in old_maps.values() {
+                writeln!(out, "  - {}", m)?;
+            }
+        }
+    }
+    for (uid, new_maps) in after {
+        let old_maps = match before.get(uid) {
+            Some(old_maps) => old_maps,
+            None => {
+                writeln!(out, "+ {} is new", vm_label(uid))?;
+                &empty
+            }
+        };
+        let mut header_written = old_maps.is_empty();
+        let mut header = |out: &mut dyn Write| -> io::Result<()> {
+            if !header_written {
+                writeln!(out, "~ {}", vm_label(uid))?;
+                header_written = true;
+            }
+            Ok(())
+        };
+        for (range, m) in old_maps {
+            if !new_maps.contains_key(range) {
+                header(out)?;
+                writeln!(out, "  - {}", m)?;
+            }
+        }
+        for (range, m) in new_maps {
+            match old_maps.get(range) {
+                None => {
+                    header(out)?;
+                    writeln!(out, "  + {}", m)?;
+                }
+                Some(old) if old.prot() != m.prot() || old.flags() != m.flags() => {
+                    header(out)?;
+                    writeln!(out, "  ~ {}", old)?;
+                    writeln!(out, "    -> {}", m)?;
+                }
+                Some(_) => (),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    trace::{trace_reader::TraceReader, trace_stream::RecordedFileStat},
+};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::File,
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+pub struct PackCommand {
+    trace_dir: Option<PathBuf>,
+}
+
+impl PackCommand {
+    pub fn new(options: &RdOptions) -> PackCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Pack { trace_dir } => PackCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Pack` variant!"),
+        }
+    }
+}
+
+impl RdCommand for PackCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.pack() {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+impl PackCommand {
+    fn pack(&mut self) -> io::Result<()> {
+        // Let go of the reader's shared lock before taking the exclusive one.
+        let stream = TraceReader::new(self.trace_dir.as_ref())
+            .trace_stream()
+            .clone();
+        let dir = PathBuf::from(stream.dir());
+        let _lock = stream.lock_exclusive().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Unable to lock {:?}, is it being replayed? {}", dir, e),
+            )
+        })?;
+
+        // Backing files recorded by absolute path, and what we copied them to.
+        let mut packed: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        stream.rewrite_mmap_sources(&mut |name, recorded| {
+            if !name.starts_with(b"/") {
+                // Already in the trace directory.
+                return Ok(None);
+            }
+            if let Some(new_name) = packed.get(name) {
+                return Ok(Some(new_name.clone()));
+            }
+            let src = Path::new(OsStr::from_bytes(name));
+            let mut new_name = format!("mmap_pack_{}_", packed.len()).into_bytes();
+            if let Some(file_name) = src.file_name() {
+                new_name.extend_from_slice(file_name.as_bytes());
+            }
+            copy_if_unchanged(src, recorded, &dir.join(OsStr::from_bytes(&new_name))).map_err(
+                |e| io::Error::new(e.kind(), format!("Unable to copy {:?}: {}", src, e)),
+            )?;
+            packed.insert(name.to_vec(), new_name.clone());
+            Ok(Some(new_name))
+        })?;
+
+        println!("Copied {} files into {:?}", packed.len(), dir);
+        Ok(())
+    }
+}
+
+/// Copy `src` to `dest`, unless `src` isn't the file that was recorded anymore.
+/// Replay would otherwise read the new contents without noticing, since the
+/// packed mapping doesn't keep the stat data it could be validated with.
+fn copy_if_unchanged(src: &Path, recorded: &RecordedFileStat, dest: &Path) -> io::Result<()> {
+    // Check the file we copy from, not whatever is at the path afterwards.
+    let mut src_file = File::open(src)?;
+    if recorded.has_stat {
+        let metadata = src_file.metadata()?;
+        if metadata.ino() != recorded.inode
+            || metadata.size() as i64 != recorded.size
+            || metadata.mtime() != recorded.mtime
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the file changed since it was recorded \
+                    (inode {}, size {}, mtime {}; recorded inode {}, size {}, mtime {})",
+                    metadata.ino(),
+                    metadata.size(),
+                    metadata.mtime(),
+                    recorded.inode,
+                    recorded.size,
+                    recorded.mtime
+                ),
+            ));
+        }
+    }
+    let mut dest_file = File::create(dest)?;
+    io::copy(&mut src_file, &mut dest_file)?;
+    Ok(())
+}
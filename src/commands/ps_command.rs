@@ -1,18 +1,23 @@
 use super::exit_result::ExitResult;
 use crate::{
     commands::{
+        json_output::{json_strings, write_json},
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
     trace::{
+        trace_annotation::read_annotations,
+        trace_frame::FrameTime,
         trace_reader::TraceReader,
         trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
     },
     wait_status::WaitType,
 };
 use libc::pid_t;
+use serde::Serialize;
 use std::{
     collections::HashMap,
+    ffi::OsString,
     io,
     io::{stdout, Write},
     os::unix::ffi::OsStrExt,
@@ -21,12 +26,13 @@ use std::{
 
 pub struct PsCommand {
     trace_dir: Option<PathBuf>,
+    json: bool,
 }
 
 impl PsCommand {
     pub fn new(options: &RdOptions) -> PsCommand {
         match options.cmd.clone() {
-            RdSubCommand::Ps { trace_dir } => PsCommand { trace_dir },
+            RdSubCommand::Ps { trace_dir, json } => PsCommand { trace_dir, json },
             _ => panic!("Unexpected RdSubCommand variant. Not a `Ps` variant!"),
         }
     }
@@ -43,75 +49,160 @@ impl RdCommand for PsCommand {
 
 type TidPidMap = HashMap<pid_t, pid_t>;
 
+/// A process that was created during recording.
+struct Process {
+    pid: pid_t,
+    /// The pid in the process' own pid namespace, if that's different.
+    ns_pid: Option<pid_t>,
+    /// `None` for the initial process.
+    ppid: Option<pid_t>,
+    /// The exit code, or minus the signal that killed the process. `None` if the
+    /// process was still running at the end of the recording.
+    exit: Option<i32>,
+    /// `None` if the process never exec'd.
+    cmd_line: Option<Vec<OsString>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonProcess {
+    pid: pid_t,
+    ns_pid: Option<pid_t>,
+    ppid: Option<pid_t>,
+    exit: Option<i32>,
+    cmd_line: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonAnnotation {
+    event: FrameTime,
+    tid: pid_t,
+    annotation: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonPs {
+    processes: Vec<JsonProcess>,
+    annotations: Vec<JsonAnnotation>,
+}
+
 impl PsCommand {
     fn ps(&mut self, out: &mut dyn Write) -> io::Result<()> {
         let mut trace = TraceReader::new(self.trace_dir.as_ref());
-        writeln!(out, "PID\tPPID\tEXIT\tCMD")?;
-
-        let mut events: Vec<TraceTaskEvent> = Vec::new();
-        while let Some(r) = trace.read_task_event(None) {
-            events.push(r);
+        let processes = processes(&mut trace)?;
+        let annotations = read_annotations(self.trace_dir.as_ref());
+        if self.json {
+            let ps = JsonPs {
+                processes: processes
+                    .into_iter()
+                    .map(|p| JsonProcess {
+                        pid: p.pid,
+                        ns_pid: p.ns_pid,
+                        ppid: p.ppid,
+                        exit: p.exit,
+                        cmd_line: p.cmd_line.as_deref().map(json_strings),
+                    })
+                    .collect(),
+                annotations: annotations
+                    .into_iter()
+                    .map(|a| JsonAnnotation {
+                        event: a.time,
+                        tid: a.tid,
+                        annotation: a.name.to_string_lossy().into_owned(),
+                    })
+                    .collect(),
+            };
+            return write_json(out, &ps);
         }
 
-        let not_exec = !matches!(events[0].event_variant(), TraceTaskEventVariant::Exec(_));
-        if events.is_empty() || not_exec {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid Trace. No task events found or the first task event was not an Exec",
-            ));
+        writeln!(out, "PID\tPPID\tEXIT\tCMD")?;
+        for p in processes {
+            write!(out, "{}", p.pid)?;
+            if let Some(ns_pid) = p.ns_pid {
+                write!(out, " ({})", ns_pid)?;
+            }
+            match p.ppid {
+                Some(ppid) => write!(out, "\t{}\t", ppid)?,
+                None => write!(out, "\t--\t")?,
+            }
+            match p.exit {
+                Some(exit) => write!(out, "{}\t", exit)?,
+                None => write!(out, "none\t")?,
+            }
+            match p.cmd_line {
+                Some(cmd_line) => write_cmd_line(&cmd_line, out)?,
+                // The main thread exited. All other threads must too, so there
+                // is no more opportunity for the process to exec.
+                None => writeln!(out, "(forked without exec)")?,
+            }
         }
 
-        let mut tid_to_pid = HashMap::<pid_t, pid_t>::new();
-
-        let initial_tid = events[0].tid();
-        tid_to_pid.insert(initial_tid, initial_tid);
-        write!(
-            out,
-            "{}\t--\t{}\t",
-            initial_tid,
-            find_exit_code(initial_tid, &events, &tid_to_pid)
-        )?;
-        write_exec_cmd_line(&events[0], out)?;
-
-        for (i, e) in events.iter().enumerate() {
-            update_tid_to_pid_map(&mut tid_to_pid, e);
-
-            match e.event_variant() {
-                TraceTaskEventVariant::Clone(c)
-                    if (c.clone_flags() & libc::CLONE_THREAD != libc::CLONE_THREAD) =>
-                {
-                    let pid = tid_to_pid[&e.tid()];
-                    write!(out, "{}", e.tid())?;
-                    if c.own_ns_tid() != e.tid() {
-                        write!(out, " ({})", c.own_ns_tid())?;
-                    }
-                    write!(
-                        out,
-                        "\t{}\t{}\t",
-                        tid_to_pid[&c.parent_tid()],
-                        find_exit_code(pid, &events[i..], &tid_to_pid)
-                    )?;
-
-                    let maybe_cmd_line_index: Option<usize> =
-                        find_cmd_line(pid, &events, i, &tid_to_pid);
-                    match maybe_cmd_line_index {
-                        None => {
-                            // The main thread exited. All other threads must too, so there
-                            // is no more opportunity for e's pid to exec.
-                            writeln!(out, "(forked without exec)")?
-                        }
-                        Some(cmd_line_index) => {
-                            write_exec_cmd_line(&events[cmd_line_index], out)?;
-                        }
-                    }
-                }
-                _ => (),
+        if !annotations.is_empty() {
+            writeln!(out, "\nEVENT\tTID\tANNOTATION")?;
+            for a in annotations {
+                write!(out, "{}\t{}\t", a.time, a.tid)?;
+                out.write_all(a.name.as_bytes())?;
+                writeln!(out)?;
             }
         }
         Ok(())
     }
 }
 
+fn processes(trace: &mut TraceReader) -> io::Result<Vec<Process>> {
+    let mut events: Vec<TraceTaskEvent> = Vec::new();
+    while let Some(r) = trace.read_task_event(None) {
+        events.push(r);
+    }
+
+    let starts_with_exec = matches!(
+        events.first().map(|e| e.event_variant()),
+        Some(TraceTaskEventVariant::Exec(_))
+    );
+    if !starts_with_exec {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid Trace. No task events found or the first task event was not an Exec",
+        ));
+    }
+
+    let mut tid_to_pid = HashMap::<pid_t, pid_t>::new();
+
+    let initial_tid = events[0].tid();
+    tid_to_pid.insert(initial_tid, initial_tid);
+    let mut processes = vec![Process {
+        pid: initial_tid,
+        ns_pid: None,
+        ppid: None,
+        exit: find_exit_code(initial_tid, &events, &tid_to_pid),
+        cmd_line: Some(events[0].exec_variant().cmd_line().to_vec()),
+    }];
+
+    for (i, e) in events.iter().enumerate() {
+        update_tid_to_pid_map(&mut tid_to_pid, e);
+
+        match e.event_variant() {
+            TraceTaskEventVariant::Clone(c)
+                if (c.clone_flags() & libc::CLONE_THREAD != libc::CLONE_THREAD) =>
+            {
+                let pid = tid_to_pid[&e.tid()];
+                processes.push(Process {
+                    pid: e.tid(),
+                    ns_pid: Some(c.own_ns_tid()).filter(|&ns_tid| ns_tid != e.tid()),
+                    ppid: Some(tid_to_pid[&c.parent_tid()]),
+                    exit: find_exit_code(pid, &events[i..], &tid_to_pid),
+                    cmd_line: find_cmd_line(pid, &events, i, &tid_to_pid)
+                        .map(|index| events[index].exec_variant().cmd_line().to_vec()),
+                });
+            }
+            _ => (),
+        }
+    }
+    Ok(processes)
+}
+
 fn update_tid_to_pid_map(tid_to_pid: &mut TidPidMap, e: &TraceTaskEvent) {
     match e.event_variant() {
         TraceTaskEventVariant::Clone(c) => {
@@ -130,7 +221,11 @@ fn update_tid_to_pid_map(tid_to_pid: &mut TidPidMap, e: &TraceTaskEvent) {
     }
 }
 
-fn find_exit_code(pid: pid_t, events: &[TraceTaskEvent], current_tid_to_pid: &TidPidMap) -> String {
+fn find_exit_code(
+    pid: pid_t,
+    events: &[TraceTaskEvent],
+    current_tid_to_pid: &TidPidMap,
+) -> Option<i32> {
     let mut tid_to_pid = current_tid_to_pid.clone();
     for e in events {
         match e.event_variant() {
@@ -139,10 +234,8 @@ fn find_exit_code(pid: pid_t, events: &[TraceTaskEvent], current_tid_to_pid: &Ti
             {
                 let status = ex.exit_status();
                 match status.wait_type() {
-                    WaitType::Exit => return status.exit_code().unwrap().to_string(),
-                    WaitType::FatalSignal => {
-                        return (-status.fatal_sig().unwrap().as_raw()).to_string()
-                    }
+                    WaitType::Exit => return status.exit_code(),
+                    WaitType::FatalSignal => return Some(-status.fatal_sig().unwrap().as_raw()),
                     w => {
                         fatal!("Unexpected WaitType {:?}", w);
                     }
@@ -152,7 +245,7 @@ fn find_exit_code(pid: pid_t, events: &[TraceTaskEvent], current_tid_to_pid: &Ti
         }
         update_tid_to_pid_map(&mut tid_to_pid, e);
     }
-    "none".into()
+    None
 }
 
 fn count_tids_for_pid(tid_to_pid: &TidPidMap, pid: pid_t) -> usize {
@@ -189,9 +282,9 @@ fn find_cmd_line(
     None
 }
 
-fn write_exec_cmd_line(event: &TraceTaskEvent, out: &mut dyn Write) -> io::Result<()> {
+fn write_cmd_line(cmd_line: &[OsString], out: &mut dyn Write) -> io::Result<()> {
     let mut first = true;
-    for word in event.exec_variant().cmd_line() {
+    for word in cmd_line {
         if !first {
             write!(out, " ")?;
         } else {
@@ -1,16 +1,18 @@
 use crate::{
     commands::rerun_command::TraceFields,
     flags::{Checksum, DumpOn, StorageBackend},
-    kernel_metadata::signal_name,
+    kernel_abi::SupportedArch,
+    kernel_metadata::{errno_name, signal_name, syscall_number_for_name},
     kernel_supplement::NUM_SIGNALS,
     scheduler::TicksHowMany,
-    session::record_session::TraceUuid,
+    session::record_session::{InjectError, TraceUuid},
     sig::Sig,
     ticks::Ticks,
-    trace::trace_frame::FrameTime,
+    trace::{compressed_writer::Compression, trace_frame::FrameTime},
     util::{find, page_size},
 };
 use libc::pid_t;
+use regex::Regex;
 use std::{
     convert::TryFrom,
     error::Error,
@@ -120,11 +122,12 @@ pub struct RdOptions {
     short = "C",
     long="checksum",
     parse(try_from_str = parse_checksum),
-    help = "Where <checksum> := `on-syscalls` | `on-all-events` | <from-time>\n\n\
+    help = "Where <checksum> := `on-syscalls` | `on-all-events` | `every-`<n> | <from-time>\n\n\
                 Compute and store (during recording) or read and verify (during replay) checksums \
                 of each of a tracee's memory mappings either at the end of all syscalls (`on-syscalls`), \
-                at all events (`on-all-events`), or starting from a global timepoint <from-time> \
-                (which is a positive integer).",
+                at all events (`on-all-events`), at every <n>th event (`every-`<n>), or starting from \
+                a global timepoint <from-time> (which is a positive integer). Replay reports the \
+                first page that differs.",
     )]
     pub checksum: Option<Checksum>,
 
@@ -191,9 +194,17 @@ fn parse_checksum(checksum_s: &str) -> Result<Checksum, Box<dyn Error>> {
         Ok(Checksum::Syscall)
     } else if checksum_s == "on-all-events" {
         Ok(Checksum::All)
+    } else if let Some(interval_s) = checksum_s.strip_prefix("every-") {
+        match interval_s.parse::<FrameTime>()? {
+            0 => Err(Box::new(clap::Error::with_description(
+                "The checksum interval must be at least 1",
+                clap::ErrorKind::InvalidValue,
+            ))),
+            interval => Ok(Checksum::Every(interval)),
+        }
     } else if checksum_s.chars().all(|c| !c.is_ascii_digit()) {
         Err(Box::new(clap::Error::with_description(
-            "Only `on-syscalls` or `on-all-events` or an unsigned integer is valid here",
+            "Only `on-syscalls`, `on-all-events`, `every-<n>` or an unsigned integer is valid here",
             clap::ErrorKind::InvalidValue,
         )))
     } else {
@@ -268,6 +279,15 @@ pub enum RdSubCommand {
         #[structopt(short = "t", long = "tid")]
         only_tid: Option<libc::pid_t>,
 
+        /// Number of threads used to scan the trace. Defaults to the number
+        /// of cpus
+        #[structopt(short = "j", long)]
+        jobs: Option<usize>,
+
+        /// Print each frame as a line of JSON. `--raw` is ignored
+        #[structopt(long)]
+        json: bool,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
 
@@ -381,6 +401,61 @@ pub enum RdSubCommand {
         #[structopt(long = "stats", parse(try_from_str = parse_stats))]
         stats: Option<u32>,
 
+        /// Report the time-slice signals rd uses internally to the debugger instead of hiding
+        /// them. Useful when debugging rd itself.
+        #[structopt(long = "report-all-signals")]
+        report_all_signals: bool,
+
+        /// Where <hide-signal> := <signal-name> | <signal-number>. Never stop the debugger for
+        /// this signal. There can be any number of --hide-signal params.
+        #[structopt(long = "hide-signal", parse(try_from_str = parse_signal_name))]
+        hide_signals: Vec<Sig>,
+
+        /// Save breakpoints, watchpoints and checkpoints when the debugger quits and restore
+        /// them the next time this trace is replayed with --save-session. Only applies when rd
+        /// launches the debugger
+        #[structopt(long = "save-session")]
+        save_session: bool,
+
+        /// Tell the debugger about every process in the trace, not just the one being debugged.
+        /// Stops in any process are reported and the debugger can switch between processes.
+        /// Needs a debugger that supports gdb's multiprocess extensions
+        #[structopt(long = "all-processes")]
+        all_processes: bool,
+
+        /// Add an `rd-position` field with the current event, the tick count of the stopped
+        /// thread and the run direction to every stop reply, for front-ends that show the
+        /// replay position. gdb ignores the field
+        #[structopt(long = "stop-reply-position")]
+        stop_reply_position: bool,
+
+        /// Replay processes other than the one being debugged without checking their registers
+        /// and memory against the trace, and without returning to the debugger between their
+        /// events. Speeds up reaching breakpoints in large multi-process traces, but divergence
+        /// in those processes is only noticed once it affects the debugged one. Has no effect
+        /// with --all-processes
+        #[structopt(long = "fast-skip")]
+        fast_skip: bool,
+
+        /// Publish stops, checkpoints, divergences and replay progress as JSON lines on a unix
+        /// socket created at this path, for IDE plugins and other front-ends. Any number of
+        /// clients may connect at any time
+        #[structopt(long = "notify-socket")]
+        notify_socket: Option<PathBuf>,
+
+        /// Abort the replay if it makes no progress for this many seconds while replaying on its
+        /// own or on behalf of the debugger (e.g. during reverse execution), after printing what
+        /// it was doing. Time spent waiting for the debugger doesn't count. Meant for replays in
+        /// CI, which would otherwise hang forever on a livelock
+        #[structopt(long = "watchdog", parse(try_from_str = parse_watchdog))]
+        watchdog: Option<Duration>,
+
+        /// Only warn when a task reaches an event with a different tick count than recorded,
+        /// instead of aborting. The replay is likely to diverge soon after, but this can help
+        /// to find out how
+        #[structopt(long = "ignore-ticks-mismatch")]
+        ignore_ticks_mismatch: bool,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
 
@@ -439,10 +514,20 @@ pub enum RdSubCommand {
                  <AAA>: Bitmask of bits to clear from EAX")]
         disable_cpuid_features_xsave: Option<u32>,
 
-        /// Randomize scheduling decisions to try reproduce bugs
+        /// Randomize scheduling decisions to try reproduce bugs. Also places mmaps at
+        /// random addresses and pretends to have a random number (1-8) of cores unless
+        /// --num-cores is given
         #[structopt(short = "h", long = "chaos")]
         chaos_mode: bool,
 
+        /// Seed for the random number generator used by --chaos. Implies --chaos. This
+        /// only fixes the stream of random numbers: scheduling also depends on timing, so
+        /// the same seed doesn't necessarily make the same decisions as an earlier
+        /// recording. A random seed is used if omitted; either way the seed is stored in
+        /// the trace and shown by `rd traceinfo`.
+        #[structopt(long = "chaos-seed")]
+        chaos_seed: Option<u64>,
+
         /// block <ignore-signal> from being delivered to tracees. Probably only useful
         /// for unit tests.
         #[structopt(short = "i", long = "ignore-signal", parse(try_from_str = parse_signal_name))]
@@ -540,6 +625,48 @@ pub enum RdSubCommand {
         #[structopt(long = "copy-preload-src")]
         copy_preload_src: bool,
 
+        /// Stop recording once the trace takes up more than <max-trace-size> bytes on disk.
+        /// A K, M or G suffix multiplies the size by 1024, 1024^2 or 1024^3. The trace is
+        /// finalized and can be replayed up to that point.
+        #[structopt(long = "max-trace-size", parse(try_from_str = parse_trace_size))]
+        max_trace_size: Option<u64>,
+
+        /// Stop recording after <max-record-seconds> seconds. The trace is finalized and can
+        /// be replayed up to that point.
+        #[structopt(long = "max-record-seconds")]
+        max_record_seconds: Option<u64>,
+
+        /// Where <only-record> := a glob like '*flaky_test*' matched against the command line
+        /// of each exec. After the initial exec, processes that exec a command matching none of
+        /// the --only-record globs are detached and run untraced, along with everything they
+        /// start. Pass a glob for every process on the way to the one you care about.
+        /// There can be any number of --only-record params.
+        #[structopt(long = "only-record", parse(try_from_str = parse_cmd_glob))]
+        only_record: Vec<Regex>,
+
+        /// Where <inject-errors> := <syscall>:<errno>[:<n>], e.g. `read:EIO:3`
+        /// Make every <n>th call (every call if <n> is omitted) of <syscall> fail with <errno>
+        /// instead of running it, so error handling paths can be recorded and then debugged.
+        /// Calls are counted across all tasks. The failures are marked in the trace.
+        /// Implies --no-syscall-buffer. There can be any number of --inject-errors params.
+        #[structopt(long = "inject-errors", parse(try_from_str = parse_inject_error))]
+        inject_errors: Vec<InjectError>,
+
+        #[structopt(
+            long = "compression",
+            parse(try_from_str = parse_compression),
+            help = "<compression> := `brotli` (default) | `zstd` | `none`\n\n\
+                    How blocks of trace data are compressed. `zstd` compresses faster than \
+                    `brotli` for a slightly larger trace. `none` makes recording cheaper \
+                    at the cost of a much larger trace."
+        )]
+        compression: Option<Compression>,
+
+        /// On AMD Zen CPUs, disable the SpecLockMap optimization (until the next reboot) so
+        /// ticks are counted exactly. Needs root and the msr kernel module.
+        #[structopt(long = "force-zen-workaround")]
+        force_zen_workaround: bool,
+
         /// Program being recorded
         exe: OsString,
 
@@ -594,6 +721,11 @@ pub enum RdSubCommand {
     /// Dump trace header in JSON format.
     #[structopt(name = "traceinfo")]
     TraceInfo {
+        /// The output is always JSON. Accepted so that every subcommand with
+        /// machine-parseable output can be invoked the same way
+        #[structopt(long)]
+        json: bool,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
@@ -601,9 +733,94 @@ pub enum RdSubCommand {
     /// Dump information on the processes encountered during recording.
     #[structopt(name = "ps")]
     Ps {
+        /// Print the processes and annotations as a single JSON object
+        #[structopt(long)]
+        json: bool,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Print, as JSON, the binaries the trace maps code from along with the
+    /// compilation directories and source files their DWARF debug info names,
+    /// so that editors can map a trace to a workspace.
+    #[structopt(name = "sources")]
+    Sources {
+        /// The output is always JSON. Accepted so that every subcommand with
+        /// machine-parseable output can be invoked the same way
+        #[structopt(long)]
+        json: bool,
+
         /// Which directory is the trace data in? If omitted the latest trace dir is used
         trace_dir: Option<PathBuf>,
     },
+
+    /// Copy every file the trace maps from outside the trace directory into it, so the
+    /// trace can be replayed on another machine.
+    #[structopt(name = "pack")]
+    Pack {
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Replay to two events and print how the memory maps changed between them:
+    /// new, removed and permission-changed regions of every address space.
+    #[structopt(name = "mapdiff")]
+    MapDiff {
+        /// Event to take the first snapshot of the memory maps at
+        from: FrameTime,
+
+        /// Event to take the second snapshot of the memory maps at
+        to: FrameTime,
+
+        /// Allow replay to run on any CPU. Default is to run on the CPU stored in the trace.
+        /// Note that this may cause a diverge from the recording in some cases
+        #[structopt(short = "u", long)]
+        cpu_unbound: bool,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+    },
+
+    /// Print the events of the trace that match all of the given conditions, for use with
+    /// `rd replay -g`. Only syscalls that weren't buffered are seen; record with -n to see
+    /// every syscall
+    #[structopt(name = "find")]
+    Find {
+        /// Entry to or exit from this syscall, e.g. `openat`
+        #[structopt(long)]
+        syscall: Option<String>,
+
+        /// A file matching this glob was mapped or executed. `*` and `?` are supported.
+        /// The paths passed to open(2) are not in the trace
+        #[structopt(long, parse(try_from_str = parse_cmd_glob))]
+        path: Option<Regex>,
+
+        /// A syscall was passed this fd, or returned it as a new fd
+        #[structopt(long, parse(try_from_str = parse_fd))]
+        fd: Option<i32>,
+
+        /// Where <signal> := <signal-name> | <signal-number>. This signal arrived, was
+        /// delivered or had its handler set up
+        #[structopt(long, parse(try_from_str = parse_signal_name))]
+        signal: Option<Sig>,
+
+        /// The kernel wrote to this address, or a mapping covering it was created
+        #[structopt(long, parse(try_from_str = parse_address))]
+        address: Option<usize>,
+
+        /// Only events of this tid
+        #[structopt(short = "t", long = "tid")]
+        only_tid: Option<libc::pid_t>,
+
+        /// Which directory is the trace data in? If omitted the latest trace dir is used
+        trace_dir: Option<PathBuf>,
+
+        /// Event specs can be either an event number like `127`, or a range
+        /// like `1000-5000`. By default, the whole trace is searched
+        #[structopt(parse(try_from_str = parse_range))]
+        event_spec: Option<(FrameTime, Option<FrameTime>)>,
+    },
 }
 
 fn parse_env_name_val(maybe_name_val: &OsStr) -> Result<(OsString, OsString), OsString> {
@@ -718,6 +935,105 @@ fn parse_num_cpu_ticks(maybe_num_ticks: &str) -> Result<Ticks, Box<dyn Error>> {
     }
 }
 
+fn parse_trace_size(maybe_size: &str) -> Result<u64, Box<dyn Error>> {
+    let (digits, multiplier) = match maybe_size.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&maybe_size[..maybe_size.len() - 1], 1 << 10),
+        Some(b'm') | Some(b'M') => (&maybe_size[..maybe_size.len() - 1], 1 << 20),
+        Some(b'g') | Some(b'G') => (&maybe_size[..maybe_size.len() - 1], 1 << 30),
+        _ => (maybe_size, 1),
+    };
+    match digits.parse::<u64>()?.checked_mul(multiplier) {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(Box::new(clap::Error::with_description(
+            "Max trace size must be greater than 0 and fit in 64 bits",
+            clap::ErrorKind::InvalidValue,
+        ))),
+    }
+}
+
+fn parse_compression(compression: &str) -> Result<Compression, Box<dyn Error>> {
+    match compression {
+        "brotli" => Ok(Compression::Brotli),
+        "zstd" => Ok(Compression::Zstd),
+        "none" => Ok(Compression::None),
+        _ => Err(Box::new(clap::Error::with_description(
+            "Only `brotli`, `zstd` or `none` are valid options",
+            clap::ErrorKind::InvalidValue,
+        ))),
+    }
+}
+
+fn parse_cmd_glob(glob: &str) -> Result<Regex, Box<dyn Error>> {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(c.encode_utf8(&mut [0u8; 4]))),
+        }
+    }
+    re.push('$');
+    Ok(Regex::new(&re)?)
+}
+
+/// Syscalls that don't return normally, so can't be made to fail, or that
+/// rd relies on working.
+const NO_INJECT_SYSCALLS: [&str; 5] = [
+    "exit",
+    "exit_group",
+    "rt_sigreturn",
+    "sigreturn",
+    "restart_syscall",
+];
+
+fn parse_inject_error(spec: &str) -> Result<InjectError, Box<dyn Error>> {
+    let invalid = |msg: String| -> Box<dyn Error> {
+        Box::new(clap::Error::with_description(
+            &msg,
+            clap::ErrorKind::InvalidValue,
+        ))
+    };
+    let parts: Vec<&str> = spec.split(':').map(str::trim).collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(invalid(format!(
+            "`{}` is not of the form <syscall>:<errno>[:<n>]",
+            spec
+        )));
+    }
+
+    let syscall = parts[0];
+    if syscall_number_for_name(syscall, SupportedArch::X64).is_none()
+        && syscall_number_for_name(syscall, SupportedArch::X86).is_none()
+    {
+        return Err(invalid(format!("Unknown syscall `{}`", syscall)));
+    }
+    if syscall.starts_with("rdcall") || NO_INJECT_SYSCALLS.contains(&syscall) {
+        return Err(invalid(format!("Can't inject errors into `{}`", syscall)));
+    }
+
+    let errno = match parts[1].parse::<i32>() {
+        Ok(errno) => errno,
+        Err(_) => (1..4096).find(|&e| errno_name(e) == parts[1]).unwrap_or(0),
+    };
+    if errno <= 0 || errno >= 4096 {
+        return Err(invalid(format!("Unknown errno `{}`", parts[1])));
+    }
+
+    let every = match parts.get(2) {
+        None => 1,
+        Some(n) => n.parse::<u64>()?,
+    };
+    if every == 0 {
+        return Err(invalid(String::from("<n> must be greater than 0")));
+    }
+
+    Ok(InjectError {
+        syscall: syscall.to_owned(),
+        errno,
+        every,
+    })
+}
+
 fn parse_syscallbuf_size(maybe_size: &str) -> Result<usize, Box<dyn Error>> {
     match maybe_size.parse::<usize>() {
         Err(e) => Err(Box::new(e)),
@@ -819,6 +1135,18 @@ fn parse_stats(maybe_stats: &str) -> Result<u32, Box<dyn Error>> {
     }
 }
 
+fn parse_watchdog(secs: &str) -> Result<Duration, Box<dyn Error>> {
+    let secs = secs.trim().parse::<u64>()?;
+    if secs == 0 {
+        Err(Box::new(clap::Error::with_description(
+            "Please provide a number of seconds greater than 0",
+            clap::ErrorKind::InvalidValue,
+        )))
+    } else {
+        Ok(Duration::from_secs(secs))
+    }
+}
+
 fn parse_u32(s: &str) -> Result<u32, Box<dyn Error>> {
     let ts: &str = s.trim();
     if let Some(stripped) = ts.strip_prefix("0x") {
@@ -838,6 +1166,14 @@ fn parse_u32(s: &str) -> Result<u32, Box<dyn Error>> {
     }
 }
 
+fn parse_address(s: &str) -> Result<usize, Box<dyn Error>> {
+    let ts: &str = s.trim();
+    match ts.strip_prefix("0x") {
+        Some(stripped) => Ok(usize::from_str_radix(stripped, 16)?),
+        None => Ok(ts.parse::<usize>()?),
+    }
+}
+
 fn parse_disable_cpuid_features_xsave(
     disable_cpuid_features_xsave: &str,
 ) -> Result<u32, ParseIntError> {
@@ -907,3 +1243,51 @@ fn parse_onprocess(pid_or_command: &OsStr) -> Result<PidOrCommand, OsString> {
         Ok(PidOrCommand::Command(pid_or_command.into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inject_error(spec: &str) -> (String, i32, u64) {
+        let e = parse_inject_error(spec).unwrap();
+        (e.syscall, e.errno, e.every)
+    }
+
+    #[test]
+    fn parse_inject_error_test() {
+        assert_eq!(
+            (String::from("read"), libc::EIO, 3),
+            inject_error("read:EIO:3")
+        );
+        // Numeric errnos, and <n> defaults to 1.
+        assert_eq!(
+            (String::from("write"), libc::ENOSPC, 1),
+            inject_error("write:28")
+        );
+        assert_eq!(
+            (String::from("openat"), libc::EACCES, 2),
+            inject_error(" openat : EACCES : 2 ")
+        );
+    }
+
+    #[test]
+    fn parse_inject_error_malformed_test() {
+        for spec in &[
+            "",
+            "read",
+            "read:",
+            "read:EIO:3:4",
+            "nosuchsyscall:EIO",
+            "rt_sigreturn:EIO",
+            "read:ENOTANERRNO",
+            "read:0",
+            "read:-5",
+            "read:4096",
+            "read:EIO:0",
+            "read:EIO:-1",
+            "read:EIO:x",
+        ] {
+            assert!(parse_inject_error(spec).is_err(), "{:?}", spec);
+        }
+    }
+}
@@ -9,11 +9,12 @@ use crate::{
     log::{notifying_abort, LogInfo, LogWarn},
     scheduler::TicksHowMany,
     session::record_session::{
-        DisableCPUIDFeatures, RecordResult, RecordSession, SyscallBuffering, TraceUuid,
+        DisableCPUIDFeatures, InjectError, RecordResult, RecordSession, SyscallBuffering, TraceUuid,
     },
     sig,
     sig::Sig,
     ticks::Ticks,
+    trace::compressed_writer::Compression,
     util::{check_for_leaks, page_size, running_under_rd, write_all, BindCPU},
     wait_status::{WaitStatus, WaitType},
 };
@@ -22,13 +23,19 @@ use nix::{
     sys::signal::{kill, sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal},
     unistd::{geteuid, getpid, Uid},
 };
-use rand::random;
+use rand::{random, rngs::StdRng, Rng, SeedableRng};
+use regex::Regex;
 use std::{
     env::var_os,
     ffi::{OsStr, OsString},
-    io,
-    os::unix::ffi::{OsStrExt, OsStringExt},
+    fmt::{self, Display, Formatter},
+    fs, io,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
     sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
 /// DIFF NOTE: Many struct members are Option<> when compared to rr equivalents.
@@ -73,6 +80,9 @@ pub struct RecordCommand {
     /// Whether to enable chaos mode in the scheduler
     pub chaos: bool,
 
+    /// Seed for chaos mode's random decisions. Recorded in the trace.
+    pub chaos_seed: u64,
+
     /// Controls number of cores reported to recorded process.
     pub num_cores: Option<u32>,
 
@@ -94,6 +104,25 @@ pub struct RecordCommand {
     /// The signal to use for syscallbuf desched events
     pub syscallbuf_desched_sig: Sig,
 
+    /// Stop recording once the trace uses more than this many bytes on disk.
+    pub max_trace_size: Option<u64>,
+
+    /// Stop recording once it has run for this long.
+    pub max_record_duration: Option<Duration>,
+
+    /// If not empty, only processes whose command line matches one of these
+    /// are recorded after the initial exec.
+    pub only_record: Vec<Regex>,
+
+    /// Syscalls to make fail deterministically.
+    pub inject_errors: Vec<InjectError>,
+
+    /// How trace data blocks are compressed.
+    pub compression: Compression,
+
+    /// Disable SpecLockMap on AMD Zen ourselves instead of just warning.
+    pub force_zen_workaround: bool,
+
     // The exe and exe_args
     pub args: Vec<OsString>,
 }
@@ -126,6 +155,7 @@ impl RecordCommand {
                 disable_cpuid_features_ext,
                 disable_cpuid_features_xsave,
                 chaos_mode,
+                chaos_seed,
                 ignore_signal,
                 no_syscall_buffer,
                 no_file_cloning,
@@ -146,6 +176,12 @@ impl RecordCommand {
                 setuid_sudo,
                 trace_id,
                 copy_preload_src,
+                max_trace_size,
+                max_record_seconds,
+                only_record,
+                inject_errors,
+                compression,
+                force_zen_workaround,
             } => RecordCommand {
                 extra_env: env.unwrap_or(Vec::new()),
                 max_ticks: num_cpu_ticks.unwrap_or(TicksHowMany::DefaultMaxTicks as u64),
@@ -158,7 +194,14 @@ impl RecordCommand {
                     if force_syscall_buffer && no_syscall_buffer {
                         log!(LogWarn, "--force-syscall-buffer and --no-syscall-buffer are contradictory. Giving preference to --force-syscall-buffer");
                     }
-                    if force_syscall_buffer {
+                    if !inject_errors.is_empty() {
+                        // Buffered syscalls never stop in rd, so we couldn't
+                        // see (let alone count) them.
+                        if force_syscall_buffer {
+                            log!(LogWarn, "--inject-errors disables the syscall buffer. Ignoring --force-syscall-buffer");
+                        }
+                        SyscallBuffering::DisableSyscallBuf
+                    } else if force_syscall_buffer {
                         SyscallBuffering::EnableSycallBuf
                     } else if no_syscall_buffer {
                         SyscallBuffering::DisableSyscallBuf
@@ -196,11 +239,12 @@ impl RecordCommand {
                 },
                 always_switch,
                 chaos: {
-                    if chaos_mode {
+                    if chaos_mode || chaos_seed.is_some() {
                         log!(LogInfo, "Enabled chaos mode");
                     }
-                    chaos_mode
+                    chaos_mode || chaos_seed.is_some()
                 },
+                chaos_seed: chaos_seed.unwrap_or_else(random),
                 num_cores,
                 wait_for_all: wait,
                 ignore_nested: ignore_error,
@@ -209,6 +253,12 @@ impl RecordCommand {
                 trace_id: Box::new(trace_id.unwrap_or(TraceUuid::generate_new())),
                 copy_preload_src,
                 syscallbuf_desched_sig: syscall_buffer_sig.unwrap_or(sig::SIGPWR),
+                max_trace_size,
+                max_record_duration: max_record_seconds.map(Duration::from_secs),
+                only_record,
+                inject_errors,
+                compression: compression.unwrap_or_default(),
+                force_zen_workaround,
                 args: {
                     let mut args = vec![exe];
                     args.extend(exe_args);
@@ -223,7 +273,34 @@ impl RecordCommand {
         unimplemented!()
     }
 
-    fn record(&self) -> WaitStatus {
+    /// Return the limit recording has run into, if any.
+    fn check_limits(
+        &self,
+        rec_session: &RecordSession,
+        start: Instant,
+        last_size_check: &mut Instant,
+    ) -> Option<RecordLimit> {
+        let now = Instant::now();
+        if let Some(max) = self.max_record_duration {
+            if now - start > max {
+                return Some(RecordLimit::Duration(max));
+            }
+        }
+        if let Some(max) = self.max_trace_size {
+            if now - *last_size_check >= TRACE_SIZE_CHECK_INTERVAL {
+                *last_size_check = now;
+                let dir = rec_session.trace_writer().trace_stream().dir().to_owned();
+                if trace_disk_usage(&dir) > max {
+                    return Some(RecordLimit::TraceSize(max));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns Err if recording was stopped early because it ran into one
+    /// of the limits the user set. The trace is still complete and replayable.
+    fn record(&self) -> Result<WaitStatus, RecordLimit> {
         log!(LogInfo, "Start recording...");
 
         let session = RecordSession::create(self);
@@ -247,6 +324,9 @@ impl RecordCommand {
         // inherited by the tracee.
         install_signal_handlers();
 
+        let start = Instant::now();
+        let mut last_size_check = start;
+        let mut limit = None;
         let mut step_result: RecordResult;
         loop {
             let done_initial_exec = rec_session.done_initial_exec();
@@ -257,12 +337,20 @@ impl RecordCommand {
             if step_result != RecordResult::StepContinue || TERM_REQUEST.load(Ordering::SeqCst) {
                 break;
             }
+            limit = self.check_limits(rec_session, start, &mut last_size_check);
+            if limit.is_some() {
+                break;
+            }
         }
 
         rec_session.terminate_recording();
         unsafe { STATIC_SESSION = std::ptr::null() };
 
-        match step_result {
+        if let Some(limit) = limit {
+            return Err(limit);
+        }
+
+        Ok(match step_result {
             RecordResult::StepContinue => {
                 // SIGTERM interrupted us.
                 WaitStatus::for_fatal_sig(sig::SIGTERM)
@@ -273,10 +361,52 @@ impl RecordCommand {
                 eprintln!("\n{:?}", message);
                 WaitStatus::for_exit_code(EX_UNAVAILABLE as i32)
             }
+        })
+    }
+}
+
+/// A limit set with `--max-trace-size` or `--max-record-seconds`.
+enum RecordLimit {
+    TraceSize(u64),
+    Duration(Duration),
+}
+
+impl Display for RecordLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordLimit::TraceSize(size) => {
+                write!(f, "Trace exceeded the maximum size of {} bytes", size)
+            }
+            RecordLimit::Duration(duration) => write!(
+                f,
+                "Recording exceeded the maximum duration of {} seconds",
+                duration.as_secs()
+            ),
         }
     }
 }
 
+/// Checking the trace size means stat-ing every file in the trace directory,
+/// which is too slow to do after every event.
+const TRACE_SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The disk space used by the trace in `dir`. Files with more than one link
+/// are mapped files that were hard-linked into the trace rather than copied,
+/// so they don't count.
+fn trace_disk_usage(dir: &OsStr) -> u64 {
+    let mut total = 0;
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if let Ok(metadata) = entry.metadata() {
+                if metadata.is_file() && metadata.nlink() == 1 {
+                    total += metadata.blocks() * 512;
+                }
+            }
+        }
+    }
+    total
+}
+
 /// DIFF NOTE: In rr the success of sigaction() is not checked. In rd, we do an unwrap().
 fn install_signal_handlers() {
     let sa = SigAction::new(
@@ -347,7 +477,8 @@ impl RdCommand for RecordCommand {
         if self.chaos {
             // Add up to one page worth of random padding to the environment to induce
             // a variety of possible stack pointer offsets
-            let chars = vec![b'a'; random::<usize>() % page_size() + 1];
+            let chars =
+                vec![b'a'; StdRng::seed_from_u64(self.chaos_seed).gen::<usize>() % page_size() + 1];
             // chars should contain at least 1 u8.
             self.extra_env.push((
                 OsString::from("RD_CHAOS_PADDING"),
@@ -355,7 +486,19 @@ impl RdCommand for RecordCommand {
             ));
         }
 
-        let status: WaitStatus = self.record();
+        let status: WaitStatus = match self.record() {
+            Ok(status) => status,
+            Err(limit) => {
+                check_for_leaks();
+                return ExitResult::err_from(
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("rd: {}. Stopped recording.", limit),
+                    ),
+                    1,
+                );
+            }
+        };
 
         // Everything should have been cleaned up by now.
         check_for_leaks();
@@ -1,17 +1,19 @@
 use crate::{
     assert_prerequisites,
     bindings::kernel::{gettimeofday, timeval},
-    commands::{gdb_server, RdCommand},
+    commands::{gdb_server, gdb_stop_policy::GdbStopPolicy, RdCommand},
     flags::Flags,
     kernel_metadata::errno_name,
     log::{LogDebug, LogInfo},
+    replay_notifier::{self, Notification},
+    replay_watchdog,
     scoped_fd::ScopedFd,
     session::{
         replay_session,
         session_inner::{RunCommand, Statistics},
         SessionSharedPtr,
     },
-    trace::trace_frame::FrameTime,
+    trace::{trace_frame::FrameTime, trace_reader::TraceReader},
     util::{check_for_leaks, find_pid_for_command, pid_execs, pid_exists, running_under_rd},
 };
 use io::stderr;
@@ -25,11 +27,13 @@ use nix::{
 use replay_session::{ReplaySession, ReplayStatus};
 use std::{
     cell::RefCell, collections::HashMap, ffi::OsString, io, io::Write, path::PathBuf, ptr, rc::Rc,
+    time::Duration,
 };
 
 use super::{
     exit_result::ExitResult,
     gdb_server::{ConnectionFlags, GdbServer},
+    gdb_session::GdbSessionFiles,
     rd_options::{PidOrCommand, RdOptions, RdSubCommand},
 };
 
@@ -93,6 +97,30 @@ pub struct ReplayCommand {
     /// When Some(_), display statistics every N steps.
     dump_interval: Option<u32>,
 
+    /// Decides which stops are reported to the debugger.
+    stop_policy: GdbStopPolicy,
+
+    /// Carry the debugging session over to the next replay of this trace.
+    save_session: bool,
+
+    /// Expose every process in the trace to the debugger.
+    all_processes: bool,
+
+    /// Add the replay position to stop replies.
+    stop_reply_position: bool,
+
+    /// Replay processes other than the debuggee with less validation.
+    fast_skip: bool,
+
+    /// Where to publish JSON notifications about the replay.
+    notify_socket: Option<PathBuf>,
+
+    /// Abort if the replay stalls for this long.
+    watchdog: Option<Duration>,
+
+    /// Carry on after a ticks mismatch.
+    ignore_ticks_mismatch: bool,
+
     trace_dir: Option<PathBuf>,
 }
 
@@ -116,6 +144,14 @@ impl Default for ReplayCommand {
             share_private_mappings: false,
             dump_interval: None,
             gdb_options: vec![],
+            stop_policy: Default::default(),
+            save_session: false,
+            all_processes: false,
+            stop_reply_position: false,
+            fast_skip: false,
+            notify_socket: None,
+            watchdog: None,
+            ignore_ticks_mismatch: false,
             trace_dir: None,
         }
     }
@@ -144,6 +180,15 @@ impl ReplayCommand {
                 cpu_unbound,
                 gdb_x_file,
                 stats,
+                report_all_signals,
+                hide_signals,
+                save_session,
+                all_processes,
+                stop_reply_position,
+                fast_skip,
+                notify_socket,
+                watchdog,
+                ignore_ticks_mismatch,
                 trace_dir,
                 share_private_mappings,
             } => {
@@ -224,6 +269,21 @@ impl ReplayCommand {
 
                 flags.cpu_unbound = cpu_unbound;
 
+                if report_all_signals {
+                    flags.stop_policy = GdbStopPolicy::report_all();
+                }
+                for sig in hide_signals {
+                    flags.stop_policy.hide_signal(sig);
+                }
+
+                flags.save_session = save_session;
+                flags.all_processes = all_processes;
+                flags.stop_reply_position = stop_reply_position;
+                flags.fast_skip = fast_skip;
+                flags.notify_socket = notify_socket;
+                flags.watchdog = watchdog;
+                flags.ignore_ticks_mismatch = ignore_ticks_mismatch;
+
                 if let Some(inter) = interpreter {
                     flags.gdb_options.push("-i".into());
                     flags.gdb_options.push(OsString::from(inter));
@@ -266,6 +326,7 @@ impl ReplayCommand {
             redirect_stdio: self.redirect,
             share_private_mappings: self.share_private_mappings,
             cpu_unbound: self.cpu_unbound,
+            ignore_ticks_mismatch: self.ignore_ticks_mismatch,
         }
     }
 
@@ -278,6 +339,7 @@ impl ReplayCommand {
         let mut last_dump_rectime: f64 = 0.0;
         let mut last_stats = Statistics::default();
         unsafe { gettimeofday(&raw mut last_dump_time, ptr::null_mut()) };
+        replay_watchdog::set_activity(Some("replaying on autopilot".into()));
 
         loop {
             let mut cmd = RunCommand::Continue;
@@ -300,6 +362,10 @@ impl ReplayCommand {
             if last_dump_rectime == 0.0 {
                 last_dump_rectime = replay_session.trace_reader().recording_time();
             }
+            replay_notifier::publish(&Notification::Progress {
+                event: after_time,
+                recording_time: replay_session.trace_reader().recording_time(),
+            });
             step_count += 1;
             if self.dump_interval.is_some() && step_count % self.dump_interval.unwrap() == 0 {
                 let mut now = timeval::default();
@@ -350,10 +416,27 @@ impl ReplayCommand {
         }
         target.event = self.goto_event;
 
+        // Listen before forking so that only the replaying process (the other
+        // one execs the debugger) publishes.
+        if let Some(path) = self.notify_socket.as_ref() {
+            if let Err(e) = replay_notifier::listen(path) {
+                return ExitResult::err_from(
+                    io::Error::new(
+                        e.kind(),
+                        format!("Unable to create notification socket {:?}: {}", path, e),
+                    ),
+                    1,
+                );
+            }
+        }
+
         // If we're not going to autolaunch the debugger, don't go
         // through the rigamarole to set that up.  All it does is
         // complicate the process tree and confuse users.
         if self.dont_launch_debugger {
+            if let Some(timeout) = self.watchdog {
+                replay_watchdog::start(timeout);
+            }
             if target.event == FrameTime::MAX {
                 if let Err(e) = self.serve_replay_no_debugger(&mut stderr()) {
                     return ExitResult::Err(Box::new(e), 1);
@@ -366,10 +449,16 @@ impl ReplayCommand {
                     keep_listening: self.keep_listening,
                     debugger_params_write_pipe: None,
                     debugger_name: self.gdb_binary_file_path.clone(),
+                    all_thread_groups: self.all_processes,
+                    position_in_stop_reply: self.stop_reply_position,
                 };
-                GdbServer::new(session, &target).serve_replay(&conn_flags);
+                let mut server = GdbServer::new(session, &target);
+                server.set_stop_policy(self.stop_policy.clone());
+                server.set_fast_skip(self.fast_skip);
+                server.serve_replay(&conn_flags);
             }
 
+            replay_notifier::close();
             check_for_leaks();
             return ExitResult::Ok(());
         }
@@ -394,6 +483,9 @@ impl ReplayCommand {
 
                 {
                     unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) };
+                    if let Some(timeout) = self.watchdog {
+                        replay_watchdog::start(timeout);
+                    }
 
                     let debugger_params_write_pipe =
                         Rc::new(RefCell::new(ScopedFd::from_raw(debugger_params_pipe[1])));
@@ -407,8 +499,12 @@ impl ReplayCommand {
                             &debugger_params_write_pipe,
                         )),
                         debugger_name: self.gdb_binary_file_path.clone(),
+                        all_thread_groups: self.all_processes,
+                        position_in_stop_reply: self.stop_reply_position,
                     };
                     let mut server = GdbServer::new(session, &target);
+                    server.set_stop_policy(self.stop_policy.clone());
+                    server.set_fast_skip(self.fast_skip);
                     let sa = SigAction::new(
                         SigHandler::Handler(handle_sigint_in_child),
                         SaFlags::SA_RESTART,
@@ -421,6 +517,7 @@ impl ReplayCommand {
 
                     server.serve_replay(&conn_flags);
                 }
+                replay_notifier::close();
                 // Everything should have been cleaned up by now.
                 check_for_leaks();
             }
@@ -439,11 +536,18 @@ impl ReplayCommand {
                 }
 
                 {
+                    let session_files = if self.save_session {
+                        let trace = TraceReader::new(self.trace_dir.as_ref());
+                        Some(GdbSessionFiles::new(trace.uuid()))
+                    } else {
+                        None
+                    };
                     let params_pipe_read_fd = ScopedFd::from_raw(debugger_params_pipe[0]);
                     GdbServer::launch_gdb(
                         &params_pipe_read_fd,
                         &self.gdb_binary_file_path,
                         &self.gdb_options,
+                        session_files.as_ref(),
                     );
                 }
                 // Child must have died before we were able to get debugger parameters
@@ -370,6 +370,7 @@ impl ReRunCommand {
             redirect_stdio: false,
             share_private_mappings: false,
             cpu_unbound: self.cpu_unbound,
+            ignore_ticks_mismatch: false,
         }
     }
 
@@ -623,22 +624,17 @@ impl ReRunCommand {
                 }
                 // @TODO Will this work properly if rr is a x86 build?
                 TraceFieldKind::GpReg => {
+                    let gp_regs = maybe_gp_regs.get_or_insert_with(|| RegsData {
+                        native: t.regs_ref().get_ptrace(),
+                    });
                     let mut value: u64 = 0;
-                    if maybe_gp_regs.is_none() {
-                        let gp_regs = RegsData {
-                            native: t.regs_ref().get_ptrace(),
-                        };
-
-                        if (field.reg_num as usize) < USER_REGS_FIELDS.len() {
-                            value = unsafe {
-                                gp_regs.regs_values
-                                    [USER_REGS_FIELDS[field.reg_num as usize] / size_of::<usize>()]
-                                    as u64
-                            }
-                        };
-
-                        maybe_gp_regs = Some(gp_regs);
-                    }
+                    if (field.reg_num as usize) < USER_REGS_FIELDS.len() {
+                        value = unsafe {
+                            gp_regs.regs_values
+                                [USER_REGS_FIELDS[field.reg_num as usize] / size_of::<usize>()]
+                                as u64
+                        }
+                    };
 
                     if field.reg_num == 0 && t.arch() == SupportedArch::X86 {
                         // EAX->RAX is sign-extended, so undo that.
@@ -0,0 +1,280 @@
+use super::exit_result::ExitResult;
+use crate::{
+    commands::{
+        json_output::write_json,
+        rd_options::{RdOptions, RdSubCommand},
+        RdCommand,
+    },
+    log::LogWarn,
+    trace::{
+        trace_reader::{TimeConstraint, TraceReader, ValidateSourceFile},
+        trace_stream::{MappedData, MappedDataSource},
+    },
+};
+use nix::sys::mman::ProtFlags;
+use object::{Object, ObjectSection};
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fmt::Write as FmtWrite,
+    fs, io,
+    io::stdout,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Where distributions install separate debug info.
+const DEBUG_FILE_DIRECTORY: &str = "/usr/lib/debug";
+
+pub struct SourcesCommand {
+    trace_dir: Option<PathBuf>,
+}
+
+impl SourcesCommand {
+    pub fn new(options: &RdOptions) -> SourcesCommand {
+        match options.cmd.clone() {
+            RdSubCommand::Sources { trace_dir, .. } => SourcesCommand { trace_dir },
+            _ => panic!("Unexpected RdSubCommand variant. Not a `Sources` variant!"),
+        }
+    }
+}
+
+impl RdCommand for SourcesCommand {
+    fn run(&mut self) -> ExitResult<()> {
+        match self.sources(&mut stdout()) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+/// Where `rr sources` has an equivalent, the field names are the same so that
+/// front-ends can consume the output of either.
+#[derive(Serialize)]
+struct Sources {
+    /// Every binary the trace maps executable code from.
+    relevant_binaries: Vec<String>,
+    external_debug_info: Vec<ExternalDebugInfo>,
+    comp_dirs: Vec<String>,
+    /// Every source file mentioned in the line tables, mapped to itself if it
+    /// exists on this machine and to `null` otherwise.
+    files: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Serialize)]
+struct ExternalDebugInfo {
+    /// The binary the debug info belongs to.
+    binary: String,
+    path: String,
+    build_id: String,
+    /// How the debug info was found: "build-id" or "debuglink".
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+impl SourcesCommand {
+    fn sources(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        let mut trace = TraceReader::new(self.trace_dir.as_ref());
+        let binaries = relevant_binaries(&mut trace);
+
+        let mut external_debug_info = Vec::new();
+        let mut comp_dirs = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        for (binary, readable) in &binaries {
+            let data = match fs::read(readable) {
+                Ok(data) => data,
+                Err(e) => {
+                    log!(LogWarn, "Can't read {:?}: {}", readable, e);
+                    continue;
+                }
+            };
+            let file = match object::File::parse(&*data) {
+                Ok(file) => file,
+                // Not everything that gets mapped executable is an ELF file.
+                Err(_) => continue,
+            };
+            if let Err(e) = dwarf_sources(&file, &mut comp_dirs, &mut files) {
+                log!(LogWarn, "Bad DWARF in {:?}: {}", binary, e);
+            }
+
+            if let Some((path, kind)) = find_external_debug_info(binary, &file) {
+                match fs::read(&path) {
+                    Ok(debug_data) => match object::File::parse(&*debug_data) {
+                        Ok(debug_file) => {
+                            if let Err(e) = dwarf_sources(&debug_file, &mut comp_dirs, &mut files) {
+                                log!(LogWarn, "Bad DWARF in {:?}: {}", path, e);
+                            }
+                        }
+                        Err(e) => log!(LogWarn, "Can't parse {:?}: {}", path, e),
+                    },
+                    Err(e) => log!(LogWarn, "Can't read {:?}: {}", path, e),
+                }
+                external_debug_info.push(ExternalDebugInfo {
+                    binary: binary.to_string_lossy().into_owned(),
+                    path: path.to_string_lossy().into_owned(),
+                    build_id: file
+                        .build_id()
+                        .ok()
+                        .flatten()
+                        .map(to_hex)
+                        .unwrap_or_default(),
+                    kind,
+                });
+            }
+        }
+
+        let sources = Sources {
+            relevant_binaries: binaries
+                .keys()
+                .map(|b| b.to_string_lossy().into_owned())
+                .collect(),
+            external_debug_info,
+            comp_dirs: comp_dirs
+                .iter()
+                .map(|d| d.to_string_lossy().into_owned())
+                .collect(),
+            files: files
+                .iter()
+                .map(|f| {
+                    let name = f.to_string_lossy().into_owned();
+                    let resolved = Some(name.clone()).filter(|_| f.is_file());
+                    (name, resolved)
+                })
+                .collect(),
+        };
+        write_json(out, &sources)
+    }
+}
+
+/// The binaries the trace maps executable code from, by their original name,
+/// along with where to read them from now. That's the copy in the trace
+/// directory if there is one.
+fn relevant_binaries(trace: &mut TraceReader) -> BTreeMap<PathBuf, PathBuf> {
+    let mut binaries = BTreeMap::new();
+    loop {
+        let mut data = MappedData::default();
+        let km = match trace.read_mapped_region(
+            Some(&mut data),
+            Some(ValidateSourceFile::DontValidate),
+            Some(TimeConstraint::AnyTime),
+            None,
+            None,
+        ) {
+            Some(km) => km,
+            None => break,
+        };
+        // Skip anonymous mappings and the likes of [vdso].
+        if !km.prot().contains(ProtFlags::PROT_EXEC) || !km.fsname().as_bytes().starts_with(b"/") {
+            continue;
+        }
+        let readable = if data.source == MappedDataSource::File {
+            PathBuf::from(&data.filename)
+        } else {
+            PathBuf::from(km.fsname())
+        };
+        binaries
+            .entry(PathBuf::from(km.fsname()))
+            .or_insert(readable);
+    }
+    binaries
+}
+
+/// Look for debug info that was split off from `binary`, the way gdb does:
+/// first by build id, then by the `.gnu_debuglink` section.
+fn find_external_debug_info(binary: &Path, file: &object::File) -> Option<(PathBuf, &'static str)> {
+    if let Ok(Some(build_id)) = file.build_id() {
+        if build_id.len() > 1 {
+            let hex = to_hex(build_id);
+            let path = Path::new(DEBUG_FILE_DIRECTORY)
+                .join(".build-id")
+                .join(&hex[..2])
+                .join(format!("{}.debug", &hex[2..]));
+            if path.is_file() {
+                return Some((path, "build-id"));
+            }
+        }
+    }
+
+    if let Ok(Some((name, _crc))) = file.gnu_debuglink() {
+        let name = OsStr::from_bytes(name);
+        let dir = binary.parent().unwrap_or_else(|| Path::new("/"));
+        let candidates = vec![
+            dir.join(name),
+            dir.join(".debug").join(name),
+            Path::new(DEBUG_FILE_DIRECTORY)
+                .join(dir.strip_prefix("/").unwrap_or(dir))
+                .join(name),
+        ];
+        for path in candidates {
+            // The debuglink may name the binary itself.
+            if path.is_file() && path != binary {
+                return Some((path, "debuglink"));
+            }
+        }
+    }
+    None
+}
+
+/// Add the compilation directories and the source files named in the line
+/// tables of `file` to `comp_dirs` and `files`.
+fn dwarf_sources(
+    file: &object::File,
+    comp_dirs: &mut BTreeSet<PathBuf>,
+    files: &mut BTreeSet<PathBuf>,
+) -> gimli::Result<()> {
+    let endian = if file.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+    let load_section = |id: gimli::SectionId| -> gimli::Result<Cow<[u8]>> {
+        Ok(file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[])))
+    };
+    let sections = gimli::Dwarf::load(load_section)?;
+    let dwarf = sections.borrow(|section| gimli::EndianSlice::new(section, endian));
+
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let comp_dir = unit
+            .comp_dir
+            .map(|dir| PathBuf::from(OsStr::from_bytes(dir.slice())))
+            .unwrap_or_default();
+        if !comp_dir.as_os_str().is_empty() {
+            comp_dirs.insert(comp_dir.clone());
+        }
+        // DIFF NOTE: The unit name isn't added. rustc doesn't make it a path.
+        let program = match unit.line_program.as_ref() {
+            Some(program) => program,
+            None => continue,
+        };
+        let header = program.header();
+        for file_entry in header.file_names() {
+            // A relative file name is relative to its directory, which in turn is
+            // relative to the compilation directory. Pushing an absolute path
+            // replaces what came before.
+            let mut path = comp_dir.clone();
+            if let Some(dir) = file_entry.directory(header) {
+                path.push(OsStr::from_bytes(dwarf.attr_string(&unit, dir)?.slice()));
+            }
+            path.push(OsStr::from_bytes(
+                dwarf.attr_string(&unit, file_entry.path_name())?.slice(),
+            ));
+            files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(hex, "{:02x}", b).unwrap();
+    }
+    hex
+}
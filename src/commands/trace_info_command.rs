@@ -1,6 +1,7 @@
 use super::exit_result::ExitResult;
 use crate::{
     commands::{
+        json_output::{json_strings, write_json},
         rd_options::{RdOptions, RdSubCommand},
         RdCommand,
     },
@@ -9,11 +10,11 @@ use crate::{
         replay_session::{Flags, ReplaySession, ReplayStatus},
         session_inner::RunCommand,
     },
-    trace::trace_reader::TraceReader,
+    trace::{host_environment::HostEnvironment, trace_reader::TraceReader},
     util::read_env,
 };
 use serde::Serialize;
-use std::{convert::TryInto, ffi::CString, io, path::PathBuf};
+use std::{convert::TryInto, ffi::CString, io, io::stdout, path::PathBuf};
 
 pub struct TraceInfoCommand {
     trace_dir: Option<PathBuf>,
@@ -22,7 +23,7 @@ pub struct TraceInfoCommand {
 impl TraceInfoCommand {
     pub fn new(options: &RdOptions) -> TraceInfoCommand {
         match options.cmd.clone() {
-            RdSubCommand::TraceInfo { trace_dir } => TraceInfoCommand { trace_dir },
+            RdSubCommand::TraceInfo { trace_dir, .. } => TraceInfoCommand { trace_dir },
             _ => panic!("Unexpected RdSubCommand variant. Not a `TraceInfo` variant!"),
         }
     }
@@ -37,7 +38,11 @@ struct TraceHeader {
     cpuid_faulting: bool,
     ticks_semantics: String,
     cpuid_records: Vec<[u32; 6]>,
+    argv: Vec<String>,
     environ: Vec<String>,
+    cwd: String,
+    host_environment: HostEnvironment,
+    chaos_seed: Option<u64>,
 }
 
 impl RdCommand for TraceInfoCommand {
@@ -48,6 +53,8 @@ impl RdCommand for TraceInfoCommand {
         let xcr0 = trace.xcr0();
         let bind_to_cpu = trace.trace_stream().bound_to_cpu();
         let cpuid_faulting = trace.uses_cpuid_faulting();
+        let host_environment = trace.host_environment().clone();
+        let chaos_seed = trace.chaos_seed();
         let ticks_semantics = match trace.ticks_semantics() {
             TicksSemantics::TicksRetiredConditionalBranches => "rcb".into(),
             TicksSemantics::TicksTakenBranches => "branches".into(),
@@ -60,12 +67,49 @@ impl RdCommand for TraceInfoCommand {
             ]);
         }
 
+        let argv = json_strings(trace.argv());
+        let cwd = trace.cwd().to_string_lossy().into_owned();
+        let environ = if trace.environ().is_empty() {
+            // Traces from older versions of rd don't have it in the header.
+            match self.initial_environ() {
+                Ok(environ) => environ,
+                Err(e) => return ExitResult::err_from(e, 1),
+            }
+        } else {
+            json_strings(trace.environ())
+        };
+
+        let header = TraceHeader {
+            uuid: uuid_bytes,
+            xcr0,
+            bind_to_cpu: bind_to_cpu.map_or(-1, |c| c.try_into().unwrap()),
+            cpuid_faulting,
+            ticks_semantics,
+            cpuid_records,
+            argv,
+            environ,
+            cwd,
+            host_environment,
+            chaos_seed,
+        };
+
+        match write_json(&mut stdout(), &header) {
+            Ok(()) => ExitResult::Ok(()),
+            Err(e) => ExitResult::err_from(e, 1),
+        }
+    }
+}
+
+impl TraceInfoCommand {
+    /// Replay up to the initial exec to read its environment.
+    fn initial_environ(&self) -> io::Result<Vec<String>> {
         let flags = Flags {
             log_writes_fd: Default::default(),
             log_reads_fd: Default::default(),
             redirect_stdio: false,
             share_private_mappings: false,
             cpu_unbound: true,
+            ignore_ticks_mismatch: false,
         };
         let session = ReplaySession::create(self.trace_dir.as_ref(), flags);
         let replay_session = session.as_replay().unwrap();
@@ -79,28 +123,15 @@ impl RdCommand for TraceInfoCommand {
             }
 
             if result.status == ReplayStatus::ReplayExited {
-                return ExitResult::err_from(
-                    io::Error::new(io::ErrorKind::Other, "Replay finished before initial exec!"),
-                    1,
-                );
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Replay finished before initial exec!",
+                ));
             }
         }
-        let environ_strings: Vec<String> = environ
+        Ok(environ
             .iter()
             .map(|c_str| c_str.to_string_lossy().into_owned())
-            .collect();
-        let header = TraceHeader {
-            uuid: uuid_bytes,
-            xcr0,
-            bind_to_cpu: bind_to_cpu.map_or(-1, |c| c.try_into().unwrap()),
-            cpuid_faulting,
-            ticks_semantics,
-            cpuid_records,
-            environ: environ_strings,
-        };
-
-        let serialized = serde_json::to_string(&header).unwrap();
-        println!("{}", serialized);
-        ExitResult::Ok(())
+            .collect())
     }
 }
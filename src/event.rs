@@ -264,6 +264,9 @@ pub struct SyscallEventData {
     /// and clone system calls that failed. These system calls failed no matter
     /// what the syscall-result register says.
     pub failed_during_preparation: bool,
+    /// True when `--inject-errors` made this syscall fail. The kernel never
+    /// ran it.
+    pub injected_error: bool,
     /// Syscall is being emulated via PTRACE_SYSEMU.
     pub in_sysemu: bool,
 }
@@ -281,6 +284,7 @@ impl SyscallEventData {
             exec_fds_to_close: vec![],
             is_restart: false,
             failed_during_preparation: false,
+            injected_error: false,
             in_sysemu: false,
             opened: vec![],
         }
@@ -499,6 +503,9 @@ impl Event {
                     )
                 )
                 .unwrap_or(());
+                if self.syscall_event().injected_error {
+                    ss.push_str(" (injected error)");
+                }
             }
             _ => {
                 // No auxiliary information.
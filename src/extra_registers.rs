@@ -3,10 +3,17 @@ use crate::{
     kernel_abi::{x64, x86, SupportedArch, SupportedArch::*, RD_NATIVE_ARCH},
     kernel_metadata::xsave_feature_string,
     log::LogLevel::LogError,
+    registers::MAX_REG_SIZE_BYTES,
     session::task::task_inner::TaskInner,
     util::{xsave_native_layout, XSaveFeatureLayout, XSaveLayout},
 };
-use std::{convert::TryInto, fmt::Write, io, mem::size_of, ptr::copy_nonoverlapping};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt::Write,
+    io,
+    mem::size_of,
+    ptr::copy_nonoverlapping,
+};
 
 const AVX_FEATURE_BIT: usize = 2;
 
@@ -29,6 +36,11 @@ const ST_REG_SPACE: usize = 16;
 const XMM_REGS_OFFSET: usize = 160;
 const XMM_REG_SPACE: usize = 16;
 
+/// The AVX-512 state components.
+const OPMASK_FEATURE_BIT: usize = 5;
+const ZMM_HI256_FEATURE_BIT: usize = 6;
+const HI16_ZMM_FEATURE_BIT: usize = 7;
+
 const XSAVE_FEATURE_PKRU: usize = 9;
 
 /// The Intel documentation says that the following layout is only valid in
@@ -313,6 +325,30 @@ impl ExtraRegisters {
         Some(reg_data.size)
     }
 
+    /// The registers whose values differ between `self` and `other`, in gdb
+    /// numbering. Registers that either side can't read are skipped.
+    pub fn mismatched_registers(&self, other: &ExtraRegisters) -> Vec<GdbRegister> {
+        debug_assert_eq!(self.arch_, other.arch_);
+        let last = match self.arch_ {
+            X86 => DREG_YMM7H,
+            X64 => DREG_64_ZMM31H,
+        };
+        let mut buf = [0u8; MAX_REG_SIZE_BYTES];
+        let mut other_buf = [0u8; MAX_REG_SIZE_BYTES];
+        let mut result = Vec::new();
+        for i in 0..=last.as_usize() {
+            let regno = GdbRegister::try_from(i as u32).unwrap();
+            match (
+                self.read_register(&mut buf, regno),
+                other.read_register(&mut other_buf, regno),
+            ) {
+                (Some(size), Some(_)) if buf[..size] != other_buf[..size] => result.push(regno),
+                _ => (),
+            }
+        }
+        result
+    }
+
     /// Get a user_fpregs_struct for a particular Arch from these ExtraRegisters.
     pub fn get_user_fpregs_struct(&self, arch: SupportedArch) -> Vec<u8> {
         debug_assert_eq!(self.format_, Format::XSave);
@@ -605,6 +641,10 @@ fn xsave_register_data(arch: SupportedArch, regno_param: GdbRegister) -> RegData
         return result;
     }
 
+    if regno >= DREG_64_XMM16 && regno <= DREG_64_ZMM31H {
+        return avx512_register_data(regno);
+    }
+
     if regno < DREG_64_FIRST_FXSAVE_REG || regno > DREG_64_LAST_FXSAVE_REG {
         return RegData::default();
     }
@@ -622,6 +662,35 @@ fn xsave_register_data(arch: SupportedArch, regno_param: GdbRegister) -> RegData
     )
 }
 
+/// Unlike AVX, the offsets of the AVX-512 components vary between CPUs. Our
+/// data always uses the native layout, so take them from that.
+fn avx512_register_data(regno: GdbRegister) -> RegData {
+    let index = |first: GdbRegister| (regno - first).unwrap().as_usize();
+    // Hi16_ZMM holds all 64 bytes of each of ZMM16-31: XMM16-31 in the low
+    // 16 bytes, then the upper halves of YMM16-31, then the upper 256 bits.
+    let (feature_bit, reg_offset, size) = if regno <= DREG_64_XMM31 {
+        (HI16_ZMM_FEATURE_BIT, 64 * index(DREG_64_XMM16), 16)
+    } else if regno <= DREG_64_YMM31H {
+        (HI16_ZMM_FEATURE_BIT, 64 * index(DREG_64_YMM16H) + 16, 16)
+    } else if regno <= DREG_64_K7 {
+        (OPMASK_FEATURE_BIT, 8 * index(DREG_64_K0), 8)
+    } else if regno <= DREG_64_ZMM15H {
+        (ZMM_HI256_FEATURE_BIT, 32 * index(DREG_64_ZMM0H), 32)
+    } else {
+        (HI16_ZMM_FEATURE_BIT, 64 * index(DREG_64_ZMM16H) + 32, 32)
+    };
+
+    let layout = xsave_native_layout();
+    match layout.feature_layouts.get(feature_bit) {
+        Some(feature) if layout.supported_feature_bits & (1 << feature_bit) != 0 => RegData {
+            offset: Some(feature.offset as usize + reg_offset),
+            size,
+            xsave_feature_bit: Some(feature_bit),
+        },
+        _ => RegData::default(),
+    }
+}
+
 // Note: uses usize for variables instead of i32 as in rr
 fn reg_in_range(
     regno: GdbRegister,
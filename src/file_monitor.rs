@@ -15,10 +15,12 @@ use std::{
     rc::{Rc, Weak},
 };
 
+pub mod annotation_monitor;
 pub mod base_file_monitor;
 pub mod magic_save_data_monitor;
 pub mod mmapped_file_monitor;
 pub mod preserve_file_monitor;
+pub mod proc_comm_monitor;
 pub mod proc_fd_dir_monitor;
 pub mod proc_mem_monitor;
 pub mod stdio_monitor;
@@ -32,10 +34,12 @@ pub struct FileMonitorInner;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FileMonitorType {
+    Annotation,
     Base,
     MagicSaveData,
     Mmapped,
     Preserve,
+    ProcComm,
     ProcFd,
     ProcMem,
     Stdio,
@@ -0,0 +1,49 @@
+use crate::{
+    file_monitor::{FileMonitor, FileMonitorType, LazyOffset, Range},
+    log::LogDebug,
+    trace::trace_annotation::{strip_annotation_magic, ANNOTATION_MAGIC},
+};
+
+/// A FileMonitor to track writes to RD_MAGIC_ANNOTATE_FD.
+///
+/// During recording the written bytes (the annotation name) are saved as raw
+/// data of the write event, behind ANNOTATION_MAGIC. During replay they are
+/// simply consumed again.
+pub struct AnnotationMonitor;
+
+impl FileMonitor for AnnotationMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::Annotation
+    }
+
+    fn did_write<'b, 'a: 'b>(&mut self, rv: &[Range], l: &LazyOffset<'b, 'a>) {
+        for r in rv {
+            if l.t.session().is_recording() {
+                let mut buf = ANNOTATION_MAGIC.to_vec();
+                buf.resize(ANNOTATION_MAGIC.len() + r.length, 0);
+                l.t.read_bytes_helper(r.data, &mut buf[ANNOTATION_MAGIC.len()..], None);
+                let rec_task = l.t.as_record_task().unwrap();
+                rec_task.record_local(r.data, &buf);
+            } else if l.t.session().is_replaying() {
+                let rep_task = l.t.as_replay_task().unwrap();
+                let rec = rep_task
+                    .session()
+                    .as_replay()
+                    .unwrap()
+                    .trace_reader_mut()
+                    .read_raw_data();
+                log!(
+                    LogDebug,
+                    "Reached annotation {:?}",
+                    String::from_utf8_lossy(strip_annotation_magic(&rec.data).unwrap_or_default())
+                );
+            }
+        }
+    }
+}
+
+impl AnnotationMonitor {
+    pub fn new() -> AnnotationMonitor {
+        AnnotationMonitor
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    file_monitor::{FileMonitor, FileMonitorType, LazyOffset, Range},
+    log::LogDebug,
+    session::task::Task,
+    taskish_uid::TaskUid,
+};
+use libc::pid_t;
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path},
+};
+
+/// A FileMonitor to track writes to /proc/<pid>/comm and
+/// /proc/<pid>/task/<tid>/comm. This is how pthread_setname_np() names
+/// threads other than the calling one, so we need it to keep task names
+/// (which we report to the debugger) up to date.
+pub struct ProcCommMonitor {
+    maybe_tuid: Option<TaskUid>,
+}
+
+impl ProcCommMonitor {
+    pub fn new(t: &dyn Task, pathname: &OsStr) -> ProcCommMonitor {
+        let components: Vec<Component> = Path::new(pathname).components().collect();
+        // The task is named by the component just before "comm": either
+        // /proc/<pid>/comm or /proc/<pid>/task/<tid>/comm
+        let maybe_tid: Option<pid_t> = match components.as_slice() {
+            [.., Component::Normal(tid), Component::Normal(comm)] if *comm == "comm" => {
+                if *tid == "self" {
+                    Some(t.tgid())
+                } else if *tid == "thread-self" {
+                    Some(t.rec_tid())
+                } else {
+                    String::from_utf8_lossy(tid.as_bytes()).parse().ok()
+                }
+            }
+            _ => None,
+        };
+        let maybe_tuid = maybe_tid.and_then(|tid| {
+            if t.rec_tid() == tid {
+                Some(t.tuid())
+            } else {
+                t.session().find_task_from_rec_tid(tid).map(|ft| ft.tuid())
+            }
+        });
+        ProcCommMonitor { maybe_tuid }
+    }
+}
+
+impl FileMonitor for ProcCommMonitor {
+    fn file_monitor_type(&self) -> FileMonitorType {
+        FileMonitorType::ProcComm
+    }
+
+    fn did_write<'b, 'a: 'b>(&mut self, ranges: &[Range], lazy_offset: &LazyOffset<'b, 'a>) {
+        let tuid = match self.maybe_tuid {
+            Some(tuid) => tuid,
+            None => return,
+        };
+
+        let t = &lazy_offset.t;
+        let mut name = Vec::new();
+        for r in ranges {
+            let mut buf = vec![0u8; r.length];
+            match t.read_bytes_fallible(r.data, &mut buf) {
+                Ok(nread) => name.extend_from_slice(&buf[0..nread]),
+                Err(_) => return,
+            }
+        }
+        if name.last() == Some(&b'\n') {
+            name.pop();
+        }
+        let name = OsString::from_vec(name);
+
+        let maybe_target = t.session().find_task_from_task_uid(tuid);
+        if let Some(target) = maybe_target {
+            log!(
+                LogDebug,
+                "Task {} renamed to {:?} through /proc",
+                target.rec_tid(),
+                name
+            );
+            target.set_name(&name);
+        }
+    }
+}
@@ -8,13 +8,15 @@ lazy_static! {
 
 /// When to generate or check memory checksums. One of ChecksumNone,
 /// ChecksumSyscall or ChecksumAll, or ChecksumAt(<a positive integer representing the
-/// event time at which to start checksumming>).
+/// event time at which to start checksumming>), or Every(<checksum events whose time is a
+/// multiple of this>).
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Checksum {
     None,
     Syscall,
     All,
     At(FrameTime),
+    Every(FrameTime),
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
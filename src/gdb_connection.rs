@@ -10,19 +10,19 @@ use crate::{
     replay_timeline::RunDirection,
     scoped_fd::ScopedFd,
     sig::Sig,
+    target_description::TargetDescription,
+    ticks::Ticks,
+    trace::trace_frame::FrameTime,
     util,
-    util::{resource_path, str0_to_isize, str16_to_isize, str16_to_usize, u8_slice, u8_slice_mut},
+    util::{str0_to_isize, str16_to_isize, str16_to_usize, u8_slice, u8_slice_mut},
 };
 use libc::pid_t;
 use memchr::memchr;
 use nix::{
     errno::Errno,
-    fcntl::OFlag,
     poll::{poll, PollFd, PollFlags},
     sys::socket::accept,
     unistd,
-    unistd::read,
-    Error,
 };
 use std::{
     convert::TryInto,
@@ -40,15 +40,22 @@ include!(concat!(
 
 macro_rules! parser_assert {
     ( $x:expr ) => {
-        // DIFF NOTE: In rr the logic is ever so slightly different.
-        // In rr there is a fputs followed by a debug_assert and exit.
-        assert!($x, "Failed to parse gdb request");
+        // DIFF NOTE: rr prints an error and exits. We reject the packet.
+        if !$x {
+            return Err(GdbProtocolError::Malformed(stringify!($x)));
+        }
     };
 }
 
 macro_rules! parser_assert_eq {
     ( $x:expr, $y:expr ) => {
-        assert_eq!($x, $y, "Failed to parse gdb request");
+        if $x != $y {
+            return Err(GdbProtocolError::Malformed(concat!(
+                stringify!($x),
+                " == ",
+                stringify!($y)
+            )));
+        }
     };
 }
 
@@ -61,6 +68,50 @@ macro_rules! unhandled_req {
 
 const INTERRUPT_CHAR: u8 = b'\x03';
 
+/// Why a packet from gdb could not be parsed.
+/// DIFF NOTE: rr treats all of these as fatal. We reply with an error packet
+/// and keep the connection alive instead.
+#[derive(Debug)]
+pub enum GdbProtocolError {
+    /// A structural check on the packet failed.
+    Malformed(&'static str),
+    /// A numeric field could not be parsed or is out of range.
+    BadNumber(String),
+}
+
+impl Display for GdbProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GdbProtocolError::Malformed(what) => write!(f, "failed check `{}`", what),
+            GdbProtocolError::BadNumber(e) => write!(f, "bad number: {}", e),
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for GdbProtocolError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        GdbProtocolError::BadNumber(e.to_string())
+    }
+}
+
+impl From<std::num::TryFromIntError> for GdbProtocolError {
+    fn from(e: std::num::TryFromIntError) -> Self {
+        GdbProtocolError::BadNumber(e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for GdbProtocolError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        GdbProtocolError::BadNumber(e.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for GdbProtocolError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        GdbProtocolError::BadNumber(e.to_string())
+    }
+}
+
 /// Represents a possibly-undefined register `name`.  `size` indicates how
 /// many bytes of `value` are valid, if any.
 #[derive(Clone, Default, Debug)]
@@ -197,6 +248,7 @@ pub enum GdbRequestValue {
     GdbRequestFileOpen(gdb_request::FileOpen),
     GdbRequestFilePread(gdb_request::FilePread),
     GdbRequestFileClose(gdb_request::FileClose),
+    GdbRequestXfer(gdb_request::Xfer),
     GdbRequestNoAddlData,
 }
 
@@ -228,9 +280,10 @@ impl GdbRequest {
             DREQ_FILE_OPEN => GdbRequestValue::GdbRequestFileOpen(Default::default()),
             DREQ_FILE_PREAD => GdbRequestValue::GdbRequestFilePread(Default::default()),
             DREQ_FILE_CLOSE => GdbRequestValue::GdbRequestFileClose(Default::default()),
-            DREQ_GET_AUXV
-            | DREQ_GET_EXEC_FILE
-            | DREQ_GET_IS_THREAD_ALIVE
+            DREQ_GET_AUXV | DREQ_GET_EXEC_FILE | DREQ_GET_LIBRARIES_SVR4 => {
+                GdbRequestValue::GdbRequestXfer(Default::default())
+            }
+            DREQ_GET_IS_THREAD_ALIVE
             | DREQ_GET_THREAD_EXTRA_INFO
             | DREQ_SET_CONTINUE_THREAD
             | DREQ_SET_QUERY_THREAD
@@ -378,6 +431,16 @@ impl GdbRequest {
         }
     }
 
+    pub fn xfer(&self) -> &gdb_request::Xfer {
+        match &self.value {
+            GdbRequestValue::GdbRequestXfer(v) => v,
+            _ => panic!(
+                "Unexpected GdbRequestValue enum variant. GdbRequestType was: {}",
+                self.type_
+            ),
+        }
+    }
+
     pub fn mem_mut(&mut self) -> &mut gdb_request::Mem {
         match &mut self.value {
             GdbRequestValue::GdbRequestMem(v) => v,
@@ -486,6 +549,15 @@ impl GdbRequest {
             ),
         }
     }
+    pub fn xfer_mut(&mut self) -> &mut gdb_request::Xfer {
+        match &mut self.value {
+            GdbRequestValue::GdbRequestXfer(v) => v,
+            _ => panic!(
+                "Unexpected GdbRequestValue enum variant. GdbRequestType was: {}",
+                self.type_
+            ),
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -531,6 +603,28 @@ impl GdbContAction {
     }
 }
 
+/// Where the replay stopped, for front-ends that show a timeline.
+#[derive(Copy, Clone, Default)]
+pub struct ReplayPosition {
+    pub event: FrameTime,
+    /// The tick count of the stopped thread.
+    pub ticks: Ticks,
+    /// The direction of the resume request that led to the stop.
+    pub direction: RunDirection,
+}
+
+impl Display for ReplayPosition {
+    /// Formatted as `<event>,<ticks>,<forward|reverse>`, which can be used as a
+    /// stop reply value.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            RunDirection::RunForward => "forward",
+            RunDirection::RunBackward => "reverse",
+        };
+        write!(f, "{},{},{}", self.event, self.ticks, direction)
+    }
+}
+
 pub mod gdb_request {
     use super::{GdbContAction, GdbRestartType};
     use crate::{
@@ -614,11 +708,24 @@ pub mod gdb_request {
     pub struct FileClose {
         pub fd: i32,
     }
+
+    /// The part of an object that a qXfer read asks for.
+    #[derive(Default, Clone)]
+    pub struct Xfer {
+        pub offset: usize,
+        pub len: usize,
+    }
 }
 
 #[derive(Copy, Clone)]
 pub struct GdbConnectionFeatures {
     pub reverse_execution: bool,
+    /// Report the threads of every thread group in the session, not just
+    /// `tgid`. Only takes effect if gdb supports the multiprocess extensions.
+    pub all_thread_groups: bool,
+    /// Add an `rd-position` field with the current `ReplayPosition` to stop
+    /// replies. gdb ignores stop reply fields it doesn't know.
+    pub position_in_stop_reply: bool,
 }
 
 impl Default for GdbConnectionFeatures {
@@ -626,6 +733,8 @@ impl Default for GdbConnectionFeatures {
         Self {
             // This is _not_ an arbitrary choice
             reverse_execution: true,
+            all_thread_groups: false,
+            position_in_stop_reply: false,
         }
     }
 }
@@ -641,7 +750,8 @@ pub struct GdbConnection {
     query_thread: GdbThreadId,
     /// gdb and rd don't work well together in multi-process and
     /// multi-exe-image debugging scenarios, so we pretend only
-    /// this thread group exists when interfacing with gdb
+    /// this thread group exists when interfacing with gdb, unless
+    /// `reports_all_thread_groups()`.
     tgid: pid_t,
     cpu_features_: u32,
     /// true when "no-ack mode" enabled, in which we don't have
@@ -654,16 +764,22 @@ pub struct GdbConnection {
     packetend: usize,
     /// buffered output from gdb
     outbuf: Vec<u8>,
+    /// The last packet we sent, including framing and checksum. Resent if gdb
+    /// NAKs it with a "-" while we're not in no-ack mode.
+    last_sent_packet: Vec<u8>,
     features_: GdbConnectionFeatures,
     connection_alive_: bool,
     /// client supports multiprocess extension
     multiprocess_supported_: bool,
+    /// Sent with stop replies if `features_.position_in_stop_reply`
+    replay_position: Option<ReplayPosition>,
 }
 
 impl GdbConnection {
     pub const CPU_64BIT: u32 = 0x1;
     pub const CPU_AVX: u32 = 0x2;
     pub const CPU_64BIT_AND_CPU_AVX: u32 = 0x1 | 0x2;
+    pub const CPU_AVX512: u32 = 0x4;
 
     pub fn new(tgid: pid_t, features: GdbConnectionFeatures) -> GdbConnection {
         GdbConnection {
@@ -680,7 +796,9 @@ impl GdbConnection {
             inbuf: Default::default(),
             packetend: Default::default(),
             outbuf: Default::default(),
+            last_sent_packet: Default::default(),
             multiprocess_supported_: Default::default(),
+            replay_position: Default::default(),
         }
     }
 
@@ -826,17 +944,8 @@ impl GdbConnection {
     ) {
         debug_assert!(self.req.is_resume_request() || self.req.type_ == DREQ_INTERRUPT);
 
-        if self.tgid != thread.pid {
-            log!(
-                LogDebug,
-                "ignoring stop of {} because we're debugging tgid {}",
-                thread,
-                self.tgid
-            );
-            // Re-use the existing continue request to advance to
-            // the next stop we're willing to tell gdb about.
-            return;
-        }
+        // DIFF NOTE: rr ignores stops of threads outside `tgid` here. In rd the
+        // GdbServer's stop policy decides which stops are worth reporting.
         self.send_stop_reply_packet(thread, maybe_sig, watch_addr);
 
         // This isn't documented in the gdb remote protocol, but if we
@@ -898,7 +1007,8 @@ impl GdbConnection {
         debug_assert_eq!(DREQ_GET_AUXV, self.req.type_);
 
         if !auxv.is_empty() {
-            self.write_binary_packet(b"l", auxv);
+            let xfer = self.req.xfer().clone();
+            self.write_xfer_response(auxv, xfer.offset, xfer.len);
         } else {
             self.write_packet_bytes(b"E01");
         }
@@ -911,7 +1021,23 @@ impl GdbConnection {
         debug_assert_eq!(DREQ_GET_EXEC_FILE, self.req.type_);
 
         if !exec_file.is_empty() {
-            self.write_binary_packet(b"l", exec_file.as_bytes());
+            let xfer = self.req.xfer().clone();
+            self.write_xfer_response(exec_file.as_bytes(), xfer.offset, xfer.len);
+        } else {
+            self.write_packet_bytes(b"E01");
+        }
+
+        self.consume_request();
+    }
+
+    /// Reply with the SVR4 shared library list of the target thread's
+    /// process. `libraries.empty()` if there was an error reading it.
+    pub fn reply_get_libraries_svr4(&mut self, libraries: &[u8]) {
+        debug_assert_eq!(DREQ_GET_LIBRARIES_SVR4, self.req.type_);
+
+        if !libraries.is_empty() {
+            let xfer = self.req.xfer().clone();
+            self.write_xfer_response(libraries, xfer.offset, xfer.len);
         } else {
             self.write_packet_bytes(b"E01");
         }
@@ -1085,8 +1211,9 @@ impl GdbConnection {
             self.write_packet_bytes(b"l");
         } else {
             let mut buf = vec![b'm'];
+            let all_thread_groups = self.reports_all_thread_groups();
             for &t in threads {
-                if self.tgid != t.pid {
+                if self.tgid != t.pid && !all_thread_groups {
                     continue;
                 }
                 if self.multiprocess_supported_ {
@@ -1254,10 +1381,22 @@ impl GdbConnection {
             // We've already seen a (possibly partial) packet.
             return true;
         }
-        parser_assert!(self.inbuf.is_empty());
+        debug_assert!(self.inbuf.is_empty());
         poll_incoming(&self.sock_fd, 0 /*don't wait*/)
     }
 
+    /// True if gdb is told about the threads of every thread group. That
+    /// needs the multiprocess extensions: without them gdb can't tell which
+    /// process a thread belongs to.
+    pub fn reports_all_thread_groups(&self) -> bool {
+        self.features_.all_thread_groups && self.multiprocess_supported_
+    }
+
+    /// Set the position reported with the following stop replies.
+    pub fn set_replay_position(&mut self, position: Option<ReplayPosition>) {
+        self.replay_position = position;
+    }
+
     pub fn features(&self) -> GdbConnectionFeatures {
         self.features_
     }
@@ -1348,15 +1487,14 @@ impl GdbConnection {
     }
 
     fn write_packet_bytes(&mut self, data: &[u8]) {
-        let mut checksum: u8 = 0;
-
+        let start = self.outbuf.len();
         self.write_data_raw(b"$");
-        for &b in data {
-            checksum = checksum.overflowing_add(b).0;
-        }
         self.write_data_raw(data);
         self.write_data_raw(b"#");
-        self.write_hex(checksum as usize);
+        self.write_hex(packet_checksum(data) as usize);
+        if !self.no_ack {
+            self.last_sent_packet = self.outbuf[start..].to_vec();
+        }
     }
 
     /// DIFF NOTE: prefix is a null terminated c-string in rr. Here its just a slice.
@@ -1424,6 +1562,12 @@ impl GdbConnection {
                 break;
             }
         }
+        let discard_len = maybe_end.unwrap_or_else(|| self.inbuf.len());
+        // Outside of packets gdb only sends acks. A "-" means our last packet
+        // was received damaged, so send it again.
+        if !self.no_ack && self.inbuf[..discard_len].contains(&b'-') {
+            self.resend_last_packet();
+        }
         match maybe_end {
             None => {
                 // Discard all read bytes, which we don't care about
@@ -1436,63 +1580,89 @@ impl GdbConnection {
             }
         }
 
-        parser_assert!(!self.inbuf.is_empty());
-        parser_assert!(b'$' == self.inbuf[0] || INTERRUPT_CHAR == self.inbuf[0]);
+        debug_assert!(!self.inbuf.is_empty());
+        debug_assert!(b'$' == self.inbuf[0] || INTERRUPT_CHAR == self.inbuf[0]);
 
         true
     }
 
+    fn resend_last_packet(&mut self) {
+        if self.last_sent_packet.is_empty() {
+            return;
+        }
+        log!(LogDebug, "gdb NAKed our last packet, resending it");
+        let packet = self.last_sent_packet.clone();
+        self.write_data_raw(&packet);
+        self.write_flush();
+    }
+
     /// Block until the sequence of bytes
     ///
-    ///    "[^$]*\$[^#]*#.*"
+    ///    "[^$]*\$[^#]*#.."
+    ///
+    /// has been read from the client fd and its checksum is valid.  This is
+    /// one (or more) gdb packet(s).
     ///
-    /// has been read from the client fd.  This is one (or more) gdb
-    /// packet(s).
+    /// DIFF NOTE: rr ignores checksums and "+/-" responses from gdb. We verify
+    /// the checksum, NAK damaged packets and resend our last packet if gdb NAKs
+    /// it (unless no-ack mode has been negotiated). gdb never run-length
+    /// encodes the packets it sends to a stub, so there is nothing to decode.
     fn read_packet(&mut self) {
-        // Read and discard bytes until we see the start of a
-        // packet.
-        //
-        // NB: we're ignoring "+/-" responses from gdb.  There doesn't
-        // seem to be any sane reason why we would send a damaged
-        // packet to gdb over TCP, then see a "-" reply from gdb and
-        // somehow magically fix our bug that led to the malformed
-        // packet in the first place.
-        while !self.skip_to_packet_start() && self.connection_alive_ {
-            self.read_data_once();
-        }
+        loop {
+            // Read and discard bytes until we see the start of a
+            // packet.
+            while !self.skip_to_packet_start() && self.connection_alive_ {
+                self.read_data_once();
+            }
 
-        if !self.connection_alive_ {
-            return;
-        }
+            if !self.connection_alive_ {
+                return;
+            }
 
-        if self.inbuf[0] == INTERRUPT_CHAR {
-            // Interrupts are kind of an ugly duckling in the gdb
-            // protocol ...
-            self.packetend = 1;
-            return;
-        }
+            if self.inbuf[0] == INTERRUPT_CHAR {
+                // Interrupts are kind of an ugly duckling in the gdb
+                // protocol ...
+                self.packetend = 1;
+                return;
+            }
 
-        // Read until we see end-of-packet.
-        let mut checkedlen: usize = 0;
-        loop {
-            let maybe_p = memchr(b'#', &self.inbuf[checkedlen..]);
-            if let Some(p) = maybe_p {
-                self.packetend = p;
+            // Read until we see end-of-packet.
+            let mut checkedlen: usize = 0;
+            loop {
+                let maybe_p = memchr(b'#', &self.inbuf[checkedlen..]);
+                if let Some(p) = maybe_p {
+                    self.packetend = checkedlen + p;
+                    break;
+                };
+                checkedlen = self.inbuf.len();
+                self.read_data_once();
+                if !self.connection_alive_ {
+                    return;
+                }
+            }
+
+            // Read the two checksum characters after the '#'
+            while self.inbuf.len() < self.packetend + 3 {
+                self.read_data_once();
+                if !self.connection_alive_ {
+                    return;
+                }
+            }
+
+            debug_assert_eq!(b'$', self.inbuf[0]);
+            debug_assert!(self.packetend < self.inbuf.len());
+
+            if self.packet_checksum_ok() {
                 break;
-            };
-            checkedlen = self.inbuf.len();
-            self.read_data_once();
-            if !self.connection_alive_ {
-                return;
             }
-        }
 
-        // NB: we're ignoring the gdb packet checksums here too.  If
-        // gdb is corrupted enough to garble a checksum over TCP, it's
-        // not really clear why asking for the packet again might make
-        // the bug go away.
-        parser_assert_eq!(b'$', self.inbuf[0]);
-        parser_assert!(self.packetend < self.inbuf.len());
+            // Drop the damaged packet and ask gdb for it again.
+            self.inbuf.drain(0..self.packetend + 3);
+            if !self.no_ack {
+                self.write_data_raw(b"-");
+                self.write_flush();
+            }
+        }
 
         // Acknowledge receipt of the packet.
         if !self.no_ack {
@@ -1501,34 +1671,54 @@ impl GdbConnection {
         }
     }
 
+    /// Check the checksum of the packet at the start of `inbuf`.
+    fn packet_checksum_ok(&self) -> bool {
+        let checksum = packet_checksum(&self.inbuf[1..self.packetend]);
+        let digits = &self.inbuf[self.packetend + 1..self.packetend + 3];
+        match parse_checksum_digits(digits) {
+            Some(expected) if expected == checksum => true,
+            // In no-ack mode gdb is allowed to send anything as checksum
+            _ if self.no_ack => true,
+            _ => {
+                log!(
+                    LogWarn,
+                    "Bad checksum on gdb packet: expected {:?}, computed {:02x}",
+                    String::from_utf8_lossy(digits),
+                    checksum
+                );
+                false
+            }
+        }
+    }
+
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
     #[allow(unused_assignments)]
-    fn xfer(&mut self, name: &[u8], mut args: &[u8]) -> bool {
-        let args_loc = memchr(b':', args).unwrap();
+    fn xfer(&mut self, name: &[u8], mut args: &[u8]) -> Result<bool, GdbProtocolError> {
+        let args_loc = memchr(b':', args).ok_or(GdbProtocolError::Malformed("missing ':'"))?;
         let mode = &args[0..args_loc];
         args = &args[args_loc + 1..];
 
         if mode != b"read" && mode != b"write" {
             self.write_packet_bytes(b"");
-            return false;
+            return Ok(false);
         }
 
-        let colon_loc = memchr(b':', args).unwrap();
+        let colon_loc = memchr(b':', args).ok_or(GdbProtocolError::Malformed("missing ':'"))?;
         let annex = &args[0..colon_loc];
         args = &args[colon_loc + 1..];
 
-        let offset = str16_to_usize(args, &mut args).unwrap();
+        let offset = str16_to_usize(args, &mut args)?;
 
         let mut len: usize = 0;
         if mode == b"read" {
-            parser_assert_eq!(b',', args[0]);
+            parser_assert_eq!(Some(&b','), args.first());
             args = &args[1..];
-            len = str16_to_usize(args, &mut args).unwrap();
+            len = str16_to_usize(args, &mut args)?;
             // Assert that its the end
             parser_assert!(args.is_empty());
         } else {
-            parser_assert_eq!(args[0], b':');
+            parser_assert_eq!(args.first(), Some(&b':'));
             args = &args[1..];
         }
 
@@ -1544,78 +1734,97 @@ impl GdbConnection {
         if name == b"auxv" {
             if annex != b"" {
                 self.write_packet_bytes(b"E00");
-                return false;
+                return Ok(false);
             }
             if mode != b"read" {
                 self.write_packet_bytes(b"");
-                return false;
+                return Ok(false);
             }
 
             self.req = GdbRequest::new(DREQ_GET_AUXV);
             self.req.target = self.query_thread;
-            // XXX handle offset/len here!
-            return true;
+            self.req.xfer_mut().offset = offset;
+            self.req.xfer_mut().len = len;
+            return Ok(true);
         }
 
         if name == b"exec-file" {
             if mode != b"read" {
                 self.write_packet_bytes(b"");
-                return false;
+                return Ok(false);
             }
 
             self.req = GdbRequest::new(DREQ_GET_EXEC_FILE);
             // Handles the empty annex case by setting the tid to 0
             let mut ignore: &[u8] = Default::default();
-            self.req.target.tid = str16_to_usize(annex, &mut ignore)
-                .unwrap()
-                .try_into()
-                .unwrap();
+            self.req.target.tid = str16_to_usize(annex, &mut ignore)?.try_into()?;
             self.req.target.pid = self.req.target.tid;
-            // XXX handle offset/len here!
-            return true;
+            self.req.xfer_mut().offset = offset;
+            self.req.xfer_mut().len = len;
+            return Ok(true);
+        }
+
+        if name == b"libraries-svr4" {
+            // We don't advertise augmented-libraries-svr4-read, so gdb always
+            // asks for the whole list.
+            if annex != b"" {
+                self.write_packet_bytes(b"E00");
+                return Ok(false);
+            }
+            if mode != b"read" {
+                self.write_packet_bytes(b"");
+                return Ok(false);
+            }
+
+            self.req = GdbRequest::new(DREQ_GET_LIBRARIES_SVR4);
+            self.req.target = self.query_thread;
+            self.req.xfer_mut().offset = offset;
+            self.req.xfer_mut().len = len;
+            return Ok(true);
         }
 
         if name == b"siginfo" {
             if annex != b"" {
                 self.write_packet_bytes(b"E00");
-                return false;
+                return Ok(false);
             }
             if mode == b"read" {
                 self.req = GdbRequest::new(DREQ_READ_SIGINFO);
                 self.req.target = self.query_thread;
                 self.req.mem_mut().addr = offset.into();
                 self.req.mem_mut().len = len;
-                return true;
+                return Ok(true);
             }
 
             self.req = GdbRequest::new(DREQ_WRITE_SIGINFO);
             self.req.target = self.query_thread;
-            return true;
+            return Ok(true);
         }
 
         if name == b"features" {
             if mode != b"read" {
                 self.write_packet_bytes(b"");
-                return false;
+                return Ok(false);
             }
 
-            let filename = if annex != b"" && annex != b"target.xml" {
-                annex
-            } else {
-                target_description_name(self.cpu_features_)
-            };
-            let target_desc = read_target_desc(filename).unwrap();
-            self.write_xfer_response(&target_desc, offset, len);
-            return false;
+            // Our target.xml doesn't include any other files, so that's the
+            // only annex gdb should ask for.
+            if annex != b"" && annex != b"target.xml" {
+                self.write_packet_bytes(b"E00");
+                return Ok(false);
+            }
+            let target_desc = TargetDescription::new(self.cpu_features_).to_xml();
+            self.write_xfer_response(target_desc.as_bytes(), offset, len);
+            return Ok(false);
         }
 
         self.write_packet_bytes(b"");
-        false
+        Ok(false)
     }
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn query(&mut self, payload: &[u8]) -> bool {
+    fn query(&mut self, payload: &[u8]) -> Result<bool, GdbProtocolError> {
         let maybe_args_loc = memchr(b':', payload);
         let name = match maybe_args_loc {
             Some(l) => &payload[0..l],
@@ -1632,14 +1841,16 @@ impl GdbConnection {
             );
             self.req = GdbRequest::new(DREQ_RD_CMD);
             // Assumes there is always a `:` after `RDCmd`
-            *self.req.text_mut() = maybe_args.unwrap().to_vec();
-            return true;
+            *self.req.text_mut() = maybe_args
+                .ok_or(GdbProtocolError::Malformed("missing arguments"))?
+                .to_vec();
+            return Ok(true);
         }
 
         if name == b"C" {
             log!(LogDebug, "gdb requests current thread ID");
             self.req = GdbRequest::new(DREQ_GET_CURRENT_THREAD);
-            return true;
+            return Ok(true);
         }
 
         if name == b"Attached" {
@@ -1647,54 +1858,54 @@ impl GdbConnection {
             // Tell gdb this is an existing process; it might be
             // (see emergency_debug()).
             self.write_packet_bytes(b"1");
-            return false;
+            return Ok(false);
         }
 
         if name == b"fThreadInfo" {
             log!(LogDebug, "gdb asks for thread list");
             self.req = GdbRequest::new(DREQ_GET_THREAD_LIST);
-            return true;
+            return Ok(true);
         }
 
         if name == b"sThreadInfo" {
             // "end of list"
             self.write_packet_bytes(b"l");
-            return false;
+            return Ok(false);
         }
 
         if name == b"GetTLSAddr" {
-            let mut args = maybe_args.unwrap();
+            let mut args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
             log!(LogDebug, "gdb asks for TLS addr");
             self.req = GdbRequest::new(DREQ_TLS);
-            self.req.target = parse_threadid(args, &mut args);
-            parser_assert_eq!(args[0], b',');
+            self.req.target = parse_threadid(args, &mut args)?;
+            parser_assert_eq!(args.first(), Some(&b','));
             args = &args[1..];
-            let offset = str16_to_usize(args, &mut args).unwrap();
-            parser_assert_eq!(args[0], b',');
+            let offset = str16_to_usize(args, &mut args)?;
+            parser_assert_eq!(args.first(), Some(&b','));
             args = &args[1..];
             // @TODO Specialized method to handle u64?
-            let load_module = str16_to_usize(args, &mut args).unwrap();
+            let load_module = str16_to_usize(args, &mut args)?;
             parser_assert_eq!(args.len(), 0);
             self.req.tls_mut().offset = offset as u64;
             self.req.tls_mut().load_module = load_module.into();
-            return true;
+            return Ok(true);
         }
 
         if name == b"Offsets" {
             log!(LogDebug, "gdb asks for section offsets");
             self.req = GdbRequest::new(DREQ_GET_OFFSETS);
             self.req.target = self.query_thread;
-            return true;
+            return Ok(true);
         }
 
-        if b'P' == name[0] {
+        if name.first() == Some(&b'P') {
             // The docs say not to use this packet ...
             self.write_packet_bytes(b"");
-            return false;
+            return Ok(false);
         }
 
         if name == b"Supported" {
-            let args = maybe_args.unwrap();
+            let args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
             // TODO process these
             log!(LogDebug, "gdb supports {:?}", OsStr::from_bytes(args));
 
@@ -1709,6 +1920,7 @@ impl GdbConnection {
                  ;qXfer:features:read+\
                  ;qXfer:auxv:read+\
                  ;qXfer:exec-file:read+\
+                 ;qXfer:libraries-svr4:read+\
                  ;qXfer:siginfo:read+\
                  ;qXfer:siginfo:write+\
                  ;multiprocess+\
@@ -1725,24 +1937,24 @@ impl GdbConnection {
                 .unwrap();
             }
             self.write_packet_bytes(&supported);
-            return false;
+            return Ok(false);
         }
 
         if name == b"Symbol" {
             log!(LogDebug, "gdb is ready for symbol lookups");
-            let mut args = maybe_args.unwrap();
-            let _colon = memchr(b':', args).unwrap();
+            let mut args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
+            let _colon = memchr(b':', args).ok_or(GdbProtocolError::Malformed("missing ':'"))?;
             self.req = GdbRequest::new(DREQ_QSYMBOL);
             if args[0] == b':' {
                 self.req.sym_mut().has_address = false;
             } else {
                 self.req.sym_mut().has_address = true;
-                self.req.sym_mut().address = str16_to_usize(args, &mut args).unwrap().into();
+                self.req.sym_mut().address = str16_to_usize(args, &mut args)?.into();
             }
-            parser_assert_eq!(args[0], b':');
+            parser_assert_eq!(args.first(), Some(&b':'));
             args = &args[1..];
-            self.req.sym_mut().name = decode_ascii_encoded_hex_str(args);
-            return true;
+            self.req.sym_mut().name = decode_ascii_encoded_hex_str(args)?;
+            return Ok(true);
         }
 
         if name.starts_with(b"ThreadExtraInfo") {
@@ -1750,14 +1962,14 @@ impl GdbConnection {
             // delimits its args with ','.
             parser_assert!(maybe_args.is_none());
             let mut args = payload;
-            let loc_args = memchr(b',', args).unwrap();
+            let loc_args = memchr(b',', args).ok_or(GdbProtocolError::Malformed("missing ','"))?;
             args = &args[loc_args + 1..];
 
             self.req = GdbRequest::new(DREQ_GET_THREAD_EXTRA_INFO);
-            self.req.target = parse_threadid(args, &mut args);
+            self.req.target = parse_threadid(args, &mut args)?;
             // We should have consumed everything
             parser_assert_eq!(args.len(), 0);
-            return true;
+            return Ok(true);
         }
 
         if name == b"TStatus" {
@@ -1767,18 +1979,18 @@ impl GdbConnection {
             // us with trace queries.  So pretend we don't know
             // what it's talking about.
             self.write_packet_bytes(b"");
-            return false;
+            return Ok(false);
         }
 
         if name == b"Xfer" {
-            let args = maybe_args.unwrap();
-            let colon_loc = memchr(b':', args).unwrap();
+            let args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
+            let colon_loc = memchr(b':', args).ok_or(GdbProtocolError::Malformed("missing ':'"))?;
             let name = &args[0..colon_loc];
             return self.xfer(name, &args[colon_loc + 1..]);
         }
 
         if name == b"Search" {
-            let mut args = maybe_args.unwrap();
+            let mut args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
             let args_loc = memchr(b':', args);
             let name = match args_loc {
                 Some(l) => &args[0..l],
@@ -1791,15 +2003,15 @@ impl GdbConnection {
             if name == b"memory" && args_loc.is_some() {
                 self.req = GdbRequest::new(DREQ_SEARCH_MEM);
                 self.req.target = self.query_thread;
-                self.req.mem_mut().addr = str16_to_usize(args, &mut args).unwrap().into();
-                parser_assert_eq!(b';', args[0]);
+                self.req.mem_mut().addr = str16_to_usize(args, &mut args)?.into();
+                parser_assert_eq!(Some(&b';'), args.first());
                 args = &args[1..];
-                self.req.mem_mut().len = str16_to_usize(args, &mut args).unwrap();
-                parser_assert_eq!(b';', args[0]);
+                self.req.mem_mut().len = str16_to_usize(args, &mut args)?;
+                parser_assert_eq!(Some(&b';'), args.first());
                 args = &args[1..];
                 // @TODO Is this the correct interpretation?
                 // https://sourceware.org/gdb/current/onlinedocs/gdb/General-Query-Packets.html#General-Query-Packets
-                read_binary_data(args, &mut self.req.mem_mut().data);
+                read_binary_data(args, &mut self.req.mem_mut().data)?;
 
                 log!(
                     LogDebug,
@@ -1808,10 +2020,10 @@ impl GdbConnection {
                     self.req.mem().len
                 );
 
-                return true;
+                return Ok(true);
             }
             self.write_packet_bytes(b"");
-            return false;
+            return Ok(false);
         }
 
         unhandled_req!(
@@ -1820,7 +2032,7 @@ impl GdbConnection {
             String::from_utf8_lossy(name)
         );
 
-        false
+        Ok(false)
     }
 
     /// Return true if we need to do something in a debugger request,
@@ -1848,7 +2060,7 @@ impl GdbConnection {
 
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
-    fn process_vpacket(&mut self, payload: &[u8]) -> bool {
+    fn process_vpacket(&mut self, payload: &[u8]) -> Result<bool, GdbProtocolError> {
         let maybe_args_loc = memchr(b';', payload);
         let name = match maybe_args_loc {
             Some(l) => &payload[0..l],
@@ -1858,7 +2070,7 @@ impl GdbConnection {
         let maybe_args = maybe_args_loc.map(|l| &payload[l + 1..]);
 
         if name == b"Cont" {
-            let mut args = maybe_args.unwrap();
+            let mut args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
             let mut actions: Vec<GdbContAction> = Vec::new();
             let mut maybe_default_action: Option<GdbContAction> = None;
 
@@ -1875,7 +2087,7 @@ impl GdbConnection {
                     if args[0] == b':' {
                         is_default = false;
                         args = &args[1..];
-                        target = parse_threadid(args, &mut args);
+                        target = parse_threadid(args, &mut args)?;
                     }
                     let args_loc = memchr(b';', args);
                     match args_loc {
@@ -1898,10 +2110,8 @@ impl GdbConnection {
                     b'C' => {
                         let mut endptr: &[u8] = Default::default();
                         action = GdbActionType::ActionContinue;
-                        maybe_signal_to_deliver = str16_to_usize(cmd_maybe_sig, &mut endptr)
-                            .unwrap()
-                            .try_into()
-                            .ok();
+                        maybe_signal_to_deliver =
+                            str16_to_usize(cmd_maybe_sig, &mut endptr)?.try_into().ok();
                         maybe_endptr = Some(endptr);
                     }
                     b'c' => {
@@ -1910,10 +2120,8 @@ impl GdbConnection {
                     b'S' => {
                         let mut endptr: &[u8] = Default::default();
                         action = GdbActionType::ActionStep;
-                        maybe_signal_to_deliver = str16_to_usize(cmd_maybe_sig, &mut endptr)
-                            .unwrap()
-                            .try_into()
-                            .ok();
+                        maybe_signal_to_deliver =
+                            str16_to_usize(cmd_maybe_sig, &mut endptr)?.try_into().ok();
                         maybe_endptr = Some(endptr);
                     }
                     b's' => {
@@ -1925,7 +2133,7 @@ impl GdbConnection {
                             "Unhandled vCont command {:?}",
                             OsStr::from_bytes(&cmd)
                         );
-                        return false;
+                        return Ok(false);
                     }
                 }
                 match maybe_endptr {
@@ -1935,7 +2143,7 @@ impl GdbConnection {
                             "Unhandled vCont command parameters {:?}",
                             OsStr::from_bytes(endptr)
                         );
-                        return false;
+                        return Ok(false);
                     }
                     _ => (),
                 }
@@ -1946,7 +2154,7 @@ impl GdbConnection {
                                 self,
                                 "Unhandled vCont command with multiple default actions"
                             );
-                            return false;
+                            return Ok(false);
                         }
                         None => {
                             maybe_default_action = Some(GdbContAction::new(
@@ -1972,13 +2180,13 @@ impl GdbConnection {
             self.req = GdbRequest::new(DREQ_CONT);
             self.req.cont_mut().run_direction = RunDirection::RunForward;
             self.req.cont_mut().actions = actions;
-            return true;
+            return Ok(true);
         }
 
         if name == b"Cont?" {
             log!(LogDebug, "gdb queries which continue commands we support");
             self.write_packet_bytes(b"vCont;c;C;s;S;");
-            return false;
+            return Ok(false);
         }
 
         if name == b"Kill" {
@@ -1988,11 +2196,11 @@ impl GdbConnection {
             // to implement vRun, so we'll ignore this one.
             log!(LogDebug, "gdb asks us to kill tracee(s); ignoring");
             self.write_packet_bytes(b"OK");
-            return false;
+            return Ok(false);
         }
 
         if name == b"Run" {
-            let mut args = maybe_args.unwrap();
+            let mut args = maybe_args.ok_or(GdbProtocolError::Malformed("missing arguments"))?;
             self.req = GdbRequest::new(DREQ_RESTART);
 
             let mut filename = args;
@@ -2009,7 +2217,7 @@ impl GdbConnection {
             }
             if args.is_empty() {
                 self.req.restart_mut().type_ = GdbRestartType::FromPrevious;
-                return true;
+                return Ok(true);
             }
             let mut arg1 = args;
             let maybe_args_loc = memchr(b';', args);
@@ -2022,12 +2230,14 @@ impl GdbConnection {
                     String::from_utf8_lossy(args)
                 );
             }
-            let event_str = decode_ascii_encoded_hex_str(arg1);
+            let event_str = decode_ascii_encoded_hex_str(arg1)?;
             let mut event_strb = event_str.as_bytes();
             let mut endp: &[u8] = Default::default();
-            if event_strb[0] == b'c' {
+            if event_strb.first() == Some(&b'c') {
                 event_strb = &event_strb[1..];
-                let param = str0_to_isize(event_strb, &mut endp).unwrap();
+                // Checkpoints may also be referred to by name, in which case
+                // `param` stays 0 and the GdbServer resolves `param_str`.
+                let param = str0_to_isize(event_strb, &mut endp).unwrap_or_default();
                 self.req.restart_mut().type_ = GdbRestartType::FromCheckpoint;
                 self.req.restart_mut().param_str = String::from_utf8_lossy(event_strb).into();
                 // Note the unwrap_or_default()
@@ -2040,10 +2250,7 @@ impl GdbConnection {
                 );
             } else {
                 self.req.restart_mut().type_ = GdbRestartType::FromEvent;
-                self.req.restart_mut().param = str0_to_isize(event_strb, &mut endp)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
+                self.req.restart_mut().param = str0_to_isize(event_strb, &mut endp)?.try_into()?;
                 log!(
                     LogDebug,
                     "next replayer advancing to event {}",
@@ -2061,85 +2268,66 @@ impl GdbConnection {
                 self.req.restart_mut().param = u64::MAX;
             }
 
-            return true;
+            return Ok(true);
         }
 
         if name.starts_with(b"File:") {
             let operation = &payload[5..];
             if operation.starts_with(b"open:") {
-                let file_name_end_loc = memchr(b',', operation).unwrap();
+                let file_name_end_loc =
+                    memchr(b',', operation).ok_or(GdbProtocolError::Malformed("missing ','"))?;
                 let file_name = &operation[5..file_name_end_loc];
                 self.req = GdbRequest::new(DREQ_FILE_OPEN);
-                self.req.file_open_mut().file_name = decode_ascii_encoded_hex_str(file_name);
+                self.req.file_open_mut().file_name = decode_ascii_encoded_hex_str(file_name)?;
                 let mut flags_end: &[u8] = Default::default();
                 let flags: i32 =
-                    str16_to_usize(&operation[file_name_end_loc + 1..], &mut flags_end)
-                        .unwrap()
-                        .try_into()
-                        .unwrap();
+                    str16_to_usize(&operation[file_name_end_loc + 1..], &mut flags_end)?
+                        .try_into()?;
 
-                parser_assert_eq!(flags_end[0], b',');
-                self.req.file_open_mut().flags = gdb_open_flags_to_system_flags(flags);
+                parser_assert_eq!(flags_end.first(), Some(&b','));
+                self.req.file_open_mut().flags = gdb_open_flags_to_system_flags(flags)?;
                 let mut mode_end: &[u8] = Default::default();
-                let mode: i32 = str16_to_isize(&flags_end[1..], &mut mode_end)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
+                let mode: i32 = str16_to_isize(&flags_end[1..], &mut mode_end)?.try_into()?;
                 parser_assert_eq!(mode_end.len(), 0);
                 parser_assert_eq!(mode & !0o777, 0);
                 self.req.file_open_mut().mode = mode;
-                return true;
+                return Ok(true);
             } else if operation.starts_with(b"close:") {
                 let mut endptr: &[u8] = Default::default();
-                let fd: i32 = str16_to_isize(&operation[6..], &mut endptr)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
+                let fd: i32 = str16_to_isize(&operation[6..], &mut endptr)?.try_into()?;
                 parser_assert_eq!(endptr.len(), 0);
                 self.req = GdbRequest::new(DREQ_FILE_CLOSE);
                 self.req.file_close_mut().fd = fd;
-                return true;
+                return Ok(true);
             } else if operation.starts_with(b"pread:") {
                 let mut fd_end: &[u8] = Default::default();
-                let fd: i32 = str16_to_isize(&operation[6..], &mut fd_end)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                parser_assert_eq!(fd_end[0], b',');
+                let fd: i32 = str16_to_isize(&operation[6..], &mut fd_end)?.try_into()?;
+                parser_assert_eq!(fd_end.first(), Some(&b','));
                 self.req = GdbRequest::new(DREQ_FILE_PREAD);
                 self.req.file_pread_mut().fd = fd;
                 let mut size_end: &[u8] = Default::default();
-                let size: i64 = str16_to_isize(&fd_end[1..], &mut size_end)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                parser_assert_eq!(size_end[0], b',');
+                let size: i64 = str16_to_isize(&fd_end[1..], &mut size_end)?.try_into()?;
+                parser_assert_eq!(size_end.first(), Some(&b','));
                 parser_assert!(size >= 0);
-                self.req.file_pread_mut().size = size.try_into().unwrap();
+                self.req.file_pread_mut().size = size.try_into()?;
                 let mut offset_end: &[u8] = Default::default();
                 // @TODO Shouldn't we have a str16_to_i64 or something like that?
                 // Interestingly rr has a strtol() here.
-                let offset: i64 = str16_to_isize(&size_end[1..], &mut offset_end)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
+                let offset: i64 = str16_to_isize(&size_end[1..], &mut offset_end)?.try_into()?;
                 parser_assert_eq!(offset_end.len(), 0);
                 parser_assert!(offset >= 0);
-                self.req.file_pread_mut().offset = offset.try_into().unwrap();
-                return true;
+                self.req.file_pread_mut().offset = offset.try_into()?;
+                return Ok(true);
             } else if operation.starts_with(b"setfs:") {
                 let mut endptr: &[u8] = Default::default();
-                let pid: pid_t = str16_to_isize(&operation[6..], &mut endptr)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
+                let pid: pid_t = str16_to_isize(&operation[6..], &mut endptr)?.try_into()?;
                 parser_assert_eq!(endptr.len(), 0);
                 self.req = GdbRequest::new(DREQ_FILE_SETFS);
                 self.req.file_setfs_mut().pid = pid;
-                return true;
+                return Ok(true);
             } else {
                 self.write_packet_bytes(b"");
-                return false;
+                return Ok(false);
             }
         }
 
@@ -2148,7 +2336,7 @@ impl GdbConnection {
             "Unhandled gdb vpacket: v{}",
             String::from_utf8_lossy(name)
         );
-        false
+        Ok(false)
     }
 
     /// Return true if we need to do something in a debugger request,
@@ -2185,9 +2373,9 @@ impl GdbConnection {
     /// Return true if we need to do something in a debugger request,
     /// false if we already handled the packet internally.
     fn process_packet(&mut self) -> bool {
-        parser_assert!(
+        debug_assert!(
             INTERRUPT_CHAR == self.inbuf[0]
-                || (b'$' == self.inbuf[0] && memchr(b'#', &self.inbuf).unwrap() == self.packetend)
+                || (b'$' == self.inbuf[0] && memchr(b'#', &self.inbuf) == Some(self.packetend))
         );
 
         if INTERRUPT_CHAR == self.inbuf[0] {
@@ -2208,10 +2396,42 @@ impl GdbConnection {
             String::from_utf8_lossy(&payload)
         );
 
+        let ret = match self.process_request(request, &payload) {
+            Ok(ret) => ret,
+            Err(e) => {
+                log!(
+                    LogWarn,
+                    "Rejecting malformed gdb packet {}{}: {}",
+                    request_c,
+                    String::from_utf8_lossy(&payload),
+                    e
+                );
+                self.write_packet_bytes(b"E01");
+                false
+            }
+        };
+        // Erase the newly processed packet from the input buffer. The checksum
+        // after the '#' will be skipped later as we look for the next packet start.
+        {
+            self.inbuf.drain(0..self.packetend + 1);
+        }
+
+        // If we processed the request internally, consume it.
+        if !ret {
+            self.consume_request();
+        }
+
+        ret
+    }
+
+    /// Parse the packet `request``payload`. Return true if we need to do
+    /// something in a debugger request, false if we already handled the packet
+    /// internally.
+    fn process_request(&mut self, request: u8, payload: &[u8]) -> Result<bool, GdbProtocolError> {
         let ret;
         match request {
             b'b' => {
-                ret = self.process_bpacket(&payload);
+                ret = self.process_bpacket(payload);
             }
             b'c' => {
                 log!(LogDebug, "gdb is asking to continue");
@@ -2244,13 +2464,14 @@ impl GdbConnection {
                 ret = false;
             }
             b'H' => {
+                parser_assert!(!payload.is_empty());
                 if b'c' == payload[0] {
                     self.req = GdbRequest::new(DREQ_SET_CONTINUE_THREAD);
                 } else {
                     self.req = GdbRequest::new(DREQ_SET_QUERY_THREAD);
                 }
                 let mut payload_slice = &payload[1..];
-                self.req.target = parse_threadid(payload_slice, &mut payload_slice);
+                self.req.target = parse_threadid(payload_slice, &mut payload_slice)?;
                 parser_assert_eq!(payload_slice.len(), 0);
 
                 log!(LogDebug, "gdb selecting {}", self.req.target);
@@ -2265,14 +2486,12 @@ impl GdbConnection {
             b'm' => {
                 self.req = GdbRequest::new(DREQ_GET_MEM);
                 self.req.target = self.query_thread;
-                let mut payload_sl: &[u8] = &payload;
-                self.req.mem_mut().addr = str16_to_usize(payload_sl, &mut payload_sl)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                parser_assert_eq!(b',', payload_sl[0]);
+                let mut payload_sl: &[u8] = payload;
+                self.req.mem_mut().addr =
+                    str16_to_usize(payload_sl, &mut payload_sl)?.try_into()?;
+                parser_assert_eq!(Some(&b','), payload_sl.first());
                 payload_sl = &payload_sl[1..];
-                self.req.mem_mut().len = str16_to_usize(payload_sl, &mut payload_sl).unwrap();
+                self.req.mem_mut().len = str16_to_usize(payload_sl, &mut payload_sl)?;
                 parser_assert_eq!(payload_sl.len(), 0);
 
                 log!(
@@ -2295,12 +2514,11 @@ impl GdbConnection {
             b'p' => {
                 self.req = GdbRequest::new(DREQ_GET_REG);
                 self.req.target = self.query_thread;
-                let mut payload_sl: &[u8] = &payload;
-                let num: u32 = str16_to_usize(payload_sl, &mut payload_sl)
-                    .unwrap()
+                let mut payload_sl: &[u8] = payload;
+                let num: u32 = str16_to_usize(payload_sl, &mut payload_sl)?.try_into()?;
+                self.req.reg_mut().name = num
                     .try_into()
-                    .unwrap();
-                self.req.reg_mut().name = num.try_into().unwrap();
+                    .map_err(|_| GdbProtocolError::Malformed("register number"))?;
                 parser_assert_eq!(payload_sl.len(), 0);
                 log!(
                     LogDebug,
@@ -2312,31 +2530,30 @@ impl GdbConnection {
             b'P' => {
                 self.req = GdbRequest::new(DREQ_SET_REG);
                 self.req.target = self.query_thread;
-                let mut payload_sl: &[u8] = &payload;
-                let num: u32 = str16_to_usize(payload_sl, &mut payload_sl)
-                    .unwrap()
+                let mut payload_sl: &[u8] = payload;
+                let num: u32 = str16_to_usize(payload_sl, &mut payload_sl)?.try_into()?;
+                self.req.reg_mut().name = num
                     .try_into()
-                    .unwrap();
-                self.req.reg_mut().name = num.try_into().unwrap();
-                parser_assert_eq!(b'=', payload_sl[0]);
+                    .map_err(|_| GdbProtocolError::Malformed("register number"))?;
+                parser_assert_eq!(Some(&b'='), payload_sl.first());
                 payload_sl = &payload_sl[1..];
 
-                read_reg_value(&mut payload_sl, self.req.reg_mut());
+                read_reg_value(&mut payload_sl, self.req.reg_mut())?;
 
                 parser_assert_eq!(payload_sl.len(), 0);
 
                 ret = true;
             }
             b'q' => {
-                ret = self.query(&payload);
+                ret = self.query(payload)?;
             }
             b'Q' => {
-                ret = self.set_var(&payload);
+                ret = self.set_var(payload);
             }
             b'T' => {
                 self.req = GdbRequest::new(DREQ_GET_IS_THREAD_ALIVE);
-                let mut payload_sl: &[u8] = &payload;
-                self.req.target = parse_threadid(payload_sl, &mut payload_sl);
+                let mut payload_sl: &[u8] = payload;
+                self.req.target = parse_threadid(payload_sl, &mut payload_sl)?;
                 parser_assert_eq!(payload_sl.len(), 0);
                 log!(
                     LogDebug,
@@ -2346,20 +2563,19 @@ impl GdbConnection {
                 ret = true;
             }
             b'v' => {
-                ret = self.process_vpacket(&payload);
+                ret = self.process_vpacket(payload)?;
             }
             b'X' => {
                 self.req = GdbRequest::new(DREQ_SET_MEM);
                 self.req.target = self.query_thread;
-                let mut payload_sl: &[u8] = &payload;
-                self.req.mem_mut().addr =
-                    str16_to_usize(payload_sl, &mut payload_sl).unwrap().into();
-                parser_assert_eq!(b',', payload_sl[0]);
+                let mut payload_sl: &[u8] = payload;
+                self.req.mem_mut().addr = str16_to_usize(payload_sl, &mut payload_sl)?.into();
+                parser_assert_eq!(Some(&b','), payload_sl.first());
                 payload_sl = &payload_sl[1..];
-                self.req.mem_mut().len = str16_to_usize(payload_sl, &mut payload_sl).unwrap();
-                parser_assert_eq!(b':', payload_sl[0]);
+                self.req.mem_mut().len = str16_to_usize(payload_sl, &mut payload_sl)?;
+                parser_assert_eq!(Some(&b':'), payload_sl.first());
                 payload_sl = &payload_sl[1..];
-                read_binary_data(payload_sl, &mut self.req.mem_mut().data);
+                read_binary_data(payload_sl, &mut self.req.mem_mut().data)?;
                 parser_assert_eq!(self.req.mem().len, self.req.mem().data.len());
 
                 log!(
@@ -2373,12 +2589,9 @@ impl GdbConnection {
                 ret = true;
             }
             b'z' | b'Z' => {
-                let mut payload_sl: &[u8] = &payload;
-                let type_: u32 = str16_to_usize(payload_sl, &mut payload_sl)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                parser_assert_eq!(b',', payload_sl[0]);
+                let mut payload_sl: &[u8] = payload;
+                let type_: u32 = str16_to_usize(payload_sl, &mut payload_sl)?.try_into()?;
+                parser_assert_eq!(Some(&b','), payload_sl.first());
                 payload_sl = &payload_sl[1..];
                 if type_ > 4 {
                     log!(LogWarn, "Unknown watch type {}", type_);
@@ -2391,30 +2604,26 @@ impl GdbConnection {
                         DREQ_REMOVE_SW_BREAK
                     };
                     self.req = GdbRequest::new(type_ + add);
-                    self.req.watch_mut().addr =
-                        str16_to_usize(payload_sl, &mut payload_sl).unwrap().into();
-                    parser_assert_eq!(b',', payload_sl[0]);
+                    self.req.watch_mut().addr = str16_to_usize(payload_sl, &mut payload_sl)?.into();
+                    parser_assert_eq!(Some(&b','), payload_sl.first());
                     payload_sl = &payload_sl[1..];
-                    self.req.watch_mut().kind =
-                        str16_to_usize(payload_sl, &mut payload_sl).unwrap();
+                    self.req.watch_mut().kind = str16_to_usize(payload_sl, &mut payload_sl)?;
 
                     if !payload_sl.is_empty() && b';' == payload_sl[0] {
                         payload_sl = &payload_sl[1..];
                         while !payload_sl.is_empty() && b'X' == payload_sl[0] {
                             payload_sl = &payload_sl[1..];
-                            let len: usize = str16_to_usize(payload_sl, &mut payload_sl).unwrap();
+                            let len: usize = str16_to_usize(payload_sl, &mut payload_sl)?;
 
-                            parser_assert_eq!(b',', payload_sl[0]);
+                            parser_assert_eq!(Some(&b','), payload_sl.first());
                             payload_sl = &payload_sl[1..];
                             let mut bytes = Vec::<u8>::new();
                             for _ in 0..len {
                                 parser_assert!(payload_sl.len() >= 2);
                                 let mut end_of_byte = Default::default();
                                 bytes.push(
-                                    str16_to_usize(&payload_sl[0..2], &mut end_of_byte)
-                                        .unwrap()
-                                        .try_into()
-                                        .unwrap(),
+                                    str16_to_usize(&payload_sl[0..2], &mut end_of_byte)?
+                                        .try_into()?,
                                 );
                                 parser_assert_eq!(end_of_byte.len(), 0);
                                 payload_sl = &payload_sl[2..];
@@ -2447,22 +2656,12 @@ impl GdbConnection {
                 ret = true;
             }
             _ => {
-                unhandled_req!(self, "Unhandled gdb request '{}'", self.inbuf[1]);
+                unhandled_req!(self, "Unhandled gdb request '{}'", request);
                 ret = false;
             }
         }
-        // Erase the newly processed packet from the input buffer. The checksum
-        // after the '#' will be skipped later as we look for the next packet start.
-        {
-            self.inbuf.drain(0..self.packetend + 1);
-        }
 
-        // If we processed the request internally, consume it.
-        if !ret {
-            self.consume_request();
-        }
-
-        ret
+        Ok(ret)
     }
 
     fn consume_request(&mut self) {
@@ -2499,6 +2698,11 @@ impl GdbConnection {
         if !watch_addr.is_null() {
             write!(buf, "watch:{:x};", watch_addr.as_usize()).unwrap();
         }
+        if self.features_.position_in_stop_reply {
+            if let Some(position) = self.replay_position {
+                write!(buf, "rd-position:{};", position).unwrap();
+            }
+        }
 
         self.write_packet_bytes(&buf);
     }
@@ -2605,13 +2809,13 @@ fn poll_socket(sock_fd: &ScopedFd, events: PollFlags, timeout_ms: i32) -> bool {
 
 // @TODO Since this is ASCII encoded it might be a good idea to
 // use a specific ASCII type instead of String?
-fn decode_ascii_encoded_hex_str(encoded: &[u8]) -> String {
+fn decode_ascii_encoded_hex_str(encoded: &[u8]) -> Result<String, GdbProtocolError> {
     let enc_len = encoded.len();
     parser_assert_eq!(enc_len % 2, 0);
     let mut decoded_str = String::new();
     for i in 0..enc_len / 2 {
-        let enc_byte_str = std::str::from_utf8(&encoded[2 * i..2 * i + 2]).unwrap();
-        let c_u8 = u8::from_str_radix(enc_byte_str, 16).unwrap();
+        let enc_byte_str = std::str::from_utf8(&encoded[2 * i..2 * i + 2])?;
+        let c_u8 = u8::from_str_radix(enc_byte_str, 16)?;
         // @TODO Why should this be the case? the hex string is ascii encoded but why
         // should the final string be purely ascii too?? Things like filenames may be
         // in arbitrary encoding in linux
@@ -2620,12 +2824,12 @@ fn decode_ascii_encoded_hex_str(encoded: &[u8]) -> String {
         decoded_str.push(c);
     }
 
-    decoded_str
+    Ok(decoded_str)
 }
 
 /// Format `reg` into `buf` in the manner gdb expects.
 fn print_reg_value(reg: &GdbRegisterValue, buf: &mut Vec<u8>) {
-    parser_assert!(reg.size <= GdbRegisterValue::MAX_SIZE);
+    debug_assert!(reg.size <= GdbRegisterValue::MAX_SIZE);
     if reg.defined {
         // gdb wants the register value in native endianness.
         // reg.value read in native endianness is exactly that.
@@ -2723,7 +2927,7 @@ fn to_gdb_signum(maybe_sig: Option<Sig>) -> i32 {
     }
 }
 
-fn gdb_open_flags_to_system_flags(flags: i32) -> i32 {
+fn gdb_open_flags_to_system_flags(flags: i32) -> Result<i32, GdbProtocolError> {
     let mut ret: i32;
     match flags & 3 {
         0 => {
@@ -2736,8 +2940,7 @@ fn gdb_open_flags_to_system_flags(flags: i32) -> i32 {
             ret = libc::O_RDWR;
         }
         _ => {
-            parser_assert!(false);
-            return 0;
+            return Err(GdbProtocolError::Malformed("open access mode"));
         }
     }
     parser_assert_eq!(0, flags & !(3 | 0x8 | 0x200 | 0x400 | 0x800));
@@ -2754,7 +2957,22 @@ fn gdb_open_flags_to_system_flags(flags: i32) -> i32 {
         ret |= libc::O_EXCL;
     }
 
-    ret
+    Ok(ret)
+}
+
+/// The modulo-256 sum of a packet's payload, i.e. everything between the '$'
+/// and the '#'.
+fn packet_checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Parse the two hex digits following a packet's '#'. Returns `None` if
+/// `digits` is anything else.
+fn parse_checksum_digits(digits: &[u8]) -> Option<u8> {
+    if digits.len() != 2 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).ok()
 }
 
 fn request_needs_immediate_response(req: &GdbRequest) -> bool {
@@ -2765,7 +2983,10 @@ fn request_needs_immediate_response(req: &GdbRequest) -> bool {
 /// first char points to the character just after the last character in the
 /// thread-id.  `new_text` may be set as an empty slice if there are no
 /// characters remaining after thread-id.
-fn parse_threadid<'a>(mut text: &'a [u8], new_text: &mut &'a [u8]) -> GdbThreadId {
+fn parse_threadid<'a>(
+    mut text: &'a [u8],
+    new_text: &mut &'a [u8],
+) -> Result<GdbThreadId, GdbProtocolError> {
     let mut t = GdbThreadId::new(-1, -1);
     let mut multiprocess = false;
     parser_assert!(!text.is_empty());
@@ -2774,27 +2995,29 @@ fn parse_threadid<'a>(mut text: &'a [u8], new_text: &mut &'a [u8]) -> GdbThreadI
         text = &text[1..];
     }
 
-    t.pid = str16_to_isize(text, &mut text).unwrap().try_into().unwrap();
-    if text.is_empty() {
+    t.pid = str16_to_isize(text, &mut text)?.try_into()?;
+    // Terminators: single process, no PID or TID, depending on the 'p' prefix.
+    // The id is followed by the next action in a vCont packet.
+    if text.is_empty() || text[0] == b';' || text[0] == b',' {
         if multiprocess {
             t.tid = -1;
         } else {
             t.tid = t.pid;
             t.pid = -1;
         }
-        *new_text = text;
-        return t;
+    } else {
+        // Multiprocess syntax "<PID>.<TID>"
+        parser_assert_eq!(Some(&b'.'), text.first());
+        text = &text[1..];
+        t.tid = str16_to_isize(text, &mut text)?.try_into()?;
     }
-
-    parser_assert_eq!(b'.', text[0]);
-    text = &text[1..];
-    t.tid = str16_to_isize(text, &mut text).unwrap().try_into().unwrap();
+    parser_assert!(text.is_empty() || text[0] == b';' || text[0] == b',');
 
     *new_text = text;
-    t
+    Ok(t)
 }
 
-fn read_binary_data(payload: &[u8], data: &mut Vec<u8>) {
+fn read_binary_data(payload: &[u8], data: &mut Vec<u8>) -> Result<(), GdbProtocolError> {
     data.clear();
     let l = payload.len();
     let mut it = payload.iter().enumerate();
@@ -2802,70 +3025,37 @@ fn read_binary_data(payload: &[u8], data: &mut Vec<u8>) {
         if b'}' == b {
             // There is at least one more u8 left
             parser_assert!(i < l - 1);
-            let (_, &next_b) = it.next().unwrap();
-            data.push(0x20 ^ next_b);
+            if let Some((_, &next_b)) = it.next() {
+                data.push(0x20 ^ next_b);
+            }
         } else {
             data.push(b);
         }
     }
-}
-
-fn target_description_name(cpu_features: u32) -> &'static [u8] {
-    // This doesn't scale, but it's what gdb does...
-    match cpu_features {
-        0 => b"i386-linux.xml",
-        GdbConnection::CPU_64BIT => b"amd64-linux.xml",
-        GdbConnection::CPU_AVX => b"i386-avx-linux.xml",
-        GdbConnection::CPU_64BIT_AND_CPU_AVX => b"amd64-avx-linux.xml",
-        _ => fatal!("Unknown features"),
-    }
-}
-
-fn read_target_desc(file_name: &[u8]) -> Result<Vec<u8>, Error> {
-    let mut path = resource_path().to_owned();
-    path.push("share/rd");
-    path.push(OsStr::from_bytes(file_name));
-    let f = ScopedFd::open_path(&path, OFlag::O_RDONLY);
-    // DIFF NOTE: This is a debug assert in rr. Why?
-    assert!(f.is_open(), "Could not open {:?}", path);
-    let mut buf = [0u8; 4 * 1024];
-    let mut text_buf = Vec::<u8>::with_capacity(4 * 1024);
-    loop {
-        let bytes_result = read(f.as_raw(), &mut buf);
-        match bytes_result {
-            Err(e) => return Err(e),
-            Ok(0) => break,
-            Ok(nread) => {
-                text_buf.extend_from_slice(&buf[0..nread]);
-            }
-        }
-    }
 
-    Ok(text_buf)
+    Ok(())
 }
 
 /// Read the encoded register value in `strp` into `reg`.  `strp` may
 /// be mutated.
-fn read_reg_value(strp: &mut &[u8], reg: &mut GdbRegisterValue) {
+fn read_reg_value(strp: &mut &[u8], reg: &mut GdbRegisterValue) -> Result<(), GdbProtocolError> {
     let mut numstr = *strp;
 
-    if b'x' == numstr[0] {
+    if numstr.first() == Some(&b'x') {
         reg.defined = false;
         reg.size = 0;
-        return;
+        return Ok(());
     }
 
     reg.defined = true;
-    assert_eq!(numstr.len() % 2, 0);
+    parser_assert_eq!(numstr.len() % 2, 0);
+    parser_assert!(numstr.len() / 2 <= GdbRegisterValue::MAX_SIZE);
     reg.size = numstr.len() / 2;
     let mut buf = [0u8; GdbRegisterValue::MAX_SIZE];
     let mut new_sl: &[u8] = Default::default();
     for i in 0..reg.size {
-        buf[i] = str16_to_usize(&numstr[0..2], &mut new_sl)
-            .unwrap()
-            .try_into()
-            .unwrap();
-        assert_eq!(new_sl.len(), 0);
+        buf[i] = str16_to_usize(&numstr[0..2], &mut new_sl)?.try_into()?;
+        parser_assert_eq!(new_sl.len(), 0);
         numstr = &numstr[2..];
     }
     reg.value = match reg.size {
@@ -2877,6 +3067,7 @@ fn read_reg_value(strp: &mut &[u8], reg: &mut GdbRegisterValue) {
     };
 
     *strp = numstr;
+    Ok(())
 }
 
 fn to_string(bytes: &[u8], max_len: usize) -> String {
@@ -2891,3 +3082,84 @@ fn to_string(bytes: &[u8], max_len: usize) -> String {
 
     ss
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threadid(text: &[u8]) -> Result<((pid_t, pid_t), &[u8]), GdbProtocolError> {
+        let mut rest: &[u8] = Default::default();
+        let t = parse_threadid(text, &mut rest)?;
+        Ok(((t.pid, t.tid), rest))
+    }
+
+    #[test]
+    fn replay_position_test() {
+        let position = ReplayPosition {
+            event: 12,
+            ticks: 345,
+            direction: RunDirection::RunBackward,
+        };
+        assert_eq!("12,345,reverse", position.to_string());
+    }
+
+    #[test]
+    fn packet_checksum_test() {
+        assert_eq!(0, packet_checksum(b""));
+        // "$OK#9a" is the canonical reply.
+        assert_eq!(0x9a, packet_checksum(b"OK"));
+        // The sum wraps around.
+        assert_eq!(0xfe, packet_checksum(&[0xff, 0xff]));
+    }
+
+    #[test]
+    fn parse_checksum_digits_test() {
+        assert_eq!(Some(0x9a), parse_checksum_digits(b"9a"));
+        assert_eq!(Some(0x9a), parse_checksum_digits(b"9A"));
+        assert_eq!(Some(0), parse_checksum_digits(b"00"));
+        assert_eq!(None, parse_checksum_digits(b"9"));
+        assert_eq!(None, parse_checksum_digits(b"9ab"));
+        assert_eq!(None, parse_checksum_digits(b"zz"));
+        // from_str_radix alone would take a sign.
+        assert_eq!(None, parse_checksum_digits(b"+a"));
+        assert_eq!(None, parse_checksum_digits(b"-1"));
+    }
+
+    #[test]
+    fn parse_threadid_test() {
+        assert_eq!(threadid(b"p1a.2b").unwrap(), ((0x1a, 0x2b), &b""[..]));
+        assert_eq!(threadid(b"p-1.-1").unwrap(), ((-1, -1), &b""[..]));
+        // Without the 'p' prefix there's only a tid.
+        assert_eq!(threadid(b"2b").unwrap(), ((-1, 0x2b), &b""[..]));
+        // With it, the tid may be left out.
+        assert_eq!(threadid(b"p1a").unwrap(), ((0x1a, -1), &b""[..]));
+    }
+
+    #[test]
+    fn parse_threadid_terminators_test() {
+        assert_eq!(threadid(b"p1a.2b;c").unwrap(), ((0x1a, 0x2b), &b";c"[..]));
+        assert_eq!(threadid(b"p1a;c").unwrap(), ((0x1a, -1), &b";c"[..]));
+        assert_eq!(threadid(b"2b,0").unwrap(), ((-1, 0x2b), &b",0"[..]));
+    }
+
+    #[test]
+    fn parse_threadid_malformed_test() {
+        assert!(matches!(threadid(b""), Err(GdbProtocolError::Malformed(_))));
+        assert!(matches!(
+            threadid(b"p1a:2b"),
+            Err(GdbProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            threadid(b"p1a.2bx"),
+            Err(GdbProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            threadid(b"pzz"),
+            Err(GdbProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            threadid(b"p1ffffffffff.1"),
+            Err(GdbProtocolError::BadNumber(_))
+        ));
+    }
+}
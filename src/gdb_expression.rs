@@ -5,7 +5,11 @@ use crate::{
     remote_ptr::RemotePtr,
     session::task::{task_common::read_val_mem, Task},
 };
-use std::{convert::TryFrom, intrinsics::transmute, mem::size_of};
+use std::{
+    convert::{TryFrom, TryInto},
+    intrinsics::transmute,
+    mem::size_of,
+};
 use Opcode::*;
 
 /// Extracted from
@@ -66,6 +70,20 @@ enum Opcode {
     OP_printf = 0x34,
 }
 
+impl TryFrom<u8> for Opcode {
+    type Error = ();
+
+    fn try_from(op: u8) -> Result<Self, Self::Error> {
+        // 0x31 is unassigned.
+        if op < OP_float as u8 || op > OP_printf as u8 || op == 0x31 {
+            return Err(());
+        }
+        // Safe because `Opcode` is a `u8` with every other value in
+        // OP_float..=OP_printf assigned.
+        Ok(unsafe { transmute(op) })
+    }
+}
+
 /// DIFF NOTE: Simply called Value in rr
 #[derive(Copy, Clone, Default, Eq, PartialEq)]
 pub struct GdbExpressionValue {
@@ -271,8 +289,18 @@ impl<'a> ExpressionState<'a> {
     pub fn step(&mut self, t: &dyn Task) {
         debug_assert!(!self.error);
         let operands: BinaryOperands;
-        // @TODO Will the catch all case `_` deal with even invalid enum values?
-        match unsafe { transmute(self.fetch_u8()) } {
+        let op = self.fetch_u8();
+        if self.error {
+            return;
+        }
+        let op: Opcode = match op.try_into() {
+            Ok(op) => op,
+            Err(()) => {
+                self.set_error();
+                return;
+            }
+        };
+        match op {
             OP_add => {
                 operands = self.pop_a_b();
                 self.push(operands.a.overflowing_add(operands.b).0)
@@ -303,7 +331,7 @@ impl<'a> ExpressionState<'a> {
             OP_rem_signed => {
                 operands = self.pop_a_b();
                 let b = self.nonzero(operands.b);
-                self.push(operands.a % b)
+                self.push(operands.a.overflowing_rem(b).0)
             }
 
             OP_rem_unsigned => {
@@ -487,7 +515,8 @@ impl<'a> ExpressionState<'a> {
             OP_end => {
                 self.end = true;
             }
-            // @TODO Does the transmute play well with this?
+            // Floating point, tracing, trace state variables and printf are
+            // not supported in conditions.
             _ => {
                 self.set_error();
             }
@@ -157,6 +157,78 @@ pub const DREG_64_YMM12H: GdbRegister = GdbRegister(__DREG_64_YMM12H);
 pub const DREG_64_YMM13H: GdbRegister = GdbRegister(__DREG_64_YMM13H);
 pub const DREG_64_YMM14H: GdbRegister = GdbRegister(__DREG_64_YMM14H);
 pub const DREG_64_YMM15H: GdbRegister = GdbRegister(__DREG_64_YMM15H);
+pub const DREG_64_XMM16: GdbRegister = GdbRegister(__DREG_64_XMM16);
+pub const DREG_64_XMM17: GdbRegister = GdbRegister(__DREG_64_XMM17);
+pub const DREG_64_XMM18: GdbRegister = GdbRegister(__DREG_64_XMM18);
+pub const DREG_64_XMM19: GdbRegister = GdbRegister(__DREG_64_XMM19);
+pub const DREG_64_XMM20: GdbRegister = GdbRegister(__DREG_64_XMM20);
+pub const DREG_64_XMM21: GdbRegister = GdbRegister(__DREG_64_XMM21);
+pub const DREG_64_XMM22: GdbRegister = GdbRegister(__DREG_64_XMM22);
+pub const DREG_64_XMM23: GdbRegister = GdbRegister(__DREG_64_XMM23);
+pub const DREG_64_XMM24: GdbRegister = GdbRegister(__DREG_64_XMM24);
+pub const DREG_64_XMM25: GdbRegister = GdbRegister(__DREG_64_XMM25);
+pub const DREG_64_XMM26: GdbRegister = GdbRegister(__DREG_64_XMM26);
+pub const DREG_64_XMM27: GdbRegister = GdbRegister(__DREG_64_XMM27);
+pub const DREG_64_XMM28: GdbRegister = GdbRegister(__DREG_64_XMM28);
+pub const DREG_64_XMM29: GdbRegister = GdbRegister(__DREG_64_XMM29);
+pub const DREG_64_XMM30: GdbRegister = GdbRegister(__DREG_64_XMM30);
+pub const DREG_64_XMM31: GdbRegister = GdbRegister(__DREG_64_XMM31);
+pub const DREG_64_YMM16H: GdbRegister = GdbRegister(__DREG_64_YMM16H);
+pub const DREG_64_YMM17H: GdbRegister = GdbRegister(__DREG_64_YMM17H);
+pub const DREG_64_YMM18H: GdbRegister = GdbRegister(__DREG_64_YMM18H);
+pub const DREG_64_YMM19H: GdbRegister = GdbRegister(__DREG_64_YMM19H);
+pub const DREG_64_YMM20H: GdbRegister = GdbRegister(__DREG_64_YMM20H);
+pub const DREG_64_YMM21H: GdbRegister = GdbRegister(__DREG_64_YMM21H);
+pub const DREG_64_YMM22H: GdbRegister = GdbRegister(__DREG_64_YMM22H);
+pub const DREG_64_YMM23H: GdbRegister = GdbRegister(__DREG_64_YMM23H);
+pub const DREG_64_YMM24H: GdbRegister = GdbRegister(__DREG_64_YMM24H);
+pub const DREG_64_YMM25H: GdbRegister = GdbRegister(__DREG_64_YMM25H);
+pub const DREG_64_YMM26H: GdbRegister = GdbRegister(__DREG_64_YMM26H);
+pub const DREG_64_YMM27H: GdbRegister = GdbRegister(__DREG_64_YMM27H);
+pub const DREG_64_YMM28H: GdbRegister = GdbRegister(__DREG_64_YMM28H);
+pub const DREG_64_YMM29H: GdbRegister = GdbRegister(__DREG_64_YMM29H);
+pub const DREG_64_YMM30H: GdbRegister = GdbRegister(__DREG_64_YMM30H);
+pub const DREG_64_YMM31H: GdbRegister = GdbRegister(__DREG_64_YMM31H);
+pub const DREG_64_K0: GdbRegister = GdbRegister(__DREG_64_K0);
+pub const DREG_64_K1: GdbRegister = GdbRegister(__DREG_64_K1);
+pub const DREG_64_K2: GdbRegister = GdbRegister(__DREG_64_K2);
+pub const DREG_64_K3: GdbRegister = GdbRegister(__DREG_64_K3);
+pub const DREG_64_K4: GdbRegister = GdbRegister(__DREG_64_K4);
+pub const DREG_64_K5: GdbRegister = GdbRegister(__DREG_64_K5);
+pub const DREG_64_K6: GdbRegister = GdbRegister(__DREG_64_K6);
+pub const DREG_64_K7: GdbRegister = GdbRegister(__DREG_64_K7);
+pub const DREG_64_ZMM0H: GdbRegister = GdbRegister(__DREG_64_ZMM0H);
+pub const DREG_64_ZMM1H: GdbRegister = GdbRegister(__DREG_64_ZMM1H);
+pub const DREG_64_ZMM2H: GdbRegister = GdbRegister(__DREG_64_ZMM2H);
+pub const DREG_64_ZMM3H: GdbRegister = GdbRegister(__DREG_64_ZMM3H);
+pub const DREG_64_ZMM4H: GdbRegister = GdbRegister(__DREG_64_ZMM4H);
+pub const DREG_64_ZMM5H: GdbRegister = GdbRegister(__DREG_64_ZMM5H);
+pub const DREG_64_ZMM6H: GdbRegister = GdbRegister(__DREG_64_ZMM6H);
+pub const DREG_64_ZMM7H: GdbRegister = GdbRegister(__DREG_64_ZMM7H);
+pub const DREG_64_ZMM8H: GdbRegister = GdbRegister(__DREG_64_ZMM8H);
+pub const DREG_64_ZMM9H: GdbRegister = GdbRegister(__DREG_64_ZMM9H);
+pub const DREG_64_ZMM10H: GdbRegister = GdbRegister(__DREG_64_ZMM10H);
+pub const DREG_64_ZMM11H: GdbRegister = GdbRegister(__DREG_64_ZMM11H);
+pub const DREG_64_ZMM12H: GdbRegister = GdbRegister(__DREG_64_ZMM12H);
+pub const DREG_64_ZMM13H: GdbRegister = GdbRegister(__DREG_64_ZMM13H);
+pub const DREG_64_ZMM14H: GdbRegister = GdbRegister(__DREG_64_ZMM14H);
+pub const DREG_64_ZMM15H: GdbRegister = GdbRegister(__DREG_64_ZMM15H);
+pub const DREG_64_ZMM16H: GdbRegister = GdbRegister(__DREG_64_ZMM16H);
+pub const DREG_64_ZMM17H: GdbRegister = GdbRegister(__DREG_64_ZMM17H);
+pub const DREG_64_ZMM18H: GdbRegister = GdbRegister(__DREG_64_ZMM18H);
+pub const DREG_64_ZMM19H: GdbRegister = GdbRegister(__DREG_64_ZMM19H);
+pub const DREG_64_ZMM20H: GdbRegister = GdbRegister(__DREG_64_ZMM20H);
+pub const DREG_64_ZMM21H: GdbRegister = GdbRegister(__DREG_64_ZMM21H);
+pub const DREG_64_ZMM22H: GdbRegister = GdbRegister(__DREG_64_ZMM22H);
+pub const DREG_64_ZMM23H: GdbRegister = GdbRegister(__DREG_64_ZMM23H);
+pub const DREG_64_ZMM24H: GdbRegister = GdbRegister(__DREG_64_ZMM24H);
+pub const DREG_64_ZMM25H: GdbRegister = GdbRegister(__DREG_64_ZMM25H);
+pub const DREG_64_ZMM26H: GdbRegister = GdbRegister(__DREG_64_ZMM26H);
+pub const DREG_64_ZMM27H: GdbRegister = GdbRegister(__DREG_64_ZMM27H);
+pub const DREG_64_ZMM28H: GdbRegister = GdbRegister(__DREG_64_ZMM28H);
+pub const DREG_64_ZMM29H: GdbRegister = GdbRegister(__DREG_64_ZMM29H);
+pub const DREG_64_ZMM30H: GdbRegister = GdbRegister(__DREG_64_ZMM30H);
+pub const DREG_64_ZMM31H: GdbRegister = GdbRegister(__DREG_64_ZMM31H);
 
 impl Display for GdbRegister {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
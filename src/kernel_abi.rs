@@ -222,6 +222,17 @@ pub fn get_syscall_instruction_arch(
     }
 }
 
+/// Set in the syscall number of syscalls made through the x32 (ILP32 on
+/// x86-64) ABI.
+pub const X32_SYSCALL_BIT: isize = 0x4000_0000;
+
+/// x32 tasks run in 64-bit mode and use the `syscall` instruction, so the
+/// syscall number is the only thing that tells their syscalls apart from
+/// x86-64 ones.
+pub fn is_x32_syscall(syscallno: isize, arch: SupportedArch) -> bool {
+    arch == SupportedArch::X64 && syscallno >= 0 && syscallno & X32_SYSCALL_BIT != 0
+}
+
 pub fn is_at_syscall_instruction(t: &dyn Task, ptr: RemoteCodePtr) -> bool {
     let mut arch = SupportedArch::X64;
     get_syscall_instruction_arch(t, ptr, &mut arch)
@@ -16,6 +16,12 @@ pub fn syscall_name(syscall: i32, arch: SupportedArch) -> String {
     }
 }
 
+/// The number of the syscall called `name` on `arch`, if there is one.
+pub fn syscall_number_for_name(name: &str, arch: SupportedArch) -> Option<i32> {
+    // The generated tables only map numbers to names, but they're small.
+    (0..2048).find(|&no| syscall_name(no, arch) == name)
+}
+
 pub fn signal_name(sig: i32) -> String {
     // strsignal() would be nice to use here, but it provides TMI.
     if 32 <= sig && sig <= 64 {
@@ -619,6 +619,9 @@ fn dump_last_events(trace_stream: &TraceStream, f: &mut dyn Write) -> io::Result
         only_tid: None,
         trace_dir: Some(PathBuf::from(trace_stream.dir())),
         event_spec: Some((start, Some(end))),
+        // We're in the middle of aborting. Keep it simple.
+        jobs: 1,
+        json: false,
     };
 
     writeln!(f, "Tail of trace dump: {}-{}", start, end)?;
@@ -35,6 +35,11 @@ extern crate static_assertions;
 #[macro_use]
 extern crate memoffset;
 
+// Tracees, registers, syscall tables and the preload library are all x86
+// specific. See kernel_abi::SupportedArch.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+compile_error!("rd can only be built for x86 and x86-64 hosts");
+
 #[macro_use]
 mod log;
 #[macro_use]
@@ -76,8 +81,10 @@ mod rd;
 mod record_signal;
 mod record_syscall;
 mod remote_code_ptr;
+mod replay_notifier;
 mod replay_syscall;
 mod replay_timeline;
+mod replay_watchdog;
 mod return_address_list;
 mod scheduler;
 mod scoped_fd;
@@ -85,6 +92,7 @@ mod seccomp_bpf;
 mod seccomp_filter_rewriter;
 mod session;
 mod sig;
+mod target_description;
 mod taskish_uid;
 mod thread_db;
 mod thread_group;
@@ -100,9 +108,13 @@ use crate::{
         build_id_command::BuildIdCommand,
         dump_command::DumpCommand,
         env_command::EnvCommand,
+        find_command::FindCommand,
+        map_diff_command::MapDiffCommand,
+        pack_command::PackCommand,
         ps_command::PsCommand,
         rd_options::{RdOptions, RdSubCommand},
         rerun_command::ReRunCommand,
+        sources_command::SourcesCommand,
         trace_info_command::TraceInfoCommand,
         RdCommand,
     },
@@ -181,6 +193,18 @@ fn main() -> ExitResult<()> {
         RdSubCommand::Ps { .. } => {
             return PsCommand::new(&options).run();
         }
+        RdSubCommand::Pack { .. } => {
+            return PackCommand::new(&options).run();
+        }
+        RdSubCommand::Sources { .. } => {
+            return SourcesCommand::new(&options).run();
+        }
+        RdSubCommand::MapDiff { .. } => {
+            return MapDiffCommand::new(&options).run();
+        }
+        RdSubCommand::Find { .. } => {
+            return FindCommand::new(&options).run();
+        }
         RdSubCommand::Record { .. } => {
             return RecordCommand::new(&options).run();
         }
@@ -23,7 +23,14 @@ use nix::{
     unistd::read,
 };
 use raw_cpuid::CpuId;
-use std::{mem::size_of, os::unix::io::RawFd, sync::Mutex};
+use std::{
+    fs::{read_dir, File, OpenOptions},
+    io,
+    mem::size_of,
+    os::unix::{fs::FileExt, io::RawFd},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 lazy_static! {
     static ref PMU_BRANCHES_ACCUMULATOR: Mutex<u32> = Mutex::new(0);
@@ -79,8 +86,16 @@ bitflags! {
         /// (excluding interrupts, far branches, and rets).
         const PMU_TICKS_TAKEN_BRANCHES = 1<<3;
 
+        /// Set if speculatively executed locked instructions get counted as retired
+        /// conditional branches unless the SpecLockMap optimization is disabled
+        /// (AMD Zen). See check_zen_speclockmap().
+        const PMU_SPEC_LOCK_MAP = 1<<4;
+
         const PMU_TICKS_TAKEN_BRANCHES_WITH_SKIP_INTEL_BUG_CHECK =
             Self::PMU_TICKS_TAKEN_BRANCHES.bits | Self::PMU_SKIP_INTEL_BUG_CHECK.bits;
+
+        const PMU_TICKS_RCB_WITH_SPEC_LOCK_MAP =
+            Self::PMU_TICKS_RCB.bits | Self::PMU_SPEC_LOCK_MAP.bits;
     }
 }
 
@@ -120,8 +135,10 @@ enum CpuMicroarch {
     IntelCometlake,
     IntelIcelake,
     IntelTigerlake,
+    IntelRocketlake,
+    IntelSapphireRapid,
     AMDF15R30,
-    AMDRyzen,
+    AMDZen,
 }
 use CpuMicroarch::*;
 
@@ -173,23 +190,20 @@ fn get_cpu_microarch() -> CpuMicroarch {
         0x406e0 | 0x50650 | 0x506e0 => return IntelSkylake,
         0x30670 | 0x406c0 | 0x50670 => return IntelSilvermont,
         0x506f0 => return IntelGoldmont,
-        0x706e0 => return IntelIcelake,
+        0x706e0 | 0x606a0 | 0x606c0 => return IntelIcelake,
         0x806e0 | 0x906e0 => return IntelKabylake,
-        0xa0660 => return IntelCometlake,
-        0x806c0 => return IntelTigerlake,
+        0xa0650 | 0xa0660 => return IntelCometlake,
+        0x806c0 | 0x806d0 => return IntelTigerlake,
+        0xa0670 => return IntelRocketlake,
+        0x806f0 => return IntelSapphireRapid,
         0x30f00 => return AMDF15R30,
-        0x00f10 => {
-            if ext_family == 8 {
-                if !Flags::get().suppress_environment_warnings {
-                    eprintln!(
-                        "You have a Ryzen CPU. The Ryzen\n\
-                     retired-conditional-branches hardware\n\
-                     performance counter is not accurate enough; rd will\n\
-                     be unreliable.\n\
-                     See https://github.com/rr-debugger/rr/issues/2034."
-                    );
-                }
-                return AMDRyzen;
+        // Family 17h (Zen, Zen+, Zen 2) and family 19h (Zen 3, Zen 4) parts.
+        0x00f10 | 0x10f10 | 0x10f80 | 0x20f00 | 0x00f80 | 0x30f10 | 0x60f00 | 0x70f10 | 0x60f80
+        | 0x90f00 | 0x20f10 | 0x50f00 | 0x40f40 | 0x60f10 | 0x70f40 => {
+            if ext_family == 8 || ext_family == 0xa {
+                // Whether ticks are reliable depends on SpecLockMap, see
+                // check_zen_speclockmap().
+                return AMDZen;
             }
         }
         _ => (),
@@ -197,13 +211,132 @@ fn get_cpu_microarch() -> CpuMicroarch {
 
     if vendor_info_string == "AuthenticAMD" {
         clean_fatal!(
-            "AMD CPUs not supported.\n\
-             For Ryzen, see https://github.com/rr-debugger/rr/issues/2034.\n\
-             For post-Ryzen CPUs, please file a Github issue."
+            "AMD CPU type {:#x} (extended family {:#x}) unknown.\n\
+             If it is a Zen variant, try `rd --microarch Zen`, and please file a Github issue.",
+            cpu_type,
+            ext_family
         );
     } else {
-        clean_fatal!("Intel CPU type {:#x} unknown", cpu_type);
+        clean_fatal!(
+            "Intel CPU type {:#x} unknown.\n\
+             If you know its microarchitecture, try passing it to `rd --microarch`,\n\
+             and please file a Github issue.",
+            cpu_type
+        );
+    }
+}
+
+/// The MSR holding AMD Zen's SpecLockMap disable bit.
+const MSR_AMD64_LS_CFG: u64 = 0xc0011020;
+/// Setting this bit stops speculatively executed locked instructions from
+/// being counted as retired conditional branches.
+const LS_CFG_SPEC_LOCK_MAP_DISABLE: u64 = 1 << 54;
+
+/// On AMD Zen, ticks are only exact while the SpecLockMap optimization is
+/// disabled. Warn if it isn't on every CPU, or if `force` is set, disable it
+/// ourselves (which needs root and the msr kernel module). The setting lasts
+/// until the next reboot.
+pub fn check_zen_speclockmap(force: bool) {
+    if running_under_rd()
+        || !PMU_ATTRIBUTES
+            .pmu_flags
+            .contains(PmuFlags::PMU_SPEC_LOCK_MAP)
+    {
+        return;
+    }
+
+    let msr_files = match cpu_msr_files() {
+        Ok(msr_files) => msr_files,
+        Err(e) => {
+            if force {
+                clean_fatal!(
+                    "Unable to list /dev/cpu: {}. --force-zen-workaround needs the msr kernel module.",
+                    e
+                );
+            }
+            log!(
+                LogWarn,
+                "Unable to check the Zen SpecLockMap workaround: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    let mut unpatched = 0;
+    for path in &msr_files {
+        let value = match read_msr(path, MSR_AMD64_LS_CFG) {
+            Ok(value) => value,
+            Err(e) => {
+                if force {
+                    clean_fatal!(
+                        "Unable to read {:?}: {}. --force-zen-workaround needs root.",
+                        path,
+                        e
+                    );
+                }
+                log!(
+                    LogWarn,
+                    "Unable to check the Zen SpecLockMap workaround: {}",
+                    e
+                );
+                return;
+            }
+        };
+        if value & LS_CFG_SPEC_LOCK_MAP_DISABLE != 0 {
+            continue;
+        }
+        if force {
+            if let Err(e) = write_msr(path, MSR_AMD64_LS_CFG, value | LS_CFG_SPEC_LOCK_MAP_DISABLE)
+            {
+                clean_fatal!("Unable to write {:?}: {}", path, e);
+            }
+            log!(LogInfo, "Disabled SpecLockMap via {:?}", path);
+        } else {
+            unpatched += 1;
+        }
+    }
+
+    if unpatched > 0 && !Flags::get().suppress_environment_warnings {
+        eprintln!(
+            "The SpecLockMap optimization is enabled on {} of {} CPUs.\n\
+             AMD Zen CPUs then count some locked instructions as conditional branches,\n\
+             and the recording will probably not replay. Run `rd record` as root with\n\
+             --force-zen-workaround to disable it until the next reboot.\n\
+             See https://github.com/rr-debugger/rr/issues/2034.",
+            unpatched,
+            msr_files.len()
+        );
+    }
+}
+
+fn cpu_msr_files() -> io::Result<Vec<PathBuf>> {
+    let mut res = Vec::new();
+    for entry in read_dir("/dev/cpu")? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.parse::<u32>().is_ok())
+        {
+            res.push(entry.path().join("msr"));
+        }
     }
+    res.sort();
+    Ok(res)
+}
+
+fn read_msr(path: &Path, msr: u64) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    File::open(path)?.read_exact_at(&mut buf, msr)?;
+    Ok(u64::from_ne_bytes(buf))
+}
+
+fn write_msr(path: &Path, msr: u64, value: u64) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .write_all_at(&value.to_ne_bytes(), msr)
 }
 
 struct PmuBugsAndExtra {
@@ -346,7 +479,11 @@ fn get_init_attributes() -> PmuAttributes {
     if !(pmu.flags.contains(PmuFlags::PMU_TICKS_RCB)
         || pmu.flags.contains(PmuFlags::PMU_TICKS_TAKEN_BRANCHES))
     {
-        fatal!("Microarchitecture `{}' currently unsupported.", pmu.name);
+        clean_fatal!(
+            "Microarchitecture `{}' currently unsupported: rd needs a hardware counter of\n\
+             retired conditional branches or taken branches, and it has neither.",
+            pmu.name
+        );
     }
 
     let pmu_flags;
@@ -403,7 +540,25 @@ fn get_init_attributes() -> PmuAttributes {
 /// - cb = eventsel for event HW_INTERRUPTS.RECEIVED
 /// See Intel 64 and IA32 Architectures Performance Monitoring Events.
 /// See check_events from libpfm4.
-const PMU_CONFIGS: [PmuConfig; 17] = [
+const PMU_CONFIGS: [PmuConfig; 19] = [
+    PmuConfig {
+        uarch: IntelSapphireRapid,
+        name: "Intel Sapphire Rapids",
+        rcb_cntr_event: 0x5111c4,
+        minus_ticks_cntr_event: 0,
+        hw_intr_cntr_event: 0,
+        skid_size: 100,
+        flags: PmuFlags::PMU_TICKS_RCB,
+    },
+    PmuConfig {
+        uarch: IntelRocketlake,
+        name: "Intel Rocketlake",
+        rcb_cntr_event: 0x5111c4,
+        minus_ticks_cntr_event: 0,
+        hw_intr_cntr_event: 0,
+        skid_size: 100,
+        flags: PmuFlags::PMU_TICKS_RCB,
+    },
     PmuConfig {
         uarch: IntelCometlake,
         name: "Intel Cometlake",
@@ -549,13 +704,15 @@ const PMU_CONFIGS: [PmuConfig; 17] = [
         flags: PmuFlags::PMU_TICKS_TAKEN_BRANCHES_WITH_SKIP_INTEL_BUG_CHECK,
     },
     PmuConfig {
-        uarch: AMDRyzen,
-        name: "AMD Ryzen",
+        uarch: AMDZen,
+        name: "AMD Zen",
         rcb_cntr_event: 0x5100d1,
         minus_ticks_cntr_event: 0,
         hw_intr_cntr_event: 0,
-        skid_size: 1000,
-        flags: PmuFlags::PMU_TICKS_RCB,
+        // The counter interrupt can land much further past the requested
+        // period than on Intel parts.
+        skid_size: 10000,
+        flags: PmuFlags::PMU_TICKS_RCB_WITH_SPEC_LOCK_MAP,
     },
 ];
 
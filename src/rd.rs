@@ -14,6 +14,15 @@
 /// it will lose its magical properties.
 pub const RD_MAGIC_SAVE_DATA_FD: i32 = 999;
 
+/// rd tracees can write a name to this special fd to insert a marker (an
+/// "annotation") into the trace at the current event. The name is saved
+/// during recording and shows up in `rd dump` and `rd ps`. The debugger
+/// can seek to an annotation with the `seek-annotation` command.
+///
+/// Like RD_MAGIC_SAVE_DATA_FD, this is a valid fd opened to /dev/null and
+/// loses its magical properties if the tracee closes it or dup()s over it.
+pub const RD_MAGIC_ANNOTATE_FD: i32 = 998;
+
 /// rd uses this fd to ensure the tracee has access to the original root
 /// directory after a chroot(). Tracee close()es of this fd will be silently
 /// ignored, and tracee dup()s to this fd will fail with EBADF.
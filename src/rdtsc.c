@@ -7,4 +7,8 @@
 // NOTE: static and  __inline__ annotations removed though
 unsigned long long rdtsc(void) { return __rdtsc(); }
 
+// Like rdtsc() but also returns the IA32_TSC_AUX value (the cpu number the
+// kernel stores there) through `aux`.
+unsigned long long rdtscp(unsigned int* aux) { return __rdtscp(aux); }
+
 
@@ -51,6 +51,7 @@ use std::{
     cmp::{max, min},
     convert::TryFrom,
     ffi::OsString,
+    fs,
     intrinsics::copy_nonoverlapping,
     mem::size_of,
     ptr,
@@ -58,6 +59,7 @@ use std::{
 
 extern "C" {
     fn rdtsc() -> u64;
+    fn rdtscp(aux: *mut u32) -> u64;
 }
 
 pub const SIGCHLD_SYNTHETIC: i32 = 0xbeadf00du32 as i32;
@@ -273,6 +275,36 @@ fn restore_signal_state(t: &RecordTask, sig: Sig, signal_was_blocked: SignalBloc
     }
 }
 
+/// The cpu `t` last ran on, from field 39 of /proc/<tid>/stat.
+fn last_cpu_of(t: &RecordTask) -> Option<u32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", t.tid())).ok()?;
+    // comm may contain spaces, so count fields from the ')' that ends it.
+    // The field after it is field 3.
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(36)?
+        .parse()
+        .ok()
+}
+
+/// The IA32_TSC_AUX value Linux gives `cpu`: the cpu number in the low 12
+/// bits and its NUMA node above them.
+fn tsc_aux_for_cpu(cpu: u32) -> u32 {
+    let node = fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+        .ok()
+        .and_then(|entries| {
+            entries.filter_map(|e| e.ok()).find_map(|e| {
+                e.file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("node"))
+                    .and_then(|n| n.parse::<u32>().ok())
+            })
+        })
+        .unwrap_or(0);
+    (node << 12) | (cpu & 0xfff)
+}
+
 /// Return true if `t` was stopped because of a SIGSEGV resulting
 /// from a disabled instruction and `t` was updated appropriately, false
 /// otherwise.
@@ -298,13 +330,36 @@ fn try_handle_trapped_instruction(t: &RecordTask, si: &siginfo_t) -> bool {
     ed_assert!(t, len > 0);
 
     let mut r: Registers = t.regs_ref().clone();
-    if trapped_instruction == TrappedInstruction::Rdtsc
-        || trapped_instruction == TrappedInstruction::Rdtscp
-    {
+    if trapped_instruction == TrappedInstruction::Rdtsc {
         let current_time = unsafe { rdtsc() };
         r.set_rdtsc_output(current_time);
 
         log!(LogDebug, " trapped for rdtsc: returning {}", current_time);
+    } else if trapped_instruction == TrappedInstruction::Rdtscp {
+        // rdtscp also writes IA32_TSC_AUX to ecx.
+        let mut own_tsc_aux: u32 = 0;
+        let current_time = unsafe { rdtscp(&mut own_tsc_aux) };
+        let bound_cpu = t.trace_writer().trace_stream().bound_to_cpu();
+        let tsc_aux = match bound_cpu {
+            Some(cpu) => {
+                // rd binds itself to the tracees' cpu, so our value is the
+                // one the tracee would have seen.
+                ed_assert_eq!(t, own_tsc_aux & 0xfff, cpu);
+                own_tsc_aux
+            }
+            // We may be running on a different cpu than the tracee. Report
+            // the one it last ran on.
+            None => last_cpu_of(t).map_or(own_tsc_aux, tsc_aux_for_cpu),
+        };
+        r.set_rdtsc_output(current_time);
+        r.set_cx(tsc_aux as usize);
+
+        log!(
+            LogDebug,
+            " trapped for rdtscp: returning {}, aux {}",
+            current_time,
+            tsc_aux
+        );
     } else if trapped_instruction == TrappedInstruction::CpuId {
         let eax = r.syscallno() as u32;
         let ecx = r.cx() as u32;
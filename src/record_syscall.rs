@@ -50,7 +50,7 @@ use crate::{
             _JSIOCGNAME_0, _JSIOCGVERSION, _VFAT_IOCTL_READDIR_BOTH,
         },
         packet::{PACKET_RX_RING, PACKET_TX_RING},
-        perf_event::perf_event_attr,
+        perf_event::{perf_event_attr, PERF_FLAG_FD_CLOEXEC},
         personality::{PER_LINUX, PER_LINUX32},
         prctl::{
             ARCH_GET_CPUID, ARCH_GET_FS, ARCH_GET_GS, ARCH_SET_CPUID, ARCH_SET_FS, ARCH_SET_GS,
@@ -84,16 +84,15 @@ use crate::{
     fd_table::FdTable,
     file_monitor::{
         self, base_file_monitor::BaseFileMonitor, mmapped_file_monitor::MmappedFileMonitor,
-        proc_fd_dir_monitor::ProcFdDirMonitor, proc_mem_monitor::ProcMemMonitor,
-        stdio_monitor::StdioMonitor, virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
-        FileMonitor, LazyOffset, Range,
+        proc_comm_monitor::ProcCommMonitor, proc_fd_dir_monitor::ProcFdDirMonitor,
+        proc_mem_monitor::ProcMemMonitor, stdio_monitor::StdioMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor, FileMonitor, LazyOffset, Range,
     },
     kernel_abi::{
         common, is_at_syscall_instruction, is_clone_syscall, is_exit_group_syscall,
         is_exit_syscall, is_vfork_syscall, syscall_instruction_length, syscall_number_for_close,
-        syscall_number_for_munmap, syscall_number_for_pause, syscall_number_for_rt_sigprocmask,
-        x64, x86, CloneTLSType, FcntlOperation, MmapCallingSemantics, Ptr, SelectCallingSemantics,
-        SupportedArch,
+        syscall_number_for_pause, syscall_number_for_rt_sigprocmask, x64, x86, CloneTLSType,
+        FcntlOperation, MmapCallingSemantics, Ptr, SelectCallingSemantics, SupportedArch,
     },
     kernel_metadata::{
         errno_name, is_sigreturn, ptrace_req_name, shm_flags_to_mmap_prot, syscall_name,
@@ -146,8 +145,8 @@ use crate::{
     },
     util::{
         ceil_page_size, clone_flags_to_task_flags, copy_file, extract_clone_parameters,
-        has_effective_caps, is_proc_fd_dir, is_proc_mem_file, open_memory_file, page_size,
-        read_auxv, u8_slice_mut, word_at, word_size, write_all, CloneParameters,
+        has_effective_caps, is_proc_comm_file, is_proc_fd_dir, is_proc_mem_file, open_memory_file,
+        page_size, read_auxv, u8_slice_mut, word_at, word_size, write_all, CloneParameters,
     },
     wait_status::WaitStatus,
     weak_ptr_set::WeakPtrSet,
@@ -297,6 +296,32 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::PreventSwitch;
     }
 
+    if let Some(errno) = t
+        .session()
+        .as_record()
+        .unwrap()
+        .injected_errno(sys, Arch::arch())
+    {
+        log!(
+            LogDebug,
+            "Injecting {} into {}",
+            errno_name(errno),
+            syscall_name(sys, Arch::arch())
+        );
+        // Like a seccomp filter returning SECCOMP_RET_ERRNO: the kernel skips
+        // the syscall and returns our result, and replay just restores the
+        // recorded registers.
+        let mut r: Registers = regs.clone();
+        r.set_original_syscallno(SECCOMP_MAGIC_SKIP_ORIGINAL_SYSCALLNO);
+        r.set_syscall_result_signed(-errno as isize);
+        t.set_regs(&r);
+        let mut ev = t.ev_mut();
+        let syscall_ev = ev.syscall_event_mut();
+        syscall_ev.failed_during_preparation = true;
+        syscall_ev.injected_error = true;
+        return Switchable::PreventSwitch;
+    }
+
     include!(concat!(
         env!("OUT_DIR"),
         "/syscall_record_case_generated.rs"
@@ -1393,7 +1418,8 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         return Switchable::AllowSwitch;
     }
 
-    if sys == Arch::EPOLL_PWAIT {
+    // epoll_pwait2() only differs in the type of its timeout argument.
+    if sys == Arch::EPOLL_PWAIT || sys == Arch::EPOLL_PWAIT2 {
         // DIFF NOTE: This is arg3_signed() in rr
         syscall_state.reg_parameter_with_size(
             2,
@@ -1705,14 +1731,19 @@ fn rec_prepare_syscall_arch<Arch: Architecture>(t: &RecordTask, regs: &Registers
         let target = t.session().find_task_from_rec_tid(tid);
         let cpu = regs.arg3_signed() as i32;
         let flags = regs.arg5();
-        if target.is_some() && cpu == -1 && flags == 0 {
+        if target.is_some() && cpu == -1 && flags & !(PERF_FLAG_FD_CLOEXEC as usize) == 0 {
             let attr = read_val_mem(t, RemotePtr::<perf_event_attr>::from(regs.arg1()), None);
             if VirtualPerfCounterMonitor::should_virtualize(&attr) {
                 let mut r = regs.clone();
-                // Turn this into an inotify_init() syscall. This just gives us an
-                // allocated fd. Syscalls using this fd will be emulated (except for
-                // close()).
-                r.set_original_syscallno(Arch::INOTIFY_INIT as isize);
+                // Turn this into an inotify_init1() syscall. This just gives us an
+                // allocated fd, close-on-exec if the tracee asked for that. Syscalls
+                // using this fd will be emulated (except for close()).
+                r.set_original_syscallno(Arch::INOTIFY_INIT1 as isize);
+                if flags & PERF_FLAG_FD_CLOEXEC as usize != 0 {
+                    r.set_arg1(libc::IN_CLOEXEC as usize);
+                } else {
+                    r.set_arg1(0);
+                }
                 t.set_regs(&r);
             }
         }
@@ -2389,11 +2420,12 @@ pub fn rec_process_syscall_arch<Arch: Architecture>(
     }
 
     if sys == Arch::PERF_EVENT_OPEN {
-        if t.regs_ref().original_syscallno() == Arch::INOTIFY_INIT as isize {
+        if t.regs_ref().original_syscallno() == Arch::INOTIFY_INIT1 as isize {
             ed_assert!(t, !t.regs_ref().syscall_failed());
             let fd = t.regs_ref().syscall_result_signed() as i32;
             let mut r: Registers = t.regs_ref().clone();
             r.set_original_syscallno(syscall_state.syscall_entry_registers.original_syscallno());
+            r.set_arg1(syscall_state.syscall_entry_registers.arg1());
             t.set_regs(&r);
             let child_addr = RemotePtr::<perf_event_attr>::from(t.regs_ref().arg1());
             let attr = read_val_mem(t, child_addr, None);
@@ -2985,6 +3017,9 @@ fn handle_opened_file(t: &RecordTask, fd: i32, flags: i32) -> OsString {
     } else if is_proc_mem_file(&pathname) {
         log!(LogInfo, "Installing ProcMemMonitor for {}", fd);
         file_monitor = Some(Box::new(ProcMemMonitor::new(t, &pathname)));
+    } else if is_proc_comm_file(&pathname) {
+        log!(LogInfo, "Installing ProcCommMonitor for {}", fd);
+        file_monitor = Some(Box::new(ProcCommMonitor::new(t, &pathname)));
     } else if is_proc_fd_dir(&pathname) {
         log!(LogInfo, "Installing ProcFdDirMonitor for {}", fd);
         file_monitor = Some(Box::new(ProcFdDirMonitor::new(t, &pathname)));
@@ -3368,6 +3403,10 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
         return;
     }
 
+    // The initial exec is always recorded, otherwise there'd be nothing to
+    // replay.
+    let is_initial_exec = !t.session().done_initial_exec();
+
     t.post_exec_syscall();
     t.ev_mut().syscall_event_mut().exec_fds_to_close = t.fd_table().fds_to_close_after_exec(t);
 
@@ -3460,6 +3499,27 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
     t.trace_writer_mut()
         .write_task_event(syscall_state.exec_saved_event.as_ref().unwrap());
 
+    if !is_initial_exec
+        && !t.session().as_record().unwrap().records_exec(
+            syscall_state
+                .exec_saved_event
+                .as_ref()
+                .unwrap()
+                .exec_variant()
+                .cmd_line(),
+        )
+    {
+        if t.emulated_ptracer().is_some() {
+            log!(
+                LogWarn,
+                "Not detaching from {} since a tracee is ptracing it",
+                t.tid()
+            );
+        } else {
+            t.detach_after_exec.set(true);
+        }
+    }
+
     {
         let mut remote =
             AutoRemoteSyscalls::new_with_mem_params(t, MemParamsEnabled::DisableMemoryParams);
@@ -3471,8 +3531,7 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
             // Unmapping it now makes recording look more like replay.
             // Also note that under 4.0.7-300.fc22.x86_64 (at least) /proc/<pid>/mem
             // can't read the contents of [vvar].
-            let munmap_no: i32 = syscall_number_for_munmap(remote.arch());
-            rd_infallible_syscall!(remote, munmap_no, vvar.start().as_usize(), vvar.len());
+            remote.infallible_munmap_syscall(vvar.start(), vvar.len());
             remote
                 .task()
                 .vm()
@@ -3503,8 +3562,7 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
             // Remove MAP_GROWSDOWN from stacks by remapping the memory and
             // writing the contents back.
             let flags = (km.flags() & !MapFlags::MAP_GROWSDOWN) | MapFlags::MAP_ANONYMOUS;
-            let munmap_no: i32 = syscall_number_for_munmap(remote.arch());
-            rd_infallible_syscall!(remote, munmap_no, km.start().as_usize(), km.len());
+            remote.infallible_munmap_syscall(km.start(), km.len());
             if remote
                 .task()
                 .vm()
@@ -3514,12 +3572,7 @@ fn process_execve(t: &RecordTask, syscall_state: &mut TaskSyscallState) {
                 // Unmap an extra page at the start; this seems to be necessary
                 // to properly wipe out the growsdown mapping. Doing it as a separate
                 // munmap call also seems to be necessary.
-                rd_infallible_syscall!(
-                    remote,
-                    munmap_no,
-                    km.start().as_usize() - page_size(),
-                    page_size()
-                );
+                remote.infallible_munmap_syscall(km.start() - page_size(), page_size());
             }
             remote.infallible_mmap_syscall(Some(km.start()), km.len(), km.prot(), flags, -1, 0);
             write_mem(remote.task(), km.start(), &buf, None);
@@ -4588,14 +4641,19 @@ fn extra_expected_errno_info<Arch: Architecture>(
                 let nr = unsafe { ioctl_nr(request) };
                 let dir = unsafe { ioctl_dir(request) };
                 let size = unsafe { ioctl_size(request) };
+                let fd = t.regs_ref().arg1() as i32;
                 return format!(
-                    "; Unknown ioctl({:x}): type:{:x} nr:{:x} dir:{:x} size:{:x} addr:{:x}",
+                    "; Unknown ioctl({:x}): type:{:x} nr:{:x} dir:{:x} size:{:x} addr:{:x} \
+                     on fd {} ({:?}). rd doesn't know which memory this ioctl writes, so it \
+                     can't be recorded; support for it has to be added to prepare_ioctl()",
                     request,
                     type_,
                     nr,
                     dir,
                     size,
-                    t.regs_ref().arg3()
+                    t.regs_ref().arg3(),
+                    fd,
+                    t.file_name_of_fd(fd)
                 );
             }
             if sys == Arch::FCNTL || sys == Arch::FCNTL64 {
@@ -6,6 +6,7 @@ use crate::{
     log::LogLevel::{LogError, LogInfo, LogWarn},
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
+    replay_notifier,
 };
 use std::{
     collections::BTreeMap,
@@ -70,6 +71,14 @@ pub enum MismatchBehavior {
     BailOnMismatch = 3,
 }
 
+/// A register whose value differs between two register files.
+#[derive(Clone, Debug)]
+pub struct RegisterMismatch {
+    pub name: &'static str,
+    pub value1: u64,
+    pub value2: u64,
+}
+
 pub const X86_RESERVED_FLAG: usize = 1 << 1;
 pub const X86_TF_FLAG: usize = 1 << 8;
 pub const X86_IF_FLAG: usize = 1 << 9;
@@ -78,8 +87,9 @@ pub const X86_RF_FLAG: usize = 1 << 16;
 pub const X86_ID_FLAG: usize = 1 << 21;
 
 // Max register size
-// DIFF NOTE: Called MAX_SIZE in rr and within the Registers struct
-pub const MAX_REG_SIZE_BYTES: usize = 16;
+// DIFF NOTE: Called MAX_SIZE in rr and within the Registers struct. It's 32
+// here rather than 16 because of the upper halves of the AVX-512 zmm registers.
+pub const MAX_REG_SIZE_BYTES: usize = 32;
 
 #[derive(Clone)]
 pub enum Registers {
@@ -155,14 +165,8 @@ impl Registers {
         }
     }
 
-    fn compare_registers_arch(
-        name1: &str,
-        name2: &str,
-        regs1: &Registers,
-        regs2: &Registers,
-        mismatch_behavior: MismatchBehavior,
-    ) -> bool {
-        let mut match_ = true;
+    fn register_mismatches_arch(regs1: &Registers, regs2: &Registers) -> Vec<RegisterMismatch> {
+        let mut mismatches = Vec::new();
         debug_assert_eq!(regs1.arch(), regs2.arch());
         let regs_info = regs1.get_regs_info();
 
@@ -175,15 +179,11 @@ impl Registers {
                 // they reflect original syscall numbers, in which case both will be positive.
                 if regs1_x86.orig_eax >= 0 && regs2_x86.orig_eax > 0 {
                     if regs1_x86.orig_eax != regs2_x86.orig_eax {
-                        maybe_log_reg_mismatch(
-                            mismatch_behavior,
-                            "orig_eax",
-                            name1,
-                            regs1_x86.orig_eax as u64,
-                            name2,
-                            regs2_x86.orig_eax as u64,
-                        );
-                        match_ = false;
+                        mismatches.push(RegisterMismatch {
+                            name: "orig_eax",
+                            value1: regs1_x86.orig_eax as u64,
+                            value2: regs2_x86.orig_eax as u64,
+                        });
                     }
                 }
             }
@@ -192,15 +192,11 @@ impl Registers {
                 // See comment in the x86 case
                 if (regs1_x64.orig_rax as i64) >= 0 && (regs2_x64.orig_rax as i64) > 0 {
                     if regs1_x64.orig_rax != regs2_x64.orig_rax {
-                        maybe_log_reg_mismatch(
-                            mismatch_behavior,
-                            "orig_rax",
-                            name1,
-                            regs1_x64.orig_rax,
-                            name2,
-                            regs2_x64.orig_rax,
-                        );
-                        match_ = false;
+                        mismatches.push(RegisterMismatch {
+                            name: "orig_rax",
+                            value1: regs1_x64.orig_rax,
+                            value2: regs2_x64.orig_rax,
+                        });
                     }
                 }
             }
@@ -243,23 +239,22 @@ impl Registers {
             }
 
             if val1 & rv.comparison_mask != val2 & rv.comparison_mask {
-                maybe_log_reg_mismatch(mismatch_behavior, rv.name, name1, val1, name2, val2);
-                match_ = false;
+                mismatches.push(RegisterMismatch {
+                    name: rv.name,
+                    value1: val1,
+                    value2: val2,
+                });
             }
         }
 
-        match_
+        mismatches
     }
 
-    fn compare_register_files_internal(
-        name1: &str,
-        regs1: &Registers,
-        name2: &str,
-        regs2: &Registers,
-        mismatch_behavior: MismatchBehavior,
-    ) -> bool {
-        debug_assert_eq!(regs1.arch(), regs2.arch());
-        Registers::compare_registers_arch(name1, name2, regs1, regs2, mismatch_behavior)
+    /// Every register whose value differs between `self` and `other`, with
+    /// `self`'s value as `value1`. Empty if the register files match.
+    pub fn compare_with_mismatch_report(&self, other: &Registers) -> Vec<RegisterMismatch> {
+        debug_assert_eq!(self.arch(), other.arch());
+        Registers::register_mismatches_arch(self, other)
     }
 
     /// Return true if `regs1` matches `regs2`.  Passing EXPECT_MISMATCHES
@@ -277,21 +272,29 @@ impl Registers {
         mismatch_behavior: MismatchBehavior,
     ) -> bool {
         let bail_error = mismatch_behavior >= MismatchBehavior::BailOnMismatch;
-        let match_ = Registers::compare_register_files_internal(
-            name1,
-            regs1,
-            name2,
-            regs2,
-            mismatch_behavior,
-        );
+        let mismatches = regs1.compare_with_mismatch_report(regs2);
+        for m in &mismatches {
+            maybe_log_reg_mismatch(mismatch_behavior, m.name, name1, m.value1, name2, m.value2);
+        }
+        let match_ = mismatches.is_empty();
         if let Some(t) = maybe_t {
-            ed_assert!(
-                t,
-                !bail_error || match_,
-                "Fatal register mismatch (ticks/rec:{}/{})",
-                t.tick_count(),
-                t.current_trace_frame().ticks(),
-            );
+            if bail_error && !match_ {
+                let report = t.session().as_replay().unwrap().divergence_report(
+                    t,
+                    name1,
+                    name2,
+                    &mismatches,
+                );
+                replay_notifier::publish_divergence(t, true, &report);
+                ed_assert!(
+                    t,
+                    false,
+                    "Fatal register mismatch (ticks/rec:{}/{})\n{}",
+                    t.tick_count(),
+                    t.current_trace_frame().ticks(),
+                    report
+                );
+            }
         } else {
             debug_assert!(!bail_error || match_);
         }
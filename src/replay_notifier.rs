@@ -0,0 +1,188 @@
+//! An optional unix socket on which a replay publishes what it is doing as
+//! JSON, one notification per line. IDE plugins and other front-ends can
+//! follow the session this way without parsing gdb's console output.
+//!
+//! Clients can connect at any time and get every notification published
+//! after that. Nothing is ever read from them. A client that can't keep up
+//! or has gone away is dropped, so it can never hold up the replay.
+
+use crate::{
+    log::LogDebug,
+    session::task::{replay_task::ReplayTask, Task},
+    ticks::Ticks,
+    trace::trace_frame::FrameTime,
+};
+use libc::pid_t;
+use nix::{
+    errno::Errno,
+    sys::socket::{send, MsgFlags},
+};
+use serde::Serialize;
+use std::{
+    fs, io,
+    os::unix::{
+        fs::FileTypeExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Progress is published at most this often.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Notification {
+    /// The debugger was told that `tid` stopped.
+    #[serde(rename_all = "camelCase")]
+    Stop {
+        event: FrameTime,
+        tid: pid_t,
+        ticks: Ticks,
+        /// The signal reported with the stop, if any.
+        signal: Option<String>,
+    },
+    /// A checkpoint was created with the 'checkpoint' command.
+    #[serde(rename_all = "camelCase")]
+    CheckpointCreated {
+        id: u64,
+        name: Option<String>,
+        event: FrameTime,
+        /// The location gdb gave for the checkpoint.
+        location: String,
+    },
+    /// The replay no longer matches the recording. A fatal divergence is
+    /// published just before rd aborts.
+    #[serde(rename_all = "camelCase")]
+    Divergence {
+        event: FrameTime,
+        tid: pid_t,
+        fatal: bool,
+        message: String,
+    },
+    /// How far the replay has got. Rate limited to one per
+    /// `PROGRESS_INTERVAL`.
+    #[serde(rename_all = "camelCase")]
+    Progress {
+        event: FrameTime,
+        recording_time: f64,
+    },
+}
+
+struct Notifier {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+    last_progress: Option<Instant>,
+}
+
+impl Notifier {
+    fn accept_clients(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    log!(LogDebug, "Notification client connected");
+                    self.clients.push(stream);
+                }
+                // Usually WouldBlock: no more pending connections. Anything
+                // else is retried on the next publish.
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref NOTIFIER: Mutex<Option<Notifier>> = Mutex::new(None);
+}
+
+/// Start publishing notifications on a unix socket bound to `path`. A socket
+/// left behind by an earlier replay is replaced.
+pub fn listen(path: &Path) -> io::Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    *NOTIFIER.lock().unwrap() = Some(Notifier {
+        path: path.to_owned(),
+        listener,
+        clients: Vec::new(),
+        last_progress: None,
+    });
+    Ok(())
+}
+
+/// Disconnect all clients and remove the socket.
+pub fn close() {
+    if let Some(notifier) = NOTIFIER.lock().unwrap().take() {
+        fs::remove_file(&notifier.path).unwrap_or(());
+    }
+}
+
+/// Send `notification` to every connected client. Does nothing unless
+/// `listen()` was called.
+pub fn publish(notification: &Notification) {
+    let mut maybe_notifier = NOTIFIER.lock().unwrap();
+    let notifier = match maybe_notifier.as_mut() {
+        Some(notifier) => notifier,
+        None => return,
+    };
+    if let Notification::Progress { .. } = notification {
+        let now = Instant::now();
+        if let Some(last) = notifier.last_progress {
+            if now.duration_since(last) < PROGRESS_INTERVAL {
+                return;
+            }
+        }
+        notifier.last_progress = Some(now);
+    }
+
+    notifier.accept_clients();
+    if notifier.clients.is_empty() {
+        return;
+    }
+    let mut line = serde_json::to_vec(notification).unwrap();
+    line.push(b'\n');
+    notifier
+        .clients
+        .retain(|client| match send_all(client, &line) {
+            Ok(()) => true,
+            Err(e) => {
+                log!(LogDebug, "Dropping notification client: {}", e);
+                false
+            }
+        });
+}
+
+/// Publish a divergence of `t` from the recording at its current trace frame.
+pub fn publish_divergence(t: &ReplayTask, fatal: bool, message: &str) {
+    publish(&Notification::Divergence {
+        event: t.current_trace_frame().time(),
+        tid: t.rec_tid(),
+        fatal,
+        message: message.to_owned(),
+    });
+}
+
+/// Never blocks and never raises SIGPIPE. A client whose socket buffer is
+/// full would get a torn line, so that is an error too.
+fn send_all(client: &UnixStream, mut buf: &[u8]) -> Result<(), Errno> {
+    while !buf.is_empty() {
+        match send(
+            client.as_raw_fd(),
+            buf,
+            MsgFlags::MSG_NOSIGNAL | MsgFlags::MSG_DONTWAIT,
+        ) {
+            Ok(nwritten) => buf = &buf[nwritten..],
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
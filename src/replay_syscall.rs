@@ -12,7 +12,7 @@ use crate::{
     },
     bindings::{
         kernel::{user_desc, SHMAT, SHMDT},
-        perf_event::perf_event_attr,
+        perf_event::{perf_event_attr, PERF_FLAG_FD_CLOEXEC},
         ptrace::{
             PTRACE_CONT, PTRACE_DETACH, PTRACE_POKEDATA, PTRACE_POKETEXT, PTRACE_SET_THREAD_AREA,
             PTRACE_SINGLESTEP, PTRACE_SYSCALL, PTRACE_SYSEMU, PTRACE_SYSEMU_SINGLESTEP,
@@ -22,15 +22,15 @@ use crate::{
     event::SyscallEventData,
     file_monitor::{
         base_file_monitor::BaseFileMonitor, mmapped_file_monitor::MmappedFileMonitor,
-        proc_fd_dir_monitor::ProcFdDirMonitor, proc_mem_monitor::ProcMemMonitor,
-        stdio_monitor::StdioMonitor, virtual_perf_counter_monitor::VirtualPerfCounterMonitor,
-        FileMonitor, FileMonitorType,
+        proc_comm_monitor::ProcCommMonitor, proc_fd_dir_monitor::ProcFdDirMonitor,
+        proc_mem_monitor::ProcMemMonitor, stdio_monitor::StdioMonitor,
+        virtual_perf_counter_monitor::VirtualPerfCounterMonitor, FileMonitor, FileMonitorType,
     },
     kernel_abi::{
         is_rdcall_notify_syscall_hook_exit_syscall, is_restart_syscall_syscall, is_write_syscall,
-        syscall_number_for_close, syscall_number_for_execve, syscall_number_for_munmap,
-        syscall_number_for_open, syscall_number_for_prctl, CloneTLSType, MmapCallingSemantics,
-        SupportedArch, RD_NATIVE_ARCH,
+        syscall_number_for_close, syscall_number_for_execve, syscall_number_for_open,
+        syscall_number_for_prctl, CloneTLSType, MmapCallingSemantics, SupportedArch,
+        RD_NATIVE_ARCH,
     },
     kernel_metadata::{is_sigreturn, shm_flags_to_mmap_prot, syscall_name},
     kernel_supplement::{ARCH_GET_CPUID, ARCH_SET_CPUID},
@@ -66,8 +66,8 @@ use crate::{
     },
     util::{
         ceil_page_size, ceil_page_u64, clone_flags_to_task_flags, extract_clone_parameters,
-        floor_page_size, is_proc_fd_dir, is_proc_mem_file, page_size, resource_path,
-        CloneParameters,
+        floor_page_size, is_proc_comm_file, is_proc_fd_dir, is_proc_mem_file, page_size,
+        resource_path, CloneParameters,
     },
     wait_status::WaitStatus,
 };
@@ -749,7 +749,7 @@ fn rep_process_syscall_arch<Arch: Architecture>(
         let flags = trace_regs.arg5() as u32;
         let fd = trace_regs.syscall_result_signed() as i32;
         if let Some(target) = maybe_target {
-            if cpu == -1 && flags == 0 {
+            if cpu == -1 && flags & !(PERF_FLAG_FD_CLOEXEC as u32) == 0 {
                 let attr = read_val_mem(
                     t,
                     RemotePtr::<perf_event_attr>::from(trace_regs.arg1()),
@@ -912,14 +912,8 @@ fn process_brk(t: &ReplayTask) {
             None,
         );
     } else if km.len() > 0 {
-        let arch = t.arch();
         let mut remote = AutoRemoteSyscalls::new(t);
-        rd_infallible_syscall!(
-            remote,
-            syscall_number_for_munmap(arch),
-            km.start().as_usize(),
-            km.len()
-        );
+        remote.infallible_munmap_syscall(km.start(), km.len());
         remote
             .task()
             .vm()
@@ -1218,8 +1212,6 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
 
     // Unmap almost all mappings and restore the stack mapping
     {
-        let arch = t.arch();
-
         // Now fix up the address space. First unmap all the mappings other than
         // our rd page and preload thread locals
         let mut unmaps: Vec<MemoryRangeKey> = Vec::new();
@@ -1238,12 +1230,7 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
         let mut remote =
             AutoRemoteSyscalls::new_with_mem_params(t, MemParamsEnabled::DisableMemoryParams);
         for m in unmaps {
-            rd_infallible_syscall!(
-                remote,
-                syscall_number_for_munmap(arch),
-                m.start().as_usize(),
-                m.len()
-            );
+            remote.infallible_munmap_syscall(m.start(), m.len());
             remote.vm().unmap(remote.task(), m.start(), m.len());
         }
         // We will have unmapped the stack memory that `remote` _could_ have used for
@@ -1297,6 +1284,7 @@ pub fn process_execve(t: &ReplayTask, step: &mut ReplayTraceStep) {
 
     // Now it's safe to save the auxv data
     t.vm().save_auxv(t);
+    t.vm().save_initial_sp(t);
 
     // Notify outer rd if there is one
     unsafe { syscall(SYS_rdcall_reload_auxv as _, t.tid()) };
@@ -1478,6 +1466,8 @@ fn handle_opened_files(t: &ReplayTask, flags_raw: i32) {
             file_monitor = Box::new(StdioMonitor::new(STDERR_FILENO));
         } else if is_proc_mem_file(&o.path) {
             file_monitor = Box::new(ProcMemMonitor::new(t, &o.path));
+        } else if is_proc_comm_file(&o.path) {
+            file_monitor = Box::new(ProcCommMonitor::new(t, &o.path));
         } else if is_proc_fd_dir(&o.path) {
             file_monitor = Box::new(ProcFdDirMonitor::new(t, &o.path));
         } else if flags.contains(OFlag::O_DIRECT) {
@@ -2147,14 +2137,8 @@ fn process_shmdt(
 
     {
         let size: usize = t.vm().get_shm_size(addr);
-        let arch = t.arch();
         let mut remote = AutoRemoteSyscalls::new(t);
-        rd_infallible_syscall!(
-            remote,
-            syscall_number_for_munmap(arch),
-            addr.as_usize(),
-            size
-        );
+        remote.infallible_munmap_syscall(addr, size);
         remote
             .initial_regs_mut()
             .set_syscall_result(trace_regs.syscall_result());
@@ -20,6 +20,7 @@ use crate::{
     ticks::Ticks,
     trace::trace_frame::FrameTime,
 };
+use libc::pid_t;
 use nix::sys::mman::ProtFlags;
 use std::{
     cell::{Ref, RefCell},
@@ -156,6 +157,10 @@ pub struct ReplayTimeline {
     /// A single checkpoint that's very close to the current point, used to
     /// accelerate a sequence of reverse singlestep operations.
     reverse_exec_short_checkpoint: Option<Mark>,
+
+    /// Stop replay_step_forward() at time-slice signals. Seeking and reverse
+    /// execution never stop at them.
+    report_time_slice_signals: bool,
 }
 
 impl Drop for ReplayTimeline {
@@ -592,6 +597,10 @@ impl ReplayTimeline {
         self.reverse_execution_barrier_event = event;
     }
 
+    pub fn set_report_time_slice_signals(&mut self, report: bool) {
+        self.report_time_slice_signals = report;
+    }
+
     /// State-changing APIs. These may alter state associated with
     /// current_session().
     /// Reset the current session to the last available session before event
@@ -655,7 +664,8 @@ impl ReplayTimeline {
     /// and executing forwards if necessary.
     pub fn seek_to_mark(&mut self, mark: &Mark) {
         self.seek_up_to_mark(mark);
-        // @TODO Check this. Make sure logic is correct.
+        // seek_up_to_mark() leaves us at or before `mark`, so stepping forward
+        // must eventually land on it.
         while self
             .current_mark()
             .as_ref()
@@ -669,6 +679,51 @@ impl ReplayTimeline {
         // XXX handle cases where breakpoints can't yet be applied
     }
 
+    /// Run forward until the task with recorded tid `rec_tid` has retired
+    /// exactly `ticks` ticks. Whole events are replayed until we reach the event
+    /// during which the task gets to `ticks`. Then the PMU is programmed to stop
+    /// us just short of the target and we singlestep the rest of the way.
+    ///
+    /// Returns false if the task can't be stopped at `ticks`, e.g. because it
+    /// is already past that point or the trace ends first.
+    pub fn seek_to_ticks(&mut self, rec_tid: pid_t, ticks: Ticks) -> bool {
+        self.unapply_breakpoints_and_watchpoints();
+        // A ticks_target of 0 means no target, so singlestep right away when
+        // the target is that close.
+        let mut approaching_ticks_target = ticks <= 1;
+        loop {
+            let t = match self.current_session().current_task() {
+                Some(t) => t,
+                None => return false,
+            };
+            if t.rec_tid() == rec_tid && t.tick_count() >= ticks {
+                return t.tick_count() == ticks;
+            }
+            let in_target_event = {
+                let frame = self.current_session().current_trace_frame();
+                frame.tid() == rec_tid && frame.ticks() >= ticks
+            };
+            let result = if t.rec_tid() == rec_tid && in_target_event {
+                if approaching_ticks_target {
+                    self.current_session().replay_step(RunCommand::Singlestep)
+                } else {
+                    let mut constraints = StepConstraints::new(RunCommand::Continue);
+                    constraints.ticks_target = ticks - 1;
+                    let result = self
+                        .current_session()
+                        .replay_step_with_constraints(&constraints);
+                    approaching_ticks_target = result.break_status.approaching_ticks_target;
+                    result
+                }
+            } else {
+                self.current_session().replay_step(RunCommand::Continue)
+            };
+            if result.status == ReplayStatus::ReplayExited {
+                return false;
+            }
+        }
+    }
+
     /// Replay 'current'.
     /// If there is a breakpoint at the current task's current ip(), then
     /// when running forward we will immediately break at the breakpoint. When
@@ -694,6 +749,7 @@ impl ReplayTimeline {
         self.current_session().set_visible_execution(true);
         let mut constraints = StepConstraints::new(command);
         constraints.stop_at_time = stop_at_time;
+        constraints.report_time_slice_signals = self.report_time_slice_signals;
         result = self
             .current_session()
             .replay_step_with_constraints(&constraints);
@@ -2008,7 +2064,11 @@ impl ReplayTimeline {
         *m1 < *m2
     }
 
-    fn estimate_progress(&self) -> Progress {
+    /// Roughly how many microseconds it took to replay the current session up to
+    /// where it is now, computed from its ticks, syscalls and bytes written.
+    /// Reverse-exec checkpoints are spaced by this rather than by event count.
+    /// DIFF NOTE: Called estimate_progress() and private in rr.
+    pub fn estimated_progress(&self) -> Progress {
         let stats = self.current_session().statistics();
         // The following parameters were estimated by running Firefox startup
         // and shutdown in an opt build on a Lenovo W530 laptop, replaying with
@@ -2035,7 +2095,7 @@ impl ReplayTimeline {
     fn maybe_add_reverse_exec_checkpoint(&mut self, strategy: CheckpointStrategy) {
         self.discard_future_reverse_exec_checkpoints();
 
-        let now: Progress = self.estimate_progress();
+        let now: Progress = self.estimated_progress();
         let it = self
             .reverse_exec_checkpoints
             .iter()
@@ -2082,7 +2142,7 @@ impl ReplayTimeline {
     /// Discard some reverse-exec checkpoints in the past, if necessary. We do
     /// this to stop the number of checkpoints growing out of control.
     fn discard_past_reverse_exec_checkpoints(&mut self, strategy: CheckpointStrategy) {
-        let now: Progress = self.estimate_progress();
+        let now: Progress = self.estimated_progress();
         // No checkpoints are allowed in the first interval, since we're about to
         // add one there.
         let mut checkpoints_to_delete: Vec<Mark> = Vec::new();
@@ -2130,7 +2190,7 @@ impl ReplayTimeline {
     /// Discard all reverse-exec checkpoints that are in the future (they're
     /// useless).
     fn discard_future_reverse_exec_checkpoints(&mut self) {
-        let now: Progress = self.estimate_progress();
+        let now: Progress = self.estimated_progress();
         loop {
             let res = self
                 .reverse_exec_checkpoints
@@ -2245,6 +2305,10 @@ impl Display for Mark {
 impl Eq for Mark {}
 
 impl Ord for Mark {
+    /// Marks with different MarkKeys are ordered by their keys. Marks sharing a
+    /// key are ordered by their position in the timeline's mark vector for that
+    /// key, which mark() keeps in execution order.
+    ///
     /// See ReplayTimeline::less_than() in rr
     /// @TODO Check this again
     fn cmp(&self, m2: &Self) -> Ordering {
@@ -2304,11 +2368,33 @@ impl Mark {
         self.ptr.borrow().proto.key.trace_time
     }
 
+    pub fn ticks(&self) -> Ticks {
+        self.ptr.borrow().proto.key.ticks
+    }
+
     fn from_internal_mark(ptr: InternalMarkSharedPtr) -> Mark {
         Mark { ptr }
     }
 }
 
+#[cfg(test)]
+impl Mark {
+    /// A mark at `trace_time` that belongs to no timeline. Only good for
+    /// testing code that keeps marks around and compares them.
+    pub fn new_detached(trace_time: FrameTime) -> Mark {
+        let key = MarkKey::new(trace_time, 0, Default::default());
+        Mark::from_internal_mark(Rc::new(RefCell::new(InternalMark {
+            owner: Weak::new(),
+            proto: ProtoMark::new_from_key(key),
+            extra_regs: Default::default(),
+            checkpoint: None,
+            checkpoint_refcount: 0,
+            ticks_at_event_start: 0,
+            singlestep_to_next_mark_no_signal: false,
+        })))
+    }
+}
+
 /// Everything we know about the tracee state for a particular Mark.
 /// This data alone does not allow us to determine the time ordering
 /// of two Marks.
@@ -0,0 +1,152 @@
+//! Aborts a replay that has stopped making progress, instead of letting it
+//! spin (or hang) forever. Mostly useful for unattended replays, e.g. in CI.
+//!
+//! The replay reports its position as it goes. A thread checks that the
+//! position keeps changing while the replay is busy with something. Waiting
+//! for the debugger doesn't count as being busy.
+//!
+//! The report is put together by the replaying thread, since the session
+//! can't be looked at from the watchdog thread. It is refreshed at most every
+//! `REPORT_INTERVAL`, so it can be a little out of date when the replay hangs
+//! rather than spins.
+
+use crate::{
+    log::LogDebug,
+    session::{replay_session::ReplaySession, task::Task, Session},
+    ticks::Ticks,
+    trace::trace_frame::FrameTime,
+};
+use libc::pid_t;
+use std::{
+    fmt::Write,
+    process::exit,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the report of what the replay is doing is refreshed.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exit status when the watchdog aborts the replay.
+const WATCHDOG_EXIT_STATUS: i32 = 124;
+
+struct Watchdog {
+    timeout: Duration,
+    /// What the replay is busy with. `None` while it's idle.
+    activity: Option<String>,
+    /// Event, tid and ticks of the current task when we last looked.
+    position: Option<(FrameTime, pid_t, Ticks)>,
+    last_progress: Instant,
+    report: String,
+    last_report: Option<Instant>,
+}
+
+lazy_static! {
+    static ref WATCHDOG: Mutex<Option<Watchdog>> = Mutex::new(None);
+}
+
+/// Abort the replay if it doesn't make progress for `timeout` while it's
+/// busy.
+pub fn start(timeout: Duration) {
+    *WATCHDOG.lock().unwrap() = Some(Watchdog {
+        timeout,
+        activity: None,
+        position: None,
+        last_progress: Instant::now(),
+        report: String::new(),
+        last_report: None,
+    });
+    thread::Builder::new()
+        .name("replay-watchdog".into())
+        .spawn(move || watch(timeout))
+        .unwrap();
+}
+
+/// Say what the replay is busy with, for the report, or `None` while it's
+/// waiting for something that may legitimately take forever (the debugger).
+/// The time without progress starts counting from here.
+pub fn set_activity(activity: Option<String>) {
+    if let Some(w) = WATCHDOG.lock().unwrap().as_mut() {
+        w.activity = activity;
+        w.last_progress = Instant::now();
+    }
+}
+
+/// Called by `session` whenever it's about to replay a step.
+pub fn note_position(session: &ReplaySession) {
+    let mut maybe_watchdog = WATCHDOG.lock().unwrap();
+    let w = match maybe_watchdog.as_mut() {
+        Some(w) => w,
+        None => return,
+    };
+    let position = session
+        .current_task()
+        .map(|t| (session.current_frame_time(), t.rec_tid(), t.tick_count()));
+    if position != w.position {
+        w.position = position;
+        w.last_progress = Instant::now();
+    }
+    let now = Instant::now();
+    if w.last_report
+        .map_or(true, |last| now - last >= REPORT_INTERVAL)
+    {
+        w.report = report(session);
+        w.last_report = Some(now);
+    }
+}
+
+fn report(session: &ReplaySession) -> String {
+    let mut out = String::new();
+    let frame = session.current_trace_frame();
+    writeln!(
+        out,
+        "  current event: {} (tid {}, ticks {}) {}",
+        frame.time(),
+        frame.tid(),
+        frame.ticks(),
+        frame.event()
+    )
+    .unwrap();
+    for t in session.tasks().values() {
+        writeln!(
+            out,
+            "  task {} (rec {}): status {}, ip {}, ticks {}",
+            t.tid(),
+            t.rec_tid(),
+            t.status(),
+            t.ip(),
+            t.tick_count()
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn watch(timeout: Duration) {
+    let period = timeout.min(Duration::from_secs(1));
+    loop {
+        thread::sleep(period);
+        let maybe_watchdog = WATCHDOG.lock().unwrap();
+        let w = match maybe_watchdog.as_ref() {
+            Some(w) => w,
+            None => return,
+        };
+        let activity = match w.activity.as_ref() {
+            Some(activity) => activity,
+            None => continue,
+        };
+        let stalled = w.last_progress.elapsed();
+        if stalled < w.timeout {
+            continue;
+        }
+        log!(LogDebug, "Watchdog fired after {:?}", stalled);
+        eprintln!(
+            "\nrd: The replay made no progress for {} seconds while {}. Aborting.\n{}",
+            stalled.as_secs(),
+            activity,
+            w.report
+        );
+        exit(WATCHDOG_EXIT_STATUS);
+    }
+}
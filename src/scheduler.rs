@@ -64,9 +64,9 @@ use nix::{
     unistd::Pid,
 };
 use owning_ref::OwningHandle;
-use rand::{random, seq::SliceRandom, thread_rng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use std::{
-    cell::{Cell, RefCell},
+    cell::{Cell, RefCell, RefMut},
     cmp::min,
     collections::{BTreeSet, VecDeque},
     mem, ptr,
@@ -173,6 +173,9 @@ pub struct Scheduler {
     /// When true, make random scheduling decisions to try to increase the
     /// probability of finding buggy schedules.
     enable_chaos: Cell<bool>,
+    /// Source of all our random decisions. Chaos mode seeds it explicitly so
+    /// the same decisions can be made again.
+    rng: RefCell<StdRng>,
 
     enable_poll: Cell<bool>,
     last_reschedule_in_high_priority_only_interval: Cell<bool>,
@@ -235,6 +238,7 @@ impl Scheduler {
             pretend_num_cores_: Cell::new(1),
             always_switch: Cell::new(always_switch),
             enable_chaos: Default::default(),
+            rng: RefCell::new(StdRng::from_entropy()),
             enable_poll: Default::default(),
             last_reschedule_in_high_priority_only_interval: Default::default(),
             in_exec_tgid: Default::default(),
@@ -273,6 +277,25 @@ impl Scheduler {
 
     pub fn set_enable_chaos(&self, enable_chaos: bool) {
         self.enable_chaos.set(enable_chaos);
+        // When chaos mode is enabled, pretend to have 1-8 cores at random,
+        // otherwise return 1 to maximize throughput (since effectively we really
+        // only have one core).
+        let num_cores = if enable_chaos {
+            self.rng.borrow_mut().gen_range(1..=8)
+        } else {
+            1
+        };
+        self.pretend_num_cores_.set(num_cores);
+    }
+
+    pub fn set_random_seed(&self, seed: u64) {
+        *self.rng.borrow_mut() = StdRng::seed_from_u64(seed);
+    }
+
+    /// Random choices made elsewhere during recording should come from here so
+    /// that a chaos seed reproduces them too.
+    pub fn rng(&self) -> RefMut<StdRng> {
+        self.rng.borrow_mut()
     }
 
     pub fn set_num_cores(&self, num_cores: u32) {
@@ -447,6 +470,11 @@ impl Scheduler {
                     "  all tasks blocked or some unstable, waiting for runnable ({} total)",
                     self.task_priority_set.borrow().len()
                 );
+                // We may wait for a long time, and the recording may get
+                // killed meanwhile. Rate limited like the flush after every
+                // frame, so tracees that block all the time don't leave lots
+                // of small blocks behind.
+                self.record_session().trace_writer_mut().maybe_flush();
 
                 let mut status: WaitStatus;
                 loop {
@@ -818,10 +846,9 @@ impl Scheduler {
                         }
                     }
                 } else {
-                    let mut rg = thread_rng();
                     let mut same_priority_shuffled =
                         same_priority_range.cloned().collect::<Vec<PriorityTup>>();
-                    same_priority_shuffled.shuffle(&mut rg);
+                    same_priority_shuffled.shuffle(&mut *self.rng.borrow_mut());
 
                     for PriorityTup(_, _, task_weak) in same_priority_shuffled {
                         if self.is_task_runnable(
@@ -880,7 +907,7 @@ impl Scheduler {
             // want the average timeslice to be too small. So make 10% of timeslices
             // very short, 10% short-ish, and the rest uniformly distributed between 0
             // and `max_ticks_`.
-            let timeslice_kind_frac = random_frac();
+            let timeslice_kind_frac = self.random_frac();
             if timeslice_kind_frac < VERY_SHORT_TIMESLICE_PROBABILITY {
                 max_timeslice_duration = VERY_SHORT_TIMESLICE_MAX_DURATION;
             } else if timeslice_kind_frac
@@ -894,7 +921,9 @@ impl Scheduler {
 
         let tick_count = self.current().unwrap().tick_count();
         self.current_timeslice_end_.set(
-            tick_count + (random::<Ticks>() % min(self.max_ticks_.get(), max_timeslice_duration)),
+            tick_count
+                + (self.rng.borrow_mut().gen::<Ticks>()
+                    % min(self.max_ticks_.get(), max_timeslice_duration)),
         );
     }
 
@@ -905,7 +934,7 @@ impl Scheduler {
 
         // Reset task priorities again at some point in the future.
         self.priorities_refresh_time
-            .set(now + random_frac() * PRIORITIES_REFRESH_MAX_INTERVAL as f64);
+            .set(now + self.random_frac() * PRIORITIES_REFRESH_MAX_INTERVAL as f64);
         let mut tasks = Vec::new();
         for p in self.task_priority_set.borrow().iter() {
             tasks.push(p.2.clone());
@@ -929,7 +958,7 @@ impl Scheduler {
             LOW_PRIORITY_PROBABILITY
         };
 
-        if random_frac() < prob {
+        if self.random_frac() < prob {
             1
         } else {
             0
@@ -970,7 +999,8 @@ impl Scheduler {
         if !self.enable_chaos.get() || self.high_priority_only_intervals_refresh_time.get() > now {
             return;
         }
-        let duration_step = random::<u16>() as i32 % HIGH_PRIORITY_ONLY_DURATION_STEPS;
+        let duration_step =
+            self.rng.borrow_mut().gen::<u16>() as i32 % HIGH_PRIORITY_ONLY_DURATION_STEPS;
         self.high_priority_only_intervals_duration.set(
             MIN_HIGH_PRIORITY_ONLY_DURATION
                 * HIGH_PRIORITY_ONLY_DURATION_STEP_FACTOR.powi(duration_step),
@@ -978,7 +1008,7 @@ impl Scheduler {
         self.high_priority_only_intervals_period
             .set(self.high_priority_only_intervals_duration.get() / HIGH_PRIORITY_ONLY_FRACTION);
         self.high_priority_only_intervals_start.set(
-            now + random_frac()
+            now + self.random_frac()
                 * (self.high_priority_only_intervals_period.get()
                     - self.high_priority_only_intervals_duration.get()),
         );
@@ -1207,8 +1237,7 @@ impl Scheduler {
                     other_cpus.push(i);
                 }
             }
-            let mut rg = thread_rng();
-            other_cpus.shuffle(&mut rg);
+            other_cpus.shuffle(&mut *self.rng.borrow_mut());
             for i in 0..self.pretend_num_cores_.get() as usize - 1 {
                 // DIFF NOTE: rr swallows any error. We don't for now.
                 pretend_affinity_mask.set(other_cpus[i] as usize).unwrap();
@@ -1217,6 +1246,10 @@ impl Scheduler {
 
         self.pretend_affinity_mask_.set(pretend_affinity_mask);
     }
+
+    fn random_frac(&self) -> f64 {
+        self.rng.borrow_mut().gen::<u32>() as f64 / u32::MAX as f64
+    }
 }
 
 fn sleep_time(t: f64) {
@@ -1226,10 +1259,6 @@ fn sleep_time(t: f64) {
     unsafe { nanosleep(&ts, ptr::null_mut()) };
 }
 
-fn random_frac() -> f64 {
-    random::<u32>() as f64 / u32::MAX as f64
-}
-
 fn treat_syscall_as_nonblocking(syscallno: i32, arch: SupportedArch) -> bool {
     is_sched_yield_syscall(syscallno, arch)
         || is_exit_syscall(syscallno, arch)
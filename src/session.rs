@@ -1,7 +1,7 @@
 use crate::{
     auto_remote_syscalls::AutoRemoteSyscalls,
     emu_fs::EmuFs,
-    kernel_abi::{syscall_number_for_close, syscall_number_for_munmap, SupportedArch},
+    kernel_abi::{syscall_number_for_close, SupportedArch},
     log::LogDebug,
     preload_interface::syscallbuf_hdr,
     remote_ptr::{RemotePtr, Void},
@@ -437,13 +437,7 @@ fn remap_shared_mmap(
         m.map.start(),
         m.map.end()
     );
-    let arch = remote.arch();
-    rd_infallible_syscall!(
-        remote,
-        syscall_number_for_munmap(arch),
-        m.map.start().as_usize(),
-        m.map.len()
-    );
+    remote.infallible_munmap_syscall(m.map.start(), m.map.len());
 
     let emu_file;
     if let Some(file) = dest_emu_fs.at(&m.recorded_map) {
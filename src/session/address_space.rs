@@ -8,8 +8,9 @@ use crate::{
     emu_fs::EmuFileSharedPtr,
     kernel_abi::{
         is_execve_syscall, syscall_instruction, syscall_number_for_brk, syscall_number_for_close,
-        syscall_number_for_munmap, SupportedArch,
+        syscall_number_for_mprotect, SupportedArch,
     },
+    kernel_metadata::errno_name,
     log::LogLevel::{LogDebug, LogError},
     monitored_shared_memory::MonitoredSharedMemorySharedPtr,
     monkey_patcher::MonkeyPatcher,
@@ -31,7 +32,7 @@ use crate::{
         task::{
             record_task::RecordTask,
             task_common::{read_mem, read_val_mem, write_val_mem, write_val_mem_with_flags},
-            task_inner::WriteFlags,
+            task_inner::{ResumeRequest, TicksRequest, WaitRequest, WriteFlags},
             Task, TaskSharedPtr, WeakTaskPtrSet,
         },
         SessionSharedPtr, SessionSharedWeakPtr,
@@ -609,6 +610,13 @@ pub struct AddressSpace {
     /// behalf of debuggers that assume that model.
     watchpoints: RefCell<HashMap<MemoryRange, Watchpoint>>,
     saved_watchpoints: RefCell<Vec<HashMap<MemoryRange, Watchpoint>>>,
+    /// Pages of write watchpoints that don't fit in the debug registers. During
+    /// replay we write-protect the pages of write watchpoints that don't fit,
+    /// and check the watched values whenever a write to them faults.
+    software_watch_pages: RefCell<BTreeSet<RemotePtr<Void>>>,
+    /// The software-watched pages are writable for now, see
+    /// `suspend_software_watch_pages()`.
+    software_watch_suspended: Cell<bool>,
     /// Tracee memory is read and written through this fd, which is
     /// opened for the tracee's magic /proc/{tid}/mem device.  The
     /// advantage of this over ptrace is that we can access it even
@@ -625,6 +633,9 @@ pub struct AddressSpace {
     syscallbuf_enabled_: Cell<bool>,
 
     saved_auxv_: RefCell<Vec<u8>>,
+    /// Where argc, argv and the initial environment are on the stack. Null
+    /// until the exec has been replayed.
+    saved_initial_sp_: Cell<RemotePtr<Void>>,
 
     /// The time of the first event that ran code for a task in this address space.
     /// 0 if no such event has occurred.
@@ -922,6 +933,7 @@ impl AddressSpace {
             local_addr,
             monitored,
         );
+        self.reapply_software_watch_pages(t, addr, num_bytes);
 
         m
     }
@@ -1156,6 +1168,7 @@ impl AddressSpace {
             // are adjacent to `last_overlap_key`.
             self.coalesce_around(t, last_overlap_key);
         }
+        self.reapply_software_watch_pages(t, addr, num_bytes);
     }
 
     /// Fix up mprotect registers parameters to take account of PROT_GROWSDOWN.
@@ -1472,6 +1485,75 @@ impl AddressSpace {
         triggered
     }
 
+    /// Return true if `addr` is in a page we write-protected for a software
+    /// watchpoint and the tracee may write to the page, i.e. a write fault at
+    /// `addr` is ours and not the tracee's.
+    pub fn is_software_watch_fault(&self, addr: RemotePtr<Void>) -> bool {
+        !software_watch_fault_pages(&self.software_watch_pages.borrow(), addr).is_empty()
+            && self.mapping_of(addr).map_or(false, |m| {
+                m.recorded_map.prot().contains(ProtFlags::PROT_WRITE)
+            })
+    }
+
+    /// `t` faulted writing to `addr` in a software-watched page. Let the write
+    /// happen by singlestepping `t` with the real protection restored, then
+    /// recheck the watchpoints. Returns true if any watchpoint fired.
+    ///
+    /// `ticks_request` should be what's left of the request of the resume
+    /// that faulted, so the singlestep can't run past a ticks target.
+    pub fn step_over_software_watch_fault(
+        &self,
+        t: &dyn Task,
+        addr: RemotePtr<Void>,
+        ticks_request: TicksRequest,
+    ) -> bool {
+        let pages = software_watch_fault_pages(&self.software_watch_pages.borrow(), addr);
+        self.set_page_write_protection(t, &pages, false);
+        t.resume_execution(
+            ResumeRequest::Singlestep,
+            WaitRequest::ResumeWait,
+            ticks_request,
+            None,
+        );
+        self.set_page_write_protection(t, &pages, true);
+        self.notify_watchpoint_fired(t.debug_status(), RemoteCodePtr::null())
+    }
+
+    /// Give the software-watched pages their real protection back until
+    /// `resume_software_watch_pages()`. Replay needs this while it executes a
+    /// syscall natively: the kernel fails the syscall's writes to
+    /// write-protected user memory with EFAULT instead of faulting.
+    pub fn suspend_software_watch_pages(&self, t: &dyn Task) {
+        if self.software_watch_suspended.get() || self.software_watch_pages.borrow().is_empty() {
+            return;
+        }
+        let pages: Vec<RemotePtr<Void>> =
+            self.software_watch_pages.borrow().iter().copied().collect();
+        self.set_page_write_protection(t, &pages, false);
+        self.software_watch_suspended.set(true);
+    }
+
+    /// Write-protect the software-watched pages again after
+    /// `suspend_software_watch_pages()`.
+    pub fn resume_software_watch_pages(&self) {
+        if !self.software_watch_suspended.replace(false) {
+            return;
+        }
+        let t = match self.task_set().iter().next() {
+            Some(t) => t,
+            None => return,
+        };
+        let pages: Vec<RemotePtr<Void>> =
+            self.software_watch_pages.borrow().iter().copied().collect();
+        self.set_page_write_protection(&**t, &pages, true);
+    }
+
+    /// Give software-watched pages their real protection back. Write
+    /// watchpoints that didn't fit in the debug registers stop firing.
+    pub fn drop_software_watch_pages(&self) {
+        self.set_software_watch_pages(BTreeSet::new());
+    }
+
     /// Return true if any watchpoint has fired. Will keep returning true until
     /// consume_watchpoint_changes() is called.
     pub fn has_any_watchpoint_changes(&self) -> bool {
@@ -1603,6 +1685,19 @@ impl AddressSpace {
     pub fn has_breakpoints(&self) -> bool {
         !self.breakpoints.borrow().is_empty()
     }
+    /// The number of addresses that hold a reference of `type_`.
+    /// `BreakpointType::None` counts every address with a breakpoint.
+    pub fn breakpoint_count(&self, type_: BreakpointType) -> usize {
+        self.breakpoints
+            .borrow()
+            .values()
+            .filter(|bp| match type_ {
+                BreakpointType::None => true,
+                BreakpointType::User => bp.user_count > 0,
+                BreakpointType::Internal => bp.internal_count > 0,
+            })
+            .count()
+    }
     pub fn has_watchpoints(&self) -> bool {
         !self.watchpoints.borrow().is_empty()
     }
@@ -1772,13 +1867,7 @@ impl AddressSpace {
             // have had its dontfork areas unmapped by the kernel already
             if !t.session().is_recording() {
                 let mut remote = AutoRemoteSyscalls::new(t);
-                let arch = remote.arch();
-                rd_infallible_syscall!(
-                    remote,
-                    syscall_number_for_munmap(arch),
-                    range.start().as_usize(),
-                    range.len()
-                );
+                remote.infallible_munmap_syscall(range.start(), range.len());
             }
             t.vm().unmap(t, range.start(), range.len());
         }
@@ -1800,6 +1889,15 @@ impl AddressSpace {
         *self.saved_auxv_.borrow_mut() = read_auxv(t);
     }
 
+    pub fn saved_initial_sp(&self) -> RemotePtr<Void> {
+        self.saved_initial_sp_.get()
+    }
+
+    /// Call this right after exec, when `t`'s stack pointer points at argc.
+    pub fn save_initial_sp(&self, t: &dyn Task) {
+        self.saved_initial_sp_.set(t.regs_ref().sp());
+    }
+
     /// Reads the /proc/<pid>/maps entry for a specific address. Does no caching.
     /// If performed on a file in a btrfs file system, this may return the
     /// wrong device number! If you stick to anonymous or special file
@@ -2051,9 +2149,12 @@ impl AddressSpace {
             monitored_mem: Default::default(),
             dont_fork: Default::default(),
             saved_watchpoints: Default::default(),
+            software_watch_pages: Default::default(),
+            software_watch_suspended: Default::default(),
             child_mem_fd: Default::default(),
             privileged_traced_syscall_ip_: Default::default(),
             saved_auxv_: Default::default(),
+            saved_initial_sp_: Default::default(),
             task_set: Default::default(),
             thread_locals_tuid_: Default::default(),
             // These are set below. Are both OK??
@@ -2116,8 +2217,12 @@ impl AddressSpace {
             privileged_traced_syscall_ip_: clone_from_vm.privileged_traced_syscall_ip_.clone(),
             syscallbuf_enabled_: clone_from_vm.syscallbuf_enabled_.clone(),
             saved_auxv_: clone_from_vm.saved_auxv_.clone(),
+            saved_initial_sp_: clone_from_vm.saved_initial_sp_.clone(),
             first_run_event_: Default::default(),
             watchpoints: clone_from_vm.watchpoints.clone(),
+            // The page protection is inherited by the clone.
+            software_watch_pages: clone_from_vm.software_watch_pages.clone(),
+            software_watch_suspended: clone_from_vm.software_watch_suspended.clone(),
             breakpoints: clone_from_vm.breakpoints.clone(),
             // rd does not explicitly initialize these.
             child_mem_fd: Default::default(),
@@ -2399,14 +2504,12 @@ impl AddressSpace {
 
             let wb = self.watchpoints.borrow();
             let watchpoint_original = wb.get(watchpoint_range).unwrap();
-            changed = valid != watchpoint_original.valid
-                || unsafe {
-                    libc::memcmp(
-                        value_bytes.as_ptr().cast(),
-                        watchpoint_original.value_bytes.as_ptr().cast(),
-                        value_bytes.len(),
-                    )
-                } != 0;
+            changed = watchpoint_value_changed(
+                watchpoint_original.valid,
+                &watchpoint_original.value_bytes,
+                valid,
+                &value_bytes,
+            );
         }
         let mut mbm = self.watchpoints.borrow_mut();
         let mut watchpoint_original_mut = mbm.get_mut(watchpoint_range).unwrap();
@@ -2454,7 +2557,11 @@ impl AddressSpace {
         result
     }
 
-    fn get_watch_configs(&self, will_set_task_state: WillSetTaskState) -> Vec<WatchConfig> {
+    fn get_watch_configs(
+        &self,
+        will_set_task_state: WillSetTaskState,
+        with_writes: bool,
+    ) -> Vec<WatchConfig> {
         let mut result: Vec<WatchConfig> = Vec::new();
         for (r, v) in self.watchpoints.borrow_mut().iter_mut() {
             let mut assigned_regs: Option<&mut Vec<u8>> = None;
@@ -2468,7 +2575,7 @@ impl AddressSpace {
             }
             if watching.contains(RwxBits::READ_BIT) {
                 configure_watch_registers(&mut result, r, WatchType::ReadWrite, &mut assigned_regs);
-            } else if watching.contains(RwxBits::WRITE_BIT) {
+            } else if watching.contains(RwxBits::WRITE_BIT) && with_writes {
                 configure_watch_registers(&mut result, r, WatchType::Write, &mut None);
             }
         }
@@ -2479,21 +2586,35 @@ impl AddressSpace {
     /// on `set_watchpoint()` calls, and program them for each task
     /// in this address space.
     fn allocate_watchpoints(&self) -> bool {
-        let mut regs = self.get_watch_configs(WillSetTaskState::SettingTaskState);
+        if self.program_debug_regs(true) {
+            self.set_software_watch_pages(BTreeSet::new());
+            return true;
+        }
 
-        if regs.len() <= 0x7f {
-            let mut ok = true;
-            for t in self.task_set().iter() {
-                if !t.set_debug_regs(&regs) {
-                    ok = false;
+        // Fall back to catching writes with page protection, as long as the
+        // read and exec watchpoints still fit in the debug registers.
+        if self.try_session().map_or(false, |s| s.is_replaying()) && self.program_debug_regs(false)
+        {
+            let mut pages = BTreeSet::new();
+            for (r, v) in self.watchpoints.borrow().iter() {
+                if v.watched_bits() & RwxBits::READ_WRITE_BITS == RwxBits::WRITE_BIT {
+                    let mut page = floor_page_size(r.start());
+                    while page < r.end() {
+                        pages.insert(page);
+                        page += page_size();
+                    }
                 }
             }
-            if ok {
-                return true;
-            }
+            log!(
+                LogDebug,
+                "Watching {} pages with page protection",
+                pages.len()
+            );
+            self.set_software_watch_pages(pages);
+            return true;
         }
 
-        regs.clear();
+        let regs = Vec::new();
         for t2 in self.task_set().iter() {
             t2.set_debug_regs(&regs);
         }
@@ -2501,10 +2622,144 @@ impl AddressSpace {
         for v in self.watchpoints.borrow_mut().values_mut() {
             v.debug_regs_for_exec_read.clear();
         }
+        self.set_software_watch_pages(BTreeSet::new());
 
         false
     }
 
+    /// Program the debug registers of every task in this address space.
+    /// If `with_writes` is false, write-only watchpoints are left out.
+    fn program_debug_regs(&self, with_writes: bool) -> bool {
+        let regs = self.get_watch_configs(WillSetTaskState::SettingTaskState, with_writes);
+        if regs.len() > 0x7f {
+            return false;
+        }
+        let mut ok = true;
+        for t in self.task_set().iter() {
+            if !t.set_debug_regs(&regs) {
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Write-protect exactly `pages`, restoring the real protection of any
+    /// page that no longer needs watching.
+    fn set_software_watch_pages(&self, pages: BTreeSet<RemotePtr<Void>>) {
+        let old = self.software_watch_pages.replace(pages);
+        let new = self.software_watch_pages.borrow();
+        if old == *new {
+            return;
+        }
+        let t = match self.task_set().iter().next() {
+            Some(t) => t,
+            None => return,
+        };
+        let unprotect: Vec<RemotePtr<Void>> = old.difference(&new).copied().collect();
+        let protect: Vec<RemotePtr<Void>> = new.difference(&old).copied().collect();
+        drop(new);
+        self.set_page_write_protection(&**t, &unprotect, false);
+        self.set_page_write_protection(&**t, &protect, true);
+    }
+
+    /// The tracee changed the protection of or mapped over
+    /// [addr, addr + num_bytes). Write-protect the software-watched pages
+    /// in that range again.
+    fn reapply_software_watch_pages(&self, t: &dyn Task, addr: RemotePtr<Void>, num_bytes: usize) {
+        let pages: Vec<RemotePtr<Void>> = self
+            .software_watch_pages
+            .borrow()
+            .range(floor_page_size(addr)..addr + num_bytes)
+            .copied()
+            .collect();
+        self.set_page_write_protection(t, &pages, true);
+    }
+
+    /// Remove (or restore) PROT_WRITE on `pages`. The actual protection is
+    /// tracked in the `map` of their mappings, while `recorded_map` keeps the
+    /// protection the tracee asked for. Pages the tracee can't write to
+    /// anyway are skipped. While the pages are suspended, they're never
+    /// protected.
+    fn set_page_write_protection(&self, t: &dyn Task, pages: &[RemotePtr<Void>], protect: bool) {
+        if protect && self.software_watch_suspended.get() {
+            return;
+        }
+        let mut todo: Vec<(RemotePtr<Void>, ProtFlags)> = Vec::new();
+        for page in pages {
+            if let Some(m) = self.mapping_of(*page) {
+                match software_watch_prot(m.recorded_map.prot(), protect) {
+                    Some(prot) if prot != m.map.prot() => todo.push((*page, prot)),
+                    _ => (),
+                }
+            }
+        }
+        if todo.is_empty() {
+            return;
+        }
+        let mut done: Vec<(RemotePtr<Void>, ProtFlags)> = Vec::new();
+        {
+            let mut remote = AutoRemoteSyscalls::new(t);
+            let mprotect_syscallno = syscall_number_for_mprotect(remote.arch());
+            for (page, prot) in todo {
+                let ret = remote.syscall(
+                    mprotect_syscallno,
+                    &[page.as_usize(), page_size(), prot.bits() as usize],
+                );
+                if ret < 0 {
+                    log!(
+                        LogDebug,
+                        "Failed to change protection of {} for software watchpoint: {}",
+                        page,
+                        errno_name(-ret as i32)
+                    );
+                } else {
+                    done.push((page, prot));
+                }
+            }
+        }
+        for (page, prot) in done {
+            self.set_actual_page_prot(t, page, prot);
+        }
+    }
+
+    /// Record that the page at `page` now has protection `prot`, splitting its
+    /// mapping as needed. Only `map` changes, `recorded_map` is left alone.
+    fn set_actual_page_prot(&self, t: &dyn Task, page: RemotePtr<Void>, prot: ProtFlags) {
+        let m = match self.mapping_of(page) {
+            Some(m) => m.clone(),
+            None => return,
+        };
+        let page_end = page + page_size();
+        self.remove_from_map(*m.map);
+        for &(start, end, piece_prot) in &[
+            (m.map.start(), page, m.map.prot()),
+            (page, page_end, prot),
+            (page_end, m.map.end(), m.map.prot()),
+        ] {
+            if start >= end {
+                continue;
+            }
+            let local_addr = m.local_addr.map(|addr| unsafe {
+                NonNull::new(addr.as_ptr().add(start - m.map.start())).unwrap()
+            });
+            let monitored = m
+                .monitored_shared_memory
+                .clone()
+                .map(|r| r.borrow().subrange(start - m.map.start(), end - start));
+            let mut piece = Mapping::new(
+                m.map.subrange(start, end).set_prot(piece_prot),
+                m.recorded_map.subrange(start, end),
+                m.emu_file.clone(),
+                m.mapped_file_stat,
+                local_addr,
+                monitored,
+            );
+            piece.flags = m.flags;
+            self.add_to_map(piece);
+        }
+        self.coalesce_around(t, MemoryRangeKey(MemoryRange::new_range(page, page_size())));
+    }
+
     /// Merge the mappings adjacent to `key` in memory that are
     /// semantically "adjacent mappings" of the same resource as
     /// well, for example have adjacent file offsets and the same
@@ -3256,3 +3511,92 @@ fn watchpoint_triggered(debug_status: usize, regs: &[u8]) -> bool {
     }
     false
 }
+
+/// Did a watched value change? Becoming readable or unreadable counts as a
+/// change too.
+fn watchpoint_value_changed(old_valid: bool, old: &[u8], valid: bool, new: &[u8]) -> bool {
+    valid != old_valid || old != new
+}
+
+/// The software-watched pages in `watched` a write fault at `addr` has to be
+/// let through on, or nothing if the fault isn't in a watched page. An
+/// unaligned write can straddle a page boundary, so this includes the
+/// neighbouring pages.
+fn software_watch_fault_pages(
+    watched: &BTreeSet<RemotePtr<Void>>,
+    addr: RemotePtr<Void>,
+) -> Vec<RemotePtr<Void>> {
+    let page = floor_page_size(addr);
+    if !watched.contains(&page) {
+        return Vec::new();
+    }
+    let mut pages = vec![page, page + page_size()];
+    if page.as_usize() >= page_size() {
+        pages.push(page - page_size());
+    }
+    pages.retain(|p| watched.contains(p));
+    pages
+}
+
+/// The protection to give a software-watched page the tracee mapped with
+/// `recorded_prot`, or None if the tracee can't write to it anyway and we
+/// shouldn't touch it.
+fn software_watch_prot(recorded_prot: ProtFlags, protect: bool) -> Option<ProtFlags> {
+    if !recorded_prot.contains(ProtFlags::PROT_WRITE) {
+        None
+    } else if protect {
+        Some(recorded_prot - ProtFlags::PROT_WRITE)
+    } else {
+        Some(recorded_prot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn software_watch_fault_pages_test() {
+        let page = RemotePtr::<Void>::new(0x10 * page_size());
+        let next = page + page_size();
+        let mut watched = BTreeSet::new();
+        watched.insert(page);
+        assert_eq!(
+            software_watch_fault_pages(&watched, page + 8usize),
+            vec![page]
+        );
+        // Not ours, even right next to a watched page
+        assert!(software_watch_fault_pages(&watched, next).is_empty());
+        assert!(software_watch_fault_pages(&watched, page - 1usize).is_empty());
+
+        watched.insert(next);
+        let mut pages = software_watch_fault_pages(&watched, next - 2usize);
+        pages.sort();
+        assert_eq!(pages, vec![page, next]);
+        assert_eq!(
+            software_watch_fault_pages(&watched, RemotePtr::new(0)),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn watchpoint_value_changed_test() {
+        assert!(!watchpoint_value_changed(true, &[1, 2], true, &[1, 2]));
+        assert!(watchpoint_value_changed(true, &[1, 2], true, &[1, 3]));
+        assert!(watchpoint_value_changed(true, &[1, 2], false, &[1, 2]));
+        assert!(watchpoint_value_changed(false, &[0xff], true, &[0xff]));
+    }
+
+    #[test]
+    fn software_watch_prot_test() {
+        let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        assert_eq!(software_watch_prot(rw, true), Some(ProtFlags::PROT_READ));
+        assert_eq!(software_watch_prot(rw, false), Some(rw));
+        assert_eq!(
+            software_watch_prot(rw | ProtFlags::PROT_EXEC, true),
+            Some(ProtFlags::PROT_READ | ProtFlags::PROT_EXEC)
+        );
+        assert_eq!(software_watch_prot(ProtFlags::PROT_READ, true), None);
+        assert_eq!(software_watch_prot(ProtFlags::PROT_NONE, false), None);
+    }
+}
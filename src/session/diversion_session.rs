@@ -12,21 +12,29 @@ use crate::{
     kernel_metadata::syscall_name,
     log::LogDebug,
     preload_interface::preload_globals,
+    remote_ptr::{RemotePtr, Void},
     session::{
         session_inner::{BreakStatus, RunCommand, SessionInner},
         task::{
-            task_common::write_val_mem,
+            task_common::{read_val_mem, write_mem, write_val_mem},
             task_inner::{ResumeRequest, TicksRequest, WaitRequest},
             Task,
         },
         Session,
     },
     sig::Sig,
+    util::ceil_page_size,
 };
 use libc::pid_t;
+use nix::sys::mman::{MapFlags, ProtFlags};
 use std::{
     cell::{Ref, RefMut},
+    collections::BTreeMap,
+    convert::TryInto,
+    ffi::{OsStr, OsString},
+    mem::size_of,
     ops::{Deref, DerefMut},
+    os::unix::ffi::OsStrExt,
 };
 
 /// A DiversionSession lets you run task(s) forward without replay.
@@ -82,6 +90,25 @@ impl Default for DiversionStatus {
     }
 }
 
+/// Changes to argv and the environment that functions called in a diversion
+/// should see, e.g. to flip a debug flag for a logging routine run with gdb's
+/// `call`. Only the diversion's copy of the process is changed.
+#[derive(Clone, Default)]
+pub struct DiversionOverrides {
+    /// New values of argv entries, by index
+    pub args: BTreeMap<usize, OsString>,
+    /// Variables to set, or to unset when the value is None
+    pub env: BTreeMap<OsString, Option<OsString>>,
+    /// Address of libc's `environ`. Needed if `env` isn't empty.
+    pub environ_addr: RemotePtr<Void>,
+}
+
+impl DiversionOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.env.is_empty()
+    }
+}
+
 #[derive(Default)]
 pub struct DiversionResult {
     pub status: DiversionStatus,
@@ -106,6 +133,20 @@ impl DiversionSession {
         self.emu_fs.borrow_mut()
     }
 
+    /// Make the process of `t` see `overrides`. Call this before the
+    /// diversion runs any code.
+    ///
+    /// argv entries are replaced in place, so only existing entries can be
+    /// changed. The environment is replaced by a new array that `environ`
+    /// points to, which getenv() and friends read.
+    pub fn apply_overrides(
+        &self,
+        t: &dyn Task,
+        overrides: &DiversionOverrides,
+    ) -> Result<(), String> {
+        rd_arch_function_selfless!(apply_overrides_arch, t.arch(), t, overrides)
+    }
+
     /// Try make progress in this diversion session. Run task t if possible.
     pub fn diversion_step(
         &self,
@@ -329,3 +370,123 @@ fn execute_syscall(t: &dyn Task) {
         .initial_regs_mut()
         .set_syscall_result(t.regs_ref().syscall_result());
 }
+
+/// Where a pointer written by apply_overrides_arch() points to
+enum StrPtr {
+    /// A string that is already in the tracee
+    Remote(usize),
+    /// An offset into the strings we copy into the tracee
+    Local(usize),
+}
+
+fn push_c_str(strings: &mut Vec<u8>, s: &[u8]) -> StrPtr {
+    let offset = strings.len();
+    strings.extend_from_slice(s);
+    strings.push(0);
+    StrPtr::Local(offset)
+}
+
+fn apply_overrides_arch<Arch: Architecture>(
+    t: &dyn Task,
+    overrides: &DiversionOverrides,
+) -> Result<(), String> {
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    let mut strings = Vec::<u8>::new();
+
+    let mut argv: Vec<(RemotePtr<Arch::unsigned_word>, StrPtr)> = Vec::new();
+    if !overrides.args.is_empty() {
+        let sp = t.vm().saved_initial_sp();
+        if sp.is_null() {
+            return Err("Don't know where argv is".into());
+        }
+        let argc_ptr = RemotePtr::<Arch::unsigned_word>::cast(sp);
+        let argc: usize = read_val_mem(t, argc_ptr, None).try_into().unwrap();
+        for (&i, arg) in &overrides.args {
+            if i >= argc {
+                return Err(format!(
+                    "argv[{}] doesn't exist, the process has {} arguments",
+                    i, argc
+                ));
+            }
+            argv.push((argc_ptr + 1 + i, push_c_str(&mut strings, arg.as_bytes())));
+        }
+    }
+
+    let mut envp: Vec<StrPtr> = Vec::new();
+    let environ_ptr = RemotePtr::<Arch::unsigned_word>::cast(overrides.environ_addr);
+    if !overrides.env.is_empty() {
+        if environ_ptr.is_null() {
+            return Err("Don't know where `environ` is".into());
+        }
+        let mut p = RemotePtr::<Arch::unsigned_word>::new(
+            read_val_mem(t, environ_ptr, None).try_into().unwrap(),
+        );
+        let mut unchanged = overrides.env.clone();
+        loop {
+            let entry: usize = read_val_mem(t, p, None).try_into().unwrap();
+            if entry == 0 {
+                break;
+            }
+            p += 1;
+            let var = t.read_c_str(RemotePtr::new(entry));
+            let name = var.as_bytes().split(|&c| c == b'=').next().unwrap();
+            match overrides.env.get_key_value(OsStr::from_bytes(name)) {
+                None => envp.push(StrPtr::Remote(entry)),
+                Some((n, value)) => {
+                    unchanged.remove(n);
+                    if let Some(value) = value {
+                        envp.push(push_env_var(&mut strings, n, value));
+                    }
+                }
+            }
+        }
+        for (n, value) in &unchanged {
+            if let Some(value) = value {
+                envp.push(push_env_var(&mut strings, n, value));
+            }
+        }
+    }
+
+    // The new environment array (if any), followed by the strings
+    let strings_offset = (envp.len() + 1) * size_of::<Arch::unsigned_word>();
+    let len = ceil_page_size(strings_offset + strings.len());
+    let base = AutoRemoteSyscalls::new(t).infallible_mmap_syscall(
+        None,
+        len,
+        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    let strings_addr = base + strings_offset;
+    let to_word = |s: &StrPtr| match *s {
+        StrPtr::Remote(addr) => Arch::as_unsigned_word(addr),
+        StrPtr::Local(offset) => Arch::as_unsigned_word(strings_addr.as_usize() + offset),
+    };
+
+    write_mem(t, RemotePtr::<u8>::cast(strings_addr), &strings, None);
+    for (slot, s) in &argv {
+        write_val_mem(t, *slot, &to_word(s), None);
+    }
+    if !overrides.env.is_empty() {
+        let mut array: Vec<Arch::unsigned_word> = envp.iter().map(to_word).collect();
+        array.push(Arch::as_unsigned_word(0));
+        write_mem(t, RemotePtr::cast(base), &array, None);
+        write_val_mem(
+            t,
+            environ_ptr,
+            &Arch::as_unsigned_word(base.as_usize()),
+            None,
+        );
+    }
+    Ok(())
+}
+
+fn push_env_var(strings: &mut Vec<u8>, name: &OsString, value: &OsString) -> StrPtr {
+    let mut var = name.as_bytes().to_vec();
+    var.push(b'=');
+    var.extend_from_slice(value.as_bytes());
+    push_c_str(strings, &var)
+}
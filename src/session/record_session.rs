@@ -36,7 +36,8 @@ use crate::{
     kernel_abi::{
         is_at_syscall_instruction, is_exit_group_syscall, is_pause_syscall,
         is_rdcall_notify_syscall_hook_exit_syscall, is_restart_syscall_syscall, is_write_syscall,
-        native_arch, syscall_number_for_gettid, syscall_number_for_restart_syscall, SupportedArch,
+        is_x32_syscall, native_arch, syscall_number_for_gettid, syscall_number_for_restart_syscall,
+        SupportedArch,
     },
     kernel_metadata::{errno_name, is_sigreturn, ptrace_event_name, signal_name, syscall_name},
     kernel_supplement::{
@@ -45,7 +46,7 @@ use crate::{
         SECCOMP_RET_KILL, SECCOMP_RET_TRAP, SYS_SECCOMP,
     },
     log::{LogDebug, LogError, LogInfo, LogWarn},
-    perf_counters::{self, TicksSemantics},
+    perf_counters::{self, check_zen_speclockmap, TicksSemantics},
     preload_interface::{
         syscallbuf_hdr, syscallbuf_record, SYSCALLBUF_ENABLED_ENV_VAR, SYSCALLBUF_LIB_FILENAME,
         SYSCALLBUF_LIB_FILENAME_PADDED,
@@ -91,6 +92,7 @@ use nix::{
     sys::stat::{stat, Mode, SFlag},
     unistd::{access, read, AccessFlags},
 };
+use regex::Regex;
 use std::{
     cell::{Cell, Ref, RefCell, RefMut},
     cmp::max,
@@ -218,6 +220,18 @@ pub enum SyscallBuffering {
     DisableSyscallBuf,
 }
 
+/// A syscall that `rd record --inject-errors` makes fail.
+#[derive(Clone, Debug)]
+pub struct InjectError {
+    /// Name of the syscall, e.g. `read`
+    pub syscall: String,
+    /// The (positive) errno the syscall fails with
+    pub errno: i32,
+    /// Fail every `every`th call of the syscall, counting the calls of all
+    /// tasks together
+    pub every: u64,
+}
+
 /// DIFF NOTE: Subsumes RecordResult and RecordStatus from rr
 #[derive(Clone, Eq, PartialEq)]
 #[allow(clippy::enum_variant_names)]
@@ -266,6 +280,13 @@ pub struct RecordSession {
     asan_active_: bool,
     /// When true, wait for all tracees to exit before finishing recording.
     wait_for_all_: bool,
+    /// If not empty, processes that exec a command line matching none of
+    /// these are detached. See `records_exec()`.
+    only_record: Vec<Regex>,
+    /// Syscalls to make fail. See `injected_errno()`.
+    inject_errors: Vec<InjectError>,
+    /// Number of calls seen so far of each syscall in `inject_errors`
+    inject_error_calls: RefCell<Vec<u64>>,
 
     /// DIFF NOTE: This is simply a normal string in rr.
     /// `None` means the user did not provide any trace dir options and we need
@@ -304,7 +325,12 @@ impl RecordSession {
         flags: &RecordCommand,
         asan_active: bool,
     ) -> SessionSharedPtr {
+        check_zen_speclockmap(flags.force_zen_workaround);
         let sched = Scheduler::new(flags.max_ticks, flags.always_switch);
+        if flags.chaos {
+            // Seed before anything random is chosen, including the CPU below.
+            sched.set_random_seed(flags.chaos_seed);
+        }
 
         if flags.scarce_fds {
             for _ in 0..950 {
@@ -317,9 +343,10 @@ impl RecordSession {
             session_inner: SessionInner::new(),
             trace_out: RefCell::new(TraceWriter::new(
                 &flags.args[0],
-                choose_cpu(flags.bind_cpu),
+                choose_cpu(flags.bind_cpu, &mut *sched.rng()),
                 flags.output_trace_dir.as_deref(),
                 TicksSemantics::default(),
+                flags.compression,
             )),
             scheduler_: sched,
             initial_thread_group: Default::default(),
@@ -334,9 +361,12 @@ impl RecordSession {
             use_syscall_buffer_: flags.use_syscall_buffer == SyscallBuffering::EnableSycallBuf,
             use_file_cloning_: flags.use_file_cloning,
             use_read_cloning_: flags.use_read_cloning,
-            enable_chaos_: Default::default(),
+            enable_chaos_: flags.chaos,
             asan_active_: asan_active,
             wait_for_all_: flags.wait_for_all,
+            only_record: flags.only_record.clone(),
+            inject_errors: flags.inject_errors.clone(),
+            inject_error_calls: RefCell::new(vec![0; flags.inject_errors.len()]),
             output_trace_dir: flags.output_trace_dir.clone(),
         };
 
@@ -375,7 +405,15 @@ impl RecordSession {
 
         if flags.chaos {
             rs.scheduler().set_enable_chaos(flags.chaos);
+            rs.trace_writer_mut().set_chaos_seed(flags.chaos_seed);
         }
+        let cwd = std::env::current_dir().unwrap_or_default();
+        rs.trace_writer_mut()
+            .set_command(&flags.args, &env, cwd.as_os_str());
+        // close_trace_writer() replaces this. Until then it lets a trace whose
+        // recording gets killed be replayed up to where it stopped.
+        rs.trace_writer_mut()
+            .write_provisional_header(*rs.trace_id.clone());
 
         match flags.num_cores {
             Some(num_cores) => {
@@ -621,6 +659,13 @@ impl RecordSession {
             return result;
         }
 
+        if t.as_rec_unwrap().detach_after_exec.get() {
+            self.detach_task(t.as_rec_unwrap());
+            // t is gone as far as we're concerned.
+            self.last_task_switchable.set(Switchable::AllowSwitch);
+            return result;
+        }
+
         if t.unstable.get() {
             // Do not record non-ptrace-exit events for tasks in
             // an unstable exit. We can't replay them. This happens in the
@@ -1755,6 +1800,17 @@ impl RecordSession {
         step_result: &mut RecordResult,
         syscall_arch: SupportedArch,
     ) -> bool {
+        // We only know the x86-64 layouts of syscall parameters. Recording an
+        // x32 syscall as if it were x86-64 would record the wrong memory (or
+        // none at all) and the trace would fail to replay in confusing ways.
+        if is_x32_syscall(t.regs_ref().original_syscallno(), syscall_arch) {
+            clean_fatal!(
+                "Task {} made x32 syscall {:#x}. x32 ABI programs can't be recorded.",
+                t.tid(),
+                t.regs_ref().original_syscallno()
+            );
+        }
+
         if let Some(si) = t.stashed_sig_not_synthetic_sigchld() {
             // The only four cases where we allow a stashed signal to be pending on
             // syscall entry are:
@@ -1953,6 +2009,66 @@ impl RecordSession {
         self.wait_for_all_ = wait_for_all;
     }
 
+    /// Should a process that has just exec'd `cmd_line` keep being recorded?
+    pub fn records_exec(&self, cmd_line: &[OsString]) -> bool {
+        if self.only_record.is_empty() {
+            return true;
+        }
+        let cmd_line = cmd_line
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.only_record.iter().any(|re| re.is_match(&cmd_line))
+    }
+
+    /// Count a call of `syscallno` that is about to enter the kernel. Returns
+    /// the errno it should fail with if `--inject-errors` selects this call.
+    pub fn injected_errno(&self, syscallno: i32, arch: SupportedArch) -> Option<i32> {
+        if self.inject_errors.is_empty() {
+            return None;
+        }
+        let name = syscall_name(syscallno, arch);
+        let mut result = None;
+        let mut calls = self.inject_error_calls.borrow_mut();
+        for (spec, calls) in self.inject_errors.iter().zip(calls.iter_mut()) {
+            if spec.syscall != name {
+                continue;
+            }
+            *calls += 1;
+            if result.is_none() && *calls % spec.every == 0 {
+                result = Some(spec.errno);
+            }
+        }
+        result
+    }
+
+    /// Stop recording `t`, which has just exec'd a command `--only-record`
+    /// doesn't match, and let it run untraced.
+    ///
+    /// To replay, the task simply exits here: `destroy()` records an EvExit
+    /// frame for it, which replay retires with `ReplaySession::exit_task()`
+    /// like any other exit. The process goes on outside the trace: anything
+    /// it does to the recorded processes, e.g. writing to a pipe they read
+    /// from or exiting for a parent that waits for it, shows up in their
+    /// recorded syscalls like any other outside effect. We don't know how the
+    /// process will end so no exit TraceTaskEvent is written.
+    fn detach_task(&self, t: &RecordTask) {
+        log!(
+            LogDebug,
+            "Detaching from {} ({:?}) since --only-record doesn't match it",
+            t.tid(),
+            t.vm().exe_image()
+        );
+        t.detach_after_exec.set(false);
+        // exec() leaves a single thread.
+        ed_assert_eq!(t, t.thread_group().borrow().task_set().len(), 1);
+        // The task runs from wherever we leave it, so it had better be left in
+        // its real state.
+        t.flush_regs();
+        t.destroy(Some(true), self);
+    }
+
     /// This gets called when we detect that a task has been revived from the
     /// dead with a PTRACE_EVENT_EXEC. See ptrace man page under "execve(2) under
     /// ptrace" for the horrid details.
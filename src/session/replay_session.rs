@@ -26,20 +26,22 @@ use crate::{
     flags::Flags as ProgramFlags,
     kernel_abi::{is_execve_syscall, syscall_number_for_exit, SupportedArch},
     kernel_metadata::syscall_name,
-    log::LogLevel::{LogDebug, LogError},
+    log::LogLevel::{LogDebug, LogError, LogWarn},
     perf_counters,
     perf_counters::{PerfCounters, TIME_SLICE_SIGNAL},
     preload_interface::{
         mprotect_record, preload_globals, syscallbuf_hdr, syscallbuf_locked_why,
         SYS_rdcall_mprotect_record,
     },
-    registers::{MismatchBehavior, Registers},
+    registers::{MismatchBehavior, RegisterMismatch, Registers},
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
+    replay_notifier,
     replay_syscall::{
         rep_after_enter_syscall, rep_prepare_run_to_syscall, rep_process_syscall,
         restore_mapped_region,
     },
+    replay_watchdog,
     scoped_fd::ScopedFd,
     session::{
         address_space::{AddressSpace, AddressSpaceSharedPtr, BreakpointType, Enabled, Traced},
@@ -56,6 +58,7 @@ use crate::{
     },
     sig,
     sig::Sig,
+    taskish_uid::ThreadGroupUid,
     thread_group::ThreadGroupSharedPtr,
     ticks::Ticks,
     trace::{
@@ -77,9 +80,10 @@ use nix::sys::mman::{MapFlags, ProtFlags};
 use std::{
     cell::{Cell, Ref, RefCell, RefMut},
     cmp::min,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     convert::{TryFrom, TryInto},
     ffi::{OsStr, OsString},
+    fmt::Write,
     intrinsics::copy_nonoverlapping,
     mem::size_of,
     ops::{Deref, DerefMut},
@@ -89,6 +93,9 @@ use std::{
 
 const USE_BREAKPOINT_TARGET: bool = true;
 
+/// How many of the preceding events a divergence report lists.
+const RECENT_FRAMES_IN_REPORTS: usize = 8;
+
 pub type ReplaySessionSharedPtr = Rc<RefCell<ReplaySession>>;
 
 /// ReplayFlushBufferedSyscallState is saved in Session and cloned with its
@@ -261,6 +268,9 @@ pub struct StepConstraints {
     /// DIFF NOTE: @TODO? In rr this is a pointer to the registers
     /// i.e. in Rust it would be Vec<&Registers>
     pub stop_before_states: Vec<Registers>,
+    /// Report the time-slice signals replay uses to reach tick targets in
+    /// BreakStatus instead of concealing them. For debugging rd itself.
+    pub report_time_slice_signals: bool,
 }
 
 impl StepConstraints {
@@ -273,6 +283,7 @@ impl StepConstraints {
             stop_at_time: Default::default(),
             ticks_target: Default::default(),
             stop_before_states: Vec::new(),
+            report_time_slice_signals: false,
         }
     }
 }
@@ -341,6 +352,8 @@ pub struct ReplaySession {
     emu_fs: EmuFsSharedPtr,
     trace_in: RefCell<TraceReader>,
     trace_frame: RefCell<TraceFrame>,
+    /// The frames before `trace_frame`, oldest first, for divergence reports.
+    recent_frames: RefCell<VecDeque<TraceFrame>>,
     current_step: Cell<ReplayTraceStep>,
     ticks_at_start_of_event: Cell<Ticks>,
     cpuid_bug_detector: RefCell<CPUIDBugDetector>,
@@ -354,6 +367,8 @@ pub struct ReplaySession {
     /// Note that this is NOT a weak pointer!!
     /// DIFF NOTE: Made into an Option<> and also contains the syscall_bp_addr
     syscall_bp_vm: RefCell<Option<(AddressSpaceSharedPtr, RemoteCodePtr)>>,
+    /// See `set_validated_thread_group()`.
+    validated_thread_group: Cell<Option<ThreadGroupUid>>,
 }
 
 #[derive(Clone)]
@@ -363,6 +378,9 @@ pub struct Flags {
     pub redirect_stdio: bool,
     pub share_private_mappings: bool,
     pub cpu_unbound: bool,
+    /// Warn instead of aborting when a task reaches an event with a different
+    /// tick count than recorded.
+    pub ignore_ticks_mismatch: bool,
 }
 
 impl Drop for ReplaySession {
@@ -395,6 +413,7 @@ impl Clone for ReplaySession {
             emu_fs: EmuFs::create(),
             trace_in: self.trace_in.clone(),
             trace_frame: self.trace_frame.clone(),
+            recent_frames: self.recent_frames.clone(),
             current_step: self.current_step.clone(),
             ticks_at_start_of_event: self.ticks_at_start_of_event.clone(),
             cpuid_bug_detector: self.cpuid_bug_detector.clone(),
@@ -404,6 +423,7 @@ impl Clone for ReplaySession {
             trace_start_time: self.trace_start_time.clone(),
             // No breakpoint to start with initially
             syscall_bp_vm: Default::default(),
+            validated_thread_group: self.validated_thread_group.clone(),
         }
     }
 }
@@ -526,6 +546,10 @@ impl ReplaySession {
         );
 
         session.finish_initializing();
+        // Nothing in a diversion handles the faults of software watchpoints.
+        for vm in session.vms() {
+            vm.drop_software_watch_pages();
+        }
 
         session
     }
@@ -585,11 +609,28 @@ impl ReplaySession {
         &self.flags_
     }
 
+    /// Only check the registers and memory of tasks in `tguid` against the
+    /// trace; `None` checks every task. Divergence in other thread groups
+    /// then goes unnoticed until it affects `tguid`. Clones of this session
+    /// inherit the setting.
+    pub fn set_validated_thread_group(&self, tguid: Option<ThreadGroupUid>) {
+        self.validated_thread_group.set(tguid);
+    }
+
+    /// Should `t`'s state be checked against the trace?
+    pub fn validates(&self, t: &dyn Task) -> bool {
+        match self.validated_thread_group.get() {
+            Some(tguid) => t.thread_group().borrow().tguid() == tguid,
+            None => true,
+        }
+    }
+
     fn new<T: AsRef<Path>>(dir: Option<T>, flags: Flags) -> ReplaySession {
         let mut rs = ReplaySession {
             emu_fs: EmuFs::create(),
             trace_in: RefCell::new(TraceReader::new(dir)),
             trace_frame: Default::default(),
+            recent_frames: Default::default(),
             current_step: Default::default(),
             ticks_at_start_of_event: Default::default(),
             flags_: flags,
@@ -599,6 +640,7 @@ impl ReplaySession {
             cpuid_bug_detector: Default::default(),
             fast_forward_status: Default::default(),
             syscall_bp_vm: Default::default(),
+            validated_thread_group: Default::default(),
         };
 
         let semantics = rs.trace_in.borrow().ticks_semantics();
@@ -647,7 +689,60 @@ impl ReplaySession {
             return;
         }
 
-        *self.trace_frame.borrow_mut() = self.trace_in.borrow_mut().read_frame();
+        let next_frame = self.trace_in.borrow_mut().read_frame();
+        let prev_frame = self.trace_frame.replace(next_frame);
+        let mut recent_frames = self.recent_frames.borrow_mut();
+        if recent_frames.len() == RECENT_FRAMES_IN_REPORTS {
+            recent_frames.pop_front();
+        }
+        recent_frames.push_back(prev_frame);
+    }
+
+    /// Describe a divergence of `t` from the recording: the mismatching
+    /// registers (if any), where in the trace we are, and the events that led
+    /// here. `label1` and `label2` name the sides of `mismatches`.
+    pub fn divergence_report(
+        &self,
+        t: &ReplayTask,
+        label1: &str,
+        label2: &str,
+        mismatches: &[RegisterMismatch],
+    ) -> String {
+        let frame = self.current_trace_frame();
+        let mut report = String::new();
+        writeln!(
+            report,
+            "Replay diverged at event {} ({}) of tid {}:",
+            frame.time(),
+            frame.event(),
+            t.rec_tid()
+        )
+        .unwrap();
+        if !mismatches.is_empty() {
+            writeln!(report, "  {:<10} {:<20} {:<20}", "register", label1, label2).unwrap();
+        }
+        for m in mismatches {
+            writeln!(
+                report,
+                "  {:<10} {:<20} {:<20}",
+                m.name,
+                format!("{:#x}", m.value1),
+                format!("{:#x}", m.value2)
+            )
+            .unwrap();
+        }
+        writeln!(
+            report,
+            "Ticks: {} now, {} recorded for this event",
+            t.tick_count(),
+            frame.ticks()
+        )
+        .unwrap();
+        report.push_str("Preceding events:\n");
+        for f in self.recent_frames.borrow().iter() {
+            writeln!(report, "  {} tid {} {}", f.time(), f.tid(), f.event()).unwrap();
+        }
+        report
     }
 
     /// Create a replay session that will use the trace directory specified
@@ -702,6 +797,7 @@ impl ReplaySession {
     /// Always stops on a switch to a new task.
     pub fn replay_step_with_constraints(&self, constraints: &StepConstraints) -> ReplayResult {
         self.finish_initializing();
+        replay_watchdog::note_position(self);
         let mut result = ReplayResult::new(ReplayStatus::ReplayContinue);
         let mut maybe_rc_t = self.current_task();
 
@@ -751,6 +847,12 @@ impl ReplaySession {
                     "Expected either SIGTRAP at $ip {} or USER breakpoint just after it",
                     t.ip()
                 );
+                if constraints.report_time_slice_signals && t.maybe_stop_sig() == TIME_SLICE_SIGNAL
+                {
+                    let mut siginfo = t.get_siginfo();
+                    siginfo.si_signo = TIME_SLICE_SIGNAL.as_raw();
+                    result.break_status.signal = Some(Box::new(siginfo));
+                }
                 ed_assert!(
                     t,
                     !result.break_status.singlestep_complete || constraints.is_singlestep()
@@ -999,7 +1101,15 @@ impl ReplaySession {
                 {
                     rep_prepare_run_to_syscall(t, &mut current_step);
                 } else {
+                    // Syscalls replay executes natively must be able to write
+                    // to pages that are write-protected for software watchpoints.
+                    t.vm().suspend_software_watch_pages(t);
                     rep_process_syscall(t, &mut current_step);
+                    // An execve() or fork may have left `t` in another address
+                    // space, so resume them all.
+                    for vm in self.vms() {
+                        vm.resume_software_watch_pages();
+                    }
                     if current_step.action == ReplayTraceStepType::TstepRetire {
                         t.on_syscall_exit(
                             current_step.syscall().number,
@@ -1213,7 +1323,9 @@ impl ReplaySession {
                 return Completion::Incomplete;
             }
             Some(sig::SIGSEGV) => {
-                if self.handle_unrecorded_cpuid_fault(t, constraints) {
+                if self.handle_unrecorded_cpuid_fault(t, constraints)
+                    || self.handle_software_watchpoint_fault(t, constraints)
+                {
                     return Completion::Incomplete;
                 }
             }
@@ -1465,6 +1577,38 @@ impl ReplaySession {
         true
     }
 
+    /// A SIGSEGV from a write to a page AddressSpace write-protected for a
+    /// software watchpoint. Let the write through and stop as if a hardware
+    /// watchpoint fired, if a watched value changed.
+    fn handle_software_watchpoint_fault(
+        &self,
+        t: &ReplayTask,
+        constraints: &StepConstraints,
+    ) -> bool {
+        if t.maybe_stop_sig() != SIGSEGV {
+            return false;
+        }
+        let addr =
+            RemotePtr::<Void>::new(unsafe { t.get_siginfo()._sifields._sigfault.si_addr } as usize);
+        if !t.vm().is_software_watch_fault(addr) {
+            return false;
+        }
+        // Don't let the singlestep run past the ticks target. If we're
+        // already close enough, it still has to be counted.
+        let ticks_request =
+            ticks_request_for(t, constraints).unwrap_or(TicksRequest::ResumeWithTicksRequest(1));
+        let fired = t
+            .vm()
+            .step_over_software_watch_fault(t, addr, ticks_request);
+        // diagnose_debugger_trap() picks up the changed watchpoints on SIGTRAP.
+        t.set_status(if fired || constraints.is_singlestep() {
+            WaitStatus::for_stop_sig(sig::SIGTRAP)
+        } else {
+            WaitStatus::default()
+        });
+        true
+    }
+
     fn check_ticks_consistency(&self, t: &ReplayTask, ev: &Event) {
         if !self.done_initial_exec() {
             return;
@@ -1472,14 +1616,33 @@ impl ReplaySession {
 
         let ticks_now = t.tick_count();
         let trace_ticks = self.current_trace_frame().ticks();
+        if ticks_now == trace_ticks {
+            return;
+        }
 
+        let mut report = self.divergence_report(t, "replaying", "recorded", &[]);
+        writeln!(report, "Registers now: {}", t.regs_ref()).unwrap();
+        if self.flags_.ignore_ticks_mismatch {
+            log!(
+                LogWarn,
+                "ticks mismatch for '{}'; expected {}, got {}. Ignoring.\n{}",
+                ev,
+                trace_ticks,
+                ticks_now,
+                report
+            );
+            replay_notifier::publish_divergence(t, false, &report);
+            return;
+        }
+        replay_notifier::publish_divergence(t, true, &report);
         ed_assert!(
             t,
-            ticks_now == trace_ticks,
-            "ticks mismatch for '{}'; expected {}, got {}",
+            false,
+            "ticks mismatch for '{}'; expected {}, got {}\n{}",
             ev,
             trace_ticks,
-            ticks_now
+            ticks_now,
+            report
         );
     }
 
@@ -1526,7 +1689,9 @@ impl ReplaySession {
                 tick_request,
                 None,
             );
-            self.handle_unrecorded_cpuid_fault(t, constraints);
+            if !self.handle_unrecorded_cpuid_fault(t, constraints) {
+                self.handle_software_watchpoint_fault(t, constraints);
+            }
         } else if constraints.command == RunCommand::SinglestepFastForward {
             self.fast_forward_status.set(
                 self.fast_forward_status.get()
@@ -1536,7 +1701,9 @@ impl ReplaySession {
                         &constraints.stop_before_states,
                     ),
             );
-            self.handle_unrecorded_cpuid_fault(t, constraints);
+            if !self.handle_unrecorded_cpuid_fault(t, constraints) {
+                self.handle_software_watchpoint_fault(t, constraints);
+            }
         } else {
             t.resume_execution(resume_how, WaitRequest::ResumeWait, tick_request, None);
             if t.maybe_stop_sig().is_not_sig() {
@@ -1562,7 +1729,9 @@ impl ReplaySession {
                     }
                     _ => (),
                 }
-            } else if self.handle_unrecorded_cpuid_fault(t, constraints) {
+            } else if self.handle_unrecorded_cpuid_fault(t, constraints)
+                || self.handle_software_watchpoint_fault(t, constraints)
+            {
                 return Completion::Incomplete;
             }
         }
@@ -2415,25 +2584,37 @@ fn compute_ticks_request(
     constraints: &StepConstraints,
     ticks_request: &mut TicksRequest,
 ) -> bool {
-    *ticks_request = TicksRequest::ResumeUnlimitedTicks;
-    if constraints.ticks_target > 0 {
-        let ticks_period = constraints.ticks_target as i64
-            - PerfCounters::skid_size() as i64
-            - t.tick_count() as i64;
-        if ticks_period <= 0 {
+    match ticks_request_for(t, constraints) {
+        Some(request) => {
+            *ticks_request = request;
+            true
+        }
+        None => {
             // Behave as if we actually executed something. Callers assume we did.
             t.clear_wait_status();
-            return false;
-        }
-        if ticks_period > MAX_TICKS_REQUEST as i64 {
-            // Avoid overflow. The execution will stop early but we'll treat that
-            // just like a stray TIME_SLICE_SIGNAL and continue as needed.
-            *ticks_request = TicksRequest::ResumeWithTicksRequest(MAX_TICKS_REQUEST);
-        } else {
-            *ticks_request = TicksRequest::ResumeWithTicksRequest(ticks_period as u64);
+            false
         }
     }
-    true
+}
+
+/// The ticks request that takes `t` no further than `constraints.ticks_target`,
+/// or None if `t` is already within the skid of it.
+fn ticks_request_for(t: &ReplayTask, constraints: &StepConstraints) -> Option<TicksRequest> {
+    if constraints.ticks_target == 0 {
+        return Some(TicksRequest::ResumeUnlimitedTicks);
+    }
+    let ticks_period =
+        constraints.ticks_target as i64 - PerfCounters::skid_size() as i64 - t.tick_count() as i64;
+    if ticks_period <= 0 {
+        return None;
+    }
+    if ticks_period > MAX_TICKS_REQUEST as i64 {
+        // Avoid overflow. The execution will stop early but we'll treat that
+        // just like a stray TIME_SLICE_SIGNAL and continue as needed.
+        Some(TicksRequest::ResumeWithTicksRequest(MAX_TICKS_REQUEST))
+    } else {
+        Some(TicksRequest::ResumeWithTicksRequest(ticks_period as u64))
+    }
 }
 
 fn is_fatal_default_action(sig: Sig) -> bool {
@@ -2462,6 +2643,7 @@ fn debug_memory(t: &ReplayTask) {
     }
 
     if t.session().done_initial_exec()
+        && t.session().as_replay().unwrap().validates(t)
         && should_checksum(t.current_trace_frame().event(), current_time)
     {
         // Validate the checksum we computed during the
@@ -2563,33 +2745,24 @@ fn guard_overshoot(
         if t.regs_ref().ip() == target_ip.increment_by_bkpt_insn_length(t.arch()) {
             t.move_ip_before_breakpoint();
         }
-        match closest_matching_regs {
-            Some(cmr) => {
-                log!(
-                    LogError,
-                    "Replay diverged; target registers at ticks target mismatched: "
-                );
-                Registers::compare_register_files(
-                    Some(t),
-                    "rep overshoot",
-                    &t.regs_ref(),
-                    "rec",
-                    cmr,
-                    MismatchBehavior::LogMismatches,
-                );
-            }
-            None => {
-                log!(LogError, "Replay diverged; target registers mismatched: ");
-                Registers::compare_register_files(
-                    Some(t),
-                    "rep overshoot",
-                    &t.regs_ref(),
-                    "rec",
-                    target_regs,
-                    MismatchBehavior::LogMismatches,
-                );
-            }
-        }
+        let (what, rec_regs) = match closest_matching_regs {
+            Some(cmr) => ("target registers at ticks target", cmr),
+            None => ("target registers", target_regs),
+        };
+        let mismatches = t.regs_ref().compare_with_mismatch_report(rec_regs);
+        let report = t.session().as_replay().unwrap().divergence_report(
+            t,
+            "rep overshoot",
+            "rec",
+            &mismatches,
+        );
+        log!(
+            LogError,
+            "Replay diverged; {} mismatched:\n{}",
+            what,
+            report
+        );
+        replay_notifier::publish_divergence(t, true, &report);
         ed_assert!(
             t,
             false,
@@ -100,6 +100,7 @@ use nix::{
 };
 use owning_ref::OwningHandle;
 use ptr::NonNull;
+use rand::Rng;
 use std::{
     cell::{Cell, Ref, RefCell, RefMut},
     cmp::min,
@@ -468,6 +469,10 @@ pub struct RecordTask {
 
     pub did_record_robust_futex_changes: Cell<bool>,
 
+    /// This task exec'd a command that `--only-record` doesn't match. Stop
+    /// tracing it the next time it is scheduled.
+    pub detach_after_exec: Cell<bool>,
+
     /// DIFF NOTE: This field does not exist in rr
     /// Since the property system is not used intensively in rr its
     /// simpler just to add this single field instead.
@@ -1039,6 +1044,7 @@ impl RecordTask {
             break_at_syscallbuf_final_instruction: Default::default(),
             next_pmc_interrupt_is_for_user: Default::default(),
             did_record_robust_futex_changes: Default::default(),
+            detach_after_exec: Default::default(),
             // Implicit
             registers_at_start_of_last_timeslice: RefCell::new(Registers::new(a)),
             emulated_ptrace_tracees: Default::default(),
@@ -3343,9 +3349,10 @@ fn do_preload_init_arch<Arch: Architecture>(t: &RecordTask) {
     write_val_mem(t, desched_sig_ptr, &desched_sig, None);
     t.record_local_for(desched_sig_ptr, &desched_sig);
 
+    // Take the seed from the scheduler's RNG so a chaos seed reproduces it.
     let mut random_seed: u64;
     loop {
-        random_seed = rand::random();
+        random_seed = t.session().as_record().unwrap().scheduler().rng().gen();
         if random_seed > 0 {
             break;
         }
@@ -20,6 +20,7 @@ use crate::{
     preload_interface_arch::rdcall_init_buffers_params,
     registers::{MismatchBehavior, Registers},
     remote_ptr::{RemotePtr, Void},
+    replay_notifier,
     scoped_fd::ScopedFd,
     session::{
         address_space::AddressSpace,
@@ -129,6 +130,22 @@ impl ReplayTask {
         self.set_regs(&r);
         let extra_registers = self.current_trace_frame().extra_regs_ref().clone();
         ed_assert!(self, !extra_registers.is_empty());
+        // Both the recording and the replay just got the kernel's initial
+        // FPU/vector state, so any difference means the CPUs differ in a way
+        // that may break replay.
+        let mismatched = self.extra_regs_ref().mismatched_registers(&extra_registers);
+        if !mismatched.is_empty() {
+            let message = format!(
+                "Extra registers after exec differ from the recording: {}",
+                mismatched
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            log!(LogWarn, "{}", message);
+            replay_notifier::publish_divergence(self, false, &message);
+        }
         self.set_extra_regs(&extra_registers);
     }
 
@@ -140,6 +157,9 @@ impl ReplayTask {
         if !self.session().done_initial_exec() {
             return;
         }
+        if !self.session().as_replay().unwrap().validates(self) {
+            return;
+        }
 
         // @TODO When this `if` triggers trace frame may already be borrowed.
         // This could run into a borrow mut error.
@@ -18,8 +18,8 @@ use crate::{
     extra_registers::{ExtraRegisters, Format},
     fd_table::{FdTable, FdTableSharedPtr},
     file_monitor::{
-        magic_save_data_monitor::MagicSaveDataMonitor, preserve_file_monitor::PreserveFileMonitor,
-        stdio_monitor::StdioMonitor,
+        annotation_monitor::AnnotationMonitor, magic_save_data_monitor::MagicSaveDataMonitor,
+        preserve_file_monitor::PreserveFileMonitor, stdio_monitor::StdioMonitor,
     },
     flags::Flags,
     kernel_abi::{is_ioctl_syscall, SupportedArch, RD_NATIVE_ARCH},
@@ -29,7 +29,7 @@ use crate::{
     perf_counters::PerfCounters,
     preload_interface::{preload_globals, syscallbuf_hdr, PRELOAD_THREAD_LOCALS_SIZE},
     preload_interface_arch::preload_thread_locals,
-    rd::{RD_MAGIC_SAVE_DATA_FD, RD_RESERVED_SOCKET_FD},
+    rd::{RD_MAGIC_ANNOTATE_FD, RD_MAGIC_SAVE_DATA_FD, RD_RESERVED_SOCKET_FD},
     registers::Registers,
     remote_code_ptr::RemoteCodePtr,
     remote_ptr::{RemotePtr, Void},
@@ -77,7 +77,10 @@ use std::{
     cmp::{max, min},
     ffi::{c_void, CStr, CString, OsStr, OsString},
     mem::{size_of, size_of_val},
-    os::{raw::c_int, unix::ffi::OsStrExt},
+    os::{
+        raw::c_int,
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
     ptr,
     ptr::{copy_nonoverlapping, NonNull},
     rc::Rc,
@@ -756,6 +759,14 @@ impl TaskInner {
         self.prname.borrow()
     }
 
+    /// Change the "task name", e.g. after the tracee wrote to /proc/tid/comm.
+    /// Like the kernel, only keep the first 15 bytes.
+    pub fn set_name(&self, name: &OsStr) {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.truncate(15);
+        *self.prname.borrow_mut() = OsString::from_vec(bytes);
+    }
+
     /// Return true if this task has execed.
     pub fn execed(&self) -> bool {
         self.thread_group().borrow().execed
@@ -1382,7 +1393,7 @@ impl TaskInner {
             thread_areas,
             desched_fd_child: self.desched_fd_child.get(),
             cloned_file_data_fd_child: self.cloned_file_data_fd_child.get(),
-            cloned_file_data_offset: if self.cloned_file_data_fd_child.get() > 0 {
+            cloned_file_data_offset: if self.cloned_file_data_fd_child.get() >= 0 {
                 get_fd_offset(self.tid(), self.cloned_file_data_fd_child.get())
             } else {
                 0
@@ -1632,7 +1643,7 @@ impl TaskInner {
             // tracees, since this seems to help performance.
             if !set_cpu_affinity(cpu_index) {
                 if SessionInner::has_cpuid_faulting() && !is_recording {
-                    cpu_index = choose_cpu(BindCPU::RandomCPU).unwrap();
+                    cpu_index = choose_cpu(BindCPU::RandomCPU, &mut rand::thread_rng()).unwrap();
                     if !set_cpu_affinity(cpu_index) {
                         fatal!(
                             "Can't bind to requested CPU {} even after we re-selected it",
@@ -1875,6 +1886,7 @@ fn setup_fd_table(t: &dyn Task, fds: &FdTableSharedPtr, tracee_socket_fd_number:
         RD_MAGIC_SAVE_DATA_FD,
         Box::new(MagicSaveDataMonitor::new()),
     );
+    fds.add_monitor(t, RD_MAGIC_ANNOTATE_FD, Box::new(AnnotationMonitor::new()));
     fds.add_monitor(
         t,
         tracee_socket_fd_number,
@@ -1910,6 +1922,10 @@ fn set_up_process(
     if maybe_dup_magic.is_err() || RD_MAGIC_SAVE_DATA_FD != maybe_dup_magic.unwrap() {
         spawned_child_fatal_error(err_fd, "error duping to RD_MAGIC_SAVE_DATA_FD");
     }
+    let maybe_dup_annotate = dup2(fd_magic, RD_MAGIC_ANNOTATE_FD);
+    if maybe_dup_annotate.is_err() || RD_MAGIC_ANNOTATE_FD != maybe_dup_annotate.unwrap() {
+        spawned_child_fatal_error(err_fd, "error duping to RD_MAGIC_ANNOTATE_FD");
+    }
 
     let maybe_dup_sock_fd = dup2(sock_fd.as_raw(), sock_fd_number);
     if maybe_dup_sock_fd.is_err() || sock_fd_number != maybe_dup_sock_fd.unwrap() {
@@ -0,0 +1,449 @@
+//! Generates the target description that gdb reads with
+//! `qXfer:features:read:target.xml`.
+//!
+//! DIFF NOTE: rr serves the xml files in third-party/gdb from its install
+//! directory. rd renders the same descriptions from the register numbering in
+//! gdb_register.rs, so the description always matches the registers we
+//! actually send in `g` packets.
+
+use crate::{
+    gdb_connection::GdbConnection,
+    gdb_register::{
+        GdbRegister, DREG_64_K0, DREG_64_MXCSR, DREG_64_XMM0, DREG_64_XMM16, DREG_64_YMM0H,
+        DREG_64_YMM15H, DREG_64_YMM16H, DREG_64_ZMM0H, DREG_64_ZMM31H, DREG_EAX, DREG_FS_BASE,
+        DREG_GS_BASE, DREG_MXCSR, DREG_ORIG_EAX, DREG_ORIG_RAX, DREG_RAX, DREG_XMM0, DREG_YMM0H,
+        DREG_YMM7H,
+    },
+    kernel_abi::SupportedArch,
+};
+use std::{convert::TryFrom, fmt::Write};
+
+/// The names of the x87 control registers, in gdb register order.
+const X87_CONTROL_REGS: [&str; 8] = [
+    "fctrl", "fstat", "ftag", "fiseg", "fioff", "foseg", "fooff", "fop",
+];
+
+const X86_CORE_REGS: [(&str, &str); 16] = [
+    ("eax", "int32"),
+    ("ecx", "int32"),
+    ("edx", "int32"),
+    ("ebx", "int32"),
+    ("esp", "data_ptr"),
+    ("ebp", "data_ptr"),
+    ("esi", "int32"),
+    ("edi", "int32"),
+    ("eip", "code_ptr"),
+    ("eflags", "i386_eflags"),
+    ("cs", "int32"),
+    ("ss", "int32"),
+    ("ds", "int32"),
+    ("es", "int32"),
+    ("fs", "int32"),
+    ("gs", "int32"),
+];
+
+const X64_CORE_REGS: [(&str, &str); 24] = [
+    ("rax", "int64"),
+    ("rbx", "int64"),
+    ("rcx", "int64"),
+    ("rdx", "int64"),
+    ("rsi", "int64"),
+    ("rdi", "int64"),
+    ("rbp", "data_ptr"),
+    ("rsp", "data_ptr"),
+    ("r8", "int64"),
+    ("r9", "int64"),
+    ("r10", "int64"),
+    ("r11", "int64"),
+    ("r12", "int64"),
+    ("r13", "int64"),
+    ("r14", "int64"),
+    ("r15", "int64"),
+    ("rip", "code_ptr"),
+    ("eflags", "i386_eflags"),
+    ("cs", "int32"),
+    ("ss", "int32"),
+    ("ds", "int32"),
+    ("es", "int32"),
+    ("fs", "int32"),
+    ("gs", "int32"),
+];
+
+const EFLAGS_FIELDS: [(&str, u32); 17] = [
+    ("CF", 0),
+    ("", 1),
+    ("PF", 2),
+    ("AF", 4),
+    ("ZF", 6),
+    ("SF", 7),
+    ("TF", 8),
+    ("IF", 9),
+    ("DF", 10),
+    ("OF", 11),
+    ("NT", 14),
+    ("RF", 16),
+    ("VM", 17),
+    ("AC", 18),
+    ("VIF", 19),
+    ("VIP", 20),
+    ("ID", 21),
+];
+
+const MXCSR_FIELDS: [(&str, u32); 14] = [
+    ("IE", 0),
+    ("DE", 1),
+    ("ZE", 2),
+    ("OE", 3),
+    ("UE", 4),
+    ("PE", 5),
+    ("DAZ", 6),
+    ("IM", 7),
+    ("DM", 8),
+    ("ZM", 9),
+    ("OM", 10),
+    ("UM", 11),
+    ("PM", 12),
+    ("FZ", 15),
+];
+
+/// The register sets gdb knows about for x86.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TargetFeature {
+    Core,
+    Sse,
+    Linux,
+    Segment,
+    Avx,
+    Avx512,
+}
+
+impl TargetFeature {
+    fn gdb_name(self) -> &'static str {
+        match self {
+            TargetFeature::Core => "org.gnu.gdb.i386.core",
+            TargetFeature::Sse => "org.gnu.gdb.i386.sse",
+            TargetFeature::Linux => "org.gnu.gdb.i386.linux",
+            TargetFeature::Segment => "org.gnu.gdb.i386.seg",
+            TargetFeature::Avx => "org.gnu.gdb.i386.avx",
+            TargetFeature::Avx512 => "org.gnu.gdb.i386.avx512",
+        }
+    }
+}
+
+pub struct TargetDescription {
+    arch: SupportedArch,
+    features: Vec<TargetFeature>,
+}
+
+impl TargetDescription {
+    /// `cpu_features` is a combination of the GdbConnection::CPU_* flags.
+    pub fn new(cpu_features: u32) -> TargetDescription {
+        let arch = if cpu_features & GdbConnection::CPU_64BIT != 0 {
+            SupportedArch::X64
+        } else {
+            SupportedArch::X86
+        };
+        let mut features = vec![
+            TargetFeature::Core,
+            TargetFeature::Sse,
+            TargetFeature::Linux,
+        ];
+        if arch == SupportedArch::X64 {
+            features.push(TargetFeature::Segment);
+        }
+        if cpu_features & GdbConnection::CPU_AVX != 0 {
+            features.push(TargetFeature::Avx);
+        }
+        if arch == SupportedArch::X64 && cpu_features & GdbConnection::CPU_AVX512 != 0 {
+            features.push(TargetFeature::Avx512);
+        }
+        TargetDescription { arch, features }
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target>\n",
+        );
+        let architecture = match self.arch {
+            SupportedArch::X86 => "i386",
+            SupportedArch::X64 => "i386:x86-64",
+        };
+        writeln!(xml, "  <architecture>{}</architecture>", architecture).unwrap();
+        xml.push_str("  <osabi>GNU/Linux</osabi>\n");
+        for &feature in &self.features {
+            writeln!(xml, "  <feature name=\"{}\">", feature.gdb_name()).unwrap();
+            self.write_feature_body(&mut xml, feature);
+            xml.push_str("  </feature>\n");
+        }
+        xml.push_str("</target>\n");
+        xml
+    }
+
+    fn write_feature_body(&self, xml: &mut String, feature: TargetFeature) {
+        let is_64 = self.arch == SupportedArch::X64;
+        let word_bits = if is_64 { 64 } else { 32 };
+        match feature {
+            TargetFeature::Core => {
+                write_flags(xml, "i386_eflags", &EFLAGS_FIELDS);
+                let (gp_regs, first, sse_first): (&[(&str, &str)], _, _) = if is_64 {
+                    (&X64_CORE_REGS, DREG_RAX, DREG_64_XMM0)
+                } else {
+                    (&X86_CORE_REGS, DREG_EAX, DREG_XMM0)
+                };
+                let mut regno = first;
+                for &(name, type_) in gp_regs {
+                    let bits = if type_ == "int32" || type_ == "i386_eflags" {
+                        32
+                    } else {
+                        word_bits
+                    };
+                    write_reg(xml, name, bits, type_, None, regno);
+                    regno = next_reg(regno);
+                }
+                for i in 0..8 {
+                    write_reg(xml, &format!("st{}", i), 80, "i387_ext", None, regno);
+                    regno = next_reg(regno);
+                }
+                for name in X87_CONTROL_REGS.iter() {
+                    write_reg(xml, name, 32, "int", Some("float"), regno);
+                    regno = next_reg(regno);
+                }
+                debug_assert_eq!(regno, sse_first);
+            }
+            TargetFeature::Sse => {
+                write_vec128_type(xml);
+                write_flags(xml, "i386_mxcsr", &MXCSR_FIELDS);
+                let (count, first, mxcsr) = if is_64 {
+                    (16, DREG_64_XMM0, DREG_64_MXCSR)
+                } else {
+                    (8, DREG_XMM0, DREG_MXCSR)
+                };
+                let mut regno = first;
+                for i in 0..count {
+                    write_reg(xml, &format!("xmm{}", i), 128, "vec128", None, regno);
+                    regno = next_reg(regno);
+                }
+                debug_assert_eq!(regno, mxcsr);
+                write_reg(xml, "mxcsr", 32, "i386_mxcsr", Some("vector"), mxcsr);
+            }
+            TargetFeature::Linux => {
+                let (name, regno) = if is_64 {
+                    ("orig_rax", DREG_ORIG_RAX)
+                } else {
+                    ("orig_eax", DREG_ORIG_EAX)
+                };
+                write_reg(xml, name, word_bits, "int", None, regno);
+            }
+            TargetFeature::Segment => {
+                write_reg(xml, "fs_base", 64, "data_ptr", None, DREG_FS_BASE);
+                write_reg(xml, "gs_base", 64, "data_ptr", None, DREG_GS_BASE);
+            }
+            TargetFeature::Avx => {
+                let (count, first, last) = if is_64 {
+                    (16, DREG_64_YMM0H, DREG_64_YMM15H)
+                } else {
+                    (8, DREG_YMM0H, DREG_YMM7H)
+                };
+                let mut regno = first;
+                for i in 0..count {
+                    if i > 0 {
+                        regno = next_reg(regno);
+                    }
+                    write_reg(xml, &format!("ymm{}h", i), 128, "uint128", None, regno);
+                }
+                debug_assert_eq!(regno, last);
+            }
+            TargetFeature::Avx512 => {
+                // Types are scoped to their feature, like in gdb's
+                // 64bit-avx512.xml.
+                write_vec128_type(xml);
+                xml.push_str("    <vector id=\"v2ui128\" type=\"uint128\" count=\"2\"/>\n");
+                let mut regno = DREG_64_XMM16;
+                for i in 16..32 {
+                    write_reg(xml, &format!("xmm{}", i), 128, "vec128", None, regno);
+                    regno = next_reg(regno);
+                }
+                debug_assert_eq!(regno, DREG_64_YMM16H);
+                for i in 16..32 {
+                    write_reg(xml, &format!("ymm{}h", i), 128, "uint128", None, regno);
+                    regno = next_reg(regno);
+                }
+                debug_assert_eq!(regno, DREG_64_K0);
+                for i in 0..8 {
+                    write_reg(xml, &format!("k{}", i), 64, "uint64", None, regno);
+                    regno = next_reg(regno);
+                }
+                debug_assert_eq!(regno, DREG_64_ZMM0H);
+                for i in 0..32 {
+                    if i > 0 {
+                        regno = next_reg(regno);
+                    }
+                    write_reg(xml, &format!("zmm{}h", i), 256, "v2ui128", None, regno);
+                }
+                debug_assert_eq!(regno, DREG_64_ZMM31H);
+            }
+        }
+    }
+}
+
+/// The `vec128` union gdb uses for xmm registers, and its vector types.
+fn write_vec128_type(xml: &mut String) {
+    for &(id, type_, count) in &[
+        ("v4f", "ieee_single", 4),
+        ("v2d", "ieee_double", 2),
+        ("v16i8", "int8", 16),
+        ("v8i16", "int16", 8),
+        ("v4i32", "int32", 4),
+        ("v2i64", "int64", 2),
+    ] {
+        writeln!(
+            xml,
+            "    <vector id=\"{}\" type=\"{}\" count=\"{}\"/>",
+            id, type_, count
+        )
+        .unwrap();
+    }
+    xml.push_str("    <union id=\"vec128\">\n");
+    for &(name, type_) in &[
+        ("v4_float", "v4f"),
+        ("v2_double", "v2d"),
+        ("v16_int8", "v16i8"),
+        ("v8_int16", "v8i16"),
+        ("v4_int32", "v4i32"),
+        ("v2_int64", "v2i64"),
+        ("uint128", "uint128"),
+    ] {
+        writeln!(xml, "      <field name=\"{}\" type=\"{}\"/>", name, type_).unwrap();
+    }
+    xml.push_str("    </union>\n");
+}
+
+fn next_reg(regno: GdbRegister) -> GdbRegister {
+    GdbRegister::try_from(regno.as_usize() as u32 + 1).unwrap()
+}
+
+fn write_flags(xml: &mut String, id: &str, fields: &[(&str, u32)]) {
+    writeln!(xml, "    <flags id=\"{}\" size=\"4\">", id).unwrap();
+    for &(name, bit) in fields {
+        writeln!(
+            xml,
+            "      <field name=\"{}\" start=\"{}\" end=\"{}\"/>",
+            name, bit, bit
+        )
+        .unwrap();
+    }
+    xml.push_str("    </flags>\n");
+}
+
+/// Every register gets an explicit regnum so gdb's numbering can't drift
+/// from ours.
+fn write_reg(
+    xml: &mut String,
+    name: &str,
+    bitsize: u32,
+    type_: &str,
+    group: Option<&str>,
+    regno: GdbRegister,
+) {
+    write!(
+        xml,
+        "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"",
+        name, bitsize, type_, regno
+    )
+    .unwrap();
+    if let Some(group) = group {
+        write!(xml, " group=\"{}\"", group).unwrap();
+    }
+    xml.push_str("/>\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    /// The regnums of the registers in `xml`, in order.
+    fn regnums(xml: &str) -> Vec<usize> {
+        let re = Regex::new(r#"regnum="(\d+)""#).unwrap();
+        re.captures_iter(xml)
+            .map(|cap| cap[1].parse().unwrap())
+            .collect()
+    }
+
+    /// Whether `xml` describes register `name` with number `regno`.
+    fn has_reg(xml: &str, name: &str, regno: GdbRegister) -> bool {
+        let start = format!("<reg name=\"{}\" ", name);
+        let regnum = format!("regnum=\"{}\"", regno);
+        xml.lines()
+            .any(|l| l.trim_start().starts_with(&start) && l.contains(&regnum))
+    }
+
+    #[test]
+    fn features_test() {
+        let x86 = TargetDescription::new(0);
+        assert_eq!(SupportedArch::X86, x86.arch);
+        assert_eq!(
+            vec![
+                TargetFeature::Core,
+                TargetFeature::Sse,
+                TargetFeature::Linux
+            ],
+            x86.features
+        );
+        // There are no avx512 registers in 32 bit mode.
+        let x86_avx = TargetDescription::new(GdbConnection::CPU_AVX | GdbConnection::CPU_AVX512);
+        assert_eq!(Some(&TargetFeature::Avx), x86_avx.features.last());
+
+        let x64 = TargetDescription::new(
+            GdbConnection::CPU_64BIT | GdbConnection::CPU_AVX | GdbConnection::CPU_AVX512,
+        );
+        assert_eq!(SupportedArch::X64, x64.arch);
+        assert_eq!(
+            vec![
+                TargetFeature::Core,
+                TargetFeature::Sse,
+                TargetFeature::Linux,
+                TargetFeature::Segment,
+                TargetFeature::Avx,
+                TargetFeature::Avx512
+            ],
+            x64.features
+        );
+    }
+
+    #[test]
+    fn x64_xml_test() {
+        let xml = TargetDescription::new(
+            GdbConnection::CPU_64BIT | GdbConnection::CPU_AVX | GdbConnection::CPU_AVX512,
+        )
+        .to_xml();
+        assert!(xml.contains("<architecture>i386:x86-64</architecture>"));
+        assert_eq!(6, xml.matches("<feature ").count());
+        assert_eq!(6, xml.matches("</feature>").count());
+        assert!(xml.ends_with("</target>\n"));
+        // Every register we know about is described exactly once, in order.
+        let last = DREG_64_ZMM31H.as_usize();
+        assert_eq!((0..=last).collect::<Vec<_>>(), regnums(&xml));
+        assert!(has_reg(&xml, "rax", DREG_RAX));
+        assert!(has_reg(&xml, "orig_rax", DREG_ORIG_RAX));
+        assert!(has_reg(&xml, "fs_base", DREG_FS_BASE));
+        assert!(has_reg(&xml, "mxcsr", DREG_64_MXCSR));
+        assert!(has_reg(&xml, "zmm31h", DREG_64_ZMM31H));
+    }
+
+    #[test]
+    fn x86_xml_test() {
+        let xml = TargetDescription::new(GdbConnection::CPU_AVX).to_xml();
+        assert!(xml.contains("<architecture>i386</architecture>"));
+        assert!(!xml.contains("org.gnu.gdb.i386.seg"));
+        assert!(!xml.contains("org.gnu.gdb.i386.avx512"));
+        assert_eq!(
+            (0..=DREG_YMM7H.as_usize()).collect::<Vec<_>>(),
+            regnums(&xml)
+        );
+        assert!(has_reg(&xml, "eax", DREG_EAX));
+        assert!(has_reg(&xml, "orig_eax", DREG_ORIG_EAX));
+        assert!(has_reg(&xml, "mxcsr", DREG_MXCSR));
+        assert!(has_reg(&xml, "ymm7h", DREG_YMM7H));
+    }
+}
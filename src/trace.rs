@@ -1,6 +1,8 @@
 mod compressed_reader;
 mod compressed_writer;
+pub mod host_environment;
 mod lexical_key;
+pub mod trace_annotation;
 pub mod trace_frame;
 pub mod trace_reader;
 mod trace_reader_file;
@@ -1,39 +1,125 @@
 use crate::{
     scoped_fd::{ScopedFd, ScopedFdSharedPtr},
-    trace::compressed_writer::BlockHeader,
+    trace::compressed_writer::{BlockHeader, Compression},
     util::{read_to_end, u8_slice_mut},
 };
 use brotli_sys::{BrotliDecoderDecompress, BROTLI_DECODER_RESULT_SUCCESS};
+use libc::c_void;
 use nix::{
     fcntl::OFlag,
-    sys::uio::pread,
+    sys::{
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+        stat::fstat,
+        uio::pread,
+    },
     unistd::{lseek, Whence},
 };
 use std::{
     cell::RefCell,
     cmp::min,
+    collections::VecDeque,
     convert::TryInto,
     ffi::OsStr,
     io,
     io::{BufRead, ErrorKind, Read},
     mem::size_of,
+    ptr,
     ptr::copy_nonoverlapping,
     rc::Rc,
+    slice,
 };
 
+/// How many decompressed blocks of a stream we keep around. Seeking in the
+/// ReplayTimeline restores checkpoints whose trace readers sit somewhere
+/// behind the current position, so recently used blocks tend to be needed
+/// again soon.
+const BLOCK_CACHE_CAPACITY: usize = 8;
+
 /// CompressedReader opens an input file written by CompressedWriter
 /// and reads data from it. Currently data is decompressed by the thread that
 /// calls read().
+///
+/// DIFF NOTE: rr preads the compressed blocks and decompresses every block it
+/// visits. rd maps the whole file and keeps an LRU of decompressed blocks
+/// that is shared by all clones of the reader.
 #[derive(Clone)]
 pub struct CompressedReader {
     /// Our fd might be the dup of another fd, so we can't rely on its current file position.
     /// Instead track the current position in fd_offset and use pread.
     pub(super) fd_offset: u64,
     pub(super) fd: Option<ScopedFdSharedPtr>,
+    /// `None` if the file could not be mapped (e.g. because it is empty). We
+    /// fall back to pread in that case.
+    mapping: Option<Rc<MappedFile>>,
+    block_cache: Rc<RefCell<BlockCache>>,
     pub(super) eof: bool,
-    pub(super) buffer: Vec<u8>,
+    pub(super) buffer: Rc<Vec<u8>>,
     pub(super) buffer_read_pos: usize,
     pub(super) saved_state: Option<CompressedReaderState>,
+    /// Set by `truncate()`: the file offset of the header of the last block
+    /// to read and how many of its uncompressed bytes to use.
+    end: Option<(u64, usize)>,
+}
+
+/// A read-only private mapping of a whole file.
+struct MappedFile {
+    addr: *mut c_void,
+    len: usize,
+}
+
+impl MappedFile {
+    fn new(fd: &ScopedFd) -> Option<MappedFile> {
+        let len: usize = fstat(fd.as_raw()).ok()?.st_size.try_into().ok()?;
+        if len == 0 {
+            return None;
+        }
+        let addr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                fd.as_raw(),
+                0,
+            )
+        }
+        .ok()?;
+        Some(MappedFile { addr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe { munmap(self.addr, self.len) }.unwrap();
+    }
+}
+
+/// Decompressed blocks keyed by the file offset of their header. The most
+/// recently used block is at the back.
+#[derive(Default)]
+struct BlockCache {
+    blocks: VecDeque<(u64, Rc<Vec<u8>>)>,
+}
+
+impl BlockCache {
+    fn get(&mut self, offset: u64) -> Option<Rc<Vec<u8>>> {
+        let i = self.blocks.iter().position(|(o, _)| *o == offset)?;
+        let entry = self.blocks.remove(i).unwrap();
+        let block = entry.1.clone();
+        self.blocks.push_back(entry);
+        Some(block)
+    }
+
+    fn insert(&mut self, offset: u64, block: Rc<Vec<u8>>) {
+        if self.blocks.len() == BLOCK_CACHE_CAPACITY {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back((offset, block));
+    }
 }
 
 impl Read for CompressedReader {
@@ -74,7 +160,7 @@ impl Read for CompressedReader {
 #[derive(Clone)]
 pub struct CompressedReaderState {
     pub(super) saved_fd_offset: u64,
-    pub(super) saved_buffer: Vec<u8>,
+    pub(super) saved_buffer: Rc<Vec<u8>>,
     pub(super) saved_buffer_read_pos: usize,
 }
 
@@ -82,7 +168,7 @@ impl Default for CompressedReaderState {
     fn default() -> Self {
         CompressedReaderState {
             saved_fd_offset: 0,
-            saved_buffer: vec![],
+            saved_buffer: Default::default(),
             saved_buffer_read_pos: 0,
         }
     }
@@ -119,13 +205,21 @@ impl CompressedReader {
             }
         }
         let buffer_read_pos = 0;
+        let mapping = if error {
+            None
+        } else {
+            MappedFile::new(&fd).map(Rc::new)
+        };
         CompressedReader {
             fd_offset: 0,
             fd: Some(Rc::new(RefCell::new(fd))),
+            mapping,
+            block_cache: Default::default(),
             eof,
-            buffer: Vec::new(),
+            buffer: Default::default(),
             buffer_read_pos,
             saved_state: None,
+            end: None,
         }
     }
 
@@ -154,14 +248,108 @@ impl CompressedReader {
         Ok(())
     }
 
+    /// Position the reader at the uncompressed offset `pos`. Only the block
+    /// headers in front of `pos` are read, so this is cheap even far into a
+    /// big stream.
+    pub fn seek(&mut self, pos: u64) -> io::Result<()> {
+        self.saved_state = None;
+        let mut offset: u64 = 0;
+        let mut block_start: u64 = 0;
+        let mut header = BlockHeader::default();
+        loop {
+            let header_offset = offset;
+            if self.is_end_of_file(header_offset)?
+                || !self.read_at(u8_slice_mut(&mut header), &mut offset)?
+            {
+                if pos != block_start {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Attempted to seek past the end of a CompressedReader",
+                    ));
+                }
+                self.fd_offset = header_offset;
+                self.buffer = Default::default();
+                self.buffer_read_pos = 0;
+                self.eof = true;
+                return Ok(());
+            }
+            let block_end = block_start + header.uncompressed_length as u64;
+            if pos < block_end {
+                self.fd_offset = header_offset;
+                self.refill_buffer()?;
+                let buffer_read_pos = (pos - block_start) as usize;
+                // The block may have been cut short by `truncate()`.
+                if buffer_read_pos > self.buffer.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Attempted to seek past the end of a CompressedReader",
+                    ));
+                }
+                self.buffer_read_pos = buffer_read_pos;
+                return Ok(());
+            }
+            block_start = block_end;
+            offset += header.compressed_length as u64;
+        }
+    }
+
     pub fn rewind(&mut self) {
         self.fd_offset = 0;
         self.buffer_read_pos = 0;
-        self.buffer.clear();
-        self.eof = false;
+        self.buffer = Default::default();
+        self.eof = self.end == Some((0, 0));
+    }
+
+    /// Make the stream end at the uncompressed offset `pos`. Whatever follows
+    /// is never read, so it may be incomplete, e.g. because the recording was
+    /// killed. Must be called before anything is read.
+    pub fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        let mut offset: u64 = 0;
+        let mut block_start: u64 = 0;
+        let mut header = BlockHeader::default();
+        loop {
+            let header_offset = offset;
+            if pos == block_start {
+                self.end = Some((header_offset, 0));
+                break;
+            }
+            if !self.read_at(u8_slice_mut(&mut header), &mut offset)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Attempted to truncate a CompressedReader past its end",
+                ));
+            }
+            let block_end = block_start + header.uncompressed_length as u64;
+            if pos < block_end {
+                self.end = Some((header_offset, (pos - block_start) as usize));
+                break;
+            }
+            block_start = block_end;
+            offset += header.compressed_length as u64;
+        }
+        self.eof = self.is_end_of_file(self.fd_offset)?;
+        Ok(())
+    }
+
+    /// The number of uncompressed bytes in the blocks that were completely
+    /// written. Only a recording that was killed leaves a partial block
+    /// behind.
+    pub fn complete_uncompressed_bytes(&self) -> io::Result<u64> {
+        let mut offset: u64 = 0;
+        let mut uncompressed_bytes: u64 = 0;
+        let mut header = BlockHeader::default();
+        while self.read_at(u8_slice_mut(&mut header), &mut offset)? {
+            offset += header.compressed_length as u64;
+            if header.compressed_length > 0 && self.is_end_of_file(offset - 1)? {
+                break;
+            }
+            uncompressed_bytes += header.uncompressed_length as u64;
+        }
+        Ok(uncompressed_bytes)
     }
     pub fn close(&mut self) {
         self.fd.take();
+        self.mapping.take();
     }
 
     /// Gathers stats on the file stream. These are independent of what's
@@ -170,11 +358,7 @@ impl CompressedReader {
         let mut offset: u64 = 0;
         let mut uncompressed_bytes: u64 = 0;
         let mut header = BlockHeader::default();
-        while read_all(
-            &self.fd.as_ref().unwrap().borrow(),
-            u8_slice_mut(&mut header),
-            &mut offset,
-        )? {
+        while self.read_at(u8_slice_mut(&mut header), &mut offset)? {
             uncompressed_bytes += header.uncompressed_length as u64;
             offset += header.compressed_length as u64;
         }
@@ -193,71 +377,133 @@ impl CompressedReader {
         }
     }
 
-    fn refill_buffer(&mut self) -> io::Result<()> {
-        let mut header_vec: Vec<u8> = vec![0; size_of::<BlockHeader>()];
-
-        #[allow(clippy::bool_comparison)]
-        if false
-            == read_all(
-                &self.fd.as_ref().unwrap().borrow(),
-                &mut header_vec,
-                &mut self.fd_offset,
-            )?
-        {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Unexpected EOF encountered while doing read_all() on header in CompressedReader",
-            ));
+    /// Read `data.len()` bytes at `offset` and advance `offset` past them.
+    /// Returns false if the file ends before that.
+    fn read_at(&self, data: &mut [u8], offset: &mut u64) -> io::Result<bool> {
+        match self.mapping.as_ref() {
+            Some(mapping) => {
+                let file = mapping.as_slice();
+                let start = min(*offset, file.len() as u64) as usize;
+                if file.len() - start < data.len() {
+                    return Ok(false);
+                }
+                data.copy_from_slice(&file[start..start + data.len()]);
+                *offset += data.len() as u64;
+                Ok(true)
+            }
+            None => read_all(&self.fd.as_ref().unwrap().borrow(), data, offset),
         }
+    }
 
-        let mut header: BlockHeader = Default::default();
-        unsafe {
-            copy_nonoverlapping(
-                header_vec.as_ptr(),
-                &raw mut header as *mut u8,
-                size_of::<BlockHeader>(),
-            );
+    fn is_end_of_file(&self, offset: u64) -> io::Result<bool> {
+        if let Some((end_offset, len)) = self.end {
+            if offset > end_offset || (offset == end_offset && len == 0) {
+                return Ok(true);
+            }
         }
-
-        let mut compressed_buf: Vec<u8> = vec![0; header.compressed_length as usize];
-
-        #[allow(clippy::bool_comparison)]
-        if false
-            == read_all(
-                &self.fd.as_ref().unwrap().borrow(),
-                &mut compressed_buf,
-                &mut self.fd_offset,
-            )?
-        {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Unexpected EOF encountered while doing read_all() on compressed data in CompressedReader",
-            ));
+        if let Some(mapping) = self.mapping.as_ref() {
+            return Ok(offset >= mapping.len as u64);
         }
-
         let ch: u8 = 0;
-        self.eof = match pread(
+        match pread(
             self.fd.as_ref().unwrap().borrow().as_raw(),
             &mut ch.to_le_bytes(),
             // On x86 off_t is an i32 and on x86_64 off_t is an i64
-            self.fd_offset.try_into().unwrap(),
+            offset.try_into().unwrap(),
         ) {
-            Ok(0) => true,
-            Ok(_) => false,
-            Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
-        };
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(false),
+            Err(e) => Err(io::Error::new(ErrorKind::Other, e)),
+        }
+    }
 
-        self.buffer.resize(header.uncompressed_length as usize, 0);
-        self.buffer_read_pos = 0;
-        if !do_decompress(compressed_buf.as_slice(), &mut self.buffer) {
+    fn refill_buffer(&mut self) -> io::Result<()> {
+        let block_offset = self.fd_offset;
+        let mut offset = self.fd_offset;
+        let mut header = BlockHeader::default();
+        if !self.read_at(u8_slice_mut(&mut header), &mut offset)? {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "There was a Decompression Error",
+                io::ErrorKind::UnexpectedEof,
+                "Unexpected EOF encountered while doing read_all() on header in CompressedReader",
             ));
         }
+        self.fd_offset = offset;
+
+        let maybe_block = self.block_cache.borrow_mut().get(block_offset);
+        let block = match maybe_block {
+            Some(block) => {
+                self.fd_offset += header.compressed_length as u64;
+                block
+            }
+            None => {
+                let block = Rc::new(self.decompress_block(&header)?);
+                self.block_cache
+                    .borrow_mut()
+                    .insert(block_offset, block.clone());
+                block
+            }
+        };
+
+        self.eof = self.is_end_of_file(self.fd_offset)?;
+        self.buffer = match self.end {
+            Some((end_offset, len)) if end_offset == block_offset => Rc::new(block[..len].to_vec()),
+            _ => block,
+        };
+        self.buffer_read_pos = 0;
 
         Ok(())
     }
+
+    /// Decompress the block described by `header`, whose compressed data
+    /// starts at fd_offset.
+    fn decompress_block(&mut self, header: &BlockHeader) -> io::Result<Vec<u8>> {
+        let compressed_len = header.compressed_length as usize;
+        let mut uncompressed = vec![0; header.uncompressed_length as usize];
+        let ok = match self.mapping.as_ref() {
+            Some(mapping) => {
+                let file = mapping.as_slice();
+                let start = min(self.fd_offset, file.len() as u64) as usize;
+                if file.len() - start < compressed_len {
+                    None
+                } else {
+                    self.fd_offset += compressed_len as u64;
+                    Some(do_decompress(
+                        &file[start..start + compressed_len],
+                        &mut uncompressed,
+                        header.compression,
+                    ))
+                }
+            }
+            None => {
+                let mut compressed_buf: Vec<u8> = vec![0; compressed_len];
+                if read_all(
+                    &self.fd.as_ref().unwrap().borrow(),
+                    &mut compressed_buf,
+                    &mut self.fd_offset,
+                )? {
+                    Some(do_decompress(
+                        &compressed_buf,
+                        &mut uncompressed,
+                        header.compression,
+                    ))
+                } else {
+                    None
+                }
+            }
+        };
+
+        match ok {
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Unexpected EOF encountered while doing read_all() on compressed data in CompressedReader",
+            )),
+            Some(false) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "There was a Decompression Error",
+            )),
+            Some(true) => Ok(uncompressed),
+        }
+    }
 }
 
 pub fn read_all(fd: &ScopedFd, data: &mut [u8], offset: &mut u64) -> io::Result<bool> {
@@ -273,18 +519,34 @@ pub fn read_all(fd: &ScopedFd, data: &mut [u8], offset: &mut u64) -> io::Result<
     }
 }
 
-pub fn do_decompress(compressed: &[u8], uncompressed: &mut [u8]) -> bool {
-    let mut out_size = uncompressed.len();
-    let decompress_result = unsafe {
-        BrotliDecoderDecompress(
-            compressed.len(),
-            compressed.as_ptr(),
-            &raw mut out_size,
-            uncompressed.as_mut_ptr(),
-        )
-    };
-
-    decompress_result == BROTLI_DECODER_RESULT_SUCCESS && out_size == uncompressed.len()
+/// `compression` is the block's `BlockHeader::compression` tag.
+pub fn do_decompress(compressed: &[u8], uncompressed: &mut [u8], compression: u32) -> bool {
+    match Compression::from_tag(compression) {
+        Some(Compression::None) => {
+            if compressed.len() != uncompressed.len() {
+                return false;
+            }
+            uncompressed.copy_from_slice(compressed);
+            true
+        }
+        Some(Compression::Brotli) => {
+            let mut out_size = uncompressed.len();
+            let decompress_result = unsafe {
+                BrotliDecoderDecompress(
+                    compressed.len(),
+                    compressed.as_ptr(),
+                    &raw mut out_size,
+                    uncompressed.as_mut_ptr(),
+                )
+            };
+            decompress_result == BROTLI_DECODER_RESULT_SUCCESS && out_size == uncompressed.len()
+        }
+        Some(Compression::Zstd) => {
+            let len = uncompressed.len();
+            zstd::bulk::decompress_to_buffer(compressed, uncompressed).ok() == Some(len)
+        }
+        None => false,
+    }
 }
 
 impl BufRead for CompressedReader {
@@ -300,3 +562,107 @@ impl BufRead for CompressedReader {
         self.buffer_read_pos += amt;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::compressed_writer::CompressedWriter;
+    use std::{env, fs, io::Write, path::PathBuf};
+
+    const BLOCK_SIZE: usize = 16;
+
+    /// Write 0, 1, 2, ... `len - 1` to a new stream in blocks of BLOCK_SIZE.
+    fn write_stream(name: &str, len: u8) -> PathBuf {
+        let path = env::temp_dir().join(format!("rd-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut writer = CompressedWriter::new(path.as_os_str(), BLOCK_SIZE, 1, Compression::None);
+        writer.write_all(&(0..len).collect::<Vec<u8>>()).unwrap();
+        writer.close(None);
+        assert!(writer.good());
+        path
+    }
+
+    #[test]
+    fn block_cache_test() {
+        let mut cache = BlockCache::default();
+        for i in 0..BLOCK_CACHE_CAPACITY as u64 {
+            cache.insert(i, Rc::new(vec![i as u8]));
+        }
+        // Using block 0 makes block 1 the least recently used one.
+        assert_eq!(Some(Rc::new(vec![0])), cache.get(0));
+        cache.insert(100, Rc::new(vec![100]));
+        assert_eq!(None, cache.get(1));
+        assert_eq!(Some(Rc::new(vec![0])), cache.get(0));
+        assert_eq!(Some(Rc::new(vec![2])), cache.get(2));
+        assert_eq!(Some(Rc::new(vec![100])), cache.get(100));
+        assert_eq!(BLOCK_CACHE_CAPACITY, cache.blocks.len());
+    }
+
+    #[test]
+    fn seek_test() {
+        let path = write_stream("seek", 40);
+        let mut reader = CompressedReader::new(path.as_os_str());
+        let mut buf = [0u8; 5];
+
+        // Into the middle of the second block, and back into the first one.
+        reader.seek(20).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!([20, 21, 22, 23, 24], buf);
+        reader.seek(3).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!([3, 4, 5, 6, 7], buf);
+        // A block boundary.
+        reader.seek(32).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!([32, 33, 34, 35, 36], buf);
+
+        reader.seek(40).unwrap();
+        assert!(reader.at_end());
+        let err = reader.seek(41).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+        // A failed seek doesn't stop later ones from working.
+        reader.seek(39).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(vec![39], rest);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncate_test() {
+        let path = write_stream("truncate", 40);
+
+        let mut reader = CompressedReader::new(path.as_os_str());
+        reader.truncate(20).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!((0..20).collect::<Vec<u8>>(), data);
+        assert!(reader.at_end());
+        // Seeking into what was cut off fails, even within the last block.
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            reader.seek(30).unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            reader.seek(21).unwrap_err().kind()
+        );
+        reader.seek(20).unwrap();
+        assert!(reader.at_end());
+        reader.rewind();
+        data.clear();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(20, data.len());
+
+        let mut reader = CompressedReader::new(path.as_os_str());
+        reader.truncate(0).unwrap();
+        assert!(reader.at_end());
+
+        let mut reader = CompressedReader::new(path.as_os_str());
+        let err = reader.truncate(41).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -32,17 +32,49 @@ pub enum WaitFlag {
     NoWait,
 }
 
+/// How blocks are compressed before being written out. The discriminant is
+/// what `BlockHeader::compression` records for each block.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(u32)]
+pub enum Compression {
+    /// Store every block as is.
+    None = 0,
+    Brotli = 1,
+    Zstd = 2,
+}
+
+impl Compression {
+    pub fn from_tag(tag: u32) -> Option<Compression> {
+        match tag {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Brotli),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Brotli
+    }
+}
+
 #[derive(Copy, Clone, Default)]
+#[repr(C)]
 pub struct BlockHeader {
     pub compressed_length: u32,
     pub uncompressed_length: u32,
+    /// How this block was compressed. See `Compression`.
+    pub compression: u32,
 }
 
 /// CompressedWriter opens an output file and writes compressed blocks to it.
 /// Blocks of a fixed but unspecified size (currently 1MB) are compressed.
-/// Each block of compressed data is written to the file preceded by two
-/// 32-bit words: the size of the compressed data (excluding block header)
-/// and the size of the uncompressed data, in that order. See BlockHeader below.
+/// Each block of compressed data is written to the file preceded by three
+/// 32-bit words: the size of the compressed data (excluding block header),
+/// the size of the uncompressed data and how the block was compressed, in
+/// that order. See BlockHeader below.
 ///
 /// We use multiple threads to perform compression. The threads are
 /// responsible for the actual data writes. The thread that creates the
@@ -50,7 +82,11 @@ pub struct BlockHeader {
 /// 'write'. The producer thread may block in 'write' if 'buffer_size' bytes are
 /// being compressed.
 ///
-/// Each data block is compressed independently using brotli.
+/// Each data block is compressed independently using the requested
+/// `Compression`. A block that doesn't get smaller is stored as is and tagged
+/// `Compression::None`.
+///
+/// DIFF NOTE: rr's block header has no compression tag; rr always uses brotli.
 pub struct CompressedWriter {
     /// Immutable while threads are running
     fd: ScopedFd,
@@ -81,6 +117,9 @@ pub struct CompressedWriterData {
     next_thread_pos: u64,
     /// position in output stream of end of data ready to dispatch
     next_thread_end_pos: u64,
+    /// Data before this position is compressed even if it doesn't fill a
+    /// whole block. See `flush_partial_block()`.
+    flush_upto: u64,
     closing: bool,
     write_error: bool,
 }
@@ -94,7 +133,17 @@ impl CompressedWriter {
         !self.error
     }
 
-    pub fn new(filename: &OsStr, block_size: usize, num_threads: usize) -> CompressedWriter {
+    /// The number of uncompressed bytes written to this stream so far.
+    pub fn uncompressed_pos(&self) -> u64 {
+        self.producer_reserved_write_pos
+    }
+
+    pub fn new(
+        filename: &OsStr,
+        block_size: usize,
+        num_threads: usize,
+        compression: Compression,
+    ) -> CompressedWriter {
         let fd = ScopedFd::open_path_with_mode(
             filename,
             OFlag::O_CLOEXEC
@@ -119,6 +168,7 @@ impl CompressedWriter {
                 thread_pos,
                 next_thread_pos: 0,
                 next_thread_end_pos: 0,
+                flush_upto: 0,
                 closing: false,
                 write_error: false,
             })),
@@ -164,12 +214,16 @@ impl CompressedWriter {
                                 ((block_size as f64 * 1.1) as usize)
                                     + size_of::<BlockHeader>()
                             ];
+                            // Contiguous copy of the block for zstd, which can't
+                            // read from the ring buffer directly.
+                            let mut inputbuf: Vec<u8> = Vec::new();
                             let mut header: BlockHeader = Default::default();
 
                             loop {
                                 if !g.write_error
                                     && g.next_thread_pos < g.next_thread_end_pos
                                     && (g.closing
+                                        || g.next_thread_pos < g.flush_upto
                                         || g.next_thread_pos + block_size as u64
                                             <= g.next_thread_end_pos)
                                 {
@@ -187,14 +241,36 @@ impl CompressedWriter {
 
                                     let offset_in_input_buf = g.thread_pos[thread_index].unwrap();
                                     drop(g);
-                                    let compressed_length: usize = unsafe {
-                                        do_compress(
+                                    let uncompressed_length = header.uncompressed_length as usize;
+                                    let mut compressed_length: usize = match compression {
+                                        Compression::Brotli => unsafe {
+                                            do_compress(
+                                                buffer,
+                                                offset_in_input_buf,
+                                                uncompressed_length,
+                                                &mut outputbuf[size_of::<BlockHeader>()..],
+                                            )
+                                        },
+                                        Compression::Zstd => do_compress_zstd(
                                             buffer,
                                             offset_in_input_buf,
-                                            header.uncompressed_length as usize,
+                                            uncompressed_length,
+                                            &mut inputbuf,
                                             &mut outputbuf[size_of::<BlockHeader>()..],
-                                        )
+                                        ),
+                                        Compression::None => uncompressed_length,
                                     };
+                                    header.compression = compression as u32;
+                                    if compressed_length >= uncompressed_length {
+                                        copy_from_ring(
+                                            buffer,
+                                            offset_in_input_buf,
+                                            uncompressed_length,
+                                            &mut outputbuf[size_of::<BlockHeader>()..],
+                                        );
+                                        compressed_length = uncompressed_length;
+                                        header.compression = Compression::None as u32;
+                                    }
                                     g = mutex.lock().unwrap();
 
                                     if 0 == compressed_length {
@@ -299,6 +375,18 @@ impl CompressedWriter {
         self.fd.close();
     }
 
+    /// Hand everything written so far to the compression threads, even if the
+    /// last block isn't full yet. If the recording gets killed, what was
+    /// written before this is then readable once the threads are done with
+    /// it. Doesn't wait for them.
+    pub fn flush_partial_block(&mut self) {
+        if self.error {
+            return;
+        }
+        self.mutex.lock().unwrap().flush_upto = self.producer_reserved_write_pos;
+        self.update_reservation(WaitFlag::NoWait);
+    }
+
     pub fn update_reservation(&mut self, wait_flag: WaitFlag) {
         let mut g = self.mutex.lock().unwrap();
 
@@ -373,6 +461,26 @@ impl Write for CompressedWriter {
     }
 }
 
+/// Copy `len` bytes starting at `stream_offset` out of the ring buffer
+/// `shared_buf`.
+fn copy_from_ring(
+    shared_buf: &[u8],
+    mut stream_offset: u64,
+    mut len: usize,
+    output_buf: &mut [u8],
+) {
+    let mut out_offset = 0;
+    while len > 0 {
+        let shared_buf_offset = (stream_offset % shared_buf.len() as u64) as usize;
+        let amount = min(len, shared_buf.len() - shared_buf_offset);
+        output_buf[out_offset..out_offset + amount]
+            .copy_from_slice(&shared_buf[shared_buf_offset..shared_buf_offset + amount]);
+        out_offset += amount;
+        stream_offset += amount as u64;
+        len -= amount;
+    }
+}
+
 /// See <http://robert.ocallahan.org/2017/07/selecting-compression-algorithm-for-rr.html>
 const RD_BROTLI_LEVEL: u32 = 5;
 
@@ -429,3 +537,24 @@ unsafe fn do_compress(
     BrotliEncoderDestroyInstance(state);
     ret
 }
+
+/// zstd's default level. It compresses trace data about as well as brotli at
+/// RD_BROTLI_LEVEL, and faster.
+const RD_ZSTD_LEVEL: i32 = 3;
+
+/// Like do_compress(), but with zstd. `input_buf` is scratch space for a
+/// contiguous copy of the block.
+fn do_compress_zstd(
+    shared_buf: &[u8],
+    stream_offset: u64,
+    uncompressed_len: usize,
+    input_buf: &mut Vec<u8>,
+    output_buf: &mut [u8],
+) -> usize {
+    input_buf.resize(uncompressed_len, 0);
+    copy_from_ring(shared_buf, stream_offset, uncompressed_len, input_buf);
+    match zstd::bulk::compress_to_buffer(input_buf, output_buf, RD_ZSTD_LEVEL) {
+        Ok(len) => len,
+        Err(e) => fatal!("zstd compression failed: {}", e),
+    }
+}
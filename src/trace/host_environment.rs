@@ -0,0 +1,148 @@
+use crate::trace_capnp::{host_environment, key_value};
+use libc::c_char;
+use nix::sys::utsname::uname;
+use serde::Serialize;
+use std::{collections::BTreeMap, ffi::CStr, fs::read_to_string};
+
+extern "C" {
+    fn gnu_get_libc_version() -> *const c_char;
+}
+
+/// Sysctls that affect whether or how rd can record and replay.
+const SYSCTLS: [&str; 4] = [
+    "kernel/perf_event_paranoid",
+    "kernel/yama/ptrace_scope",
+    "kernel/randomize_va_space",
+    "vm/mmap_min_addr",
+];
+
+/// cgroup v2 controller files that limit the recorded processes.
+const CGROUP_LIMITS: [&str; 3] = ["memory.max", "cpu.max", "pids.max"];
+
+/// A description of the machine a trace was recorded on. This is purely
+/// informational: it is shown by `rd traceinfo` to help triage replay
+/// failures, and never consulted during replay.
+#[derive(Clone, Default, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostEnvironment {
+    /// uname(2) sysname, release, version and machine.
+    pub uname: String,
+    pub glibc_version: String,
+    pub cpu_model: String,
+    /// Limits of the cgroup rd ran in, by controller file name.
+    pub cgroup_limits: BTreeMap<String, String>,
+    /// By sysctl name, e.g. `kernel.perf_event_paranoid`.
+    pub sysctls: BTreeMap<String, String>,
+}
+
+impl HostEnvironment {
+    /// Describe the machine we're running on. Anything we can't determine is
+    /// left empty.
+    pub fn capture() -> HostEnvironment {
+        let unm = uname();
+        let uname = format!(
+            "{} {} {} {}",
+            unm.sysname(),
+            unm.release(),
+            unm.version(),
+            unm.machine()
+        );
+        let glibc_version = unsafe { CStr::from_ptr(gnu_get_libc_version()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let mut sysctls = BTreeMap::new();
+        for name in &SYSCTLS {
+            if let Some(value) = read_trimmed(&format!("/proc/sys/{}", name)) {
+                sysctls.insert(name.replace('/', "."), value);
+            }
+        }
+
+        let mut cgroup_limits = BTreeMap::new();
+        if let Some(dir) = cgroup_v2_dir() {
+            for file in &CGROUP_LIMITS {
+                if let Some(value) = read_trimmed(&format!("{}/{}", dir, file)) {
+                    cgroup_limits.insert(file.to_string(), value);
+                }
+            }
+        }
+
+        HostEnvironment {
+            uname,
+            glibc_version,
+            cpu_model: cpu_model().unwrap_or_default(),
+            cgroup_limits,
+            sysctls,
+        }
+    }
+
+    pub fn write_to(&self, mut builder: host_environment::Builder) {
+        builder.set_uname(&self.uname);
+        builder.set_glibc_version(&self.glibc_version);
+        builder.set_cpu_model(&self.cpu_model);
+        write_key_values(
+            builder
+                .reborrow()
+                .init_cgroup_limits(self.cgroup_limits.len() as u32),
+            &self.cgroup_limits,
+        );
+        write_key_values(
+            builder.init_sysctls(self.sysctls.len() as u32),
+            &self.sysctls,
+        );
+    }
+
+    pub fn read_from(reader: host_environment::Reader) -> HostEnvironment {
+        HostEnvironment {
+            uname: reader.get_uname().unwrap().into(),
+            glibc_version: reader.get_glibc_version().unwrap().into(),
+            cpu_model: reader.get_cpu_model().unwrap().into(),
+            cgroup_limits: read_key_values(reader.get_cgroup_limits().unwrap()),
+            sysctls: read_key_values(reader.get_sysctls().unwrap()),
+        }
+    }
+}
+
+fn write_key_values(
+    mut list: capnp::struct_list::Builder<key_value::Owned>,
+    map: &BTreeMap<String, String>,
+) {
+    for (i, (key, value)) in map.iter().enumerate() {
+        let mut kv = list.reborrow().get(i as u32);
+        kv.set_key(key);
+        kv.set_value(value);
+    }
+}
+
+fn read_key_values(list: capnp::struct_list::Reader<key_value::Owned>) -> BTreeMap<String, String> {
+    list.iter()
+        .map(|kv| {
+            (
+                kv.get_key().unwrap().to_owned(),
+                kv.get_value().unwrap().to_owned(),
+            )
+        })
+        .collect()
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    read_to_string(path).ok().map(|s| s.trim().to_owned())
+}
+
+fn cpu_model() -> Option<String> {
+    let cpuinfo = read_to_string("/proc/cpuinfo").ok()?;
+    cpuinfo
+        .lines()
+        .find(|l| l.starts_with("model name"))
+        .and_then(|l| l.splitn(2, ':').nth(1))
+        .map(|model| model.trim().to_owned())
+}
+
+/// The cgroup v2 directory of this process, if it is in a unified hierarchy.
+fn cgroup_v2_dir() -> Option<String> {
+    let cgroup = read_to_string("/proc/self/cgroup").ok()?;
+    cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(|path| format!("/sys/fs/cgroup{}", path))
+}
@@ -0,0 +1,108 @@
+use crate::{
+    event::{EventType, SyscallState},
+    kernel_abi::is_write_syscall,
+    rd::RD_MAGIC_ANNOTATE_FD,
+    trace::{
+        trace_frame::{FrameTime, TraceFrame},
+        trace_reader::TraceReader,
+    },
+};
+use libc::pid_t;
+use std::{ffi::OsString, os::unix::ffi::OsStringExt, path::Path};
+
+/// A named marker that the tracee inserted into the trace by writing to
+/// RD_MAGIC_ANNOTATE_FD.
+#[derive(Clone, Debug)]
+pub struct TraceAnnotation {
+    /// The event of the write to RD_MAGIC_ANNOTATE_FD
+    pub time: FrameTime,
+    pub tid: pid_t,
+    pub name: OsString,
+}
+
+/// rd puts this in front of every buffer it records for a write to
+/// RD_MAGIC_ANNOTATE_FD. A tracee that reused the fd for something else
+/// doesn't get its writes mistaken for annotations.
+pub const ANNOTATION_MAGIC: &[u8] = b"rd-annotation:";
+
+/// Returns `data` without ANNOTATION_MAGIC, or None if it doesn't start with
+/// it.
+pub fn strip_annotation_magic(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(ANNOTATION_MAGIC) {
+        Some(&data[ANNOTATION_MAGIC.len()..])
+    } else {
+        None
+    }
+}
+
+/// Returns true if `frame` is the exit of a write to RD_MAGIC_ANNOTATE_FD.
+/// The raw data recorded for such a frame is the annotation name, see
+/// read_annotation_for_frame().
+pub fn is_annotation_frame(frame: &TraceFrame) -> bool {
+    let ev = frame.event();
+    if ev.event_type() != EventType::EvSyscall {
+        return false;
+    }
+    let syscall_ev = ev.syscall_event();
+    syscall_ev.state == SyscallState::ExitingSyscall
+        && is_write_syscall(syscall_ev.number, syscall_ev.arch())
+        && frame.regs_ref().arg1() as i32 == RD_MAGIC_ANNOTATE_FD
+        && frame.regs_ref().syscall_result_signed() > 0
+}
+
+/// Read the annotation recorded for `frame`, which must be the last frame read
+/// from `trace` and must satisfy is_annotation_frame(). Returns None if the
+/// raw data of the frame wasn't recorded by rd as an annotation.
+pub fn read_annotation_for_frame(
+    trace: &mut TraceReader,
+    frame: &TraceFrame,
+) -> Option<TraceAnnotation> {
+    let mut name = Vec::new();
+    let mut is_annotation = true;
+    while let Some(data) = trace.read_raw_data_for_frame() {
+        match strip_annotation_magic(&data.data) {
+            Some(part) => name.extend_from_slice(part),
+            None => is_annotation = false,
+        }
+    }
+    if !is_annotation || name.is_empty() {
+        return None;
+    }
+    Some(TraceAnnotation {
+        time: frame.time(),
+        tid: frame.tid(),
+        name: OsString::from_vec(name),
+    })
+}
+
+/// Scan the whole trace in `maybe_dir` and return all annotations, in event order.
+pub fn read_annotations<T: AsRef<Path>>(maybe_dir: Option<T>) -> Vec<TraceAnnotation> {
+    let mut trace = TraceReader::new(maybe_dir);
+    let mut annotations = Vec::new();
+    while !trace.at_end() {
+        let frame = trace.read_frame();
+        if is_annotation_frame(&frame) {
+            annotations.extend(read_annotation_for_frame(&mut trace, &frame));
+        } else {
+            // Keep the raw data stream in step with the frames
+            while trace.read_raw_data_metadata_for_frame().is_some() {}
+        }
+    }
+    annotations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_annotation_magic_test() {
+        assert_eq!(
+            strip_annotation_magic(b"rd-annotation:start"),
+            Some(&b"start"[..])
+        );
+        assert_eq!(strip_annotation_magic(b"rd-annotation:"), Some(&b""[..]));
+        assert_eq!(strip_annotation_magic(b"start"), None);
+        assert_eq!(strip_annotation_magic(b"rd-annot"), None);
+    }
+}
@@ -24,10 +24,12 @@ use crate::{
     remote_ptr::{RemotePtr, Void},
     session::{address_space::kernel_mapping::KernelMapping, record_session::TraceUuid},
     trace::{
+        host_environment::HostEnvironment,
         trace_frame::{FrameTime, TraceFrame},
         trace_stream::{
-            latest_trace_symlink, to_trace_arch, trace_save_dir, MappedData, MappedDataSource,
-            RawDataMetadata, Substream, TraceRemoteFd, TraceStream, TRACE_VERSION,
+            latest_trace_symlink, to_trace_arch, trace_save_dir, FrameIndexCheckpoint, MappedData,
+            MappedDataSource, RawDataMetadata, RegistersDeltaBase, Substream, TraceDirState,
+            TraceRemoteFd, TraceStream, TRACE_VERSION,
         },
         trace_task_event::{
             TraceTaskEvent, TraceTaskEventClone, TraceTaskEventExec, TraceTaskEventExit,
@@ -45,18 +47,15 @@ use crate::{
     wait_status::WaitStatus,
 };
 use capnp::{
+    data_list,
     message::{self, ReaderOptions},
     serialize,
     serialize_packed::read_message,
 };
 use libc::{ino_t, pid_t, time_t, ENOENT};
-use nix::{
-    errno::errno,
-    sys::{
-        mman::{MapFlags, ProtFlags},
-        stat::{stat, FileStat},
-    },
-    unistd::{access, AccessFlags},
+use nix::sys::{
+    mman::{MapFlags, ProtFlags},
+    stat::{stat, FileStat},
 };
 use std::{
     convert::{TryFrom, TryInto},
@@ -69,6 +68,7 @@ use std::{
     path::{Path, PathBuf},
     process::exit,
     ptr::copy_nonoverlapping,
+    rc::Rc,
 };
 
 /// Read the next mapped region descriptor and return it.
@@ -106,11 +106,21 @@ pub struct TraceReader {
     xcr0_: u64,
     cpuid_records_: Vec<CPUIDRecord>,
     raw_recs: Vec<RawDataMetadata>,
+    /// Registers of the last frame read, if it had any.
+    last_frame_registers: Option<RegistersDeltaBase>,
     ticks_semantics_: TicksSemantics,
     monotonic_time_: f64,
     uuid_: TraceUuid,
     trace_uses_cpuid_faulting: bool,
     preload_thread_locals_recorded_: bool,
+    host_environment_: HostEnvironment,
+    chaos_seed_: Option<u64>,
+    argv_: Vec<OsString>,
+    environ_: Vec<OsString>,
+    cwd_: OsString,
+    /// Holds a shared flock() on the `version` file while the trace is open,
+    /// so tools that rewrite traces can tell it's in use. Clones share it.
+    _version_lock: Rc<File>,
 }
 
 impl Clone for TraceReader {
@@ -120,11 +130,18 @@ impl Clone for TraceReader {
             xcr0_: self.xcr0_,
             cpuid_records_: self.cpuid_records_.clone(),
             raw_recs: self.raw_recs.clone(),
+            last_frame_registers: self.last_frame_registers.clone(),
             ticks_semantics_: self.ticks_semantics_,
             monotonic_time_: self.monotonic_time_,
             uuid_: self.uuid_.clone(),
             trace_uses_cpuid_faulting: self.trace_uses_cpuid_faulting,
             preload_thread_locals_recorded_: self.preload_thread_locals_recorded_,
+            host_environment_: self.host_environment_.clone(),
+            chaos_seed_: self.chaos_seed_,
+            argv_: self.argv_.clone(),
+            environ_: self.environ_.clone(),
+            cwd_: self.cwd_.clone(),
+            _version_lock: self._version_lock.clone(),
         }
     }
 }
@@ -181,7 +198,24 @@ impl TraceReader {
         ret.recorded_regs = Registers::new(arch);
         let reg_data = frame.get_registers().unwrap().get_raw().unwrap();
         if !reg_data.is_empty() {
-            ret.recorded_regs.set_from_ptrace_for_arch(arch, reg_data);
+            let raw_regs = if frame.get_registers_delta() {
+                match &self.last_frame_registers {
+                    Some(base) if base.applies_to(ret.tid_, arch, reg_data.len()) => {
+                        base.xor(reg_data)
+                    }
+                    _ => fatal!("Register delta without a matching previous frame"),
+                }
+            } else {
+                reg_data.to_vec()
+            };
+            ret.recorded_regs.set_from_ptrace_for_arch(arch, &raw_regs);
+            self.last_frame_registers = Some(RegistersDeltaBase {
+                tid: ret.tid_,
+                arch,
+                raw: raw_regs,
+            });
+        } else {
+            self.last_frame_registers = None;
         }
         let extra_reg_data = frame.get_extra_registers().unwrap().get_raw().unwrap();
         if !extra_reg_data.is_empty() {
@@ -239,6 +273,7 @@ impl TraceReader {
                 let syscall_ev = ret.ev.syscall_event_mut();
                 syscall_ev.state = from_trace_syscall_state(r.get_state().unwrap());
                 syscall_ev.failed_during_preparation = r.get_failed_during_preparation();
+                syscall_ev.injected_error = r.get_injected_error();
                 let data = r.get_extra();
                 match data.which().unwrap() {
                     frame::event::syscall::extra::None(()) => (),
@@ -477,11 +512,7 @@ impl TraceReader {
             }
             task_event::Exec(r) => {
                 let file_name_ = r.get_file_name().unwrap();
-                let cmd_line_reader = r.get_cmd_line().unwrap();
-                let mut cmd_line_: Vec<OsString> = Vec::new();
-                for cmd in cmd_line_reader.iter() {
-                    cmd_line_.push(OsStr::from_bytes(cmd.unwrap()).to_os_string());
-                }
+                let cmd_line_ = read_cstrings(r.get_cmd_line().unwrap());
                 let exe_base_ = r.get_exe_base().into();
                 te = TraceTaskEvent {
                     variant: TraceTaskEventVariant::Exec(TraceTaskEventExec {
@@ -562,20 +593,40 @@ impl TraceReader {
             let mut saved_raw_recs = Vec::new();
             // self.read_frame() sets self.raw_recs to Vec::new() anyways so this is OK to do
             swap(&mut saved_raw_recs, &mut self.raw_recs);
+            let saved_last_frame_registers = self.last_frame_registers.clone();
             let frame = self.read_frame();
             self.trace_reader_backend.restore_state(Substream::Events);
             self.trace_reader_backend.global_time = saved_time;
             self.raw_recs = saved_raw_recs;
+            self.last_frame_registers = saved_last_frame_registers;
             Some(frame)
         } else {
             None
         }
     }
 
+    /// The index checkpoints recorded with the trace. Empty if the trace
+    /// doesn't have an index.
+    pub fn frame_index(&self) -> Vec<FrameIndexCheckpoint> {
+        self.trace_reader_backend.frame_index()
+    }
+
+    /// Continue reading at `checkpoint`, i.e. the next read_frame() returns
+    /// the frame at `checkpoint.time`.
+    pub fn seek_to_checkpoint(&mut self, checkpoint: &FrameIndexCheckpoint) {
+        if let Err(e) = self.trace_reader_backend.seek_to_checkpoint(checkpoint) {
+            fatal!("Unable to seek to event {}: {:?}", checkpoint.time, e);
+        }
+        self.raw_recs.clear();
+        // The frame at an index point always has full registers.
+        self.last_frame_registers = None;
+    }
+
     /// Restore the state of this to what it was just after
     /// `open()`.
     pub fn rewind(&mut self) {
-        self.trace_reader_backend.rewind()
+        self.trace_reader_backend.rewind();
+        self.last_frame_registers = None;
     }
 
     pub fn uncompressed_bytes(&self) -> u64 {
@@ -600,22 +651,44 @@ impl TraceReader {
         let mut trace_reader_backend: Box<dyn TraceReaderBackend> =
             Box::new(TraceReaderFileBackend::new(maybe_dir));
 
-        let path = trace_reader_backend.version_path();
-        let version_file: File = match File::open(&path) {
-            Err(e) => {
-                if errno() == ENOENT {
-                    let incomplete_path = trace_reader_backend.incomplete_version_path();
-                    if access(incomplete_path.as_os_str(), AccessFlags::F_OK).is_ok() {
+        let mut path = trace_reader_backend.version_path();
+        let incomplete_path = trace_reader_backend.incomplete_version_path();
+        let mut abandoned = false;
+        let version_file: File = match trace_reader_backend.open_version_shared() {
+            // The recorder died, but it may have got far enough to write a
+            // provisional header. If so we can replay what it flushed.
+            Err(e)
+                if e.raw_os_error() == Some(ENOENT)
+                    && trace_reader_backend.state() == TraceDirState::Abandoned =>
+            {
+                match File::open(&incomplete_path) {
+                    Ok(f) => {
+                        path = incomplete_path.clone();
+                        abandoned = true;
+                        f
+                    }
+                    Err(e) => {
                         eprintln!(
                             "\nrd: Trace file {:?} found.\n\
                              rd recording terminated abnormally and the trace is incomplete: {:?}.\n",
                             incomplete_path, e
                         );
-                    } else {
-                        eprintln!(
+                        exit(EX_DATAERR as i32);
+                    }
+                }
+            }
+            Err(e) => {
+                if e.raw_os_error() == Some(ENOENT) {
+                    match trace_reader_backend.state() {
+                        TraceDirState::Recording => eprintln!(
+                            "\nrd: Trace {:?} is still being recorded.\n\
+                             Wait for the recording to finish before using it.\n",
+                            trace_reader_backend.dir()
+                        ),
+                        _ => eprintln!(
                             "\nrd: Trace file {:?} not found. There is no trace there: {:?}.\n",
                             path, e
-                        );
+                        ),
                     }
                 } else {
                     eprintln!("\nrd: Trace file {:?} not readable: {:?}\n", path, e);
@@ -624,6 +697,10 @@ impl TraceReader {
             }
             Ok(f) => f,
         };
+        let version_lock = match version_file.try_clone() {
+            Ok(f) => Rc::new(f),
+            Err(e) => fatal!("Could not duplicate {:?}: {:?}", path, e),
+        };
         let mut version_str = String::new();
         let mut buf_reader = BufReader::new(version_file);
         let res = buf_reader.read_line(&mut version_str);
@@ -658,6 +735,14 @@ impl TraceReader {
         let maybe_res = read_message(&mut buf_reader, ReaderOptions::new());
         let header_msg = match maybe_res {
             Ok(res) => res,
+            Err(e) if abandoned => {
+                eprintln!(
+                    "\nrd: Trace file {:?} found.\n\
+                     rd recording terminated abnormally before the trace header was written: {:?}.\n",
+                    path, e
+                );
+                exit(EX_DATAERR as i32);
+            }
             Err(e) => {
                 fatal!("Could not read version file {:?}: {:?}", path, e);
             }
@@ -698,6 +783,34 @@ impl TraceReader {
             fatal!("Invalid UUID length");
         }
         uuid_.bytes = uuid_from_trace.try_into().unwrap();
+        let host_environment_ = HostEnvironment::read_from(header.get_host_environment().unwrap());
+        let chaos_seed_ = if header.get_chaos_mode() {
+            Some(header.get_chaos_seed())
+        } else {
+            None
+        };
+        let argv_ = read_cstrings(header.get_argv().unwrap());
+        let environ_ = read_cstrings(header.get_environ().unwrap());
+        let cwd_ = OsStr::from_bytes(header.get_cwd().unwrap()).to_os_string();
+
+        if abandoned {
+            match trace_reader_backend.truncate_to_last_checkpoint() {
+                Some(time) => eprintln!(
+                    "\nrd: Recording of trace {:?} terminated abnormally.\n\
+                     Only the events before event {} were completely written; replay stops there.\n",
+                    trace_reader_backend.dir(),
+                    time
+                ),
+                None => {
+                    eprintln!(
+                        "\nrd: Recording of trace {:?} terminated abnormally before anything\n\
+                         could be replayed.\n",
+                        trace_reader_backend.dir()
+                    );
+                    exit(EX_DATAERR as i32);
+                }
+            }
+        }
 
         TraceReader {
             trace_reader_backend,
@@ -707,8 +820,15 @@ impl TraceReader {
             uuid_,
             trace_uses_cpuid_faulting,
             preload_thread_locals_recorded_,
+            host_environment_,
+            chaos_seed_,
+            argv_,
+            environ_,
+            cwd_,
+            _version_lock: version_lock,
             monotonic_time_: 0.0,
             raw_recs: vec![],
+            last_frame_registers: None,
         }
     }
 
@@ -745,6 +865,33 @@ impl TraceReader {
         self.preload_thread_locals_recorded_
     }
 
+    /// The machine the trace was recorded on. Empty for older traces.
+    pub fn host_environment(&self) -> &HostEnvironment {
+        &self.host_environment_
+    }
+
+    /// The seed of a chaos mode recording, or None if chaos mode was off.
+    pub fn chaos_seed(&self) -> Option<u64> {
+        self.chaos_seed_
+    }
+
+    /// The command line that was recorded. Empty for older traces.
+    pub fn argv(&self) -> &[OsString] {
+        &self.argv_
+    }
+
+    /// The environment the recorded program was started with. Empty for older
+    /// traces.
+    pub fn environ(&self) -> &[OsString] {
+        &self.environ_
+    }
+
+    /// The directory the recorded program was started in. Empty for older
+    /// traces.
+    pub fn cwd(&self) -> &OsStr {
+        &self.cwd_
+    }
+
     pub fn uuid(&self) -> &TraceUuid {
         &self.uuid_
     }
@@ -836,6 +983,12 @@ fn i32_to_tid(tid: i32) -> pid_t {
     tid
 }
 
+fn read_cstrings(list: data_list::Reader) -> Vec<OsString> {
+    list.iter()
+        .map(|s| OsStr::from_bytes(s.unwrap()).to_os_string())
+        .collect()
+}
+
 pub(super) fn resolve_trace_name<T: AsRef<Path>>(maybe_trace_name: Option<T>) -> PathBuf {
     if maybe_trace_name.is_none() {
         return latest_trace_symlink();
@@ -891,6 +1044,28 @@ pub(super) trait TraceReaderBackend: DerefMut<Target = TraceStream> {
     fn skip(&mut self, substream: Substream, size: usize)
         -> Result<(), Box<dyn std::error::Error>>;
 
+    /// The index checkpoints recorded with the trace, in increasing time
+    /// order. Empty if the backend can't seek.
+    fn frame_index(&self) -> Vec<FrameIndexCheckpoint> {
+        Vec::new()
+    }
+
+    /// Cut every substream off at the last index checkpoint that was written
+    /// out completely and return its time. For traces whose recording was
+    /// killed. `None` if there is no such checkpoint or the backend can't do
+    /// this.
+    fn truncate_to_last_checkpoint(&mut self) -> Option<FrameTime> {
+        None
+    }
+
+    /// Position every substream at `checkpoint`.
+    fn seek_to_checkpoint(
+        &mut self,
+        _checkpoint: &FrameIndexCheckpoint,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("This trace backend does not support seeking".into())
+    }
+
     fn tick_time(&mut self) {
         self.global_time += 1;
     }
@@ -1,12 +1,21 @@
-use crate::trace::{
-    compressed_reader::{CompressedReader, CompressedReaderState},
-    trace_reader::{resolve_trace_name, TraceReaderBackend},
-    trace_stream::{substreams_data, Substream, TraceStream},
+use crate::{
+    log::LogLevel::LogWarn,
+    trace::{
+        compressed_reader::{CompressedReader, CompressedReaderState},
+        trace_frame::FrameTime,
+        trace_reader::{resolve_trace_name, TraceReaderBackend},
+        trace_stream::{
+            substreams_data, FrameIndexCheckpoint, Substream, TraceStream, SUBSTREAM_COUNT,
+        },
+    },
+    util::u8_slice_mut,
 };
 use capnp::{message, message::ReaderOptions, serialize, serialize_packed::read_message};
 use std::{
     collections::HashMap,
-    io::Read,
+    fs,
+    io::{self, Read},
+    mem::size_of,
     ops::{Deref, DerefMut},
     path::Path,
 };
@@ -77,6 +86,76 @@ impl TraceReaderBackend for TraceReaderFileBackend {
         }
     }
 
+    fn frame_index(&self) -> Vec<FrameIndexCheckpoint> {
+        let path = self.frame_index_path();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            // Short traces don't have an index.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                log!(LogWarn, "Unable to read {:?}: {:?}", path, e);
+                return Vec::new();
+            }
+        };
+        let record_size = size_of::<FrameIndexCheckpoint>();
+        if data.len() % record_size != 0 {
+            // The recording was killed while appending to the index.
+            log!(
+                LogWarn,
+                "Ignoring truncated record at the end of {:?}",
+                path
+            );
+        }
+        data.chunks_exact(record_size)
+            .map(|record| {
+                let mut checkpoint = FrameIndexCheckpoint::default();
+                u8_slice_mut(&mut checkpoint).copy_from_slice(record);
+                checkpoint
+            })
+            .filter(|c| self.truncated_at.map_or(true, |t| c.time <= t))
+            .collect()
+    }
+
+    fn truncate_to_last_checkpoint(&mut self) -> Option<FrameTime> {
+        let mut available = [0u64; SUBSTREAM_COUNT];
+        for s in substreams_data() {
+            available[s.substream as usize] = self
+                .reader(s.substream)
+                .complete_uncompressed_bytes()
+                .ok()?;
+        }
+        let checkpoint = self.frame_index().into_iter().rev().find(|c| {
+            c.offsets
+                .iter()
+                .zip(available.iter())
+                .all(|(offset, len)| offset <= len)
+        })?;
+        for s in substreams_data() {
+            if let Err(e) = self
+                .reader_mut(s.substream)
+                .truncate(checkpoint.offsets[s.substream as usize])
+            {
+                log!(LogWarn, "Unable to truncate {:?}: {:?}", s.name, e);
+                return None;
+            }
+        }
+        self.truncated_at = Some(checkpoint.time);
+        self.truncated_at
+    }
+
+    fn seek_to_checkpoint(
+        &mut self,
+        checkpoint: &FrameIndexCheckpoint,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for s in substreams_data() {
+            self.reader_mut(s.substream)
+                .seek(checkpoint.offsets[s.substream as usize])?;
+        }
+        // The global time is ticked as each frame is read.
+        self.global_time = checkpoint.time - 1;
+        Ok(())
+    }
+
     fn discard_state(&mut self, substream: Substream) {
         let cr = self.reader_mut(substream);
         cr.saved_state.take();
@@ -124,6 +203,8 @@ impl DerefMut for TraceReaderFileBackend {
 pub struct TraceReaderFileBackend {
     trace_stream: TraceStream,
     readers: HashMap<Substream, CompressedReader>,
+    /// Set by truncate_to_last_checkpoint().
+    truncated_at: Option<FrameTime>,
 }
 
 impl TraceReaderFileBackend {
@@ -144,6 +225,7 @@ impl TraceReaderFileBackend {
         TraceReaderFileBackend {
             trace_stream,
             readers,
+            truncated_at: None,
         }
     }
 
@@ -1,26 +1,50 @@
 use crate::{
     kernel_abi::SupportedArch,
+    log::LogLevel::LogWarn,
     remote_ptr::{RemotePtr, Void},
     taskish_uid::TaskUid,
-    trace::trace_frame::FrameTime,
-    trace_capnp::Arch as TraceArch,
-    util::{dir_exists, ensure_dir, get_num_cpus, real_path},
+    trace::{
+        compressed_reader::CompressedReader,
+        compressed_writer::{CompressedWriter, Compression, Sync},
+        trace_frame::FrameTime,
+    },
+    trace_capnp::{m_map, Arch as TraceArch},
+    util::{dir_exists, ensure_dir, get_num_cpus, real_path, u8_slice, u8_slice_mut},
+};
+use capnp::{
+    message::{self, ReaderOptions},
+    serialize_packed::{read_message, write_message},
 };
 use libc::{pid_t, EEXIST};
-use nix::{errno::errno, sys::stat::Mode, unistd::mkdir};
+use nix::{
+    errno::{errno, Errno},
+    fcntl::{flock, FlockArg},
+    sys::stat::Mode,
+    unistd::{access, mkdir, AccessFlags},
+};
 use std::{
     cmp::min,
+    collections::HashMap,
     env,
     ffi::{OsStr, OsString},
-    io::Write,
-    os::unix::ffi::{OsStrExt, OsStringExt},
+    fs::{self, File},
+    io::{self, BufRead, Read, Write},
+    mem::size_of,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        io::AsRawFd,
+    },
     path::{Path, PathBuf},
 };
 
-pub const TRACE_VERSION: u32 = 85;
+pub const TRACE_VERSION: u32 = 88;
 
 pub const SUBSTREAM_COUNT: usize = 4;
 
+/// Every this many frames the file backend records where each substream
+/// stands, so read-only tools can start scanning from the middle of a trace.
+pub const FRAME_INDEX_INTERVAL: FrameTime = 50_000;
+
 /// Update `substreams` and TRACE_VERSION when you update this list.
 #[repr(usize)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -104,6 +128,19 @@ impl Default for MappedDataSource {
     }
 }
 
+/// Where a trace directory is in the life cycle described on TraceWriter.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TraceDirState {
+    /// `version` exists: the trace is complete and ready for replay.
+    Complete,
+    /// `incomplete` exists and a recorder holds its lock.
+    Recording,
+    /// `incomplete` exists but nobody holds its lock, so the recorder died.
+    Abandoned,
+    /// There is no trace here, or recording hasn't got far enough to tell.
+    Missing,
+}
+
 /// TraceStream stores all the data common to both recording and
 /// replay.  TraceWriter deals with recording-specific logic, and
 /// TraceReader handles replay-specific details.
@@ -128,6 +165,169 @@ impl TraceStream {
         self.global_time
     }
 
+    /// Work out whether this trace is complete, still being recorded or was
+    /// abandoned by a recorder that died.
+    pub fn state(&self) -> TraceDirState {
+        if access(self.version_path().as_os_str(), AccessFlags::F_OK).is_ok() {
+            return TraceDirState::Complete;
+        }
+        let incomplete = match File::open(self.incomplete_version_path()) {
+            Ok(f) => f,
+            Err(_) => return TraceDirState::Missing,
+        };
+        // The recorder holds an exclusive lock until it's done. Our lock goes
+        // away when `incomplete` is closed.
+        match flock(incomplete.as_raw_fd(), FlockArg::LockSharedNonblock) {
+            Err(Errno::EWOULDBLOCK) => TraceDirState::Recording,
+            _ => TraceDirState::Abandoned,
+        }
+    }
+
+    /// Open the `version` file and take a shared lock on it. Any number of
+    /// readers can hold the lock at once. Keep the returned file open for as
+    /// long as the trace is in use.
+    ///
+    /// If the recorder has only just renamed `incomplete` to `version`, this
+    /// waits for it to drop its exclusive lock.
+    pub(super) fn open_version_shared(&self) -> io::Result<File> {
+        let version_file = File::open(self.version_path())?;
+        loop {
+            match flock(version_file.as_raw_fd(), FlockArg::LockShared) {
+                Ok(()) => return Ok(version_file),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(io::Error::from(e)),
+            }
+        }
+    }
+
+    /// Take an exclusive lock on the `version` file, for tools that rewrite
+    /// the trace. Fails with EWOULDBLOCK while the trace is being replayed.
+    /// Keep the returned file open until done.
+    pub fn lock_exclusive(&self) -> io::Result<File> {
+        let version_file = File::open(self.version_path())?;
+        flock(version_file.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+        Ok(version_file)
+    }
+
+    /// Rewrite the `mmaps` substream, letting `f` move the backing file of
+    /// every mapping whose data comes from a file. `f` gets the recorded
+    /// name, which is either absolute or relative to the trace directory, and
+    /// what was recorded about the file. It returns the new name or `None`
+    /// to leave the mapping alone. Moved
+    /// mappings forget the stat data of the original file, which the new
+    /// file won't match, so replay doesn't try to validate them.
+    ///
+    /// The frame index is updated to match. Only the file backend is
+    /// supported, and the caller must hold `lock_exclusive()`.
+    pub fn rewrite_mmap_sources(
+        &self,
+        f: &mut dyn FnMut(&[u8], &RecordedFileStat) -> io::Result<Option<Vec<u8>>>,
+    ) -> io::Result<()> {
+        let path = self.path(Substream::Mmaps);
+        if !Path::new(&path).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{:?} not found; only file-backed traces can be rewritten",
+                    path
+                ),
+            ));
+        }
+        let mut new_path = path.clone();
+        new_path.push(".new");
+        let data = substream(Substream::Mmaps);
+        let mut reader = PositionedReader {
+            inner: CompressedReader::new(&path),
+            pos: 0,
+        };
+        let mut writer = CompressedWriter::new(
+            &new_path,
+            data.block_size,
+            data.threads,
+            Compression::default(),
+        );
+        // Where each message starts, in the old and in the new stream.
+        let mut new_offsets: HashMap<u64, u64> = HashMap::new();
+        while !reader.inner.at_end() {
+            new_offsets.insert(reader.pos, writer.uncompressed_pos());
+            let map_msg = read_message(&mut reader, ReaderOptions::new()).map_err(capnp_error)?;
+            let map = map_msg.get_root::<m_map::Reader>().map_err(capnp_error)?;
+            let mut out_msg = message::Builder::new_default();
+            out_msg.set_root(map).map_err(capnp_error)?;
+            let source = map
+                .get_source()
+                .which()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+            if let m_map::source::File(file) = source {
+                let name = file.get_backing_file_name().map_err(capnp_error)?;
+                let stat = RecordedFileStat {
+                    inode: map.get_inode(),
+                    size: map.get_stat_size(),
+                    mtime: map.get_stat_m_time(),
+                    has_stat: map.get_stat_mode() != 0
+                        || map.get_stat_uid() != 0
+                        || map.get_stat_gid() != 0
+                        || map.get_stat_m_time() != 0,
+                };
+                if let Some(new_name) = f(name, &stat)? {
+                    let mut out_map = out_msg.get_root::<m_map::Builder>().map_err(capnp_error)?;
+                    out_map
+                        .reborrow()
+                        .get_source()
+                        .init_file()
+                        .set_backing_file_name(&new_name);
+                    out_map.set_stat_mode(0);
+                    out_map.set_stat_uid(0);
+                    out_map.set_stat_gid(0);
+                    out_map.set_stat_m_time(0);
+                }
+            }
+            write_message(&mut writer, &out_msg).map_err(capnp_error)?;
+        }
+        new_offsets.insert(reader.pos, writer.uncompressed_pos());
+        writer.close(Some(Sync::Sync));
+        if !writer.good() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unable to write {:?}", new_path),
+            ));
+        }
+        fs::rename(&new_path, &path)?;
+        self.rewrite_frame_index(Substream::Mmaps, &new_offsets)
+    }
+
+    /// Translate the offsets of substream `s` in the frame index. A
+    /// checkpoint that isn't in `new_offsets` can't be trusted anymore, so
+    /// the index is dropped in that case; it only speeds up some tools.
+    fn rewrite_frame_index(&self, s: Substream, new_offsets: &HashMap<u64, u64>) -> io::Result<()> {
+        let path = self.frame_index_path();
+        let mut index = match fs::read(&path) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for record in index.chunks_mut(size_of::<FrameIndexCheckpoint>()) {
+            let mut checkpoint = FrameIndexCheckpoint::default();
+            if record.len() != size_of::<FrameIndexCheckpoint>() {
+                log!(LogWarn, "Removing truncated frame index {:?}", path);
+                return fs::remove_file(&path);
+            }
+            u8_slice_mut(&mut checkpoint).copy_from_slice(record);
+            match new_offsets.get(&checkpoint.offsets[s as usize]) {
+                Some(&offset) => checkpoint.offsets[s as usize] = offset,
+                None => {
+                    log!(LogWarn, "Removing stale frame index {:?}", path);
+                    return fs::remove_file(&path);
+                }
+            }
+            record.copy_from_slice(u8_slice(&checkpoint));
+        }
+        let mut new_path = path.clone();
+        new_path.push(".new");
+        fs::write(&new_path, &index)?;
+        fs::rename(&new_path, &path)
+    }
+
     pub fn file_data_clone_file_name(&self, tuid: TaskUid) -> OsString {
         let mut ss: Vec<u8> = Vec::from(self.trace_dir.as_bytes());
         write!(ss, "/cloned_data_{}_{}", tuid.tid(), tuid.serial()).unwrap();
@@ -163,6 +363,15 @@ impl TraceStream {
         OsString::from_vec(version_path)
     }
 
+    /// Return the path of the frame index written by the file backend. Traces
+    /// recorded with another backend (or by older versions of rd) don't have
+    /// one.
+    pub(super) fn frame_index_path(&self) -> OsString {
+        let mut index_path: Vec<u8> = self.trace_dir.clone().into_vec();
+        index_path.extend_from_slice(b"/frame_index");
+        OsString::from_vec(index_path)
+    }
+
     /// While the trace is being built, the version file is stored under this name.
     /// When the trace is closed we rename it to the correct name. This lets us
     /// detect incomplete traces.
@@ -188,6 +397,56 @@ pub struct TraceStream {
     pub(super) global_time: FrameTime,
 }
 
+/// A substream reader that knows how far into the uncompressed stream it is.
+struct PositionedReader {
+    inner: CompressedReader,
+    pos: u64,
+}
+
+impl Read for PositionedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nread = self.inner.read(buf)?;
+        self.pos += nread as u64;
+        Ok(nread)
+    }
+}
+
+impl BufRead for PositionedReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+fn capnp_error(e: capnp::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// The uncompressed position of every substream just before the frame at
+/// `time` was written.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FrameIndexCheckpoint {
+    pub time: FrameTime,
+    pub offsets: [u64; SUBSTREAM_COUNT],
+}
+
+/// What the recording knew about the file backing a mapping, see
+/// `TraceStream::rewrite_mmap_sources()`.
+pub struct RecordedFileStat {
+    pub inode: u64,
+    pub size: i64,
+    /// Seconds since the epoch.
+    pub mtime: i64,
+    /// False if the file wasn't stat'ed during recording (e.g. it was copied
+    /// into the trace), in which case the other fields mean nothing.
+    pub has_stat: bool,
+}
+
 #[derive(Clone, Default)]
 pub struct RawDataMetadata {
     pub addr: RemotePtr<Void>,
@@ -195,6 +454,30 @@ pub struct RawDataMetadata {
     pub rec_tid: pid_t,
 }
 
+/// The raw registers of the last frame written or read. The next frame may
+/// store its registers as a delta against these if it belongs to the same
+/// task.
+#[derive(Clone)]
+pub struct RegistersDeltaBase {
+    pub tid: pid_t,
+    pub arch: SupportedArch,
+    pub raw: Vec<u8>,
+}
+
+impl RegistersDeltaBase {
+    pub fn applies_to(&self, tid: pid_t, arch: SupportedArch, len: usize) -> bool {
+        self.tid == tid && self.arch == arch && self.raw.len() == len
+    }
+
+    /// Most registers don't change between consecutive events of a task, so
+    /// XOR-ing against the previous frame leaves mostly zero words, which the
+    /// packed capnp encoding drops. The same operation undoes the delta.
+    pub fn xor(&self, raw: &[u8]) -> Vec<u8> {
+        debug_assert_eq!(self.raw.len(), raw.len());
+        self.raw.iter().zip(raw).map(|(a, b)| a ^ b).collect()
+    }
+}
+
 pub struct TraceRemoteFd {
     pub tid: pid_t,
     pub fd: i32,
@@ -322,7 +605,7 @@ fn find_rd_trace_dir() -> PathBuf {
     cached_dir
 }
 
-pub(super) fn trace_save_dir() -> PathBuf {
+pub fn trace_save_dir() -> PathBuf {
     let maybe_output_dir = env::var_os("_RD_TRACE_DIR");
     match maybe_output_dir {
         Some(dir) if !dir.is_empty() => dir.into(),
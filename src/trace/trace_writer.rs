@@ -25,10 +25,12 @@ use crate::{
         task::record_task::RecordTask,
     },
     trace::{
+        compressed_writer::Compression,
+        host_environment::HostEnvironment,
         trace_frame::FrameTime,
         trace_stream::{
-            latest_trace_symlink, to_trace_arch, RawDataMetadata, Substream, TraceRemoteFd,
-            TraceStream, TRACE_VERSION,
+            latest_trace_symlink, to_trace_arch, RawDataMetadata, RegistersDeltaBase, Substream,
+            TraceRemoteFd, TraceStream, FRAME_INDEX_INTERVAL, TRACE_VERSION,
         },
         trace_task_event::{TraceTaskEvent, TraceTaskEventVariant},
     },
@@ -39,11 +41,11 @@ use crate::{
     },
     util::{
         all_cpuid_records, copy_file, monotonic_now_sec, probably_not_interactive,
-        should_copy_mmap_region, write_all, xcr0, CPUIDRecord,
+        pwrite_all_fallible, should_copy_mmap_region, write_all, xcr0, CPUIDRecord,
     },
 };
 use capnp::{message, serialize_packed::write_message};
-use libc::{dev_t, ino_t, ioctl, pid_t, EEXIST, STDOUT_FILENO};
+use libc::{dev_t, ino_t, ioctl, off_t, pid_t, EEXIST, STDOUT_FILENO};
 use nix::{
     errno::{errno, Errno},
     fcntl::{flock, readlink, FlockArg::LockExclusiveNonblock, OFlag},
@@ -51,25 +53,30 @@ use nix::{
         mman::{MapFlags, ProtFlags},
         stat::Mode,
     },
-    unistd::unlink,
+    unistd::{ftruncate, unlink},
 };
 use std::{
     collections::HashMap,
     convert::TryInto,
     ffi::{OsStr, OsString},
-    fs::{hard_link, rename, File},
+    fs::{hard_link, rename},
     io::Write,
     mem::size_of,
     ops::{Deref, DerefMut},
     os::unix::{
         ffi::{OsStrExt, OsStringExt},
         fs::symlink,
-        io::FromRawFd,
     },
     path::Path,
     slice,
+    time::{Duration, Instant},
 };
 
+/// How often the trace is flushed while recording. A recording that gets
+/// killed can be replayed up to the last flush. See
+/// `TraceWriter::maybe_flush()`.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum RecordInTrace {
     DontRecordInTrace,
@@ -107,6 +114,9 @@ pub enum CloseStatus {
 /// -- At the end of trace recording, rd renames `incomplete` to `version`.
 /// At this point the trace is complete and ready to replay.
 /// -- rd releases its flock() lock on `version`.
+/// -- Every TraceReader takes a shared flock() lock on `version` and holds it
+/// until it's dropped, so one trace can be replayed by many rd processes at
+/// once.
 ///
 /// Thus:
 /// -- If the trace directory contains the file `version` the trace is valid
@@ -116,13 +126,16 @@ pub enum CloseStatus {
 /// is messing with us).
 /// -- If the trace directory contains the file `incomplete`, that file
 /// does not have an exclusive `flock()` lock on it, and the file is non-empty,
-/// rd must have died before the recording was complete.
+/// rd must have died before the recording was complete. If the file holds a
+/// provisional header, the trace can be replayed up to the last flush.
 /// -- If the trace directory contains the file `incomplete`, that file
 /// does not have an exclusive `flock()` lock on it, and the file is empty,
 /// rd has just started recording (or perhaps died during startup).
 /// -- If the trace directory does not contain the file `incomplete`,
 /// rd has just started recording (or perhaps died during startup) (or perhaps
 /// that isn't a trace directory at all).
+///
+/// TraceStream::state() tells these cases apart.
 pub struct TraceWriter {
     trace_writer_backend: Box<dyn TraceWriterBackend>,
     /// Files that have already been mapped without being copied to the trace,
@@ -132,6 +145,8 @@ pub struct TraceWriter {
     /// are immutable.
     files_assumed_immutable: HashMap<(dev_t, ino_t), OsString>,
     raw_recs: Vec<RawDataMetadata>,
+    /// Registers of the last frame written, if it had any.
+    last_frame_registers: Option<RegistersDeltaBase>,
     cpuid_records: Vec<CPUIDRecord>,
     ticks_semantics_: TicksSemantics,
     /// Keep the 'incomplete' (later renamed to 'version') file open until we
@@ -140,6 +155,17 @@ pub struct TraceWriter {
     mmap_count: u32,
     has_cpuid_faulting_: bool,
     supports_file_data_cloning_: bool,
+    chaos_seed: Option<u64>,
+    /// See `set_command()`.
+    argv: Vec<OsString>,
+    environ: Vec<OsString>,
+    cwd: OsString,
+    /// When the trace was last flushed, see `SYNC_INTERVAL`.
+    last_sync: Instant,
+    /// The time of the frame after the last flush.
+    flushed_upto: FrameTime,
+    /// The next frame starts an index checkpoint.
+    next_frame_is_index_point: bool,
 }
 
 impl TraceWriter {
@@ -189,9 +215,31 @@ impl TraceWriter {
         self.raw_recs.clear();
         frame.set_arch(to_trace_arch(t.arch()));
         {
-            if let Some(registers) = maybe_registers {
-                let raw_regs = registers.get_ptrace_for_self_arch();
-                frame.reborrow().init_registers().set_raw(raw_regs);
+            match maybe_registers {
+                Some(registers) => {
+                    let raw_regs = registers.get_ptrace_for_self_arch();
+                    // Frames at index points must be readable on their own.
+                    let is_index_point = self.next_frame_is_index_point;
+                    match &self.last_frame_registers {
+                        Some(base)
+                            if !is_index_point
+                                && base.applies_to(t.tid(), t.arch(), raw_regs.len()) =>
+                        {
+                            frame
+                                .reborrow()
+                                .init_registers()
+                                .set_raw(&base.xor(raw_regs));
+                            frame.set_registers_delta(true);
+                        }
+                        _ => frame.reborrow().init_registers().set_raw(raw_regs),
+                    }
+                    self.last_frame_registers = Some(RegistersDeltaBase {
+                        tid: t.tid(),
+                        arch: t.arch(),
+                        raw: raw_regs.to_vec(),
+                    });
+                }
+                None => self.last_frame_registers = None,
             }
         }
         {
@@ -258,6 +306,7 @@ impl TraceWriter {
                     syscall.set_number(syscall_num);
                     syscall.set_state(to_trace_syscall_state(e.state));
                     syscall.set_failed_during_preparation(e.failed_during_preparation);
+                    syscall.set_injected_error(e.injected_error);
                     let mut data = syscall.init_extra();
                     match e.write_offset {
                         Some(offset) => {
@@ -296,7 +345,43 @@ impl TraceWriter {
             fatal!("Unable to write events: {:?}", e)
         }
 
-        self.trace_writer_backend.tick_time()
+        self.trace_writer_backend.tick_time();
+        // All the mmaps and raw data written from here on belong to the next
+        // frame, so this is a point a reader can start from.
+        self.trace_writer_backend.note_frame_boundary();
+        self.next_frame_is_index_point = false;
+        if self.time() % FRAME_INDEX_INTERVAL == 0 {
+            self.record_index_checkpoint();
+        }
+        self.maybe_flush();
+    }
+
+    /// flush() if it's been `SYNC_INTERVAL` since the last one. Called after
+    /// every frame and whenever all tracees are blocked.
+    pub fn maybe_flush(&mut self) {
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Start writing out everything up to the last frame, with an index
+    /// checkpoint there, so a replay of the trace can get that far even if
+    /// the recording gets killed. Cheap if no frame was written since the
+    /// last call.
+    pub fn flush(&mut self) {
+        self.last_sync = Instant::now();
+        if self.flushed_upto == self.time() {
+            return;
+        }
+        self.flushed_upto = self.time();
+        self.record_index_checkpoint();
+        self.trace_writer_backend.flush();
+    }
+
+    fn record_index_checkpoint(&mut self) {
+        // Frames at index points must be readable on their own.
+        self.next_frame_is_index_point = true;
+        self.trace_writer_backend.record_index_checkpoint();
     }
 
     /// Write mapped-region record to the trace.
@@ -499,6 +584,7 @@ impl TraceWriter {
         bind_to_cpu: Option<u32>,
         output_trace_dir: Option<&OsStr>,
         ticks_semantics_: TicksSemantics,
+        compression: Compression,
     ) -> TraceWriter {
         #[cfg(feature = "rocksdb")]
         let mut tw = TraceWriter {
@@ -515,13 +601,22 @@ impl TraceWriter {
                     file_name,
                     output_trace_dir,
                     bind_to_cpu,
+                    compression,
                 )),
             },
             files_assumed_immutable: Default::default(),
             raw_recs: vec![],
+            last_frame_registers: None,
             cpuid_records: vec![],
             version_fd: ScopedFd::new(),
             supports_file_data_cloning_: false,
+            chaos_seed: None,
+            argv: vec![],
+            environ: vec![],
+            cwd: OsString::new(),
+            last_sync: Instant::now(),
+            flushed_upto: 0,
+            next_frame_is_index_point: false,
         };
 
         #[cfg(not(feature = "rocksdb"))]
@@ -533,12 +628,21 @@ impl TraceWriter {
                 file_name,
                 output_trace_dir,
                 bind_to_cpu,
+                compression,
             )),
             files_assumed_immutable: Default::default(),
             raw_recs: vec![],
+            last_frame_registers: None,
             cpuid_records: vec![],
             version_fd: ScopedFd::new(),
             supports_file_data_cloning_: false,
+            chaos_seed: None,
+            argv: vec![],
+            environ: vec![],
+            cwd: OsString::new(),
+            last_sync: Instant::now(),
+            flushed_upto: 0,
+            next_frame_is_index_point: false,
         };
 
         let ver_path = tw.trace_stream().incomplete_version_path();
@@ -601,6 +705,20 @@ impl TraceWriter {
         tw
     }
 
+    /// Record that this is a chaos mode trace whose scheduling decisions were
+    /// drawn from `seed`.
+    pub fn set_chaos_seed(&mut self, seed: u64) {
+        self.chaos_seed = Some(seed);
+    }
+
+    /// Record the command line, environment and working directory of the
+    /// recorded program in the trace header.
+    pub fn set_command(&mut self, argv: &[OsString], environ: &[OsString], cwd: &OsStr) {
+        self.argv = argv.to_owned();
+        self.environ = environ.to_owned();
+        self.cwd = cwd.to_owned();
+    }
+
     /// Called after the calling thread is actually bound to `bind_to_cpu`.
     pub fn setup_cpuid_records(
         &mut self,
@@ -621,6 +739,13 @@ impl TraceWriter {
         }
     }
 
+    /// Write a header to `incomplete` now, with the ok flag clear. If the
+    /// recording gets killed, the trace can then still be replayed up to the
+    /// last flush. close() replaces it.
+    pub fn write_provisional_header(&mut self, uuid: TraceUuid) {
+        self.write_header(CloseStatus::CloseError, Some(uuid));
+    }
+
     /// Call close() on trace writer backend and write a version file.
     ///
     /// Normally this will be called by RecordSession close_trace_writer().
@@ -628,7 +753,20 @@ impl TraceWriter {
     /// If `uuid` is `None` then a uuid will be generated for you.
     pub fn close(&mut self, status: CloseStatus, maybe_uuid: Option<TraceUuid>) {
         self.trace_writer_backend.close();
+        self.write_header(status, maybe_uuid);
 
+        let incomplete_path = self.trace_stream().incomplete_version_path();
+        let path = self.trace_stream().version_path();
+        if let Err(e) = rename(&incomplete_path, &path) {
+            fatal!("Unable to create version file {:?}: {:?}", path, e);
+        }
+
+        self.version_fd.close();
+    }
+
+    /// Write the header after the version line of `incomplete`, replacing any
+    /// header written before.
+    fn write_header(&mut self, status: CloseStatus, maybe_uuid: Option<TraceUuid>) {
         let mut header_msg = message::Builder::new_default();
         let mut header = header_msg.init_root::<header::Builder>();
         // DIFF NOTE: In rd the bound cpu is an Option<u32>. In rr it is signed.
@@ -662,22 +800,32 @@ impl TraceWriter {
             }
         }
         header.set_ok(status == CloseStatus::CloseOk);
-        let mut f = unsafe { File::from_raw_fd(self.version_fd.as_raw()) };
-        if let Err(e) = write_message(&mut f, &header_msg) {
+        if let Some(seed) = self.chaos_seed {
+            header.set_chaos_mode(true);
+            header.set_chaos_seed(seed);
+        }
+        let mut argv = header.reborrow().init_argv(self.argv.len() as u32);
+        for (i, arg) in self.argv.iter().enumerate() {
+            argv.set(i as u32, arg.as_bytes());
+        }
+        let mut environ = header.reborrow().init_environ(self.environ.len() as u32);
+        for (i, var) in self.environ.iter().enumerate() {
+            environ.set(i as u32, var.as_bytes());
+        }
+        header.set_cwd(self.cwd.as_bytes());
+        HostEnvironment::capture().write_to(header.reborrow().init_host_environment());
+
+        let mut buf = Vec::new();
+        write_message(&mut buf, &header_msg).unwrap();
+        let offset = format!("{}\n", TRACE_VERSION).len();
+        let fd = self.version_fd.as_raw();
+        let written = pwrite_all_fallible(fd, &buf, offset as isize);
+        if written != Ok(buf.len()) || ftruncate(fd, (offset + buf.len()) as off_t).is_err() {
             fatal!(
-                "Unable to write {:?}: {:?}",
-                self.trace_stream().incomplete_version_path(),
-                e
+                "Unable to write {:?}",
+                self.trace_stream().incomplete_version_path()
             );
         }
-
-        let incomplete_path = self.trace_stream().incomplete_version_path();
-        let path = self.trace_stream().version_path();
-        if let Err(e) = rename(&incomplete_path, &path) {
-            fatal!("Unable to create version file {:?}: {:?}", path, e);
-        }
-
-        self.version_fd.close();
     }
 
     /// We got far enough into recording that we should set this as the latest
@@ -904,6 +1052,20 @@ pub(super) trait TraceWriterBackend: DerefMut<Target = TraceStream> {
 
     fn close(&mut self);
 
+    /// Remember where every substream stands right after a frame was
+    /// written.
+    fn note_frame_boundary(&mut self) {}
+
+    /// Record the position of every substream noted by the last
+    /// `note_frame_boundary()`, see `FrameIndexCheckpoint`. Backends that
+    /// can't seek ignore this.
+    fn record_index_checkpoint(&mut self) {}
+
+    /// Start writing out everything buffered so far, so a recording that is
+    /// killed loses as little as possible. Backends that don't buffer ignore
+    /// this.
+    fn flush(&mut self) {}
+
     fn tick_time(&mut self) {
         self.global_time += 1;
     }
@@ -1,9 +1,18 @@
-use crate::trace::{
-    compressed_writer::CompressedWriter,
-    trace_stream::{make_trace_dir, substream, substreams_data, Substream, TraceStream},
-    trace_writer::TraceWriterBackend,
+use crate::{
+    log::LogLevel::LogWarn,
+    scoped_fd::ScopedFd,
+    trace::{
+        compressed_writer::{CompressedWriter, Compression},
+        trace_stream::{
+            make_trace_dir, substream, substreams_data, FrameIndexCheckpoint, Substream,
+            TraceStream, SUBSTREAM_COUNT,
+        },
+        trace_writer::TraceWriterBackend,
+    },
+    util::{u8_slice, write_all},
 };
 use capnp::{message, serialize_packed::write_message};
+use nix::{fcntl::OFlag, sys::stat::Mode};
 use std::{
     collections::HashMap,
     ffi::{OsStr, OsString},
@@ -18,6 +27,13 @@ pub struct TraceWriterFileBackend {
     /// compile time. This could be a [CompressedWriter; SUBSTREAM_COUNT] or a Box of
     /// the same.
     writers: HashMap<Substream, CompressedWriter>,
+    /// Checkpoints are appended as they're recorded, so a trace whose
+    /// recording was killed still has its index. Opened with the first one.
+    frame_index_fd: ScopedFd,
+    frame_index_failed: bool,
+    /// Where every substream stood after the last frame was written. See
+    /// `note_frame_boundary()`.
+    frame_boundary: FrameIndexCheckpoint,
 }
 
 impl Deref for TraceWriterFileBackend {
@@ -39,19 +55,25 @@ impl TraceWriterFileBackend {
         file_name: &OsStr,
         output_trace_dir: Option<&OsStr>,
         bind_to_cpu: Option<u32>,
+        compression: Compression,
     ) -> TraceWriterFileBackend {
         let trace_stream =
             TraceStream::new(&make_trace_dir(file_name, output_trace_dir), 1, bind_to_cpu);
         let mut tw = TraceWriterFileBackend {
             trace_stream,
             writers: HashMap::new(),
+            frame_index_fd: ScopedFd::new(),
+            frame_index_failed: false,
+            frame_boundary: Default::default(),
         };
+        // Nothing has been written before the first frame.
+        tw.frame_boundary.time = tw.global_time;
 
         for s in substreams_data() {
             let filename = tw.path(s.substream);
             tw.writers.insert(
                 s.substream,
-                CompressedWriter::new(&filename, s.block_size, s.threads),
+                CompressedWriter::new(&filename, s.block_size, s.threads, compression),
             );
         }
 
@@ -83,6 +105,28 @@ impl TraceWriterFileBackend {
         path_vec.extend_from_slice(substream(s).name.as_bytes());
         OsString::from_vec(path_vec)
     }
+
+    fn append_to_frame_index(&mut self, checkpoint: &FrameIndexCheckpoint) {
+        if self.frame_index_failed {
+            return;
+        }
+        if !self.frame_index_fd.is_open() {
+            let path = self.frame_index_path();
+            self.frame_index_fd = ScopedFd::open_path_with_mode(
+                path.as_os_str(),
+                OFlag::O_CLOEXEC | OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL,
+                Mode::S_IRUSR,
+            );
+            if !self.frame_index_fd.is_open() {
+                // The index only speeds up some read-only tools. The trace is
+                // perfectly usable without it.
+                log!(LogWarn, "Unable to create {:?}", path);
+                self.frame_index_failed = true;
+                return;
+            }
+        }
+        write_all(self.frame_index_fd.as_raw(), u8_slice(checkpoint));
+    }
 }
 
 impl TraceWriterBackend for TraceWriterFileBackend {
@@ -91,6 +135,29 @@ impl TraceWriterBackend for TraceWriterFileBackend {
             let mut w = self.writers.remove(&s.substream).unwrap();
             w.close(None);
         }
+        self.frame_index_fd.close();
+    }
+
+    fn note_frame_boundary(&mut self) {
+        let mut offsets = [0u64; SUBSTREAM_COUNT];
+        for s in substreams_data() {
+            offsets[s.substream as usize] = self.writer(s.substream).uncompressed_pos();
+        }
+        self.frame_boundary = FrameIndexCheckpoint {
+            time: self.global_time,
+            offsets,
+        };
+    }
+
+    fn record_index_checkpoint(&mut self) {
+        let checkpoint = self.frame_boundary;
+        self.append_to_frame_index(&checkpoint);
+    }
+
+    fn flush(&mut self) {
+        for w in self.writers.values_mut() {
+            w.flush_partial_block();
+        }
     }
 
     fn write_message(
@@ -60,7 +60,7 @@ use nix::{
     },
     NixPath,
 };
-use rand::random;
+use rand::{random, Rng};
 use regex::bytes::Regex;
 use std::{
     cmp::{max, min},
@@ -1224,7 +1224,7 @@ pub enum BindCPU {
 
 /// Pick a CPU at random to bind to, unless --cpu-unbound has been given,
 /// in which case we return -1.
-pub fn choose_cpu(bind_cpu: BindCPU) -> Option<u32> {
+pub fn choose_cpu<R: Rng>(bind_cpu: BindCPU, rng: &mut R) -> Option<u32> {
     match bind_cpu {
         BindCPU::UnboundCPU => None,
         // Pin tracee tasks to a random logical CPU, both in
@@ -1243,7 +1243,7 @@ pub fn choose_cpu(bind_cpu: BindCPU) -> Option<u32> {
         // better interaction with CPU frequency scaling.
         BindCPU::BindToCPU(num) => Some(num),
         BindCPU::RandomCPU => {
-            let maybe_cpu = get_random_cpu_cgroup();
+            let maybe_cpu = get_random_cpu_cgroup(rng);
             match maybe_cpu {
                 Ok(cpu) => Some(cpu),
                 Err(e) => {
@@ -1253,7 +1253,7 @@ pub fn choose_cpu(bind_cpu: BindCPU) -> Option<u32> {
                          Continuing using a simpler approach.",
                         e
                     );
-                    Some(random::<u32>() % get_num_cpus())
+                    Some(rng.gen::<u32>() % get_num_cpus())
                 }
             }
         }
@@ -1275,7 +1275,7 @@ enum CpuParseState {
 }
 
 /// Read and parse the available CPU list then select a random CPU from the list.
-pub fn get_random_cpu_cgroup() -> io::Result<u32> {
+pub fn get_random_cpu_cgroup<R: Rng>(rng: &mut R) -> io::Result<u32> {
     let self_cpuset_file = File::open("/proc/self/cpuset")?;
     let mut self_cpuset = BufReader::new(self_cpuset_file);
     let mut cpuset_path: Vec<u8> = Vec::new();
@@ -1402,7 +1402,7 @@ pub fn get_random_cpu_cgroup() -> io::Result<u32> {
         };
     }
 
-    Ok(cpus[random::<usize>() % cpus.len()])
+    Ok(cpus[rng.gen::<usize>() % cpus.len()])
 }
 
 /// If you are specifying multiple strings to match, they must all appear one after another
@@ -1651,6 +1651,7 @@ pub fn should_checksum(event: &Event, time: FrameTime) -> bool {
         Checksum::Syscall => is_syscall_exit,
         Checksum::All => true,
         Checksum::At(at_time) => time >= at_time,
+        Checksum::Every(interval) => time % interval == 0,
     }
 }
 
@@ -1672,6 +1673,11 @@ pub fn is_proc_mem_file(filename_os: &OsStr) -> bool {
     filename.starts_with(b"/proc/") && filename.ends_with(b"/mem")
 }
 
+pub fn is_proc_comm_file(filename_os: &OsStr) -> bool {
+    let filename = filename_os.as_bytes();
+    filename.starts_with(b"/proc/") && filename.ends_with(b"/comm")
+}
+
 pub fn is_proc_fd_dir(filename_os: &OsStr) -> bool {
     let filename = filename_os.as_bytes();
     filename.starts_with(b"/proc/") && (filename.ends_with(b"/fd") || filename.ends_with(b"/fd/"))
@@ -1835,7 +1841,8 @@ enum ChecksumMode {
 
 enum ChecksumData {
     ValidateChecksums(BufReader<File>),
-    StoreChecksums(BufWriter<File>),
+    /// The mapping checksums and the page checksums.
+    StoreChecksums(BufWriter<File>, BufWriter<File>),
 }
 
 struct ParsedChecksumLine {
@@ -1851,17 +1858,20 @@ const SIGBUS_CHECKSUM: u32 = 0x23456789;
 /// address space, or validate an existing computed checksum.  Behavior
 /// is selected by `mode`.
 fn iterate_checksums(t: &dyn Task, mode: ChecksumMode, global_time: FrameTime) {
-    let mut filename_vec: Vec<u8> = t.trace_dir().into_vec();
-    write!(filename_vec, "/{}_{}", global_time, t.rec_tid()).unwrap();
-    let filename = OsString::from_vec(filename_vec);
+    let filename = checksum_filename(t, global_time);
     let mut checksum_data = match mode {
         ChecksumMode::StoreChecksums => {
-            let maybe_file = File::create(filename.clone());
-            match maybe_file {
-                Ok(file) => ChecksumData::StoreChecksums(BufWriter::new(file)),
+            let pages_filename = page_checksum_filename(t, global_time);
+            let maybe_files = File::create(filename.clone())
+                .and_then(|file| Ok((file, File::create(pages_filename.clone())?)));
+            match maybe_files {
+                Ok((file, pages_file)) => {
+                    ChecksumData::StoreChecksums(BufWriter::new(file), BufWriter::new(pages_file))
+                }
                 Err(e) => fatal!(
-                    "Failed to open checksum file {:?}: error was {:?}",
+                    "Failed to open checksum files {:?} and {:?}: error was {:?}",
                     filename,
+                    pages_filename,
                     e
                 ),
             }
@@ -1892,38 +1902,26 @@ fn iterate_checksums(t: &dyn Task, mode: ChecksumMode, global_time: FrameTime) {
         ChecksumData::ValidateChecksums(file) => loop {
             let mut buf = Vec::<u8>::new();
 
-            let nread = match file.read_until(b'\n', &mut buf) {
+            match file.read_until(b'\n', &mut buf) {
                 Ok(0) | Err(_) => break,
-                Ok(nread) => nread,
+                Ok(_) => (),
             };
 
-            let startparen = find(&buf, b"(").unwrap();
-            let endparen = find(&buf, b")").unwrap();
-            let space = find(&buf, b" ").unwrap();
-            let dash = find(&buf, b"-").unwrap();
-            let space2 = find(&buf[dash + 1..], b" ").unwrap() + dash + 1;
-            ed_assert!(t, nread > dash + 1);
-
-            let mut dummy: &[u8] = Default::default();
-            let checksum: u32 = str16_to_usize(&buf[startparen + 1..endparen], &mut dummy)
-                .unwrap()
-                .try_into()
-                .unwrap();
-            let rec_start =
-                RemotePtr::from(str16_to_usize(&buf[space + 1..dash], &mut dummy).unwrap());
-            let rec_end =
-                RemotePtr::from(str16_to_usize(&buf[dash + 1..space2], &mut dummy).unwrap());
-            checksums.push(ParsedChecksumLine {
-                start: rec_start,
-                end: rec_end,
-                checksum,
-            });
+            let parsed = match parse_checksum_line(&buf) {
+                Some(parsed) => parsed,
+                None => fatal!(
+                    "Malformed line in checksum file {:?}: {}",
+                    filename,
+                    String::from_utf8_lossy(&buf)
+                ),
+            };
 
-            let mem_range = MemoryRange::from_range(rec_start, rec_end);
+            let mem_range = MemoryRange::from_range(parsed.start, parsed.end);
+            checksums.push(parsed);
             t.vm()
                 .ensure_replay_matches_single_recorded_mapping(t, mem_range);
         },
-        ChecksumData::StoreChecksums(_) => (),
+        ChecksumData::StoreChecksums(_, _) => (),
     }
 
     {
@@ -1994,7 +1992,7 @@ fn iterate_checksums(t: &dyn Task, mode: ChecksumMode, global_time: FrameTime) {
                         rec_checksum = parsed.checksum;
                     }
                 }
-                ChecksumData::StoreChecksums(checksums_file) => {
+                ChecksumData::StoreChecksums(checksums_file, _) => {
                     if !checksum_segment_filter(m) {
                         writeln!(checksums_file, "({:x}) {}", IGNORED_CHECKSUM, raw_map_line)
                             .unwrap();
@@ -2037,20 +2035,34 @@ fn iterate_checksums(t: &dyn Task, mode: ChecksumMode, global_time: FrameTime) {
             let checksum = compute_checksum(&mem);
 
             match &mut checksum_data {
-                ChecksumData::StoreChecksums(file) => {
+                ChecksumData::StoreChecksums(file, pages_file) => {
                     writeln!(file, "({:x}) {}", checksum, raw_map_line).unwrap();
+                    write!(
+                        pages_file,
+                        "{:x}-{:x}",
+                        m.map.start().as_usize(),
+                        m.map.end().as_usize()
+                    )
+                    .unwrap();
+                    for page in mem.chunks(page_size()) {
+                        write!(pages_file, " {:x}", compute_checksum(page)).unwrap();
+                    }
+                    writeln!(pages_file).unwrap();
                 }
                 ChecksumData::ValidateChecksums(_file) => {
                     ed_assert!(t, t.session().is_replaying());
 
                     // Ignore checksums when valid_mem_len == 0
                     if checksum != rec_checksum {
+                        let range = MemoryRange::from_range(m.map.start(), m.map.end());
+                        let maybe_first_page = first_diverging_page(t, range, global_time, &mem);
                         notify_checksum_error(
                             t.as_replay_task().unwrap(),
                             global_time,
                             checksum,
                             rec_checksum,
                             &raw_map_line,
+                            maybe_first_page,
                         );
                     }
                 }
@@ -2063,22 +2075,162 @@ fn iterate_checksums(t: &dyn Task, mode: ChecksumMode, global_time: FrameTime) {
     }
 }
 
+fn checksum_filename(t: &dyn Task, global_time: FrameTime) -> OsString {
+    let mut filename_vec: Vec<u8> = t.trace_dir().into_vec();
+    write!(filename_vec, "/{}_{}", global_time, t.rec_tid()).unwrap();
+    OsString::from_vec(filename_vec)
+}
+
+/// The checksum of every page of every checksummed mapping, one mapping per
+/// line. Only read when a mapping's checksum doesn't match, to find the
+/// first page that differs.
+fn page_checksum_filename(t: &dyn Task, global_time: FrameTime) -> OsString {
+    let mut filename_vec: Vec<u8> = t.trace_dir().into_vec();
+    write!(filename_vec, "/{}_{}_pages", global_time, t.rec_tid()).unwrap();
+    OsString::from_vec(filename_vec)
+}
+
+/// The page checksums recorded for exactly `range` at `global_time`. None if
+/// the recording has no page checksums for it, e.g. because it was made by
+/// an older rd.
+fn recorded_page_checksums(
+    t: &dyn Task,
+    range: MemoryRange,
+    global_time: FrameTime,
+) -> Option<Vec<u32>> {
+    let file = File::open(page_checksum_filename(t, global_time)).ok()?;
+    let wanted = format!(
+        "{:x}-{:x}",
+        range.start().as_usize(),
+        range.end().as_usize()
+    );
+    for line in BufReader::new(file).lines() {
+        let line = line.ok()?;
+        let mut fields = line.split(' ');
+        if fields.next() != Some(wanted.as_str()) {
+            continue;
+        }
+        return fields
+            .map(|field| u32::from_str_radix(field, 16).ok())
+            .collect();
+    }
+    None
+}
+
+/// The start of the first page of `range` whose contents `mem` (as read
+/// during replay) don't match the recording.
+fn first_diverging_page(
+    t: &dyn Task,
+    range: MemoryRange,
+    global_time: FrameTime,
+    mem: &[u8],
+) -> Option<RemotePtr<Void>> {
+    let recorded = recorded_page_checksums(t, range, global_time)?;
+    mem.chunks(page_size())
+        .zip(recorded.iter())
+        .position(|(page, &rec_checksum)| compute_checksum(page) != rec_checksum)
+        .map(|i| range.start() + i * page_size())
+}
+
+/// Parse a `(<checksum>) <start>-<end> ...` line of a checksum file.
+fn parse_checksum_line(line: &[u8]) -> Option<ParsedChecksumLine> {
+    let startparen = find(line, b"(")?;
+    let endparen = find(line, b")")?;
+    let space = find(line, b" ")?;
+    let dash = find(line, b"-")?;
+    let space2 = find(&line[dash + 1..], b" ")? + dash + 1;
+
+    let mut dummy: &[u8] = Default::default();
+    let checksum: u32 = str16_to_usize(&line[startparen + 1..endparen], &mut dummy)
+        .ok()?
+        .try_into()
+        .ok()?;
+    let start = RemotePtr::from(str16_to_usize(&line[space + 1..dash], &mut dummy).ok()?);
+    let end = RemotePtr::from(str16_to_usize(&line[dash + 1..space2], &mut dummy).ok()?);
+    Some(ParsedChecksumLine {
+        start,
+        end,
+        checksum,
+    })
+}
+
+/// `checksum_memory_range()` reads the tracee's memory in pieces of this
+/// size. A multiple of the checksum's word size.
+const CHECKSUM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest range `checksum_memory_range()` accepts. The checksum starts
+/// out as the length, which has to fit in a u32.
+pub const MAX_CHECKSUM_RANGE: usize = u32::MAX as usize;
+
+/// Checksum `range` of `t`'s memory the same way `checksum_process_memory()`
+/// checksums a whole mapping. Bytes that can't be read count as zero.
+pub fn checksum_memory_range(t: &dyn Task, range: MemoryRange) -> u32 {
+    debug_assert!(range.len() <= MAX_CHECKSUM_RANGE);
+    let mut checksum = range.len() as u32;
+    let mut chunk = vec![0u8; min(range.len(), CHECKSUM_CHUNK_SIZE)];
+    let mut addr = range.start();
+    while addr < range.end() {
+        let len = min(range.end() - addr, CHECKSUM_CHUNK_SIZE);
+        let buf = &mut chunk[0..len];
+        let nread = t.read_bytes_fallible(addr, buf).unwrap_or(0);
+        for b in &mut buf[nread..] {
+            *b = 0;
+        }
+        checksum = add_to_checksum(checksum, buf);
+        addr += len;
+    }
+    checksum
+}
+
+/// The checksum that was recorded for exactly `range` of `t`'s memory at
+/// `global_time`, if the recording was made with `--checksum` and covered
+/// that event.
+pub fn recorded_checksum(t: &dyn Task, range: MemoryRange, global_time: FrameTime) -> Option<u32> {
+    let file = File::open(checksum_filename(t, global_time)).ok()?;
+    BufReader::new(file)
+        .split(b'\n')
+        .filter_map(|line| parse_checksum_line(&line.ok()?))
+        .find(|parsed| parsed.start == range.start() && parsed.end == range.end())
+        .map(|parsed| parsed.checksum)
+        .filter(|&checksum| checksum != IGNORED_CHECKSUM && checksum != SIGBUS_CHECKSUM)
+}
+
 fn notify_checksum_error(
-    _t: &ReplayTask,
+    t: &ReplayTask,
     global_time: u64,
     checksum: u32,
     rec_checksum: u32,
     raw_map_line: &str,
+    maybe_first_page: Option<RemotePtr<Void>>,
 ) {
-    log!(
-        LogError,
-        "Checksum error at time {}: {}\nRecorded checksum: {:#x}, Replay checksum: {:#x}",
+    let cur_dump = format_dump_filename(t, global_time, "rep");
+    dump_process_memory(t, global_time, "rep");
+    let rec_dump = format_dump_filename(t, global_time, "rec");
+    let first_page = match maybe_first_page {
+        Some(page) => format!("The first page that differs starts at {}.\n\n", page),
+        None => String::new(),
+    };
+    ed_assert!(
+        t,
+        checksum == rec_checksum,
+        "Divergence in contents of memory segment at time {}:\n\n\
+         {}\n    (recorded checksum: {:#x}; replaying checksum: {:#x})\n\n\
+         {}\
+         Dumped current memory contents to {:?}. If you've created a memory dump for\n\
+         this event during recording by using, for example, the args\n\
+         $ rd --dump-at={} record ...\n\n\
+         then you can use the following to determine which memory cells differ:\n\n\
+         $ diff {:?} {:?} > mem-diff",
         global_time,
         raw_map_line,
         rec_checksum,
-        checksum
+        checksum,
+        first_page,
+        cur_dump,
+        global_time,
+        rec_dump,
+        cur_dump
     );
-    unimplemented!()
 }
 
 /// DIFF NOTE: Takes `t` instead of the address space as param
@@ -2127,7 +2279,13 @@ fn is_start_of_scratch_region(t: &dyn Task, start_addr: RemotePtr<Void>) -> bool
 }
 
 fn compute_checksum(data: &[u8]) -> u32 {
-    let mut checksum: u32 = data.len().try_into().unwrap();
+    add_to_checksum(data.len().try_into().unwrap(), data)
+}
+
+/// Fold the whole u32 words of `data` into `checksum`. Checksumming a buffer
+/// in pieces gives the same result as all at once, as long as every piece but
+/// the last is a whole number of words.
+fn add_to_checksum(mut checksum: u32, data: &[u8]) -> u32 {
     let words = data.len() / size_of::<u32>();
     let data_as_u32: &[u32] = unsafe { slice::from_raw_parts(data.as_ptr().cast(), words) };
 
@@ -2320,6 +2478,19 @@ pub fn str0_to_isize<'a>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn add_to_checksum_test() {
+        // Not a whole number of words, so the tail is ignored.
+        let data: Vec<u8> = (0..1001u32).map(|i| (i * 7) as u8).collect();
+        let whole = compute_checksum(&data);
+        let mut pieces = data.len() as u32;
+        for chunk in data.chunks(64) {
+            pieces = add_to_checksum(pieces, chunk);
+        }
+        assert_eq!(whole, pieces);
+        assert_eq!(3, compute_checksum(&[0xff, 0xff, 0xff]));
+    }
+
     #[test]
     fn str16_to_usize_test() {
         let mut sl = b"  -ff apples".as_slice();
@@ -0,0 +1,77 @@
+//! Record small programs with rd and replay the traces.
+//!
+//! These need a machine rd can record on (ptrace and hardware performance
+//! counters), so they are ignored by default. Run them with
+//! `cargo test -- --ignored`.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    thread,
+    time::Duration,
+};
+
+/// A trace directory that doesn't exist yet. `rd record -o` insists on
+/// creating it.
+fn trace_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rd-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn rd() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_rd"))
+}
+
+fn replay(dir: &Path) -> Output {
+    rd().arg("replay").arg("-a").arg(dir).output().unwrap()
+}
+
+#[test]
+#[ignore]
+fn only_record_detached_task_replays() {
+    let dir = trace_dir("only-record");
+    // The child that execs /bin/true matches no --only-record glob, so it's
+    // detached and leaves the trace partway through.
+    let recorded = rd()
+        .arg("record")
+        .arg("-o")
+        .arg(&dir)
+        .args(&["--only-record", "sh *"])
+        .args(&["sh", "-c", "printf a; /bin/true; printf b"])
+        .output()
+        .unwrap();
+    assert!(recorded.status.success(), "{:?}", recorded);
+    assert_eq!(recorded.stdout, b"ab");
+
+    let replayed = replay(&dir);
+    assert!(replayed.status.success(), "{:?}", replayed);
+    assert_eq!(replayed.stdout, b"ab");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore]
+fn killed_recording_replays_up_to_where_it_stopped() {
+    let dir = trace_dir("killed");
+    let mut recording = rd()
+        .arg("record")
+        .arg("-o")
+        .arg(&dir)
+        .args(&["sh", "-c", "printf a; sleep 6; printf b; sleep 30"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // The first sleep outlasts the trace writer's 5 second sync interval,
+    // so the frames up to it get flushed once the tracee wakes up. Whether
+    // the "b" made it depends on when that flush happened.
+    thread::sleep(Duration::from_secs(8));
+    recording.kill().unwrap();
+    recording.wait().unwrap();
+
+    let replayed = replay(&dir);
+    assert!(replayed.status.success(), "{:?}", replayed);
+    assert!(replayed.stdout.starts_with(b"a"), "{:?}", replayed);
+    fs::remove_dir_all(&dir).unwrap();
+}